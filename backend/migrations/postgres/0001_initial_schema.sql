@@ -0,0 +1,193 @@
+-- Baseline schema (formerly sql/schema.sql). Every statement is idempotent so
+-- databases that had that file applied by hand upgrade in place. Later schema
+-- changes go in new numbered files; never edit an applied migration.
+
+CREATE TABLE IF NOT EXISTS diary_sync (
+    uuid TEXT PRIMARY KEY,
+    author TEXT NOT NULL,
+    timestamp BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL,
+    payload_iv TEXT NOT NULL,
+    payload_data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS todo_sync (
+    uuid TEXT PRIMARY KEY,
+    author TEXT NOT NULL,
+    is_completed BOOLEAN NOT NULL,
+    created_at BIGINT NOT NULL,
+    completed_at BIGINT NULL,
+    updated_at BIGINT NOT NULL,
+    payload_iv TEXT NOT NULL,
+    payload_data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS period_sync (
+    start_date DATE PRIMARY KEY,
+    end_date DATE NOT NULL,
+    updated_at BIGINT NOT NULL,
+    payload_iv TEXT NOT NULL,
+    payload_data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS diary_images (
+    hash TEXT PRIMARY KEY,
+    blob_iv TEXT NOT NULL,
+    blob_data TEXT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS diary_image_refs (
+    diary_uuid TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    updated_at BIGINT NOT NULL,
+    PRIMARY KEY (diary_uuid, file_name)
+);
+
+CREATE INDEX IF NOT EXISTS idx_diary_image_refs_hash ON diary_image_refs(hash);
+
+CREATE TABLE IF NOT EXISTS sync_tombstones (
+    kind TEXT NOT NULL,
+    uuid TEXT NOT NULL,
+    deleted_at BIGINT NOT NULL,
+    PRIMARY KEY (kind, uuid)
+);
+
+-- Server-assigned change sequence shared by all synced tables; /sync/changes
+-- returns rows with change_seq greater than the client's cursor.
+CREATE SEQUENCE IF NOT EXISTS sync_change_seq;
+
+ALTER TABLE diary_sync ADD COLUMN IF NOT EXISTS change_seq BIGINT NOT NULL DEFAULT nextval('sync_change_seq');
+ALTER TABLE todo_sync ADD COLUMN IF NOT EXISTS change_seq BIGINT NOT NULL DEFAULT nextval('sync_change_seq');
+ALTER TABLE period_sync ADD COLUMN IF NOT EXISTS change_seq BIGINT NOT NULL DEFAULT nextval('sync_change_seq');
+ALTER TABLE sync_tombstones ADD COLUMN IF NOT EXISTS change_seq BIGINT NOT NULL DEFAULT nextval('sync_change_seq');
+
+CREATE INDEX IF NOT EXISTS idx_diary_sync_change_seq ON diary_sync(change_seq);
+CREATE INDEX IF NOT EXISTS idx_todo_sync_change_seq ON todo_sync(change_seq);
+CREATE INDEX IF NOT EXISTS idx_period_sync_change_seq ON period_sync(change_seq);
+CREATE INDEX IF NOT EXISTS idx_sync_tombstones_change_seq ON sync_tombstones(change_seq);
+
+-- Prior encrypted versions of diaries and todos, archived whenever a sync
+-- write or deletion replaces them. Pruned to HISTORY_MAX_REVISIONS per uuid.
+CREATE TABLE IF NOT EXISTS diary_history (
+    revision_id BIGSERIAL PRIMARY KEY,
+    uuid TEXT NOT NULL,
+    author TEXT NOT NULL,
+    timestamp BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL,
+    payload_iv TEXT NOT NULL,
+    payload_data TEXT NOT NULL,
+    archived_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_diary_history_uuid ON diary_history(uuid, revision_id);
+
+CREATE TABLE IF NOT EXISTS todo_history (
+    revision_id BIGSERIAL PRIMARY KEY,
+    uuid TEXT NOT NULL,
+    author TEXT NOT NULL,
+    is_completed BOOLEAN NOT NULL,
+    created_at BIGINT NOT NULL,
+    completed_at BIGINT NULL,
+    updated_at BIGINT NOT NULL,
+    payload_iv TEXT NOT NULL,
+    payload_data TEXT NOT NULL,
+    archived_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_todo_history_uuid ON todo_history(uuid, revision_id);
+
+-- Phones that talk to the server, identified by the X-Device-Id header.
+-- Times are server epoch milliseconds.
+CREATE TABLE IF NOT EXISTS devices (
+    device_id TEXT PRIMARY KEY,
+    name TEXT NOT NULL DEFAULT '',
+    app_version TEXT NOT NULL DEFAULT '',
+    first_seen BIGINT NOT NULL,
+    last_seen BIGINT NOT NULL,
+    last_upload_at BIGINT NULL,
+    last_download_at BIGINT NULL
+);
+
+-- Multi-account tenancy: every row belongs to the account whose API key wrote
+-- it. Rows that predate accounts land in the 'default' account, and primary
+-- keys are widened so two accounts may reuse the same uuid/hash.
+DO $$
+DECLARE
+    t RECORD;
+    pk_name TEXT;
+BEGIN
+    FOR t IN
+        SELECT * FROM (VALUES
+            ('diary_sync', 'uuid'),
+            ('todo_sync', 'uuid'),
+            ('period_sync', 'start_date'),
+            ('diary_images', 'hash'),
+            ('diary_image_refs', 'diary_uuid, file_name'),
+            ('sync_tombstones', 'kind, uuid'),
+            ('devices', 'device_id')
+        ) AS v(table_name, key_columns)
+    LOOP
+        EXECUTE format(
+            'ALTER TABLE %I ADD COLUMN IF NOT EXISTS account_id TEXT NOT NULL DEFAULT %L',
+            t.table_name, 'default'
+        );
+        SELECT c.conname INTO pk_name
+        FROM pg_constraint c
+        WHERE c.conrelid = t.table_name::regclass AND c.contype = 'p'
+            AND NOT EXISTS (
+                SELECT 1 FROM pg_attribute a
+                WHERE a.attrelid = c.conrelid AND a.attnum = ANY (c.conkey)
+                    AND a.attname = 'account_id'
+            );
+        IF pk_name IS NOT NULL THEN
+            EXECUTE format('ALTER TABLE %I DROP CONSTRAINT %I', t.table_name, pk_name);
+            EXECUTE format(
+                'ALTER TABLE %I ADD PRIMARY KEY (account_id, %s)',
+                t.table_name, t.key_columns
+            );
+        END IF;
+    END LOOP;
+END $$;
+
+ALTER TABLE diary_history ADD COLUMN IF NOT EXISTS account_id TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE todo_history ADD COLUMN IF NOT EXISTS account_id TEXT NOT NULL DEFAULT 'default';
+
+DROP INDEX IF EXISTS idx_diary_image_refs_hash;
+DROP INDEX IF EXISTS idx_diary_history_uuid;
+DROP INDEX IF EXISTS idx_todo_history_uuid;
+CREATE INDEX IF NOT EXISTS idx_diary_image_refs_account_hash ON diary_image_refs(account_id, hash);
+CREATE INDEX IF NOT EXISTS idx_diary_history_account_uuid ON diary_history(account_id, uuid, revision_id);
+CREATE INDEX IF NOT EXISTS idx_todo_history_account_uuid ON todo_history(account_id, uuid, revision_id);
+
+-- Stable period ids: a period keeps its uuid when its start date is corrected.
+-- Rows (and tombstones) from before period uuids get 'period-<start_date>'.
+ALTER TABLE period_sync ADD COLUMN IF NOT EXISTS uuid TEXT;
+UPDATE period_sync SET uuid = 'period-' || start_date::text WHERE uuid IS NULL;
+ALTER TABLE period_sync ALTER COLUMN uuid SET NOT NULL;
+UPDATE sync_tombstones t SET uuid = 'period-' || t.uuid
+WHERE t.kind = 'period' AND t.uuid ~ '^\d{4}-\d{2}-\d{2}$'
+    AND NOT EXISTS (
+        SELECT 1 FROM sync_tombstones o
+        WHERE o.account_id = t.account_id AND o.kind = 'period' AND o.uuid = 'period-' || t.uuid
+    );
+
+DO $$
+DECLARE
+    pk_name TEXT;
+BEGIN
+    SELECT c.conname INTO pk_name
+    FROM pg_constraint c
+    WHERE c.conrelid = 'period_sync'::regclass AND c.contype = 'p'
+        AND NOT EXISTS (
+            SELECT 1 FROM pg_attribute a
+            WHERE a.attrelid = c.conrelid AND a.attnum = ANY (c.conkey) AND a.attname = 'uuid'
+        );
+    IF pk_name IS NOT NULL THEN
+        EXECUTE format('ALTER TABLE period_sync DROP CONSTRAINT %I', pk_name);
+        ALTER TABLE period_sync ADD PRIMARY KEY (account_id, uuid);
+    END IF;
+END $$;
+
+CREATE INDEX IF NOT EXISTS idx_period_sync_account_start_date ON period_sync(account_id, start_date);
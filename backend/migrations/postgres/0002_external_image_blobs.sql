@@ -0,0 +1,3 @@
+-- Image ciphertext may live in a blob store (BLOB_STORE) instead of inline;
+-- such rows keep only metadata and have a NULL blob_data.
+ALTER TABLE diary_images ALTER COLUMN blob_data DROP NOT NULL;
@@ -0,0 +1,21 @@
+-- Resumable image uploads: a session per (account, hash) collects chunks until
+-- it is finalized into diary_images. `received` is the next expected offset.
+CREATE TABLE IF NOT EXISTS image_uploads (
+    account_id TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    blob_iv TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    received BIGINT NOT NULL DEFAULT 0,
+    updated_at BIGINT NOT NULL,
+    created_at BIGINT NOT NULL,
+    PRIMARY KEY (account_id, hash)
+);
+
+CREATE TABLE IF NOT EXISTS image_upload_chunks (
+    account_id TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    chunk_offset BIGINT NOT NULL,
+    data BYTEA NOT NULL,
+    PRIMARY KEY (account_id, hash, chunk_offset),
+    FOREIGN KEY (account_id, hash) REFERENCES image_uploads (account_id, hash) ON DELETE CASCADE
+);
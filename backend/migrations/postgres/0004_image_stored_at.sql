@@ -0,0 +1,7 @@
+-- Server time (ms) an image row was last written, so garbage collection can
+-- spare recent uploads whose refs have not arrived yet. `updated_at` comes
+-- from the client and cannot be used for that. Existing rows count as
+-- written now and get a full grace period.
+ALTER TABLE diary_images ADD COLUMN IF NOT EXISTS stored_at BIGINT NOT NULL DEFAULT 0;
+
+UPDATE diary_images SET stored_at = (EXTRACT(EPOCH FROM now()) * 1000)::BIGINT;
@@ -0,0 +1,15 @@
+-- Server-side API key registry. Only the SHA-256 of a key is stored; `scopes`
+-- is a comma-separated list of read / write / admin. Revoked keys are kept so
+-- their last use stays visible.
+CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY,
+    account_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    last_used_at BIGINT,
+    revoked_at BIGINT
+);
+
+CREATE INDEX IF NOT EXISTS api_keys_account_idx ON api_keys (account_id);
@@ -0,0 +1,7 @@
+-- Start date of a deleted period, so clients that still key periods by date
+-- can be told about the deletion. Tombstones of backfilled periods carry it
+-- in their uuid.
+ALTER TABLE sync_tombstones ADD COLUMN IF NOT EXISTS start_date DATE;
+
+UPDATE sync_tombstones SET start_date = CAST(substring(uuid FROM 8) AS DATE)
+WHERE kind = 'period' AND uuid ~ '^period-[0-9]{4}-[0-9]{2}-[0-9]{2}$';
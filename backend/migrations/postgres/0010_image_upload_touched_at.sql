@@ -0,0 +1,8 @@
+-- Last activity on a resumable upload (begin, resume or chunk), so sessions
+-- abandoned for longer than IMAGE_UPLOAD_TTL_SECS can be swept with their
+-- chunks.
+ALTER TABLE image_uploads ADD COLUMN IF NOT EXISTS touched_at BIGINT NOT NULL DEFAULT 0;
+
+UPDATE image_uploads SET touched_at = created_at WHERE touched_at = 0;
+
+CREATE INDEX IF NOT EXISTS idx_image_uploads_touched_at ON image_uploads (touched_at);
@@ -0,0 +1,188 @@
+-- SQLite version of the Postgres baseline (the tables as they stand after
+-- every upgrade step there). Dates are 'YYYY-MM-DD'
+-- text, booleans are 0/1.
+
+CREATE TABLE IF NOT EXISTS diary_sync (
+    account_id TEXT NOT NULL DEFAULT 'default',
+    uuid TEXT NOT NULL,
+    author TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    payload_iv TEXT NOT NULL,
+    payload_data TEXT NOT NULL,
+    change_seq INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (account_id, uuid)
+);
+
+CREATE TABLE IF NOT EXISTS todo_sync (
+    account_id TEXT NOT NULL DEFAULT 'default',
+    uuid TEXT NOT NULL,
+    author TEXT NOT NULL,
+    is_completed INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    completed_at INTEGER NULL,
+    updated_at INTEGER NOT NULL,
+    payload_iv TEXT NOT NULL,
+    payload_data TEXT NOT NULL,
+    change_seq INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (account_id, uuid)
+);
+
+CREATE TABLE IF NOT EXISTS period_sync (
+    account_id TEXT NOT NULL DEFAULT 'default',
+    uuid TEXT NOT NULL,
+    start_date TEXT NOT NULL,
+    end_date TEXT NOT NULL,
+    updated_at INTEGER NOT NULL,
+    payload_iv TEXT NOT NULL,
+    payload_data TEXT NOT NULL,
+    change_seq INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (account_id, uuid)
+);
+
+CREATE INDEX IF NOT EXISTS idx_period_sync_account_start_date ON period_sync(account_id, start_date);
+
+CREATE TABLE IF NOT EXISTS diary_images (
+    account_id TEXT NOT NULL DEFAULT 'default',
+    hash TEXT NOT NULL,
+    blob_iv TEXT NOT NULL,
+    blob_data TEXT NOT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (account_id, hash)
+);
+
+CREATE TABLE IF NOT EXISTS diary_image_refs (
+    account_id TEXT NOT NULL DEFAULT 'default',
+    diary_uuid TEXT NOT NULL,
+    file_name TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (account_id, diary_uuid, file_name)
+);
+
+CREATE INDEX IF NOT EXISTS idx_diary_image_refs_account_hash ON diary_image_refs(account_id, hash);
+
+CREATE TABLE IF NOT EXISTS sync_tombstones (
+    account_id TEXT NOT NULL DEFAULT 'default',
+    kind TEXT NOT NULL,
+    uuid TEXT NOT NULL,
+    deleted_at INTEGER NOT NULL,
+    change_seq INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (account_id, kind, uuid)
+);
+
+-- SQLite has no sequences: a single-row counter stands in for sync_change_seq
+-- and triggers stamp every inserted or updated row with the next value.
+CREATE TABLE IF NOT EXISTS sync_change_seq (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    value INTEGER NOT NULL
+);
+
+INSERT OR IGNORE INTO sync_change_seq (id, value) VALUES (1, 0);
+
+CREATE TRIGGER IF NOT EXISTS diary_sync_change_seq_insert AFTER INSERT ON diary_sync
+BEGIN
+    UPDATE sync_change_seq SET value = value + 1 WHERE id = 1;
+    UPDATE diary_sync SET change_seq = (SELECT value FROM sync_change_seq WHERE id = 1)
+    WHERE rowid = NEW.rowid;
+END;
+
+CREATE TRIGGER IF NOT EXISTS diary_sync_change_seq_update AFTER UPDATE ON diary_sync
+WHEN NEW.change_seq = OLD.change_seq
+BEGIN
+    UPDATE sync_change_seq SET value = value + 1 WHERE id = 1;
+    UPDATE diary_sync SET change_seq = (SELECT value FROM sync_change_seq WHERE id = 1)
+    WHERE rowid = NEW.rowid;
+END;
+
+CREATE TRIGGER IF NOT EXISTS todo_sync_change_seq_insert AFTER INSERT ON todo_sync
+BEGIN
+    UPDATE sync_change_seq SET value = value + 1 WHERE id = 1;
+    UPDATE todo_sync SET change_seq = (SELECT value FROM sync_change_seq WHERE id = 1)
+    WHERE rowid = NEW.rowid;
+END;
+
+CREATE TRIGGER IF NOT EXISTS todo_sync_change_seq_update AFTER UPDATE ON todo_sync
+WHEN NEW.change_seq = OLD.change_seq
+BEGIN
+    UPDATE sync_change_seq SET value = value + 1 WHERE id = 1;
+    UPDATE todo_sync SET change_seq = (SELECT value FROM sync_change_seq WHERE id = 1)
+    WHERE rowid = NEW.rowid;
+END;
+
+CREATE TRIGGER IF NOT EXISTS period_sync_change_seq_insert AFTER INSERT ON period_sync
+BEGIN
+    UPDATE sync_change_seq SET value = value + 1 WHERE id = 1;
+    UPDATE period_sync SET change_seq = (SELECT value FROM sync_change_seq WHERE id = 1)
+    WHERE rowid = NEW.rowid;
+END;
+
+CREATE TRIGGER IF NOT EXISTS period_sync_change_seq_update AFTER UPDATE ON period_sync
+WHEN NEW.change_seq = OLD.change_seq
+BEGIN
+    UPDATE sync_change_seq SET value = value + 1 WHERE id = 1;
+    UPDATE period_sync SET change_seq = (SELECT value FROM sync_change_seq WHERE id = 1)
+    WHERE rowid = NEW.rowid;
+END;
+
+CREATE TRIGGER IF NOT EXISTS sync_tombstones_change_seq_insert AFTER INSERT ON sync_tombstones
+BEGIN
+    UPDATE sync_change_seq SET value = value + 1 WHERE id = 1;
+    UPDATE sync_tombstones SET change_seq = (SELECT value FROM sync_change_seq WHERE id = 1)
+    WHERE rowid = NEW.rowid;
+END;
+
+CREATE TRIGGER IF NOT EXISTS sync_tombstones_change_seq_update AFTER UPDATE ON sync_tombstones
+WHEN NEW.change_seq = OLD.change_seq
+BEGIN
+    UPDATE sync_change_seq SET value = value + 1 WHERE id = 1;
+    UPDATE sync_tombstones SET change_seq = (SELECT value FROM sync_change_seq WHERE id = 1)
+    WHERE rowid = NEW.rowid;
+END;
+
+CREATE INDEX IF NOT EXISTS idx_diary_sync_change_seq ON diary_sync(change_seq);
+CREATE INDEX IF NOT EXISTS idx_todo_sync_change_seq ON todo_sync(change_seq);
+CREATE INDEX IF NOT EXISTS idx_period_sync_change_seq ON period_sync(change_seq);
+CREATE INDEX IF NOT EXISTS idx_sync_tombstones_change_seq ON sync_tombstones(change_seq);
+
+CREATE TABLE IF NOT EXISTS diary_history (
+    revision_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    account_id TEXT NOT NULL DEFAULT 'default',
+    uuid TEXT NOT NULL,
+    author TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    payload_iv TEXT NOT NULL,
+    payload_data TEXT NOT NULL,
+    archived_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_diary_history_account_uuid ON diary_history(account_id, uuid, revision_id);
+
+CREATE TABLE IF NOT EXISTS todo_history (
+    revision_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    account_id TEXT NOT NULL DEFAULT 'default',
+    uuid TEXT NOT NULL,
+    author TEXT NOT NULL,
+    is_completed INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    completed_at INTEGER NULL,
+    updated_at INTEGER NOT NULL,
+    payload_iv TEXT NOT NULL,
+    payload_data TEXT NOT NULL,
+    archived_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_todo_history_account_uuid ON todo_history(account_id, uuid, revision_id);
+
+CREATE TABLE IF NOT EXISTS devices (
+    account_id TEXT NOT NULL DEFAULT 'default',
+    device_id TEXT NOT NULL,
+    name TEXT NOT NULL DEFAULT '',
+    app_version TEXT NOT NULL DEFAULT '',
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    last_upload_at INTEGER NULL,
+    last_download_at INTEGER NULL,
+    PRIMARY KEY (account_id, device_id)
+);
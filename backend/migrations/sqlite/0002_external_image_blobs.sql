@@ -0,0 +1,18 @@
+-- Image ciphertext may live in a blob store (BLOB_STORE) instead of inline;
+-- such rows keep only metadata and have a NULL blob_data. SQLite cannot drop
+-- NOT NULL in place, so the table is rebuilt.
+CREATE TABLE diary_images_new (
+    account_id TEXT NOT NULL DEFAULT 'default',
+    hash TEXT NOT NULL,
+    blob_iv TEXT NOT NULL,
+    blob_data TEXT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (account_id, hash)
+);
+
+INSERT INTO diary_images_new (account_id, hash, blob_iv, blob_data, updated_at)
+SELECT account_id, hash, blob_iv, blob_data, updated_at FROM diary_images;
+
+DROP TABLE diary_images;
+
+ALTER TABLE diary_images_new RENAME TO diary_images;
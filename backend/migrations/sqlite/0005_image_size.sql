@@ -0,0 +1,4 @@
+-- Ciphertext length in bytes, so clients can ask which of their images the
+-- server lacks or holds with a different size. Rows written before this
+-- migration stay NULL (size unknown) until they are uploaded again.
+ALTER TABLE diary_images ADD COLUMN size INTEGER;
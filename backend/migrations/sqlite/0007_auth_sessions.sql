@@ -0,0 +1,17 @@
+-- Login sessions behind short-lived access tokens. A session holds the hash of
+-- its current refresh token, which is replaced on every refresh; `expires_at`
+-- is when that refresh token lapses. `key_id` is the registry key the session
+-- was opened with (NULL for environment keys).
+CREATE TABLE IF NOT EXISTS auth_sessions (
+    id TEXT PRIMARY KEY,
+    account_id TEXT NOT NULL,
+    key_id TEXT,
+    scopes TEXT NOT NULL,
+    refresh_hash TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    refreshed_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    revoked_at INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS auth_sessions_account_idx ON auth_sessions (account_id);
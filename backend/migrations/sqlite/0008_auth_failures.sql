@@ -0,0 +1,12 @@
+-- Audit log of refused credentials (API keys, access and refresh tokens, the
+-- admin key), by client IP. Requests refused while a client is locked out are
+-- not logged.
+CREATE TABLE IF NOT EXISTS auth_failures (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    ip TEXT NOT NULL,
+    path TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS auth_failures_created_idx ON auth_failures (created_at);
@@ -0,0 +1,7 @@
+-- Start date of a deleted period, so clients that still key periods by date
+-- can be told about the deletion. Tombstones of backfilled periods carry it
+-- in their uuid.
+ALTER TABLE sync_tombstones ADD COLUMN start_date TEXT;
+
+UPDATE sync_tombstones SET start_date = substr(uuid, 8)
+WHERE kind = 'period' AND uuid GLOB 'period-[0-9][0-9][0-9][0-9]-[0-9][0-9]-[0-9][0-9]';
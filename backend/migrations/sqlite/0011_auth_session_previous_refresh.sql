@@ -0,0 +1,9 @@
+-- The refresh token a session's current one replaced. Presenting it again
+-- means someone else holds a copy, which revokes the session; any other
+-- unknown refresh token is just refused. Refresh tokens no longer embed the
+-- session id, so sessions are looked up by either hash.
+ALTER TABLE auth_sessions ADD COLUMN previous_refresh_hash TEXT;
+
+CREATE INDEX IF NOT EXISTS auth_sessions_refresh_idx ON auth_sessions (refresh_hash);
+CREATE INDEX IF NOT EXISTS auth_sessions_previous_refresh_idx
+    ON auth_sessions (previous_refresh_hash);
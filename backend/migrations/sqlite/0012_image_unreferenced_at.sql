@@ -0,0 +1,12 @@
+-- Server time (ms) an image was left without refs, either stored with none or
+-- by losing its last one; NULL while a ref points at it. Garbage collection
+-- counts the grace period from here, so an image uploaded long ago is not
+-- deleted in the same run that sees its last ref go. Existing unreferenced
+-- rows count as unreferenced now and get a full grace period.
+ALTER TABLE diary_images ADD COLUMN unreferenced_at INTEGER;
+
+UPDATE diary_images SET unreferenced_at = CAST(strftime('%s', 'now') AS INTEGER) * 1000
+WHERE NOT EXISTS (
+    SELECT 1 FROM diary_image_refs r
+    WHERE r.account_id = diary_images.account_id AND r.hash = diary_images.hash
+);
@@ -0,0 +1,311 @@
+//! Credentials: the `API_KEY` / `ACCOUNT_KEYS` environment keys, the
+//! server-side key registry (`api_keys`), access tokens from `/auth/login`
+//! and `ADMIN_KEY`. Refused credentials count towards the client's lockout;
+//! see [`crate::lockout`].
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::future::Future;
+
+use crate::db::DEFAULT_ACCOUNT;
+use crate::lockout::{check_lockout, record_auth_failure, record_auth_success};
+use crate::models::ApiScope;
+use crate::ratelimit::check_rate_limit;
+use crate::storage::AuthSession;
+use crate::{header_str, AppState};
+
+/// A registry key's `last_used_at` is only rewritten once it is this old (ms),
+/// so authenticating is not a database write on every request.
+const KEY_TOUCH_INTERVAL_MS: i64 = 60_000;
+
+/// Constant-time string comparison to prevent timing attacks on API key validation.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut result = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        result |= x ^ y;
+    }
+    result == 0
+}
+
+/// Hex SHA-256 of a key, as stored in `api_keys.key_hash` (and of refresh
+/// secrets in `auth_sessions.refresh_hash`). Both are 256 random bits, so a
+/// plain hash is enough and allows lookup by hash.
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// A fresh `(id, key)` pair for the registry.
+pub(crate) fn generate_api_key() -> (String, String) {
+    (random_hex(8), format!("sk_{}", random_hex(32)))
+}
+
+pub(crate) fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes).expect("OS random source");
+    hex::encode(bytes)
+}
+
+/// Who a request authenticated as.
+pub(crate) struct Credential {
+    pub account_id: String,
+    pub scopes: Vec<ApiScope>,
+    /// The registry key behind the credential; `None` for environment keys.
+    pub key_id: Option<String>,
+    /// The login session, for access tokens.
+    pub session_id: Option<String>,
+}
+
+/// Looks `provided` up in the key registry; `None` when it is unknown or revoked.
+async fn registry_key(
+    state: &AppState,
+    provided: &str,
+) -> Result<Option<Credential>, HttpResponse> {
+    let key = match state.storage.api_key_by_hash(&hash_api_key(provided)).await {
+        Ok(Some(key)) if key.revoked_at.is_none() => key,
+        Ok(_) => return Ok(None),
+        Err(e) => {
+            warn!("auth: api key lookup failed: {}", e);
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    };
+    let now = Utc::now().timestamp_millis();
+    if key
+        .last_used_at
+        .is_none_or(|used| now - used >= KEY_TOUCH_INTERVAL_MS)
+    {
+        if let Err(e) = state.storage.touch_api_key(&key.id, now).await {
+            warn!("auth: recording use of key {} failed: {}", key.id, e);
+        }
+    }
+    Ok(Some(Credential {
+        account_id: key.account_id,
+        scopes: key.scopes,
+        key_id: Some(key.id),
+        session_id: None,
+    }))
+}
+
+/// Resolves `X-API-Key`. Environment keys grant read and write; every one is
+/// compared so timing does not reveal which one matched. Registry keys grant
+/// their own scopes. With no environment keys and nothing ever registered,
+/// auth is disabled and everything uses the default account.
+pub(crate) async fn api_key_credential(
+    req: &HttpRequest,
+    state: &AppState,
+) -> Result<Credential, HttpResponse> {
+    let legacy = state.env.api_key.trim();
+    let env_keys = !legacy.is_empty() || !state.env.account_keys.is_empty();
+    let provided = req
+        .headers()
+        .get("X-API-Key")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let env_credential = |account_id: String| Credential {
+        account_id,
+        scopes: vec![ApiScope::Read, ApiScope::Write],
+        key_id: None,
+        session_id: None,
+    };
+    if env_keys {
+        let mut account = None;
+        if !legacy.is_empty() && constant_time_eq(provided.as_bytes(), legacy.as_bytes()) {
+            account = Some(DEFAULT_ACCOUNT.to_string());
+        }
+        for entry in &state.env.account_keys {
+            if constant_time_eq(provided.as_bytes(), entry.key.as_bytes()) && account.is_none() {
+                account = Some(entry.account_id.clone());
+            }
+        }
+        if let Some(account) = account {
+            return Ok(env_credential(account));
+        }
+    }
+    if !provided.is_empty() {
+        if let Some(credential) = registry_key(state, provided).await? {
+            return Ok(credential);
+        }
+    }
+    if !env_keys {
+        match state.storage.has_api_keys().await {
+            Ok(false) => return Ok(env_credential(DEFAULT_ACCOUNT.to_string())),
+            Ok(true) => {}
+            Err(e) => {
+                warn!("auth: api key lookup failed: {}", e);
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+        }
+    }
+    Err(HttpResponse::Unauthorized().body("unauthorized"))
+}
+
+/// The claims signed into an access token.
+#[derive(Serialize, Deserialize)]
+struct AccessClaims {
+    /// Session id.
+    sid: String,
+    acc: String,
+    scp: Vec<ApiScope>,
+    /// Expiry, server epoch ms.
+    exp: i64,
+}
+
+/// HMAC-SHA256 of `payload` under `secret`.
+pub(crate) fn sign(secret: &str, payload: &str) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(payload.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// An access token for `session`, valid until `expires_at`: base64url claims
+/// and their HMAC-SHA256 under `TOKEN_SECRET`, joined by a dot.
+pub(crate) fn issue_access_token(secret: &str, session: &AuthSession, expires_at: i64) -> String {
+    let claims = AccessClaims {
+        sid: session.id.clone(),
+        acc: session.account_id.clone(),
+        scp: session.scopes.clone(),
+        exp: expires_at,
+    };
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).expect("claims serialize"));
+    let signature = URL_SAFE_NO_PAD.encode(sign(secret, &payload));
+    format!("{}.{}", payload, signature)
+}
+
+/// Checks an access token's signature and expiry, then that its session is
+/// still live, so revoking a session takes effect at once.
+async fn bearer_credential(state: &AppState, token: &str) -> Result<Credential, HttpResponse> {
+    let unauthorized = || HttpResponse::Unauthorized().body("invalid or expired token");
+    let (payload, signature) = token.split_once('.').ok_or_else(unauthorized)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| unauthorized())?;
+    if !constant_time_eq(&signature, &sign(&state.env.token_secret, payload)) {
+        return Err(unauthorized());
+    }
+    let claims: AccessClaims = URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(unauthorized)?;
+    let now = Utc::now().timestamp_millis();
+    if claims.exp <= now {
+        return Err(unauthorized());
+    }
+    match state.storage.auth_session(&claims.sid).await {
+        Ok(Some(session)) if session.revoked_at.is_none() && session.expires_at > now => {
+            Ok(Credential {
+                account_id: claims.acc,
+                scopes: claims.scp,
+                key_id: session.key_id,
+                session_id: Some(session.id),
+            })
+        }
+        Ok(_) => Err(unauthorized()),
+        Err(e) => {
+            warn!("auth: session lookup failed: {}", e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// The access token in `Authorization: Bearer`, if any.
+pub(crate) fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    header_str(req, "Authorization")?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Runs `attempt` unless the client is locked out. A 401 counts as a failed
+/// `reason` credential; success resets the client's failure count.
+pub(crate) async fn guarded(
+    req: &HttpRequest,
+    state: &AppState,
+    reason: &str,
+    attempt: impl Future<Output = Result<Credential, HttpResponse>>,
+) -> Result<Credential, HttpResponse> {
+    check_lockout(req, state)?;
+    match attempt.await {
+        Ok(credential) => {
+            record_auth_success(req, state);
+            Ok(credential)
+        }
+        Err(resp) => {
+            if resp.status() == StatusCode::UNAUTHORIZED {
+                record_auth_failure(req, state, reason).await;
+            }
+            Err(resp)
+        }
+    }
+}
+
+/// A bearer access token when one is sent, otherwise `X-API-Key`.
+pub(crate) async fn authenticate(
+    req: &HttpRequest,
+    state: &AppState,
+) -> Result<Credential, HttpResponse> {
+    match bearer_token(req) {
+        Some(token) => guarded(req, state, "access token", bearer_credential(state, token)).await,
+        None => guarded(req, state, "api key", api_key_credential(req, state)).await,
+    }
+}
+
+/// Authenticates the request, checks that it grants `scope` and applies the
+/// client's rate limit; returns the account to work on.
+pub(crate) async fn check_api_key(
+    req: &HttpRequest,
+    state: &AppState,
+    scope: ApiScope,
+) -> Result<String, HttpResponse> {
+    let credential = authenticate(req, state).await?;
+    if !credential.scopes.contains(&scope) {
+        return Err(
+            HttpResponse::Forbidden().body(format!("key lacks the {} scope", scope.as_str()))
+        );
+    }
+    check_rate_limit(req, state, &credential)?;
+    Ok(credential.account_id)
+}
+
+/// Admits `X-Admin-Key` matching `ADMIN_KEY`, or a registry key or access
+/// token with the admin scope. Without `ADMIN_KEY` the endpoints are refused
+/// unless such a credential is presented.
+pub(crate) async fn check_admin_key(
+    req: &HttpRequest,
+    state: &AppState,
+) -> Result<(), HttpResponse> {
+    check_lockout(req, state)?;
+    let admin_key = state.env.admin_key.trim();
+    let provided = req
+        .headers()
+        .get("X-Admin-Key")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if !admin_key.is_empty() && constant_time_eq(provided.as_bytes(), admin_key.as_bytes()) {
+        record_auth_success(req, state);
+        return Ok(());
+    }
+    if bearer_token(req).is_some() || header_str(req, "X-API-Key").is_some() {
+        if let Ok(credential) = authenticate(req, state).await {
+            if credential.scopes.contains(&ApiScope::Admin) {
+                return Ok(());
+            }
+        }
+    }
+    if admin_key.is_empty() {
+        return Err(HttpResponse::Forbidden().body("admin endpoints are disabled"));
+    }
+    if !provided.is_empty() {
+        record_auth_failure(req, state, "admin key").await;
+    }
+    Err(HttpResponse::Unauthorized().body("unauthorized"))
+}
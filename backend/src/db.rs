@@ -1,15 +1,120 @@
+use log::warn;
+use std::net::IpAddr;
+
+use crate::storage::is_blob_key;
+
+/// Account id that owns data written with the legacy single `API_KEY`
+/// (and everything when no keys are configured).
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+/// A credential and the account whose data it may read and write.
+#[derive(Clone)]
+pub struct AccountKey {
+    pub account_id: String,
+    pub key: String,
+}
+
 #[derive(Clone)]
 pub struct EnvConfig {
+    /// `postgres` (default) or `sqlite` (needs the `sqlite` cargo feature).
+    pub storage_backend: String,
+    /// Database file used when `storage_backend` is `sqlite`.
+    pub sqlite_path: String,
+    /// Where image ciphertext goes: `database` (default, inline in
+    /// `diary_images`), `fs` (files under `blob_dir`) or `s3` (the `s3_*` bucket).
+    pub blob_store: String,
+    pub blob_dir: String,
+    /// S3-compatible endpoint for `blob_store = s3`, e.g. a MinIO at `http://127.0.0.1:9000`.
+    pub s3_endpoint: String,
+    pub s3_bucket: String,
+    pub s3_region: String,
+    pub s3_access_key: String,
+    pub s3_secret_key: String,
+    /// Prepended to object keys so one bucket can be shared.
+    pub s3_prefix: String,
+    /// Apply pending migrations on startup (default); when false startup only
+    /// checks that the schema is current.
+    pub auto_migrate: bool,
     pub host: String,
     pub port: i32,
     pub database: String,
     pub user: String,
     pub password: String,
     pub api_key: String,
+    /// Per-account credentials from `ACCOUNT_KEYS` (`account:key,account:key`).
+    pub account_keys: Vec<AccountKey>,
+    /// Prior diary/todo versions kept per uuid; 0 disables revision history.
+    pub history_max_revisions: i64,
+    /// Credential for the `/admin` endpoints (`X-Admin-Key`); empty disables them.
+    pub admin_key: String,
+    /// Unreferenced images younger than this are kept by garbage collection.
+    pub image_gc_grace_secs: i64,
+    /// How often the background image GC runs; 0 (default) disables it.
+    pub image_gc_interval_secs: u64,
+    /// HMAC key for access tokens (`TOKEN_SECRET`). When unset a random one is
+    /// drawn at startup, so tokens do not survive a restart.
+    pub token_secret: String,
+    pub access_token_ttl_secs: i64,
+    pub refresh_token_ttl_secs: i64,
+    /// Shared secret for request signatures (`SIGNING_SECRET`); when set every
+    /// request must be signed. Empty (default) disables signing.
+    pub signing_secret: String,
+    /// How far a signed request's timestamp may be from server time.
+    pub signature_max_skew_secs: i64,
+    /// Consecutive auth failures from one IP before it is locked out; 0
+    /// disables lockout (failures are still logged).
+    pub auth_lockout_threshold: u32,
+    /// First lockout length; each further failure doubles it.
+    pub auth_lockout_base_secs: i64,
+    pub auth_lockout_max_secs: i64,
+    /// Reverse proxies (`TRUSTED_PROXIES`, comma-separated IPs) whose
+    /// `X-Forwarded-For` names the client. Other peers are the client.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// How long `auth_failures` rows are kept; 0 keeps them forever.
+    pub auth_failure_retention_secs: i64,
+    /// Unfinished resumable uploads idle this long are dropped with their
+    /// chunks; 0 keeps them forever.
+    pub image_upload_ttl_secs: i64,
+    /// Requests per minute (also the burst) per client IP across all routes;
+    /// 0 lifts the limit.
+    pub rate_limit_ip_per_min: u32,
+    /// Requests per minute (also the burst) per credential and device for
+    /// each route group; 0 lifts the limit. See [`crate::ratelimit`].
+    pub rate_limit_meta_per_min: u32,
+    pub rate_limit_download_per_min: u32,
+    pub rate_limit_upload_per_min: u32,
+    pub rate_limit_image_upload_per_min: u32,
+    /// Request body bytes per hour per credential and device across uploads;
+    /// 0 lifts the quota.
+    pub upload_bytes_per_hour: u64,
+    /// Devices' worth of each budget one credential may use in total,
+    /// whatever `X-Device-Id` values it sends.
+    pub rate_limit_max_devices: u32,
 }
 
 impl EnvConfig {
     pub fn from_env() -> Self {
+        let storage_backend = std::env::var("STORAGE_BACKEND")
+            .map(|v| v.trim().to_ascii_lowercase())
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| "postgres".to_string());
+        let sqlite_path = std::env::var("SQLITE_PATH").unwrap_or_else(|_| "syezw.db".to_string());
+        let blob_store = std::env::var("BLOB_STORE")
+            .map(|v| v.trim().to_ascii_lowercase())
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| "database".to_string());
+        let blob_dir = std::env::var("BLOB_DIR").unwrap_or_else(|_| "blobs".to_string());
+        let s3_endpoint = std::env::var("S3_ENDPOINT").unwrap_or_default();
+        let s3_bucket = std::env::var("S3_BUCKET").unwrap_or_default();
+        let s3_region = std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let s3_access_key = std::env::var("S3_ACCESS_KEY").unwrap_or_default();
+        let s3_secret_key = std::env::var("S3_SECRET_KEY").unwrap_or_default();
+        let s3_prefix = std::env::var("S3_PREFIX").unwrap_or_default();
+        let auto_migrate = std::env::var("AUTO_MIGRATE")
+            .map(|v| !matches!(v.trim().to_ascii_lowercase().as_str(), "false" | "0" | "no"))
+            .unwrap_or(true);
         let host = std::env::var("PG_HOST").unwrap_or_else(|_| "localhost".to_string());
         let port = std::env::var("PG_PORT")
             .ok()
@@ -19,17 +124,170 @@ impl EnvConfig {
         let user = std::env::var("PG_USER").unwrap_or_else(|_| "postgres".to_string());
         let password = std::env::var("PG_PASSWORD").unwrap_or_else(|_| "postgres".to_string());
         let api_key = std::env::var("API_KEY").unwrap_or_default();
+        let account_keys = parse_account_keys(&std::env::var("ACCOUNT_KEYS").unwrap_or_default());
+        let history_max_revisions = std::env::var("HISTORY_MAX_REVISIONS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(20)
+            .max(0);
+        let admin_key = std::env::var("ADMIN_KEY").unwrap_or_default();
+        let image_gc_grace_secs = std::env::var("IMAGE_GC_GRACE_SECS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(7 * 24 * 3600)
+            .max(0);
+        let image_gc_interval_secs = std::env::var("IMAGE_GC_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0);
+        let token_secret = std::env::var("TOKEN_SECRET")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or_else(random_secret);
+        let access_token_ttl_secs = std::env::var("ACCESS_TOKEN_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(15 * 60)
+            .max(1);
+        let refresh_token_ttl_secs = std::env::var("REFRESH_TOKEN_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(30 * 24 * 3600)
+            .max(1);
+        let signing_secret = std::env::var("SIGNING_SECRET")
+            .map(|v| v.trim().to_string())
+            .unwrap_or_default();
+        let signature_max_skew_secs = std::env::var("SIGNATURE_MAX_SKEW_SECS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(300)
+            .max(1);
+        let auth_lockout_threshold = std::env::var("AUTH_LOCKOUT_THRESHOLD")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(5);
+        let auth_lockout_base_secs = std::env::var("AUTH_LOCKOUT_BASE_SECS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(60)
+            .max(1);
+        let auth_lockout_max_secs = std::env::var("AUTH_LOCKOUT_MAX_SECS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(3600)
+            .max(auth_lockout_base_secs);
+        let trusted_proxies = std::env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .collect();
+        let auth_failure_retention_secs = std::env::var("AUTH_FAILURE_RETENTION_SECS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(30 * 24 * 3600)
+            .max(0);
+        let image_upload_ttl_secs = std::env::var("IMAGE_UPLOAD_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(24 * 3600)
+            .max(0);
+        let per_min = |name: &str, default: u32| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<u32>().ok())
+                .unwrap_or(default)
+        };
+        let rate_limit_ip_per_min = per_min("RATE_LIMIT_IP_PER_MIN", 600);
+        let rate_limit_meta_per_min = per_min("RATE_LIMIT_META_PER_MIN", 120);
+        let rate_limit_download_per_min = per_min("RATE_LIMIT_DOWNLOAD_PER_MIN", 60);
+        let rate_limit_upload_per_min = per_min("RATE_LIMIT_UPLOAD_PER_MIN", 60);
+        let rate_limit_image_upload_per_min = per_min("RATE_LIMIT_IMAGE_UPLOAD_PER_MIN", 600);
+        let upload_bytes_per_hour = std::env::var("UPLOAD_BYTES_PER_HOUR")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(2 * 1024 * 1024 * 1024);
+        let rate_limit_max_devices = std::env::var("RATE_LIMIT_MAX_DEVICES")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(5)
+            .max(1);
         Self {
+            storage_backend,
+            sqlite_path,
+            blob_store,
+            blob_dir,
+            s3_endpoint,
+            s3_bucket,
+            s3_region,
+            s3_access_key,
+            s3_secret_key,
+            s3_prefix,
+            auto_migrate,
             host,
             port,
             database,
             user,
             password,
             api_key,
+            account_keys,
+            history_max_revisions,
+            admin_key,
+            image_gc_grace_secs,
+            image_gc_interval_secs,
+            token_secret,
+            access_token_ttl_secs,
+            refresh_token_ttl_secs,
+            signing_secret,
+            signature_max_skew_secs,
+            auth_lockout_threshold,
+            auth_lockout_base_secs,
+            auth_lockout_max_secs,
+            trusted_proxies,
+            auth_failure_retention_secs,
+            image_upload_ttl_secs,
+            rate_limit_ip_per_min,
+            rate_limit_meta_per_min,
+            rate_limit_download_per_min,
+            rate_limit_upload_per_min,
+            rate_limit_image_upload_per_min,
+            upload_bytes_per_hour,
+            rate_limit_max_devices,
         }
     }
 }
 
+fn random_secret() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).expect("OS random source");
+    hex::encode(bytes)
+}
+
+/// Parses `account:key` pairs separated by commas; malformed entries are
+/// skipped, as are account ids that are not [`is_blob_key`], since they name
+/// blob locations.
+pub fn parse_account_keys(raw: &str) -> Vec<AccountKey> {
+    raw.split(',')
+        .filter_map(|entry| {
+            let (account_id, key) = entry.trim().split_once(':')?;
+            let (account_id, key) = (account_id.trim(), key.trim());
+            if account_id.is_empty() || key.is_empty() {
+                return None;
+            }
+            if !is_blob_key(account_id) {
+                warn!(
+                    "ACCOUNT_KEYS: skipping account {:?}: ids are 1-128 of [A-Za-z0-9_-]",
+                    account_id
+                );
+                return None;
+            }
+            Some(AccountKey {
+                account_id: account_id.to_string(),
+                key: key.to_string(),
+            })
+        })
+        .collect()
+}
+
 pub fn build_db_url(env: &EnvConfig) -> String {
     format!(
         "postgresql://{}:{}@{}:{}/{}",
@@ -0,0 +1,112 @@
+//! Garbage collection of images no diary ref points at, e.g. after
+//! `/images/refs/upsert` repointed a ref or a diary deletion dropped its refs.
+//! Images left without refs within the grace period are kept, since clients
+//! upload images before the refs that use them and may re-add a removed ref. Records past their retention and
+//! abandoned uploads are swept separately, see [`sweep_expired`].
+
+use actix_web::rt::time::sleep;
+use chrono::Utc;
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::EnvConfig;
+use crate::models::ImageGcResponse;
+use crate::storage::{Storage, StorageResult};
+
+/// How often [`spawn_expiry_sweep`] runs.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// What one [`sweep_expired`] pass removed.
+#[derive(Debug, Default)]
+pub struct ExpiryReport {
+    pub auth_failures: u64,
+    pub image_uploads: u64,
+}
+
+/// Finds (and unless `dry_run`, deletes) images in every account that have
+/// had no refs since more than `grace_secs` before `now` (ms).
+pub async fn collect_image_garbage(
+    storage: &dyn Storage,
+    grace_secs: i64,
+    dry_run: bool,
+    now: i64,
+) -> StorageResult<ImageGcResponse> {
+    let cutoff = now - grace_secs.max(0) * 1000;
+    let orphans = storage.orphaned_images(cutoff).await?;
+    let mut deleted = 0;
+    if !dry_run {
+        for orphan in &orphans {
+            if storage
+                .delete_orphaned_image(&orphan.account_id, &orphan.hash, cutoff)
+                .await?
+            {
+                deleted += 1;
+            }
+        }
+    }
+    Ok(ImageGcResponse {
+        dry_run,
+        cutoff,
+        orphans,
+        deleted,
+    })
+}
+
+/// Runs `collect_image_garbage` every `interval` on the current runtime for
+/// the life of the process. Failures are logged and retried next time.
+pub fn spawn_image_gc(storage: Arc<dyn Storage>, interval: Duration, grace_secs: i64) {
+    actix_web::rt::spawn(async move {
+        loop {
+            sleep(interval).await;
+            let now = Utc::now().timestamp_millis();
+            match collect_image_garbage(storage.as_ref(), grace_secs, false, now).await {
+                Ok(report) => info!(
+                    "image gc: {} orphaned image(s), {} deleted",
+                    report.orphans.len(),
+                    report.deleted
+                ),
+                Err(e) => warn!("image gc failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Drops audit log entries older than `AUTH_FAILURE_RETENTION_SECS`, and
+/// resumable uploads idle for longer than `IMAGE_UPLOAD_TTL_SECS`, at `now` (ms).
+pub async fn sweep_expired(
+    storage: &dyn Storage,
+    env: &EnvConfig,
+    now: i64,
+) -> StorageResult<ExpiryReport> {
+    let mut report = ExpiryReport::default();
+    if env.auth_failure_retention_secs > 0 {
+        report.auth_failures = storage
+            .prune_auth_failures(now - env.auth_failure_retention_secs * 1000)
+            .await?;
+    }
+    if env.image_upload_ttl_secs > 0 {
+        report.image_uploads = storage
+            .expire_image_uploads(now - env.image_upload_ttl_secs * 1000)
+            .await?;
+    }
+    Ok(report)
+}
+
+/// Runs `sweep_expired` hourly on the current runtime for the life of the
+/// process. Failures are logged and retried next time.
+pub fn spawn_expiry_sweep(storage: Arc<dyn Storage>, env: EnvConfig) {
+    actix_web::rt::spawn(async move {
+        loop {
+            sleep(EXPIRY_SWEEP_INTERVAL).await;
+            let now = Utc::now().timestamp_millis();
+            match sweep_expired(storage.as_ref(), &env, now).await {
+                Ok(report) => info!(
+                    "expiry sweep: {} auth failure(s) pruned, {} stale upload(s) dropped",
+                    report.auth_failures, report.image_uploads
+                ),
+                Err(e) => warn!("expiry sweep failed: {}", e),
+            }
+        }
+    });
+}
@@ -1,52 +1,107 @@
+use actix_web::error::PayloadError;
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
-use chrono::NaiveDate;
-use sqlx::{PgPool, Row};
+use chrono::Utc;
+use futures_util::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::mpsc;
 
+pub mod auth;
 pub mod db;
+pub mod gc;
+pub mod lockout;
 pub mod models;
+pub mod ratelimit;
+pub mod signing;
+pub mod storage;
 
+use auth::{check_admin_key, check_api_key};
 use db::EnvConfig;
+use lockout::LockoutTracker;
 use log::{info, warn};
 use models::{
-    DiaryImageRefItem, DiaryImageSyncItem, DiarySyncItem, EncryptedBlob, ImageFetchRequest,
-    ImageFetchResponse, ImageHashListResponse, ImageRefsResponse, ImageRefsUpsertRequest,
-    ImageUploadRequest, PeriodMeta, PeriodSyncItem, SyncCounts, SyncDownloadEnvelope,
-    SyncDownloadRequest, SyncDownloadResponse, SyncMeta, SyncMetaResponse, SyncUploadRequest,
-    SyncUploadResponse, TodoSyncItem,
+    ApiKeyCreateRequest, ApiKeyCreateResponse, ApiKeyListQuery, ApiKeyListResponse, ApiScope,
+    AuthFailureListQuery, AuthFailureListResponse, AuthLockoutListResponse, AuthRefreshRequest,
+    AuthTokenResponse, DeviceListResponse, DeviceRegisterRequest, EncryptedBlob,
+    HistoryListRequest, HistoryListResponse, HistoryRestoreRequest, HistoryRestoreResponse,
+    ImageChunkQuery, ImageFetchRequest, ImageGcRequest, ImageHashListResponse, ImageMissingRequest,
+    ImageMissingResponse, ImageRefsChangeResponse, ImageRefsDeleteRequest, ImageRefsReplaceRequest,
+    ImageRefsRequest, ImageRefsResponse, ImageRefsUpsertRequest, ImageUploadBeginRequest,
+    ImageUploadRequest, RecordKind, SessionRevokeRequest, SessionRevokeResponse, SyncChangesQuery,
+    SyncCounts, SyncDownloadEnvelope, SyncDownloadRequest, SyncDownloadResponse, SyncUploadRequest,
+    SyncUploadResponse, TombstoneItem,
 };
+use ratelimit::RateLimiter;
+use signing::NonceCache;
+use storage::{
+    AuthSession, ChunkOutcome, FinalizeOutcome, ImageBytes, ImageMeta, NewApiKey, NewImageUpload,
+    Storage, StorageError, StorageResult, SyncDirection,
+};
+
+/// Header carrying the client's stable device id; optional on every request.
+pub const DEVICE_ID_HEADER: &str = "X-Device-Id";
+
+/// IV of the ciphertext sent or returned by the binary image endpoints.
+pub const IMAGE_IV_HEADER: &str = "X-Image-IV";
+
+/// Optional `updated_at` for a binary image upload; defaults to now.
+pub const IMAGE_UPDATED_AT_HEADER: &str = "X-Updated-At";
+
+/// Largest image body accepted by `PUT /images/blob/{hash}`.
+pub const MAX_IMAGE_BYTES: usize = 50 * 1024 * 1024;
+
+/// Largest JSON body accepted; bigger images go through the binary endpoints.
+pub const MAX_JSON_BYTES: usize = 16 * 1024 * 1024;
+
+/// Largest body one `PUT /images/uploads/{hash}` chunk may carry.
+pub const MAX_UPLOAD_CHUNK_BYTES: usize = 8 * 1024 * 1024;
+
+/// Request body pieces buffered between the handler and the blob store.
+const BODY_CHUNKS_IN_FLIGHT: usize = 8;
+
+/// Most diary uuids one `/images/refs` filter may name.
+pub const MAX_REF_FILTER_DIARIES: usize = 500;
+
+/// Most images one `/images/missing` request may offer.
+pub const MAX_MISSING_QUERY_IMAGES: usize = 1000;
+
+/// Auth failures `/admin/auth/failures` returns unless asked for a `limit`.
+const DEFAULT_AUTH_FAILURE_LIMIT: i64 = 100;
+
+/// Most auth failures one `/admin/auth/failures` request returns.
+pub const MAX_AUTH_FAILURE_LIMIT: i64 = 1000;
 
 #[derive(Clone)]
 pub struct AppState {
     pub env: EnvConfig,
-    pub pool: PgPool,
+    pub storage: Arc<dyn Storage>,
+    /// Nonces of recently verified signed requests, shared by all workers.
+    pub nonces: Arc<NonceCache>,
+    /// Auth failure counters by client IP.
+    pub lockouts: Arc<LockoutTracker>,
+    /// Request and upload byte budgets by credential and device.
+    pub rate_limits: Arc<RateLimiter>,
 }
 
-/// Constant-time string comparison to prevent timing attacks on API key validation.
-fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
-    if a.len() != b.len() {
-        return false;
-    }
-    let mut result = 0u8;
-    for (x, y) in a.iter().zip(b.iter()) {
-        result |= x ^ y;
+impl AppState {
+    pub fn new(env: EnvConfig, storage: Arc<dyn Storage>) -> Self {
+        Self {
+            env,
+            storage,
+            nonces: Arc::new(NonceCache::new()),
+            lockouts: Arc::new(LockoutTracker::new()),
+            rate_limits: Arc::new(RateLimiter::new()),
+        }
     }
-    result == 0
 }
 
-fn check_api_key(req: &HttpRequest, state: &AppState) -> Option<HttpResponse> {
-    let expected = state.env.api_key.trim();
-    if expected.is_empty() {
-        return None;
-    }
-    let provided = req
-        .headers()
-        .get("X-API-Key")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-    if !constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
-        return Some(HttpResponse::Unauthorized().body("unauthorized"));
-    }
-    None
+fn upload_failure(status: actix_web::http::StatusCode, message: String) -> HttpResponse {
+    HttpResponse::build(status).json(SyncUploadResponse {
+        ok: false,
+        message,
+        counts: SyncCounts::default(),
+        rejected: vec![],
+    })
 }
 
 pub async fn sync_upload(
@@ -54,141 +109,121 @@ pub async fn sync_upload(
     req: HttpRequest,
     payload: web::Json<SyncUploadRequest>,
 ) -> actix_web::Result<impl Responder> {
-    if let Some(resp) = check_api_key(&req, &state) {
-        return Ok(resp);
+    let account = match check_api_key(&req, &state, ApiScope::Write).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    if let Err(message) = check_image_hashes(payload.images.iter().map(|i| i.hash.as_str())) {
+        return Ok(upload_failure(
+            actix_web::http::StatusCode::BAD_REQUEST,
+            message,
+        ));
     }
-    let mut tx = match state.pool.begin().await {
-        Ok(tx) => tx,
+    let outcome = match state
+        .storage
+        .apply_upload(&account, &payload, state.env.history_max_revisions)
+        .await
+    {
+        Ok(outcome) => outcome,
         Err(e) => {
-            warn!("sync_upload: failed to begin transaction: {}", e);
-            return Ok(
-                HttpResponse::InternalServerError().json(SyncUploadResponse {
-                    ok: false,
-                    message: format!("db transaction start failed: {}", e),
-                    counts: SyncCounts {
-                        diaries: 0,
-                        todos: 0,
-                        periods: 0,
-                        images: 0,
-                    },
-                }),
-            );
+            warn!("sync_upload: {}", e);
+            return Ok(upload_failure(
+                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+                e.to_string(),
+            ));
         }
     };
+    let (counts, rejected) = (outcome.counts, outcome.rejected);
 
-    for item in &payload.diaries {
-        if let Err(e) = upsert_diary(&mut tx, item).await {
-            warn!("sync_upload: diary upsert failed: {}", e);
-            return Ok(
-                HttpResponse::InternalServerError().json(SyncUploadResponse {
-                    ok: false,
-                    message: format!("diary upsert failed: {}", e),
-                    counts: SyncCounts {
-                        diaries: 0,
-                        todos: 0,
-                        periods: 0,
-                        images: 0,
-                    },
-                }),
-            );
-        }
-    }
-
-    for item in &payload.todos {
-        if let Err(e) = upsert_todo(&mut tx, item).await {
-            warn!("sync_upload: todo upsert failed: {}", e);
-            return Ok(
-                HttpResponse::InternalServerError().json(SyncUploadResponse {
-                    ok: false,
-                    message: format!("todo upsert failed: {}", e),
-                    counts: SyncCounts {
-                        diaries: 0,
-                        todos: 0,
-                        periods: 0,
-                        images: 0,
-                    },
-                }),
-            );
-        }
-    }
-
-    for item in &payload.periods {
-        if let Err(e) = upsert_period(&mut tx, item).await {
-            warn!("sync_upload: period upsert failed: {}", e);
-            return Ok(
-                HttpResponse::InternalServerError().json(SyncUploadResponse {
-                    ok: false,
-                    message: format!("period upsert failed: {}", e),
-                    counts: SyncCounts {
-                        diaries: 0,
-                        todos: 0,
-                        periods: 0,
-                        images: 0,
-                    },
-                }),
-            );
-        }
-    }
-
-    for item in &payload.images {
-        if let Err(e) = upsert_image(&mut tx, item).await {
-            warn!("sync_upload: image upsert failed: {}", e);
-            return Ok(
-                HttpResponse::InternalServerError().json(SyncUploadResponse {
-                    ok: false,
-                    message: format!("image upsert failed: {}", e),
-                    counts: SyncCounts {
-                        diaries: 0,
-                        todos: 0,
-                        periods: 0,
-                        images: 0,
-                    },
-                }),
-            );
-        }
-    }
-
-    if let Err(e) = tx.commit().await {
-        warn!("sync_upload: commit failed: {}", e);
-        return Ok(
-            HttpResponse::InternalServerError().json(SyncUploadResponse {
-                ok: false,
-                message: format!("commit failed: {}", e),
-                counts: SyncCounts {
-                    diaries: 0,
-                    todos: 0,
-                    periods: 0,
-                    images: 0,
-                },
-            }),
-        );
-    }
-
-    let counts = SyncCounts {
-        diaries: payload.diaries.len(),
-        todos: payload.todos.len(),
-        periods: payload.periods.len(),
-        images: payload.images.len(),
-    };
+    record_device_sync(&state, &account, &req, SyncDirection::Upload).await;
     info!(
-        "sync_upload success: diaries={}, todos={}, periods={}, images={}",
-        counts.diaries, counts.todos, counts.periods, counts.images
+        "sync_upload success: diaries={}, todos={}, periods={}, images={}, deletions={}, rejected={}",
+        counts.diaries,
+        counts.todos,
+        counts.periods,
+        counts.images,
+        counts.deletions,
+        rejected.len()
     );
     Ok(HttpResponse::Ok().json(SyncUploadResponse {
         ok: true,
         message: "ok".to_string(),
         counts,
+        rejected,
     }))
 }
 
+fn download_failure(message: String) -> HttpResponse {
+    HttpResponse::InternalServerError().json(SyncDownloadEnvelope {
+        ok: false,
+        message,
+        counts: SyncCounts::default(),
+        data: SyncDownloadResponse::default(),
+        next_page_token: None,
+    })
+}
+
+/// Rows scanned per query while filling a download page.
+const DOWNLOAD_BATCH_SIZE: i64 = 200;
+
+/// Item and byte budget for one `/sync/download` page.
+struct PageBudget {
+    limit: Option<usize>,
+    max_bytes: Option<usize>,
+    items: usize,
+    bytes: usize,
+}
+
+impl PageBudget {
+    /// Accounts for an item of `size` bytes, or returns `false` when it no
+    /// longer fits. The first item of a page is always admitted.
+    fn admit(&mut self, size: usize) -> bool {
+        if self.items > 0 {
+            if self.limit.is_some_and(|limit| self.items >= limit) {
+                return false;
+            }
+            if self
+                .max_bytes
+                .is_some_and(|max_bytes| self.bytes + size > max_bytes)
+            {
+                return false;
+            }
+        }
+        self.items += 1;
+        self.bytes += size;
+        true
+    }
+}
+
+/// Rough JSON size of a synced record, used for the page byte budget.
+fn approx_item_size(key: &str, payload: &EncryptedBlob) -> usize {
+    key.len() + payload.iv.len() + payload.data.len() + 128
+}
+
+/// Page tokens are `<type>:<last key>`: download resumes in that section with
+/// keys strictly greater than the last one sent (or skipped as up to date).
+fn parse_page_token(token: &str) -> Option<(RecordKind, String)> {
+    let (kind, key) = token.split_once(':')?;
+    Some((RecordKind::parse(kind)?, key.to_string()))
+}
+
+/// Whether the client (per its metadata) lacks this record or holds an older version.
+fn client_needs(meta: &std::collections::HashMap<String, i64>, key: &str, updated_at: i64) -> bool {
+    match meta.get(key) {
+        None => true,
+        Some(local_updated) => updated_at > *local_updated,
+    }
+}
+
 pub async fn sync_download(
     state: web::Data<AppState>,
     req: HttpRequest,
     payload: web::Json<SyncDownloadRequest>,
 ) -> actix_web::Result<impl Responder> {
-    if let Some(resp) = check_api_key(&req, &state) {
-        return Ok(resp);
-    }
+    let account = match check_api_key(&req, &state, ApiScope::Read).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
     let diary_meta: std::collections::HashMap<String, i64> = payload
         .diaries
         .iter()
@@ -199,551 +234,1528 @@ pub async fn sync_download(
         .iter()
         .map(|m| (m.uuid.clone(), m.updated_at))
         .collect();
+    // Older clients report periods by start date; uuids and dates never collide.
     let period_meta: std::collections::HashMap<String, i64> = payload
         .periods
         .iter()
-        .map(|m| (m.start_date.clone(), m.updated_at))
+        .map(|m| {
+            let key = if m.uuid.is_empty() {
+                &m.start_date
+            } else {
+                &m.uuid
+            };
+            (key.clone(), m.updated_at)
+        })
         .collect();
 
-    let diary_rows = match sqlx::query(
-        r#"
-        SELECT uuid, author, timestamp, updated_at, payload_iv, payload_data
-        FROM diary_sync
-        "#,
-    )
-    .fetch_all(&state.pool)
-    .await
-    {
-        Ok(rows) => rows,
-        Err(e) => {
-            warn!("sync_download: diary query failed: {}", e);
-            return Ok(
-                HttpResponse::InternalServerError().json(SyncDownloadEnvelope {
+    let (start_kind, start_key) = match payload.page_token.as_deref() {
+        None => (RecordKind::Diary, String::new()),
+        Some(token) => match parse_page_token(token) {
+            Some(parsed) => parsed,
+            None => {
+                return Ok(HttpResponse::BadRequest().json(SyncDownloadEnvelope {
                     ok: false,
-                    message: format!("diary query failed: {}", e),
-                    counts: SyncCounts {
-                        diaries: 0,
-                        todos: 0,
-                        periods: 0,
-                        images: 0,
-                    },
-                    data: SyncDownloadResponse {
-                        diaries: vec![],
-                        todos: vec![],
-                        periods: vec![],
-                        images: vec![],
-                    },
-                }),
-            );
-        }
+                    message: "invalid page token".to_string(),
+                    counts: SyncCounts::default(),
+                    data: SyncDownloadResponse::default(),
+                    next_page_token: None,
+                }));
+            }
+        },
     };
-    let diaries = diary_rows
-        .into_iter()
-        .map(|row| DiarySyncItem {
-            uuid: row.get("uuid"),
-            author: row.get("author"),
-            timestamp: row.get("timestamp"),
-            updated_at: row.get("updated_at"),
-            payload: EncryptedBlob {
-                iv: row.get("payload_iv"),
-                data: row.get("payload_data"),
-            },
-        })
-        .filter(|item| match diary_meta.get(&item.uuid) {
-            None => true,
-            Some(local_updated) => item.updated_at > *local_updated,
-        })
-        .collect();
+    let mut budget = PageBudget {
+        limit: payload.limit,
+        max_bytes: payload.max_bytes,
+        items: 0,
+        bytes: 0,
+    };
+    let mut response = SyncDownloadResponse::default();
+    let mut next_page_token = None;
 
-    let todo_rows = match sqlx::query(
-        r#"
-        SELECT uuid, author, is_completed, created_at, completed_at, updated_at, payload_iv, payload_data
-        FROM todo_sync
-        "#,
-    )
-    .fetch_all(&state.pool)
-    .await
-    {
-        Ok(rows) => rows,
-        Err(e) => {
-            warn!("sync_download: todo query failed: {}", e);
-            return Ok(HttpResponse::InternalServerError().json(SyncDownloadEnvelope {
-                ok: false,
-                message: format!("todo query failed: {}", e),
-                counts: SyncCounts { diaries: 0, todos: 0, periods: 0, images: 0 },
-                data: SyncDownloadResponse { diaries: vec![], todos: vec![], periods: vec![], images: vec![] },
-            }));
-        }
-    };
-    let todos = todo_rows
-        .into_iter()
-        .map(|row| TodoSyncItem {
-            uuid: row.get("uuid"),
-            author: row.get("author"),
-            is_completed: row.get("is_completed"),
-            created_at: row.get("created_at"),
-            completed_at: row.get("completed_at"),
-            updated_at: row.get("updated_at"),
-            payload: EncryptedBlob {
-                iv: row.get("payload_iv"),
-                data: row.get("payload_data"),
-            },
-        })
-        .filter(|item| match todo_meta.get(&item.uuid) {
-            None => true,
-            Some(local_updated) => item.updated_at > *local_updated,
-        })
-        .collect();
+    // Sections are walked in a fixed order (diaries, todos, periods), each by
+    // key, so a page token always identifies a unique resume point.
+    if start_kind == RecordKind::Diary {
+        let mut after = start_key.clone();
+        'diaries: loop {
+            let batch = match state
+                .storage
+                .diary_page(&account, &after, DOWNLOAD_BATCH_SIZE)
+                .await
+            {
+                Ok(batch) => batch,
+                Err(e) => {
+                    warn!("sync_download: diary query failed: {}", e);
+                    return Ok(download_failure(format!("diary query failed: {}", e)));
+                }
+            };
+            let exhausted = (batch.len() as i64) < DOWNLOAD_BATCH_SIZE;
+            for item in batch {
+                if client_needs(&diary_meta, &item.uuid, item.updated_at) {
+                    if !budget.admit(approx_item_size(&item.uuid, &item.payload)) {
+                        next_page_token = Some(format!("diary:{}", after));
+                        break 'diaries;
+                    }
+                    after = item.uuid.clone();
+                    response.diaries.push(item);
+                } else {
+                    after = item.uuid;
+                }
+            }
+            if exhausted {
+                break;
+            }
+        }
+    }
 
-    let period_rows = match sqlx::query(
-        r#"
-        SELECT start_date::text as start_date, end_date::text as end_date, updated_at, payload_iv, payload_data
-        FROM period_sync
-        "#,
-    )
-    .fetch_all(&state.pool)
-    .await
-    {
-        Ok(rows) => rows,
-        Err(e) => {
-            warn!("sync_download: period query failed: {}", e);
-            return Ok(HttpResponse::InternalServerError().json(SyncDownloadEnvelope {
-                ok: false,
-                message: format!("period query failed: {}", e),
-                counts: SyncCounts { diaries: 0, todos: 0, periods: 0, images: 0 },
-                data: SyncDownloadResponse { diaries: vec![], todos: vec![], periods: vec![], images: vec![] },
-            }));
-        }
-    };
-    let periods = period_rows
-        .into_iter()
-        .map(|row| PeriodSyncItem {
-            start_date: row.get("start_date"),
-            end_date: row.get("end_date"),
-            updated_at: row.get("updated_at"),
-            payload: EncryptedBlob {
-                iv: row.get("payload_iv"),
-                data: row.get("payload_data"),
-            },
-        })
-        .filter(|item| match period_meta.get(&item.start_date) {
-            None => true,
-            Some(local_updated) => item.updated_at > *local_updated,
-        })
-        .collect();
+    if next_page_token.is_none() && start_kind != RecordKind::Period {
+        let mut after = if start_kind == RecordKind::Todo {
+            start_key.clone()
+        } else {
+            String::new()
+        };
+        'todos: loop {
+            let batch = match state
+                .storage
+                .todo_page(&account, &after, DOWNLOAD_BATCH_SIZE)
+                .await
+            {
+                Ok(batch) => batch,
+                Err(e) => {
+                    warn!("sync_download: todo query failed: {}", e);
+                    return Ok(download_failure(format!("todo query failed: {}", e)));
+                }
+            };
+            let exhausted = (batch.len() as i64) < DOWNLOAD_BATCH_SIZE;
+            for item in batch {
+                if client_needs(&todo_meta, &item.uuid, item.updated_at) {
+                    if !budget.admit(approx_item_size(&item.uuid, &item.payload)) {
+                        next_page_token = Some(format!("todo:{}", after));
+                        break 'todos;
+                    }
+                    after = item.uuid.clone();
+                    response.todos.push(item);
+                } else {
+                    after = item.uuid;
+                }
+            }
+            if exhausted {
+                break;
+            }
+        }
+    }
+
+    if next_page_token.is_none() {
+        let mut after = if start_kind == RecordKind::Period {
+            start_key.clone()
+        } else {
+            String::new()
+        };
+        'periods: loop {
+            let batch = match state
+                .storage
+                .period_page(&account, &after, DOWNLOAD_BATCH_SIZE)
+                .await
+            {
+                Ok(batch) => batch,
+                Err(e) => {
+                    warn!("sync_download: period query failed: {}", e);
+                    return Ok(download_failure(format!("period query failed: {}", e)));
+                }
+            };
+            let exhausted = (batch.len() as i64) < DOWNLOAD_BATCH_SIZE;
+            for item in batch {
+                let key = if period_meta.contains_key(&item.uuid) {
+                    &item.uuid
+                } else {
+                    &item.start_date
+                };
+                if client_needs(&period_meta, key, item.updated_at) {
+                    if !budget.admit(approx_item_size(&item.uuid, &item.payload)) {
+                        next_page_token = Some(format!("period:{}", after));
+                        break 'periods;
+                    }
+                    after = item.uuid.clone();
+                    response.periods.push(item);
+                } else {
+                    after = item.uuid;
+                }
+            }
+            if exhausted {
+                break;
+            }
+        }
+    }
+
+    // Tombstones are small and only depend on the client's metadata, so they
+    // are sent once, with the first page.
+    if payload.page_token.is_none() {
+        let tombstones = match state.storage.tombstones(&account).await {
+            Ok(items) => items,
+            Err(e) => {
+                warn!("sync_download: tombstone query failed: {}", e);
+                return Ok(download_failure(format!("tombstone query failed: {}", e)));
+            }
+        };
+        // Only report deletions for records the client still holds in a version
+        // no newer than the deletion; anything else is either unknown to the
+        // client or was edited locally after the delete.
+        response.deletions = tombstones
+            .into_iter()
+            .filter_map(|item| {
+                let local = match item.kind {
+                    RecordKind::Diary => diary_meta.get(&item.uuid),
+                    RecordKind::Todo => todo_meta.get(&item.uuid),
+                    RecordKind::Period => period_meta.get(&item.uuid),
+                };
+                if let Some(local_updated) = local {
+                    return (*local_updated <= item.deleted_at).then_some(item);
+                }
+                // A client holding the period by start date gets the deletion
+                // keyed the same way.
+                let start_date = item
+                    .start_date
+                    .clone()
+                    .filter(|_| item.kind == RecordKind::Period)?;
+                let local_updated = period_meta.get(&start_date)?;
+                (*local_updated <= item.deleted_at).then_some(TombstoneItem {
+                    uuid: start_date,
+                    ..item
+                })
+            })
+            .collect();
+    }
 
     // Note: Images are NOT included in sync_download to avoid transferring
     // potentially huge blobs. Clients should use /images/refs + /images/fetch
     // for on-demand image downloads.
-    let response = SyncDownloadResponse {
-        diaries,
-        todos,
-        periods,
-        images: vec![],
-    };
     let counts = SyncCounts {
         diaries: response.diaries.len(),
         todos: response.todos.len(),
         periods: response.periods.len(),
         images: response.images.len(),
+        deletions: response.deletions.len(),
     };
+    // A paged download only counts as complete once the last page is served.
+    if next_page_token.is_none() {
+        record_device_sync(&state, &account, &req, SyncDirection::Download).await;
+    }
     info!(
-        "sync_download success: diaries={}, todos={}, periods={}, images={}",
-        counts.diaries, counts.todos, counts.periods, counts.images
+        "sync_download success: diaries={}, todos={}, periods={}, images={}, deletions={}, more={}",
+        counts.diaries,
+        counts.todos,
+        counts.periods,
+        counts.images,
+        counts.deletions,
+        next_page_token.is_some()
     );
     Ok(HttpResponse::Ok().json(SyncDownloadEnvelope {
         ok: true,
         message: "ok".to_string(),
         counts,
         data: response,
+        next_page_token,
     }))
 }
 
-pub async fn image_fetch(
+/// Incremental sync: returns every diary, todo, period and tombstone whose
+/// change sequence is greater than `since`, plus the cursor to pass next time.
+pub async fn sync_changes(
     state: web::Data<AppState>,
     req: HttpRequest,
-    payload: web::Json<ImageFetchRequest>,
+    query: web::Query<SyncChangesQuery>,
 ) -> actix_web::Result<impl Responder> {
-    if let Some(resp) = check_api_key(&req, &state) {
-        return Ok(resp);
-    }
-    let row = sqlx::query(
-        r#"
-        SELECT r.file_name, r.diary_uuid, r.updated_at, r.hash, i.blob_iv, i.blob_data
-        FROM diary_image_refs r
-        JOIN diary_images i ON i.hash = r.hash
-        WHERE r.diary_uuid = $1 AND r.file_name = $2
-        "#,
-    )
-    .bind(&payload.diary_uuid)
-    .bind(&payload.file_name)
-    .fetch_one(&state.pool)
-    .await
-    .map_err(actix_web::error::ErrorNotFound)?;
-
-    let response = ImageFetchResponse {
-        file_name: row.get("file_name"),
-        diary_uuid: row.get("diary_uuid"),
-        hash: row.get("hash"),
-        updated_at: row.get("updated_at"),
-        blob: EncryptedBlob {
-            iv: row.get("blob_iv"),
-            data: row.get("blob_data"),
-        },
+    let account = match check_api_key(&req, &state, ApiScope::Read).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let since = query.since.unwrap_or(0);
+    let response = match state.storage.changes_since(&account, since).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("sync_changes: {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
     };
+    record_device_sync(&state, &account, &req, SyncDirection::Download).await;
+    info!(
+        "sync_changes success: since={}, cursor={}, diaries={}, todos={}, periods={}, deletions={}",
+        since,
+        response.cursor,
+        response.diaries.len(),
+        response.todos.len(),
+        response.periods.len(),
+        response.deletions.len()
+    );
     Ok(HttpResponse::Ok().json(response))
 }
 
-pub async fn image_hashes(
+pub async fn history_list(
     state: web::Data<AppState>,
     req: HttpRequest,
+    payload: web::Json<HistoryListRequest>,
 ) -> actix_web::Result<impl Responder> {
-    if let Some(resp) = check_api_key(&req, &state) {
-        return Ok(resp);
+    let account = match check_api_key(&req, &state, ApiScope::Read).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    if payload.kind == RecordKind::Period {
+        return Ok(HttpResponse::BadRequest().body("periods have no revision history"));
     }
-    let rows = match sqlx::query("SELECT hash FROM diary_images")
-        .fetch_all(&state.pool)
+    let revisions = match state
+        .storage
+        .list_revisions(&account, payload.kind, &payload.uuid)
         .await
     {
-        Ok(rows) => rows,
+        Ok(revisions) => revisions,
         Err(e) => {
-            warn!("image_hashes: query failed: {}", e);
+            warn!("history_list: query failed: {}", e);
             return Ok(HttpResponse::InternalServerError().finish());
         }
     };
-    let hashes: Vec<String> = rows.into_iter().map(|row| row.get("hash")).collect();
-    info!("image_hashes success: {} hashes", hashes.len());
-    Ok(HttpResponse::Ok().json(ImageHashListResponse { hashes }))
+    info!(
+        "history_list success: {} {} has {} revisions",
+        payload.kind.as_str(),
+        payload.uuid,
+        revisions.len()
+    );
+    Ok(HttpResponse::Ok().json(HistoryListResponse { revisions }))
 }
 
-pub async fn image_refs(
+fn restore_failure(status: actix_web::http::StatusCode, message: String) -> HttpResponse {
+    HttpResponse::build(status).json(HistoryRestoreResponse {
+        ok: false,
+        message,
+        updated_at: 0,
+    })
+}
+
+/// Makes an archived revision the current version again. The version being
+/// replaced (if any) is archived first, and a tombstone for the uuid is lifted.
+pub async fn history_restore(
     state: web::Data<AppState>,
     req: HttpRequest,
+    payload: web::Json<HistoryRestoreRequest>,
 ) -> actix_web::Result<impl Responder> {
-    if let Some(resp) = check_api_key(&req, &state) {
-        return Ok(resp);
+    use actix_web::http::StatusCode;
+
+    let account = match check_api_key(&req, &state, ApiScope::Write).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    if payload.kind == RecordKind::Period {
+        return Ok(restore_failure(
+            StatusCode::BAD_REQUEST,
+            "periods have no revision history".to_string(),
+        ));
     }
-    let rows = match sqlx::query(
-        r#"
-        SELECT diary_uuid, file_name, hash, updated_at
-        FROM diary_image_refs
-        "#,
-    )
-    .fetch_all(&state.pool)
-    .await
+    let updated_at = match state
+        .storage
+        .restore_revision(
+            &account,
+            payload.kind,
+            &payload.uuid,
+            payload.revision_id,
+            state.env.history_max_revisions,
+        )
+        .await
     {
-        Ok(rows) => rows,
+        Ok(Some(updated_at)) => updated_at,
+        Ok(None) => {
+            return Ok(restore_failure(
+                StatusCode::NOT_FOUND,
+                "revision not found".to_string(),
+            ));
+        }
         Err(e) => {
-            warn!("image_refs: query failed: {}", e);
-            return Ok(HttpResponse::InternalServerError().finish());
+            warn!("history_restore: {}", e);
+            return Ok(restore_failure(
+                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+                e.to_string(),
+            ));
         }
     };
 
-    let refs: Vec<DiaryImageRefItem> = rows
-        .into_iter()
-        .map(|row| DiaryImageRefItem {
-            diary_uuid: row.get("diary_uuid"),
-            file_name: row.get("file_name"),
-            hash: row.get("hash"),
-            updated_at: row.get("updated_at"),
-        })
-        .collect();
+    info!(
+        "history_restore success: {} {} revision {} -> updated_at={}",
+        payload.kind.as_str(),
+        payload.uuid,
+        payload.revision_id,
+        updated_at
+    );
+    Ok(HttpResponse::Ok().json(HistoryRestoreResponse {
+        ok: true,
+        message: "ok".to_string(),
+        updated_at,
+    }))
+}
 
-    info!("image_refs success: {} refs", refs.len());
-    Ok(HttpResponse::Ok().json(ImageRefsResponse { refs }))
+fn device_id(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(DEVICE_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
 }
 
-pub async fn image_upload(
+/// Stamps the calling device's last successful upload/download. Devices that
+/// never called `/devices/register` are created on first sync. Failures are
+/// logged only; bookkeeping must not fail a sync that already succeeded.
+async fn record_device_sync(
+    state: &AppState,
+    account: &str,
+    req: &HttpRequest,
+    direction: SyncDirection,
+) {
+    let Some(device_id) = device_id(req) else {
+        return;
+    };
+    if let Err(e) = state
+        .storage
+        .record_device_sync(account, device_id, direction, Utc::now().timestamp_millis())
+        .await
+    {
+        warn!("record_device_sync: update for {} failed: {}", device_id, e);
+    }
+}
+
+pub async fn device_register(
     state: web::Data<AppState>,
     req: HttpRequest,
-    payload: web::Json<ImageUploadRequest>,
+    payload: web::Json<DeviceRegisterRequest>,
 ) -> actix_web::Result<impl Responder> {
-    if let Some(resp) = check_api_key(&req, &state) {
-        return Ok(resp);
+    let account = match check_api_key(&req, &state, ApiScope::Write).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    if payload.device_id.trim().is_empty() {
+        return Ok(HttpResponse::BadRequest().body("deviceId is required"));
     }
-    let mut tx = match state.pool.begin().await {
-        Ok(tx) => tx,
+    let device = match state
+        .storage
+        .register_device(&account, &payload, Utc::now().timestamp_millis())
+        .await
+    {
+        Ok(device) => device,
         Err(e) => {
-            warn!("image_upload: failed to begin transaction: {}", e);
+            warn!("device_register: upsert failed: {}", e);
             return Ok(HttpResponse::InternalServerError().finish());
         }
     };
-    let mut success = 0usize;
-    for item in &payload.images {
-        if let Err(e) = sqlx::query(
-            r#"
-            INSERT INTO diary_images (hash, blob_iv, blob_data, updated_at)
-            VALUES ($1, $2, $3, $4)
-            ON CONFLICT (hash) DO UPDATE SET
-                blob_iv = EXCLUDED.blob_iv,
-                blob_data = EXCLUDED.blob_data,
-                updated_at = EXCLUDED.updated_at
-            "#,
-        )
-        .bind(&item.hash)
-        .bind(&item.blob.iv)
-        .bind(&item.blob.data)
-        .bind(item.updated_at)
-        .execute(&mut *tx)
-        .await
-        {
-            warn!("image_upload: upsert failed: {}", e);
-            return Ok(HttpResponse::InternalServerError().finish());
-        }
-        success += 1;
-    }
-    if let Err(e) = tx.commit().await {
-        warn!("image_upload: commit failed: {}", e);
-        return Ok(HttpResponse::InternalServerError().finish());
-    }
-
-    info!("image_upload success: {} images", success);
-    Ok(HttpResponse::Ok().finish())
+    info!(
+        "device_register success: {} ({}, {})",
+        device.device_id, payload.name, payload.app_version
+    );
+    Ok(HttpResponse::Ok().json(device))
 }
 
-pub async fn image_refs_upsert(
+pub async fn device_list(
     state: web::Data<AppState>,
     req: HttpRequest,
-    payload: web::Json<ImageRefsUpsertRequest>,
 ) -> actix_web::Result<impl Responder> {
-    if let Some(resp) = check_api_key(&req, &state) {
-        return Ok(resp);
-    }
-    let mut tx = match state.pool.begin().await {
-        Ok(tx) => tx,
+    let account = match check_api_key(&req, &state, ApiScope::Read).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let devices = match state.storage.list_devices(&account).await {
+        Ok(devices) => devices,
         Err(e) => {
-            warn!("image_refs_upsert: failed to begin transaction: {}", e);
+            warn!("device_list: query failed: {}", e);
             return Ok(HttpResponse::InternalServerError().finish());
         }
     };
-    let mut success = 0usize;
-    for item in &payload.refs {
-        if let Err(e) = sqlx::query(
-            r#"
-            INSERT INTO diary_image_refs (diary_uuid, file_name, hash, updated_at)
-            VALUES ($1, $2, $3, $4)
-            ON CONFLICT (diary_uuid, file_name) DO UPDATE SET
-                hash = EXCLUDED.hash,
-                updated_at = EXCLUDED.updated_at
-            "#,
-        )
-        .bind(&item.diary_uuid)
-        .bind(&item.file_name)
-        .bind(&item.hash)
-        .bind(item.updated_at)
-        .execute(&mut *tx)
+    info!("device_list success: {} devices", devices.len());
+    Ok(HttpResponse::Ok().json(DeviceListResponse { devices }))
+}
+
+pub async fn image_fetch(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<ImageFetchRequest>,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Read).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let response = state
+        .storage
+        .fetch_image(&account, &payload.diary_uuid, &payload.file_name)
         .await
-        {
-            warn!("image_refs_upsert: upsert failed: {}", e);
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("image not found"))?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn image_hashes(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Read).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let hashes = match state.storage.image_hashes(&account).await {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            warn!("image_hashes: query failed: {}", e);
             return Ok(HttpResponse::InternalServerError().finish());
         }
-        success += 1;
-    }
-    if let Err(e) = tx.commit().await {
-        warn!("image_refs_upsert: commit failed: {}", e);
-        return Ok(HttpResponse::InternalServerError().finish());
-    }
-
-    info!("image_refs_upsert success: {} refs", success);
-    Ok(HttpResponse::Ok().finish())
+    };
+    info!("image_hashes success: {} hashes", hashes.len());
+    Ok(HttpResponse::Ok().json(ImageHashListResponse { hashes }))
 }
 
-pub async fn sync_meta(
+/// Tells the client which of the images it is about to upload the server
+/// still needs, instead of listing every stored hash.
+pub async fn image_missing(
     state: web::Data<AppState>,
     req: HttpRequest,
+    payload: web::Json<ImageMissingRequest>,
 ) -> actix_web::Result<impl Responder> {
-    if let Some(resp) = check_api_key(&req, &state) {
-        return Ok(resp);
+    let account = match check_api_key(&req, &state, ApiScope::Read).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    if payload.images.len() > MAX_MISSING_QUERY_IMAGES {
+        return Ok(HttpResponse::BadRequest().body("too many images"));
     }
-
-    let diary_rows = match sqlx::query("SELECT uuid, updated_at FROM diary_sync")
-        .fetch_all(&state.pool)
-        .await
-    {
-        Ok(rows) => rows,
+    let hashes: Vec<String> = payload.images.iter().map(|i| i.hash.clone()).collect();
+    let stored = match state.storage.image_sizes(&account, &hashes).await {
+        Ok(stored) => stored,
         Err(e) => {
-            warn!("sync_meta: diary query failed: {}", e);
+            warn!("image_missing: query failed: {}", e);
             return Ok(HttpResponse::InternalServerError().finish());
         }
     };
-    let diaries = diary_rows
-        .into_iter()
-        .map(|row| SyncMeta {
-            uuid: row.get("uuid"),
-            updated_at: row.get("updated_at"),
-        })
-        .collect();
+    let stored: HashMap<String, Option<i64>> =
+        stored.into_iter().map(|i| (i.hash, i.size)).collect();
+    let mut resp = ImageMissingResponse::default();
+    let mut seen = HashSet::new();
+    for image in &payload.images {
+        if !seen.insert(image.hash.as_str()) {
+            continue;
+        }
+        match stored.get(&image.hash) {
+            None => resp.missing.push(image.hash.clone()),
+            // An unknown size on either side counts as a match.
+            Some(Some(size)) if image.size.is_some_and(|s| s != *size) => {
+                resp.mismatched.push(image.hash.clone())
+            }
+            Some(_) => {}
+        }
+    }
+    info!(
+        "image_missing success: {} offered, {} missing, {} mismatched",
+        payload.images.len(),
+        resp.missing.len(),
+        resp.mismatched.len()
+    );
+    Ok(HttpResponse::Ok().json(resp))
+}
 
-    let todo_rows = match sqlx::query("SELECT uuid, updated_at FROM todo_sync")
-        .fetch_all(&state.pool)
-        .await
+/// Lists image refs, optionally narrowed by the filters in the body.
+pub async fn image_refs(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Read).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let filter: ImageRefsRequest = match optional_json(&body) {
+        Ok(filter) => filter,
+        Err(resp) => return Ok(resp),
+    };
+    if filter
+        .diary_uuids
+        .as_ref()
+        .is_some_and(|uuids| uuids.len() > MAX_REF_FILTER_DIARIES)
     {
-        Ok(rows) => rows,
+        return Ok(HttpResponse::BadRequest().body("too many diaryUuids"));
+    }
+    let refs = match state.storage.image_refs(&account, &filter).await {
+        Ok(refs) => refs,
         Err(e) => {
-            warn!("sync_meta: todo query failed: {}", e);
+            warn!("image_refs: query failed: {}", e);
             return Ok(HttpResponse::InternalServerError().finish());
         }
     };
-    let todos = todo_rows
-        .into_iter()
-        .map(|row| SyncMeta {
-            uuid: row.get("uuid"),
-            updated_at: row.get("updated_at"),
-        })
-        .collect();
+    info!("image_refs success: {} refs", refs.len());
+    Ok(HttpResponse::Ok().json(ImageRefsResponse { refs }))
+}
+
+pub async fn image_upload(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<ImageUploadRequest>,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Write).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    if let Err(message) = check_image_hashes(payload.images.iter().map(|i| i.hash.as_str())) {
+        return Ok(HttpResponse::BadRequest().body(message));
+    }
+    let success = match state.storage.put_images(&account, &payload.images).await {
+        Ok(success) => success,
+        Err(e) => {
+            warn!("image_upload: upsert failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+    info!("image_upload success: {} images", success);
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Image hashes name blob-store objects, so each must be a valid blob key
+/// whichever store is configured.
+fn check_image_hashes<'a>(hashes: impl IntoIterator<Item = &'a str>) -> Result<(), String> {
+    match hashes.into_iter().find(|hash| !storage::is_blob_key(hash)) {
+        Some(hash) => Err(format!(
+            "image hash {:?} must be 1-128 of [A-Za-z0-9_-]",
+            hash
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Parses an optional JSON body: an empty body or `null` is the defaults, and
+/// anything else must parse, so a mistyped request never falls back to them.
+fn optional_json<T: serde::de::DeserializeOwned + Default>(
+    body: &web::Bytes,
+) -> Result<T, HttpResponse> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice::<Option<T>>(body)
+        .map(Option::unwrap_or_default)
+        .map_err(|e| HttpResponse::BadRequest().body(format!("invalid JSON body: {}", e)))
+}
+
+pub(crate) fn header_str<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+/// Stores one image's ciphertext sent as the raw request body, with the IV in
+/// `X-Image-IV`. Refs are still written separately.
+pub async fn image_blob_put(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: web::Payload,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Write).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let hash = path.into_inner();
+    let Some(iv) = header_str(&req, IMAGE_IV_HEADER) else {
+        return Ok(HttpResponse::BadRequest().body("missing X-Image-IV"));
+    };
+    let updated_at = match header_str(&req, IMAGE_UPDATED_AT_HEADER) {
+        None => Utc::now().timestamp_millis(),
+        Some(v) => match v.parse::<i64>() {
+            Ok(updated_at) => updated_at,
+            Err(_) => return Ok(HttpResponse::BadRequest().body("invalid X-Updated-At")),
+        },
+    };
+    let image = ImageMeta {
+        iv: iv.to_string(),
+        updated_at,
+        size: None,
+    };
+    let (tx, mut chunks) = mpsc::channel(BODY_CHUNKS_IN_FLIGHT);
+    let (read, stored) = tokio::join!(
+        pump_body(body, tx, MAX_IMAGE_BYTES),
+        state
+            .storage
+            .put_image_stream(&account, &hash, &image, &mut chunks)
+    );
+    match read {
+        Ok(()) => {}
+        Err(BodyError::TooLarge) => return Ok(HttpResponse::PayloadTooLarge().finish()),
+        Err(BodyError::Read(e)) => return Err(e.into()),
+    }
+    let size = match stored {
+        Ok(size) => size,
+        Err(StorageError::Invalid(message)) => return Ok(HttpResponse::BadRequest().body(message)),
+        Err(e) => {
+            warn!("image_blob_put: upsert failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+    info!("image_blob_put success: {} ({} bytes)", hash, size);
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Why a request body could not be passed on in full.
+enum BodyError {
+    TooLarge,
+    Read(PayloadError),
+}
+
+/// Hands `body` to `tx` piece by piece as it arrives, so it is never held in
+/// memory whole. On a read error or more than `limit` bytes the receiver gets
+/// an error item, so it stores nothing. Stops early, without error, if the
+/// receiver goes away.
+async fn pump_body(
+    mut body: web::Payload,
+    tx: mpsc::Sender<StorageResult<web::Bytes>>,
+    limit: usize,
+) -> Result<(), BodyError> {
+    let mut len = 0;
+    while let Some(chunk) = body.next().await {
+        let (item, outcome) = match chunk {
+            Ok(chunk) if len + chunk.len() <= limit => {
+                len += chunk.len();
+                (Ok(chunk), Ok(()))
+            }
+            Ok(_) => (
+                Err(StorageError::Invalid("request body too large".to_string())),
+                Err(BodyError::TooLarge),
+            ),
+            Err(e) => (
+                Err(StorageError::Invalid(format!("request body: {}", e))),
+                Err(BodyError::Read(e)),
+            ),
+        };
+        if tx.send(item).await.is_err() {
+            return Ok(());
+        }
+        outcome?;
+    }
+    Ok(())
+}
+
+/// A `Range` request resolved against a body of known length.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// No usable single range: send everything.
+    Full,
+    /// Inclusive byte positions.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
 
-    let period_rows =
-        match sqlx::query("SELECT start_date::text as start_date, updated_at FROM period_sync")
-            .fetch_all(&state.pool)
-            .await
-        {
-            Ok(rows) => rows,
+/// Parses a single `bytes=` range. Malformed and multi-range headers are
+/// ignored (the whole body is sent), which RFC 9110 permits.
+fn parse_byte_range(header: &str, len: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        // Suffix range: the last `end` bytes.
+        return match end.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(n) => ByteRange::Partial(len.saturating_sub(n), len - 1),
+            Err(_) => ByteRange::Full,
+        };
+    }
+    let Ok(start) = start.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        match end.parse::<u64>() {
+            Ok(end) if end >= start => end,
+            _ => return ByteRange::Full,
+        }
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end.min(len - 1))
+}
+
+/// Whether an `If-None-Match` / `If-Range` list contains `etag` (weak
+/// comparison, so `W/` prefixes are ignored).
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// Returns one image's ciphertext as `application/octet-stream`, with the IV in
+/// `X-Image-IV`. The hash is the `ETag`: `If-None-Match` gets a 304 without
+/// reading the blob, and a single `Range` gets a 206 (or 416) reading only the
+/// bytes it names, or the whole blob for images stored without their size.
+pub async fn image_blob_get(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Read).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let hash = path.into_inner();
+    let etag = format!("\"{}\"", hash);
+    if let Some(if_none_match) = header_str(&req, "If-None-Match") {
+        match state.storage.image_meta(&account, &hash).await {
+            Ok(Some(meta)) if etag_matches(if_none_match, &etag) => {
+                return Ok(HttpResponse::NotModified()
+                    .insert_header(("ETag", etag))
+                    .insert_header(("Cache-Control", "private"))
+                    .insert_header((IMAGE_IV_HEADER, meta.iv))
+                    .insert_header((IMAGE_UPDATED_AT_HEADER, meta.updated_at.to_string()))
+                    .finish());
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => return Ok(HttpResponse::NotFound().body("image not found")),
             Err(e) => {
-                warn!("sync_meta: period query failed: {}", e);
+                warn!("image_blob_get: query failed: {}", e);
                 return Ok(HttpResponse::InternalServerError().finish());
             }
-        };
-    let periods = period_rows
-        .into_iter()
-        .map(|row| PeriodMeta {
-            start_date: row.get("start_date"),
-            updated_at: row.get("updated_at"),
-        })
-        .collect();
+        }
+    }
+    // A range only applies to the version the client named in `If-Range`.
+    let requested = match (header_str(&req, "Range"), header_str(&req, "If-Range")) {
+        (Some(range), None) => Some(range),
+        (Some(range), Some(if_range)) if etag_matches(if_range, &etag) => Some(range),
+        _ => None,
+    };
+    let sized = match requested {
+        Some(range) => match state.storage.image_meta(&account, &hash).await {
+            Ok(Some(meta)) => meta
+                .size
+                .map(|size| (parse_byte_range(range, size as u64), size as u64, meta)),
+            Ok(None) => return Ok(HttpResponse::NotFound().body("image not found")),
+            Err(e) => {
+                warn!("image_blob_get: query failed: {}", e);
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        },
+        None => None,
+    };
+    let (range, len, image) = match sized {
+        Some((ByteRange::Partial(start, end), len, meta)) => {
+            match state.storage.image_range(&account, &hash, start, end).await {
+                Ok(Some(data)) => (
+                    ByteRange::Partial(start, end),
+                    len,
+                    ImageBytes {
+                        iv: meta.iv,
+                        updated_at: meta.updated_at,
+                        data,
+                    },
+                ),
+                Ok(None) => return Ok(HttpResponse::NotFound().body("image not found")),
+                Err(e) => {
+                    warn!("image_blob_get: query failed: {}", e);
+                    return Ok(HttpResponse::InternalServerError().finish());
+                }
+            }
+        }
+        Some((ByteRange::Unsatisfiable, len, meta)) => (
+            ByteRange::Unsatisfiable,
+            len,
+            ImageBytes {
+                iv: meta.iv,
+                updated_at: meta.updated_at,
+                data: Vec::new(),
+            },
+        ),
+        _ => {
+            let mut image = match state.storage.image_bytes(&account, &hash).await {
+                Ok(Some(image)) => image,
+                Ok(None) => return Ok(HttpResponse::NotFound().body("image not found")),
+                Err(e) => {
+                    warn!("image_blob_get: query failed: {}", e);
+                    return Ok(HttpResponse::InternalServerError().finish());
+                }
+            };
+            let len = image.data.len() as u64;
+            let range = requested.map_or(ByteRange::Full, |range| parse_byte_range(range, len));
+            if let ByteRange::Partial(start, end) = range {
+                image.data = image.data[start as usize..=end as usize].to_vec();
+            }
+            (range, len, image)
+        }
+    };
+    let mut resp = match range {
+        ByteRange::Full => HttpResponse::Ok(),
+        ByteRange::Partial(..) => HttpResponse::PartialContent(),
+        ByteRange::Unsatisfiable => HttpResponse::RangeNotSatisfiable(),
+    };
+    resp.content_type("application/octet-stream")
+        .insert_header(("ETag", etag))
+        .insert_header(("Accept-Ranges", "bytes"))
+        .insert_header(("Cache-Control", "private"))
+        .insert_header((IMAGE_IV_HEADER, image.iv))
+        .insert_header((IMAGE_UPDATED_AT_HEADER, image.updated_at.to_string()));
+    Ok(match range {
+        ByteRange::Full => resp.body(image.data),
+        ByteRange::Partial(start, end) => resp
+            .insert_header(("Content-Range", format!("bytes {}-{}/{}", start, end, len)))
+            .body(image.data),
+        ByteRange::Unsatisfiable => resp
+            .insert_header(("Content-Range", format!("bytes */{}", len)))
+            .finish(),
+    })
+}
 
-    Ok(HttpResponse::Ok().json(SyncMetaResponse {
-        diaries,
-        todos,
-        periods,
+/// Starts a resumable upload of one image's ciphertext, or resumes the
+/// unfinished one for the same hash, IV and size; returns where to continue.
+pub async fn upload_session_begin(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    payload: web::Json<ImageUploadBeginRequest>,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Write).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let hash = path.into_inner();
+    if let Err(message) = check_image_hashes([hash.as_str()]) {
+        return Ok(HttpResponse::BadRequest().body(message));
+    }
+    if payload.size < 0 {
+        return Ok(HttpResponse::BadRequest().body("invalid size"));
+    }
+    if payload.size as usize > MAX_IMAGE_BYTES {
+        return Ok(HttpResponse::PayloadTooLarge().finish());
+    }
+    let now = Utc::now().timestamp_millis();
+    let upload = NewImageUpload {
+        iv: payload.iv.clone(),
+        size: payload.size,
+        updated_at: payload.updated_at.unwrap_or(now),
+    };
+    match state
+        .storage
+        .begin_image_upload(&account, &hash, &upload, now)
+        .await
+    {
+        Ok(status) => {
+            info!(
+                "upload_session_begin success: {} at {} of {}",
+                hash, status.offset, status.size
+            );
+            Ok(HttpResponse::Ok().json(status))
+        }
+        Err(e) => {
+            warn!("upload_session_begin: failed: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// How much of an unfinished upload has arrived, e.g. after a disconnect.
+pub async fn upload_session_status(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Read).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    match state.storage.image_upload_status(&account, &path).await {
+        Ok(Some(status)) => Ok(HttpResponse::Ok().json(status)),
+        Ok(None) => Ok(HttpResponse::NotFound().body("upload not found")),
+        Err(e) => {
+            warn!("upload_session_status: query failed: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Appends the raw request body (at most `MAX_UPLOAD_CHUNK_BYTES`) at
+/// `?offset=`. A chunk that does not start at the received offset is refused
+/// with 409 and the current status.
+pub async fn upload_session_chunk(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ImageChunkQuery>,
+    body: web::Payload,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Write).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let data = match body.to_bytes_limited(MAX_UPLOAD_CHUNK_BYTES).await {
+        Ok(data) => data?,
+        Err(_) => return Ok(HttpResponse::PayloadTooLarge().finish()),
+    };
+    match state
+        .storage
+        .append_image_chunk(
+            &account,
+            &path,
+            query.offset,
+            &data,
+            Utc::now().timestamp_millis(),
+        )
+        .await
+    {
+        Ok(Some(ChunkOutcome::Appended(status))) => Ok(HttpResponse::Ok().json(status)),
+        Ok(Some(ChunkOutcome::OffsetMismatch(status))) => Ok(HttpResponse::Conflict().json(status)),
+        Ok(None) => Ok(HttpResponse::NotFound().body("upload not found")),
+        Err(StorageError::Invalid(message)) => Ok(HttpResponse::BadRequest().body(message)),
+        Err(e) => {
+            warn!("upload_session_chunk: append failed: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Stores a fully received upload; only then does the image become visible.
+/// An incomplete upload is refused with 409 and its status.
+pub async fn upload_session_finalize(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Write).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    match state.storage.finalize_image_upload(&account, &path).await {
+        Ok(Some(FinalizeOutcome::Stored)) => {
+            info!("upload_session_finalize success: {}", path);
+            Ok(HttpResponse::Ok().finish())
+        }
+        Ok(Some(FinalizeOutcome::Incomplete(status))) => Ok(HttpResponse::Conflict().json(status)),
+        Ok(None) => Ok(HttpResponse::NotFound().body("upload not found")),
+        Err(e) => {
+            warn!("upload_session_finalize: failed: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+pub async fn image_refs_upsert(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<ImageRefsUpsertRequest>,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Write).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let success = match state.storage.put_image_refs(&account, &payload.refs).await {
+        Ok(success) => success,
+        Err(e) => {
+            warn!("image_refs_upsert: upsert failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+    info!("image_refs_upsert success: {} refs", success);
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn image_refs_delete(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<ImageRefsDeleteRequest>,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Write).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let removed = match state
+        .storage
+        .delete_image_refs(&account, &payload.refs)
+        .await
+    {
+        Ok(removed) => removed,
+        Err(e) => {
+            warn!("image_refs_delete: delete failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+    info!("image_refs_delete success: {} refs", removed);
+    Ok(HttpResponse::Ok().json(ImageRefsChangeResponse {
+        written: 0,
+        removed,
     }))
 }
 
-async fn upsert_diary(
-    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    item: &DiarySyncItem,
-) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-        INSERT INTO diary_sync (uuid, author, timestamp, updated_at, payload_iv, payload_data)
-        VALUES ($1, $2, $3, $4, $5, $6)
-        ON CONFLICT (uuid) DO UPDATE SET
-            author = EXCLUDED.author,
-            timestamp = EXCLUDED.timestamp,
-            updated_at = EXCLUDED.updated_at,
-            payload_iv = EXCLUDED.payload_iv,
-            payload_data = EXCLUDED.payload_data
-        "#,
-    )
-    .bind(&item.uuid)
-    .bind(&item.author)
-    .bind(item.timestamp)
-    .bind(item.updated_at)
-    .bind(&item.payload.iv)
-    .bind(&item.payload.data)
-    .execute(&mut **tx)
-    .await?;
-    Ok(())
+/// Replaces every ref of one diary, so images dropped from it stop being
+/// referenced (and become eligible for garbage collection).
+pub async fn image_refs_replace(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<ImageRefsReplaceRequest>,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Write).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    let payload = payload.into_inner();
+    if payload.diary_uuid.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("diaryUuid is required"));
+    }
+    if payload
+        .refs
+        .iter()
+        .any(|r| r.diary_uuid != payload.diary_uuid)
+    {
+        return Ok(HttpResponse::BadRequest().body("refs must belong to diaryUuid"));
+    }
+    let removed = match state
+        .storage
+        .replace_diary_image_refs(&account, &payload.diary_uuid, &payload.refs)
+        .await
+    {
+        Ok(removed) => removed,
+        Err(e) => {
+            warn!("image_refs_replace: replace failed: {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+    info!(
+        "image_refs_replace success: {} written, {} removed for {}",
+        payload.refs.len(),
+        removed,
+        payload.diary_uuid
+    );
+    Ok(HttpResponse::Ok().json(ImageRefsChangeResponse {
+        written: payload.refs.len(),
+        removed,
+    }))
 }
 
-async fn upsert_todo(
-    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    item: &TodoSyncItem,
-) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-        INSERT INTO todo_sync (
-            uuid, author, is_completed, created_at, completed_at, updated_at, payload_iv, payload_data
-        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-        ON CONFLICT (uuid) DO UPDATE SET
-            author = EXCLUDED.author,
-            is_completed = EXCLUDED.is_completed,
-            created_at = EXCLUDED.created_at,
-            completed_at = EXCLUDED.completed_at,
-            updated_at = EXCLUDED.updated_at,
-            payload_iv = EXCLUDED.payload_iv,
-            payload_data = EXCLUDED.payload_data
-        "#,
-    )
-    .bind(&item.uuid)
-    .bind(&item.author)
-    .bind(item.is_completed)
-    .bind(item.created_at)
-    .bind(item.completed_at)
-    .bind(item.updated_at)
-    .bind(&item.payload.iv)
-    .bind(&item.payload.data)
-    .execute(&mut **tx)
-    .await?;
-    Ok(())
+pub async fn sync_meta(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let account = match check_api_key(&req, &state, ApiScope::Read).await {
+        Ok(account) => account,
+        Err(resp) => return Ok(resp),
+    };
+    match state.storage.sync_meta(&account).await {
+        Ok(meta) => Ok(HttpResponse::Ok().json(meta)),
+        Err(e) => {
+            warn!("sync_meta: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Runs image garbage collection across all accounts; see [`gc`].
+pub async fn admin_image_gc(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> actix_web::Result<impl Responder> {
+    if let Err(resp) = check_admin_key(&req, &state).await {
+        return Ok(resp);
+    }
+    let payload: ImageGcRequest = match optional_json(&body) {
+        Ok(payload) => payload,
+        Err(resp) => return Ok(resp),
+    };
+    let grace_secs = payload.grace_secs.unwrap_or(state.env.image_gc_grace_secs);
+    let now = Utc::now().timestamp_millis();
+    match gc::collect_image_garbage(state.storage.as_ref(), grace_secs, payload.dry_run, now).await
+    {
+        Ok(report) => {
+            info!(
+                "admin_image_gc success: {} orphans, {} deleted (dry run: {})",
+                report.orphans.len(),
+                report.deleted,
+                report.dry_run
+            );
+            Ok(HttpResponse::Ok().json(report))
+        }
+        Err(e) => {
+            warn!("admin_image_gc: failed: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Mints a registry key. The key is in the response only; the server keeps its hash.
+pub async fn admin_api_key_create(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<ApiKeyCreateRequest>,
+) -> actix_web::Result<impl Responder> {
+    if let Err(resp) = check_admin_key(&req, &state).await {
+        return Ok(resp);
+    }
+    let payload = payload.into_inner();
+    let (account_id, name) = (payload.account_id.trim(), payload.name.trim());
+    if account_id.is_empty() || name.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("accountId and name are required"));
+    }
+    if !storage::is_blob_key(account_id) {
+        return Ok(HttpResponse::BadRequest().body("accountId must be 1-128 of [A-Za-z0-9_-]"));
+    }
+    let mut scopes = payload.scopes;
+    scopes.sort();
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("at least one scope is required"));
+    }
+    let (id, key) = auth::generate_api_key();
+    let new_key = NewApiKey {
+        id,
+        account_id: account_id.to_string(),
+        name: name.to_string(),
+        key_hash: auth::hash_api_key(&key),
+        scopes,
+        created_at: Utc::now().timestamp_millis(),
+    };
+    match state.storage.create_api_key(&new_key).await {
+        Ok(api_key) => {
+            info!(
+                "admin_api_key_create success: {} ({}) for {}",
+                api_key.id, api_key.name, api_key.account_id
+            );
+            Ok(HttpResponse::Ok().json(ApiKeyCreateResponse { key, api_key }))
+        }
+        Err(e) => {
+            warn!("admin_api_key_create: failed: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+pub async fn admin_api_key_list(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<ApiKeyListQuery>,
+) -> actix_web::Result<impl Responder> {
+    if let Err(resp) = check_admin_key(&req, &state).await {
+        return Ok(resp);
+    }
+    match state
+        .storage
+        .list_api_keys(query.account_id.as_deref())
+        .await
+    {
+        Ok(api_keys) => Ok(HttpResponse::Ok().json(ApiKeyListResponse { api_keys })),
+        Err(e) => {
+            warn!("admin_api_key_list: failed: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Revokes a registry key at once; it stays listed with `revokedAt`.
+pub async fn admin_api_key_revoke(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> actix_web::Result<impl Responder> {
+    if let Err(resp) = check_admin_key(&req, &state).await {
+        return Ok(resp);
+    }
+    let id = path.into_inner();
+    let now = Utc::now().timestamp_millis();
+    match state.storage.revoke_api_key(&id, now).await {
+        Ok(Some(api_key)) => {
+            // Sessions opened with the key end with it.
+            let sessions = match state
+                .storage
+                .revoke_auth_sessions(&api_key.account_id, Some(&api_key.id), now)
+                .await
+            {
+                Ok(sessions) => sessions,
+                Err(e) => {
+                    warn!("admin_api_key_revoke: session revoke failed: {}", e);
+                    return Ok(HttpResponse::InternalServerError().finish());
+                }
+            };
+            info!(
+                "admin_api_key_revoke success: {} ({} sessions)",
+                api_key.id, sessions
+            );
+            Ok(HttpResponse::Ok().json(api_key))
+        }
+        Ok(None) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            warn!("admin_api_key_revoke: failed: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Clients currently locked out for repeated auth failures.
+pub async fn admin_auth_lockouts(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    if let Err(resp) = check_admin_key(&req, &state).await {
+        return Ok(resp);
+    }
+    let lockouts = state.lockouts.locked(Utc::now().timestamp_millis());
+    Ok(HttpResponse::Ok().json(AuthLockoutListResponse { lockouts }))
+}
+
+/// The auth failure audit log, newest first.
+pub async fn admin_auth_failures(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<AuthFailureListQuery>,
+) -> actix_web::Result<impl Responder> {
+    if let Err(resp) = check_admin_key(&req, &state).await {
+        return Ok(resp);
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUTH_FAILURE_LIMIT)
+        .clamp(1, MAX_AUTH_FAILURE_LIMIT);
+    match state
+        .storage
+        .auth_failures(query.since.unwrap_or(0), limit)
+        .await
+    {
+        Ok(failures) => Ok(HttpResponse::Ok().json(AuthFailureListResponse { failures })),
+        Err(e) => {
+            warn!("admin_auth_failures: failed: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
 }
 
-async fn upsert_period(
-    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    item: &PeriodSyncItem,
-) -> Result<(), actix_web::Error> {
-    let start_date = NaiveDate::parse_from_str(&item.start_date, "%Y-%m-%d")
-        .map_err(actix_web::error::ErrorBadRequest)?;
-    let end_date = NaiveDate::parse_from_str(&item.end_date, "%Y-%m-%d")
-        .map_err(actix_web::error::ErrorBadRequest)?;
-    sqlx::query(
-        r#"
-        INSERT INTO period_sync (start_date, end_date, updated_at, payload_iv, payload_data)
-        VALUES ($1, $2, $3, $4, $5)
-        ON CONFLICT (start_date) DO UPDATE SET
-            end_date = EXCLUDED.end_date,
-            updated_at = EXCLUDED.updated_at,
-            payload_iv = EXCLUDED.payload_iv,
-            payload_data = EXCLUDED.payload_data
-        "#,
+/// Issues an access token for `session` plus the refresh token `secret`,
+/// whose hash the session holds. The refresh token does not name its session,
+/// so a session id seen in an access token cannot be used against it.
+fn token_response(
+    state: &AppState,
+    session: &AuthSession,
+    secret: &str,
+    now: i64,
+) -> AuthTokenResponse {
+    let access_expires_at = now + state.env.access_token_ttl_secs * 1000;
+    AuthTokenResponse {
+        access_token: auth::issue_access_token(&state.env.token_secret, session, access_expires_at),
+        access_expires_at,
+        refresh_token: secret.to_string(),
+        refresh_expires_at: session.expires_at,
+        scopes: session.scopes.clone(),
+    }
+}
+
+/// Trades the long-lived `X-API-Key` for a short-lived access token and a
+/// refresh token.
+pub async fn auth_login(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let credential = match auth::guarded(
+        &req,
+        &state,
+        "api key",
+        auth::api_key_credential(&req, &state),
     )
-    .bind(start_date)
-    .bind(end_date)
-    .bind(item.updated_at)
-    .bind(&item.payload.iv)
-    .bind(&item.payload.data)
-    .execute(&mut **tx)
     .await
-    .map_err(actix_web::error::ErrorInternalServerError)?;
-    Ok(())
+    {
+        Ok(credential) => credential,
+        Err(resp) => return Ok(resp),
+    };
+    let now = Utc::now().timestamp_millis();
+    let secret = auth::random_hex(32);
+    let session = AuthSession {
+        id: auth::random_hex(16),
+        account_id: credential.account_id,
+        key_id: credential.key_id,
+        scopes: credential.scopes,
+        refresh_hash: auth::hash_api_key(&secret),
+        previous_refresh_hash: None,
+        created_at: now,
+        refreshed_at: now,
+        expires_at: now + state.env.refresh_token_ttl_secs * 1000,
+        revoked_at: None,
+    };
+    if let Err(e) = state.storage.create_auth_session(&session).await {
+        warn!("auth_login: session insert failed: {}", e);
+        return Ok(HttpResponse::InternalServerError().finish());
+    }
+    info!(
+        "auth_login success: session {} for {}",
+        session.id, session.account_id
+    );
+    Ok(HttpResponse::Ok().json(token_response(&state, &session, &secret, now)))
 }
 
-async fn upsert_image(
-    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    item: &DiaryImageSyncItem,
-) -> Result<(), sqlx::Error> {
-    // Store image blob once per hash.
-    sqlx::query(
-        r#"
-        INSERT INTO diary_images (hash, blob_iv, blob_data, updated_at)
-        VALUES ($1, $2, $3, $4)
-        ON CONFLICT (hash) DO UPDATE SET
-            blob_iv = EXCLUDED.blob_iv,
-            blob_data = EXCLUDED.blob_data,
-            updated_at = EXCLUDED.updated_at
-        "#,
-    )
-    .bind(&item.hash)
-    .bind(&item.blob.iv)
-    .bind(&item.blob.data)
-    .bind(item.updated_at)
-    .execute(&mut **tx)
-    .await?;
-
-    // Track diary reference.
-    sqlx::query(
-        r#"
-        INSERT INTO diary_image_refs (diary_uuid, file_name, hash, updated_at)
-        VALUES ($1, $2, $3, $4)
-        ON CONFLICT (diary_uuid, file_name) DO UPDATE SET
-            hash = EXCLUDED.hash,
-            updated_at = EXCLUDED.updated_at
-        "#,
-    )
-    .bind(&item.diary_uuid)
-    .bind(&item.file_name)
-    .bind(&item.hash)
-    .bind(item.updated_at)
-    .execute(&mut **tx)
-    .await?;
-    Ok(())
+/// Rotates a refresh token. Presenting the one it replaced revokes the whole
+/// session, since someone else holds a copy; any other unknown token is just
+/// refused.
+async fn rotate_refresh_token(state: &AppState, refresh_token: &str) -> HttpResponse {
+    let unauthorized = || HttpResponse::Unauthorized().body("invalid or expired refresh token");
+    let presented = auth::hash_api_key(refresh_token);
+    let now = Utc::now().timestamp_millis();
+    let mut session = match state.storage.auth_session_by_refresh(&presented).await {
+        Ok(Some(session)) if session.revoked_at.is_none() && session.expires_at > now => session,
+        Ok(_) => return unauthorized(),
+        Err(e) => {
+            warn!("auth_refresh: session lookup failed: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let id = session.id.clone();
+    if !auth::constant_time_eq(presented.as_bytes(), session.refresh_hash.as_bytes()) {
+        warn!(
+            "auth_refresh: reused refresh token, revoking session {}",
+            id
+        );
+        if let Err(e) = state.storage.revoke_auth_session(&id, now).await {
+            warn!("auth_refresh: revoke failed: {}", e);
+        }
+        return unauthorized();
+    }
+    let secret = auth::random_hex(32);
+    let refresh_hash = auth::hash_api_key(&secret);
+    let expires_at = now + state.env.refresh_token_ttl_secs * 1000;
+    match state
+        .storage
+        .rotate_auth_session(&id, &presented, &refresh_hash, now, expires_at)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => return unauthorized(),
+        Err(e) => {
+            warn!("auth_refresh: rotate failed: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    session.refresh_hash = refresh_hash;
+    session.refreshed_at = now;
+    session.expires_at = expires_at;
+    info!("auth_refresh success: session {}", session.id);
+    HttpResponse::Ok().json(token_response(state, &session, &secret, now))
+}
+
+/// `/auth/refresh`; a refused refresh token counts towards the client's lockout.
+pub async fn auth_refresh(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<AuthRefreshRequest>,
+) -> actix_web::Result<impl Responder> {
+    if let Err(resp) = lockout::check_lockout(&req, &state) {
+        return Ok(resp);
+    }
+    let resp = rotate_refresh_token(&state, &payload.refresh_token).await;
+    if resp.status() == actix_web::http::StatusCode::UNAUTHORIZED {
+        lockout::record_auth_failure(&req, &state, "refresh token").await;
+    }
+    Ok(resp)
+}
+
+/// Ends the session of the access token sent with the request.
+pub async fn auth_logout(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let credential = match auth::authenticate(&req, &state).await {
+        Ok(credential) => credential,
+        Err(resp) => return Ok(resp),
+    };
+    let Some(session_id) = credential.session_id else {
+        return Ok(HttpResponse::BadRequest().body("logout needs an access token"));
+    };
+    let now = Utc::now().timestamp_millis();
+    match state.storage.revoke_auth_session(&session_id, now).await {
+        Ok(_) => {
+            info!("auth_logout success: session {}", session_id);
+            Ok(HttpResponse::Ok().finish())
+        }
+        Err(e) => {
+            warn!("auth_logout: revoke failed: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Revokes every live session of an account, e.g. after a phone is lost.
+pub async fn admin_sessions_revoke(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<SessionRevokeRequest>,
+) -> actix_web::Result<impl Responder> {
+    if let Err(resp) = check_admin_key(&req, &state).await {
+        return Ok(resp);
+    }
+    let now = Utc::now().timestamp_millis();
+    match state
+        .storage
+        .revoke_auth_sessions(&payload.account_id, None, now)
+        .await
+    {
+        Ok(revoked) => {
+            info!(
+                "admin_sessions_revoke success: {} sessions of {}",
+                revoked, payload.account_id
+            );
+            Ok(HttpResponse::Ok().json(SessionRevokeResponse { revoked }))
+        }
+        Err(e) => {
+            warn!("admin_sessions_revoke: failed: {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Registers every API route; shared by `main.rs` and the integration tests.
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/sync/upload", web::post().to(sync_upload))
+        .route("/sync/download", web::post().to(sync_download))
+        .route("/sync/meta", web::post().to(sync_meta))
+        .route("/sync/changes", web::get().to(sync_changes))
+        .route("/devices", web::post().to(device_list))
+        .route("/devices/register", web::post().to(device_register))
+        .route("/history/list", web::post().to(history_list))
+        .route("/history/restore", web::post().to(history_restore))
+        .route("/images/fetch", web::post().to(image_fetch))
+        .route("/images/hashes", web::post().to(image_hashes))
+        .route("/images/missing", web::post().to(image_missing))
+        .route("/images/refs", web::post().to(image_refs))
+        .route("/images/upload", web::post().to(image_upload))
+        .route("/images/refs/upsert", web::post().to(image_refs_upsert))
+        .route("/images/refs/delete", web::post().to(image_refs_delete))
+        .route("/images/refs/replace", web::post().to(image_refs_replace))
+        .service(
+            web::resource("/images/blob/{hash}")
+                .route(web::put().to(image_blob_put))
+                .route(web::get().to(image_blob_get)),
+        )
+        .service(
+            web::resource("/images/uploads/{hash}")
+                .route(web::post().to(upload_session_begin))
+                .route(web::get().to(upload_session_status))
+                .route(web::put().to(upload_session_chunk)),
+        )
+        .route(
+            "/images/uploads/{hash}/finalize",
+            web::post().to(upload_session_finalize),
+        )
+        .route("/admin/images/gc", web::post().to(admin_image_gc))
+        .service(
+            web::resource("/admin/api-keys")
+                .route(web::post().to(admin_api_key_create))
+                .route(web::get().to(admin_api_key_list)),
+        )
+        .route(
+            "/admin/api-keys/{id}/revoke",
+            web::post().to(admin_api_key_revoke),
+        )
+        .route(
+            "/admin/sessions/revoke",
+            web::post().to(admin_sessions_revoke),
+        )
+        .route("/admin/auth/lockouts", web::get().to(admin_auth_lockouts))
+        .route("/admin/auth/failures", web::get().to(admin_auth_failures))
+        .route("/auth/login", web::post().to(auth_login))
+        .route("/auth/refresh", web::post().to(auth_refresh))
+        .route("/auth/logout", web::post().to(auth_logout));
 }
@@ -0,0 +1,167 @@
+//! Lockout of clients that keep presenting bad credentials. Failures are
+//! counted per client IP (see [`client_ip`]); from `AUTH_LOCKOUT_THRESHOLD` consecutive failures on
+//! the client is refused for `AUTH_LOCKOUT_BASE_SECS`, doubling with every
+//! further failure up to `AUTH_LOCKOUT_MAX_SECS`. Authenticating resets the
+//! count. Every failure is also written to the `auth_failures` audit log.
+
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::Utc;
+use log::warn;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use crate::db::EnvConfig;
+use crate::models::AuthLockoutInfo;
+use crate::{header_str, AppState};
+
+/// Client name for requests without a peer address (e.g. in tests).
+const UNKNOWN_CLIENT: &str = "unknown";
+
+struct ClientFailures {
+    failures: u32,
+    last_failure_at: i64,
+    locked_until: Option<i64>,
+}
+
+/// Failure counters by client IP, shared by all workers.
+#[derive(Default)]
+pub struct LockoutTracker {
+    clients: Mutex<HashMap<String, ClientFailures>>,
+}
+
+impl LockoutTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// When the client's lockout ends, if it is locked out at `now`.
+    pub fn locked_until(&self, client: &str, now: i64) -> Option<i64> {
+        let clients = self.clients.lock().expect("lockout tracker poisoned");
+        clients
+            .get(client)
+            .and_then(|c| c.locked_until)
+            .filter(|until| *until > now)
+    }
+
+    /// Counts a failure; returns when the client is now locked out until, if it is.
+    pub fn record_failure(&self, env: &EnvConfig, client: &str, now: i64) -> Option<i64> {
+        let mut clients = self.clients.lock().expect("lockout tracker poisoned");
+        // A client whose last failure is older than the longest lockout starts over.
+        let forget_before = now - env.auth_lockout_max_secs * 1000;
+        clients.retain(|_, c| {
+            c.last_failure_at >= forget_before || c.locked_until.is_some_and(|until| until > now)
+        });
+        let entry = clients.entry(client.to_string()).or_insert(ClientFailures {
+            failures: 0,
+            last_failure_at: now,
+            locked_until: None,
+        });
+        entry.failures += 1;
+        entry.last_failure_at = now;
+        if env.auth_lockout_threshold > 0 && entry.failures >= env.auth_lockout_threshold {
+            let doublings = (entry.failures - env.auth_lockout_threshold).min(30);
+            let secs = env
+                .auth_lockout_base_secs
+                .saturating_mul(1 << doublings)
+                .min(env.auth_lockout_max_secs);
+            entry.locked_until = Some(now + secs * 1000);
+        }
+        entry.locked_until
+    }
+
+    pub fn record_success(&self, client: &str) {
+        let mut clients = self.clients.lock().expect("lockout tracker poisoned");
+        clients.remove(client);
+    }
+
+    /// Clients locked out at `now`, longest lockout first.
+    pub fn locked(&self, now: i64) -> Vec<AuthLockoutInfo> {
+        let clients = self.clients.lock().expect("lockout tracker poisoned");
+        let mut locked: Vec<AuthLockoutInfo> = clients
+            .iter()
+            .filter_map(|(ip, c)| {
+                let locked_until = c.locked_until.filter(|until| *until > now)?;
+                Some(AuthLockoutInfo {
+                    ip: ip.clone(),
+                    failures: c.failures,
+                    last_failure_at: c.last_failure_at,
+                    locked_until,
+                })
+            })
+            .collect();
+        locked.sort_by(|a, b| b.locked_until.cmp(&a.locked_until).then(a.ip.cmp(&b.ip)));
+        locked
+    }
+}
+
+/// The IP of the client behind a request: the peer, or when the peer is one
+/// of `TRUSTED_PROXIES`, the nearest hop in `X-Forwarded-For` that is not.
+/// Forwarded addresses from other peers are ignored, since anyone can send them.
+pub(crate) fn client_ip(req: &HttpRequest, env: &EnvConfig) -> String {
+    let Some(peer) = req.peer_addr().map(|addr| addr.ip()) else {
+        return UNKNOWN_CLIENT.to_string();
+    };
+    if !env.trusted_proxies.contains(&peer) {
+        return peer.to_string();
+    }
+    header_str(req, "X-Forwarded-For")
+        .into_iter()
+        .flat_map(|hops| hops.rsplit(','))
+        .map_while(|hop| hop.trim().parse::<IpAddr>().ok())
+        .find(|ip| !env.trusted_proxies.contains(ip))
+        .unwrap_or(peer)
+        .to_string()
+}
+
+fn locked_out(locked_until: i64, now: i64) -> HttpResponse {
+    let retry_after = ((locked_until - now) + 999) / 1000;
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", retry_after.max(1).to_string()))
+        .body("too many failed attempts")
+}
+
+/// Refuses a locked-out client before its credentials are looked at.
+pub(crate) fn check_lockout(req: &HttpRequest, state: &AppState) -> Result<(), HttpResponse> {
+    let now = Utc::now().timestamp_millis();
+    match state
+        .lockouts
+        .locked_until(&client_ip(req, &state.env), now)
+    {
+        Some(until) => Err(locked_out(until, now)),
+        None => Ok(()),
+    }
+}
+
+/// Counts and logs a refused `reason` credential. Audit failures are only
+/// logged; the request is refused either way.
+pub(crate) async fn record_auth_failure(req: &HttpRequest, state: &AppState, reason: &str) {
+    let ip = client_ip(req, &state.env);
+    let now = Utc::now().timestamp_millis();
+    // Requests that passed the lockout check while another one locked the
+    // client out are neither counted nor written to the audit log.
+    if state.lockouts.locked_until(&ip, now).is_some() {
+        return;
+    }
+    let locked_until = state.lockouts.record_failure(&state.env, &ip, now);
+    warn!(
+        "auth: {} refused for {} on {}{}",
+        reason,
+        ip,
+        req.path(),
+        locked_until
+            .map(|until| format!(", locked out for {}s", (until - now) / 1000))
+            .unwrap_or_default()
+    );
+    if let Err(e) = state
+        .storage
+        .record_auth_failure(&ip, req.path(), reason, now)
+        .await
+    {
+        warn!("auth: recording failure for {} failed: {}", ip, e);
+    }
+}
+
+pub(crate) fn record_auth_success(req: &HttpRequest, state: &AppState) {
+    state.lockouts.record_success(&client_ip(req, &state.env));
+}
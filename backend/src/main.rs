@@ -1,54 +1,153 @@
-use actix_governor::{Governor, GovernorConfigBuilder};
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix_web::middleware::{from_fn, Logger};
+use actix_web::{web, App, HttpServer};
 use dotenvy::dotenv;
 use env_logger::Env;
-use log::info;
+use log::{info, warn};
 use sqlx::postgres::PgPoolOptions;
+use std::sync::Arc;
+use std::time::Duration;
 use syezw_sync_backend::db::{build_db_url, EnvConfig};
-use syezw_sync_backend::{
-    image_fetch, image_hashes, image_refs, image_refs_upsert, image_upload, sync_download,
-    sync_upload, AppState,
+use syezw_sync_backend::gc::{spawn_expiry_sweep, spawn_image_gc};
+use syezw_sync_backend::ratelimit::limit_per_ip;
+use syezw_sync_backend::signing::verify_request_signature;
+use syezw_sync_backend::storage::{
+    BlobStore, FsBlobStore, MigrationMode, PgStorage, S3BlobStore, S3Config, Storage,
 };
+use syezw_sync_backend::{configure_routes, AppState, MAX_JSON_BYTES};
+
+/// The configured image blob store; `None` keeps blobs inline in the database.
+async fn open_blob_store(env: &EnvConfig) -> Option<Arc<dyn BlobStore>> {
+    match env.blob_store.as_str() {
+        "database" => None,
+        "fs" => {
+            info!("Storing image blobs under {}", env.blob_dir);
+            Some(Arc::new(
+                FsBlobStore::open(&env.blob_dir)
+                    .await
+                    .expect("open blob dir"),
+            ))
+        }
+        "s3" => {
+            info!(
+                "Storing image blobs in bucket {} at {}",
+                env.s3_bucket, env.s3_endpoint
+            );
+            Some(Arc::new(
+                S3BlobStore::new(S3Config {
+                    endpoint: env.s3_endpoint.clone(),
+                    bucket: env.s3_bucket.clone(),
+                    region: env.s3_region.clone(),
+                    access_key: env.s3_access_key.clone(),
+                    secret_key: env.s3_secret_key.clone(),
+                    prefix: env.s3_prefix.clone(),
+                })
+                .unwrap_or_else(|e| panic!("S3 blob store: {}", e)),
+            ))
+        }
+        other => panic!("unsupported BLOB_STORE: {}", other),
+    }
+}
+
+/// Connects the configured backend and migrates (or checks) its schema.
+/// Refuses to start against a schema newer than this binary.
+async fn connect_storage(env: &EnvConfig) -> Arc<dyn Storage> {
+    let mode = if env.auto_migrate {
+        MigrationMode::Apply
+    } else {
+        MigrationMode::Check
+    };
+    let blobs = open_blob_store(env).await;
+    match env.storage_backend.as_str() {
+        "postgres" => {
+            let db_url = build_db_url(env);
+            let pool = PgPoolOptions::new()
+                .max_connections(5)
+                .connect(&db_url)
+                .await
+                .expect("connect database");
+            let mut storage = PgStorage::new(pool);
+            if let Some(blobs) = blobs {
+                storage = storage.with_blob_store(blobs);
+            }
+            let version = storage
+                .migrate(mode)
+                .await
+                .unwrap_or_else(|e| panic!("database schema: {}", e));
+            info!("Database schema at version {}", version);
+            Arc::new(storage)
+        }
+        #[cfg(feature = "sqlite")]
+        "sqlite" => {
+            info!("Using SQLite storage at {}", env.sqlite_path);
+            let mut storage = syezw_sync_backend::storage::SqliteStorage::connect(&env.sqlite_path)
+                .await
+                .expect("open sqlite database");
+            if let Some(blobs) = blobs {
+                storage = storage.with_blob_store(blobs);
+            }
+            let version = storage
+                .migrate(mode)
+                .await
+                .unwrap_or_else(|e| panic!("database schema: {}", e));
+            info!("Database schema at version {}", version);
+            Arc::new(storage)
+        }
+        other => panic!("unsupported STORAGE_BACKEND: {}", other),
+    }
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
     env_logger::init_from_env(Env::default().default_filter_or("info"));
     let env = EnvConfig::from_env();
-    let db_url = build_db_url(&env);
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&db_url)
-        .await
-        .expect("connect database");
+    let storage = connect_storage(&env).await;
+    if std::env::args().nth(1).as_deref() == Some("migrate-blobs") {
+        // One-off: move ciphertext still stored inline into the configured blob store.
+        let report = storage
+            .move_inline_blobs()
+            .await
+            .unwrap_or_else(|e| panic!("migrate-blobs: {}", e));
+        for (account, hash) in &report.skipped {
+            warn!(
+                "migrate-blobs skipped {}/{}: invalid key or data",
+                account, hash
+            );
+        }
+        info!(
+            "migrate-blobs moved {} image(s), skipped {}",
+            report.moved,
+            report.skipped.len()
+        );
+        return Ok(());
+    }
+    if env.image_gc_interval_secs > 0 {
+        info!(
+            "Image GC every {}s with a {}s grace period",
+            env.image_gc_interval_secs, env.image_gc_grace_secs
+        );
+        spawn_image_gc(
+            storage.clone(),
+            Duration::from_secs(env.image_gc_interval_secs),
+            env.image_gc_grace_secs,
+        );
+    }
+    spawn_expiry_sweep(storage.clone(), env.clone());
     let bind_addr = std::env::var("BIND_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
     info!("Starting server on {}", bind_addr);
 
-    let governor_conf = GovernorConfigBuilder::default()
-        // 限制2秒一次请求
-        .seconds_per_request(2)
-        .burst_size(10)
-        .finish()
-        .expect("failed to build governor config");
-
+    // Built once so every worker shares the nonce cache, lockout counters and
+    // rate limits.
+    let state = AppState::new(env, storage);
     HttpServer::new(move || {
-        let json_cfg = web::JsonConfig::default().limit(50 * 1024 * 1024); // 50 MB limit for image uploads
+        let json_cfg = web::JsonConfig::default().limit(MAX_JSON_BYTES);
         App::new()
+            .wrap(from_fn(verify_request_signature))
             .wrap(Logger::default())
-            .wrap(Governor::new(&governor_conf))
+            .wrap(from_fn(limit_per_ip))
             .app_data(json_cfg)
-            .app_data(web::Data::new(AppState {
-                env: env.clone(),
-                pool: pool.clone(),
-            }))
-            .route("/sync/upload", web::post().to(sync_upload))
-            .route("/sync/download", web::post().to(sync_download))
-            .route("/sync/meta", web::post().to(syezw_sync_backend::sync_meta))
-            .route("/images/fetch", web::post().to(image_fetch))
-            .route("/images/hashes", web::post().to(image_hashes))
-            .route("/images/refs", web::post().to(image_refs))
-            .route("/images/upload", web::post().to(image_upload))
-            .route("/images/refs/upsert", web::post().to(image_refs_upsert))
+            .app_data(web::Data::new(state.clone()))
+            .configure(configure_routes)
     })
     .bind(bind_addr)?
     .run()
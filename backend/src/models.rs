@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct EncryptedBlob {
     pub iv: String,
     pub data: String,
@@ -14,6 +14,10 @@ pub struct DiarySyncItem {
     pub timestamp: i64,
     pub updated_at: i64,
     pub payload: EncryptedBlob,
+    /// `updatedAt` of the server version this edit was based on, if known.
+    /// Lets a write through even when its own `updatedAt` is not newer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_updated_at: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -26,15 +30,27 @@ pub struct TodoSyncItem {
     pub completed_at: Option<i64>,
     pub updated_at: i64,
     pub payload: EncryptedBlob,
+    /// `updatedAt` of the server version this edit was based on, if known.
+    /// Lets a write through even when its own `updatedAt` is not newer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_updated_at: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct PeriodSyncItem {
+    /// Stable id that survives start date corrections. Older clients omit it;
+    /// their items are matched to the stored period by `start_date`.
+    #[serde(default)]
+    pub uuid: String,
     pub start_date: String,
     pub end_date: String,
     pub updated_at: i64,
     pub payload: EncryptedBlob,
+    /// `updatedAt` of the server version this edit was based on, if known.
+    /// Lets a write through even when its own `updatedAt` is not newer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_updated_at: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -47,20 +63,84 @@ pub struct DiaryImageSyncItem {
     pub blob: EncryptedBlob,
 }
 
+/// Kind of synced record a tombstone refers to.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordKind {
+    Diary,
+    Todo,
+    Period,
+}
+
+impl RecordKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecordKind::Diary => "diary",
+            RecordKind::Todo => "todo",
+            RecordKind::Period => "period",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "diary" => Some(RecordKind::Diary),
+            "todo" => Some(RecordKind::Todo),
+            "period" => Some(RecordKind::Period),
+            _ => None,
+        }
+    }
+}
+
+/// Deletion marker for a diary, todo or period. Older clients may send a
+/// period's `start_date` as its uuid.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TombstoneItem {
+    pub uuid: String,
+    #[serde(rename = "type")]
+    pub kind: RecordKind,
+    pub deleted_at: i64,
+    /// `updatedAt` of the server version this edit was based on, if known.
+    /// Lets a write through even when its own `updatedAt` is not newer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_updated_at: Option<i64>,
+    /// Set by the server on period tombstones: the deleted period's start
+    /// date, when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_date: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SyncUploadRequest {
     pub diaries: Vec<DiarySyncItem>,
     pub todos: Vec<TodoSyncItem>,
     pub periods: Vec<PeriodSyncItem>,
     pub images: Vec<DiaryImageSyncItem>,
+    #[serde(default)]
+    pub deletions: Vec<TombstoneItem>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct SyncCounts {
     pub diaries: usize,
     pub todos: usize,
     pub periods: usize,
     pub images: usize,
+    #[serde(default)]
+    pub deletions: usize,
+}
+
+/// An upload item the server refused because it holds a newer version.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncConflict {
+    pub uuid: String,
+    #[serde(rename = "type")]
+    pub kind: RecordKind,
+    /// `updatedAt` of the server's current version (or `deletedAt` if deleted).
+    pub server_updated_at: i64,
+    /// True when the server's current version is a tombstone.
+    pub deleted: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -68,17 +148,25 @@ pub struct SyncUploadResponse {
     pub ok: bool,
     pub message: String,
     pub counts: SyncCounts,
+    #[serde(default)]
+    pub rejected: Vec<SyncConflict>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct SyncDownloadEnvelope {
     pub ok: bool,
     pub message: String,
     pub counts: SyncCounts,
     pub data: SyncDownloadResponse,
+    /// Set when the page budget was hit; send it back as `pageToken` (with the
+    /// same metadata) to continue. Absent on the last page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_page_token: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct SyncDownloadRequest {
     #[serde(default)]
     pub diaries: Vec<SyncMeta>,
@@ -86,6 +174,16 @@ pub struct SyncDownloadRequest {
     pub todos: Vec<SyncMeta>,
     #[serde(default)]
     pub periods: Vec<PeriodMeta>,
+    /// Maximum number of records per response; unlimited when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// Approximate maximum payload bytes per response; unlimited when absent.
+    /// A page always carries at least one record so sync keeps progressing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+    /// `nextPageToken` from the previous page; absent for the first page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_token: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -98,6 +196,9 @@ pub struct SyncMeta {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct PeriodMeta {
+    /// Empty for older clients, which only know periods by `start_date`.
+    #[serde(default)]
+    pub uuid: String,
     pub start_date: String,
     pub updated_at: i64,
 }
@@ -107,6 +208,28 @@ pub struct ImageHashListResponse {
     pub hashes: Vec<String>,
 }
 
+/// An image hash with its ciphertext length in bytes, when known.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ImageHashSize {
+    pub hash: String,
+    #[serde(default)]
+    pub size: Option<i64>,
+}
+
+/// The images a client is about to upload.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImageMissingRequest {
+    pub images: Vec<ImageHashSize>,
+}
+
+/// The requested hashes worth uploading: `missing` ones the server lacks and
+/// `mismatched` ones it holds with a different size.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ImageMissingResponse {
+    pub missing: Vec<String>,
+    pub mismatched: Vec<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ImageRefsResponse {
     pub refs: Vec<DiaryImageRefItem>,
@@ -121,6 +244,45 @@ pub struct DiaryImageRefItem {
     pub updated_at: i64,
 }
 
+/// Optional filters for `/images/refs`; an empty body lists every ref.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageRefsRequest {
+    /// Only refs of these diaries.
+    #[serde(default)]
+    pub diary_uuids: Option<Vec<String>>,
+    /// Only refs with a larger `updated_at`.
+    #[serde(default)]
+    pub updated_after: Option<i64>,
+}
+
+/// Identifies one diary image ref.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageRefKey {
+    pub diary_uuid: String,
+    pub file_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImageRefsDeleteRequest {
+    pub refs: Vec<ImageRefKey>,
+}
+
+/// Makes `refs` the complete set of refs of one diary.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageRefsReplaceRequest {
+    pub diary_uuid: String,
+    pub refs: Vec<DiaryImageRefItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ImageRefsChangeResponse {
+    pub written: usize,
+    pub removed: usize,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ImageUploadRequest {
     pub images: Vec<DiaryImageSyncItem>,
@@ -148,12 +310,90 @@ pub struct ImageFetchResponse {
     pub blob: EncryptedBlob,
 }
 
+/// Starts (or resumes) a resumable upload of one image's raw ciphertext.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageUploadBeginRequest {
+    pub iv: String,
+    /// Total ciphertext size in bytes.
+    pub size: i64,
+    /// Defaults to the time of the request.
+    #[serde(default)]
+    pub updated_at: Option<i64>,
+}
+
+/// Progress of an unfinished upload; the next chunk must start at `offset`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageUploadStatus {
+    pub hash: String,
+    pub size: i64,
+    pub offset: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImageChunkQuery {
+    /// Byte offset of the chunk in the body.
+    pub offset: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageGcRequest {
+    /// Only report what would be deleted.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Overrides `IMAGE_GC_GRACE_SECS` for this run.
+    #[serde(default)]
+    pub grace_secs: Option<i64>,
+}
+
+/// An image no diary ref points at.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanImage {
+    pub account_id: String,
+    pub hash: String,
+    /// Server time (ms) the image was last written.
+    pub stored_at: i64,
+    /// Server time (ms) the image was left without refs; the grace period
+    /// counts from here.
+    pub unreferenced_at: i64,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageGcResponse {
+    pub dry_run: bool,
+    /// Only images unreferenced since before this server time (ms) were considered.
+    pub cutoff: i64,
+    pub orphans: Vec<OrphanImage>,
+    pub deleted: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct SyncDownloadResponse {
     pub diaries: Vec<DiarySyncItem>,
     pub todos: Vec<TodoSyncItem>,
     pub periods: Vec<PeriodSyncItem>,
     pub images: Vec<DiaryImageSyncItem>,
+    #[serde(default)]
+    pub deletions: Vec<TombstoneItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncChangesQuery {
+    /// Cursor returned by the previous call; omit or pass 0 for a full sync.
+    pub since: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncChangesResponse {
+    pub diaries: Vec<DiarySyncItem>,
+    pub todos: Vec<TodoSyncItem>,
+    pub periods: Vec<PeriodSyncItem>,
+    pub deletions: Vec<TombstoneItem>,
+    pub cursor: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -161,4 +401,235 @@ pub struct SyncMetaResponse {
     pub diaries: Vec<SyncMeta>,
     pub todos: Vec<SyncMeta>,
     pub periods: Vec<PeriodMeta>,
+    #[serde(default)]
+    pub deletions: Vec<TombstoneItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryListRequest {
+    #[serde(rename = "type")]
+    pub kind: RecordKind,
+    pub uuid: String,
+}
+
+/// One archived version of a diary or todo, still encrypted.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RevisionItem {
+    pub revision_id: i64,
+    pub updated_at: i64,
+    pub archived_at: i64,
+    pub payload: EncryptedBlob,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryListResponse {
+    pub revisions: Vec<RevisionItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryRestoreRequest {
+    #[serde(rename = "type")]
+    pub kind: RecordKind,
+    pub uuid: String,
+    pub revision_id: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryRestoreResponse {
+    pub ok: bool,
+    pub message: String,
+    /// `updatedAt` assigned to the restored version; newer than anything the
+    /// server held so every device picks it up on its next download.
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceRegisterRequest {
+    pub device_id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub app_version: String,
+}
+
+/// A registered phone and when it last synced successfully (server epoch ms).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceItem {
+    pub device_id: String,
+    pub name: String,
+    pub app_version: String,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    pub last_upload_at: Option<i64>,
+    pub last_download_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeviceListResponse {
+    pub devices: Vec<DeviceItem>,
+}
+
+/// What a registered API key may do.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiScope {
+    /// Download, list and fetch.
+    Read,
+    /// Upload, restore, register devices and edit image refs.
+    Write,
+    /// The `/admin` endpoints.
+    Admin,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::Read => "read",
+            ApiScope::Write => "write",
+            ApiScope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read" => Some(ApiScope::Read),
+            "write" => Some(ApiScope::Write),
+            "admin" => Some(ApiScope::Admin),
+            _ => None,
+        }
+    }
+}
+
+fn default_key_account() -> String {
+    crate::db::DEFAULT_ACCOUNT.to_string()
+}
+
+fn default_key_scopes() -> Vec<ApiScope> {
+    vec![ApiScope::Read, ApiScope::Write]
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyCreateRequest {
+    #[serde(default = "default_key_account")]
+    pub account_id: String,
+    pub name: String,
+    #[serde(default = "default_key_scopes")]
+    pub scopes: Vec<ApiScope>,
+}
+
+/// A registered key as the admin endpoints show it; the key itself is only
+/// ever returned once, by `ApiKeyCreateResponse`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub account_id: String,
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyCreateResponse {
+    /// The new credential for `X-API-Key`; the server keeps only its hash.
+    pub key: String,
+    pub api_key: ApiKeyInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyListQuery {
+    #[serde(default)]
+    pub account_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyListResponse {
+    pub api_keys: Vec<ApiKeyInfo>,
+}
+
+/// Issued by `/auth/login` and `/auth/refresh`. The access token goes in
+/// `Authorization: Bearer`; the refresh token is single-use.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthTokenResponse {
+    pub access_token: String,
+    pub access_expires_at: i64,
+    pub refresh_token: String,
+    pub refresh_expires_at: i64,
+    pub scopes: Vec<ApiScope>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthRefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionRevokeRequest {
+    pub account_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionRevokeResponse {
+    pub revoked: usize,
+}
+
+/// One refused authentication attempt, as kept in the `auth_failures` audit log.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthFailure {
+    pub id: i64,
+    /// Peer IP of the client.
+    pub ip: String,
+    pub path: String,
+    /// Which credential was refused, e.g. `api key` or `refresh token`.
+    pub reason: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthFailureListQuery {
+    /// Only failures at or after this time (epoch ms).
+    #[serde(default)]
+    pub since: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthFailureListResponse {
+    /// Newest first.
+    pub failures: Vec<AuthFailure>,
+}
+
+/// A client currently refused for too many failed attempts.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthLockoutInfo {
+    pub ip: String,
+    /// Consecutive failures since the client last authenticated.
+    pub failures: u32,
+    pub last_failure_at: i64,
+    pub locked_until: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthLockoutListResponse {
+    pub lockouts: Vec<AuthLockoutInfo>,
 }
@@ -0,0 +1,311 @@
+//! Rate limits. Every request first draws on a budget per client IP, so
+//! logins, admin calls and unauthenticated or failing requests are limited
+//! too. Authenticated requests are then limited per client: the credential
+//! (registry key, or account for environment keys) plus `X-Device-Id`, so
+//! phones behind one NAT or sharing one key do not share a bucket. Each route
+//! group has its own request budget, and uploads also draw on an hourly byte
+//! quota. Device ids are chosen by the client, so the credential as a whole is
+//! capped at `RATE_LIMIT_MAX_DEVICES` devices' worth of each budget.
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use actix_web::http::Version;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::auth::Credential;
+use crate::db::EnvConfig;
+use crate::lockout::client_ip;
+use crate::{header_str, AppState, DEVICE_ID_HEADER};
+
+/// Buckets are pruned once a map holds more than this many.
+const PRUNE_THRESHOLD: usize = 1024;
+
+const MINUTE_MS: f64 = 60_000.0;
+const HOUR_MS: f64 = 3_600_000.0;
+
+/// Routes that share a request budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    /// Metadata, change feeds, listings, history and devices.
+    Meta,
+    /// `/sync/download`, `/images/fetch` and image blob reads.
+    Download,
+    /// `/sync/upload` and other record and ref writes.
+    Upload,
+    /// Image uploads: JSON, binary and resumable.
+    ImageUpload,
+}
+
+impl RouteGroup {
+    /// The group of a route, by its registered pattern.
+    pub fn of(req: &HttpRequest) -> Self {
+        let pattern = req.match_pattern();
+        match pattern.as_deref().unwrap_or_else(|| req.path()) {
+            "/sync/download" | "/images/fetch" => RouteGroup::Download,
+            "/images/blob/{hash}" if req.method() == actix_web::http::Method::GET => {
+                RouteGroup::Download
+            }
+            "/sync/upload"
+            | "/history/restore"
+            | "/devices/register"
+            | "/images/refs/upsert"
+            | "/images/refs/delete"
+            | "/images/refs/replace" => RouteGroup::Upload,
+            "/images/upload"
+            | "/images/blob/{hash}"
+            | "/images/uploads/{hash}"
+            | "/images/uploads/{hash}/finalize" => RouteGroup::ImageUpload,
+            _ => RouteGroup::Meta,
+        }
+    }
+
+    /// Requests per minute allowed (and the burst); 0 is unlimited.
+    fn per_minute(self, env: &EnvConfig) -> u32 {
+        match self {
+            RouteGroup::Meta => env.rate_limit_meta_per_min,
+            RouteGroup::Download => env.rate_limit_download_per_min,
+            RouteGroup::Upload => env.rate_limit_upload_per_min,
+            RouteGroup::ImageUpload => env.rate_limit_image_upload_per_min,
+        }
+    }
+
+    /// Whether request bodies count against the upload byte quota.
+    fn counts_bytes(self) -> bool {
+        matches!(self, RouteGroup::Upload | RouteGroup::ImageUpload)
+    }
+}
+
+/// A token bucket holding up to `capacity`, refilled continuously.
+struct Bucket {
+    tokens: f64,
+    updated_at: i64,
+}
+
+impl Bucket {
+    fn refill(&mut self, capacity: f64, per_ms: f64, now: i64) {
+        let elapsed = (now - self.updated_at).max(0) as f64;
+        self.tokens = (self.tokens + elapsed * per_ms).min(capacity);
+        self.updated_at = now;
+    }
+
+    /// Ms until `cost` tokens are available; 0 when they are.
+    fn wait_for(&self, cost: f64, per_ms: f64) -> i64 {
+        if self.tokens >= cost {
+            0
+        } else {
+            ((cost - self.tokens) / per_ms).ceil() as i64
+        }
+    }
+}
+
+/// Why a request was not admitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// Over budget; admitted again after this many ms.
+    Wait(i64),
+    /// Larger than a whole budget, so it can never be admitted.
+    TooLarge,
+}
+
+/// Takes `cost` from every listed bucket (key and capacity; each refills from
+/// empty in `refill_ms`), or from none of them. Buckets start full.
+fn take<K: Clone + Eq + std::hash::Hash>(
+    buckets: &Mutex<HashMap<K, Bucket>>,
+    keys: &[(K, f64)],
+    cost: f64,
+    refill_ms: f64,
+    now: i64,
+) -> Result<(), Refusal> {
+    if keys.iter().any(|(_, capacity)| cost > *capacity) {
+        return Err(Refusal::TooLarge);
+    }
+    let mut buckets = buckets.lock().expect("rate limiter poisoned");
+    if buckets.len() > PRUNE_THRESHOLD {
+        // A bucket idle for a whole refill is full, the same as none.
+        buckets.retain(|_, b| ((now - b.updated_at) as f64) < refill_ms);
+    }
+    let mut wait = 0;
+    for (key, capacity) in keys {
+        let per_ms = capacity / refill_ms;
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: *capacity,
+            updated_at: now,
+        });
+        bucket.refill(*capacity, per_ms, now);
+        wait = wait.max(bucket.wait_for(cost, per_ms));
+    }
+    if wait > 0 {
+        return Err(Refusal::Wait(wait));
+    }
+    for (key, _) in keys {
+        if let Some(bucket) = buckets.get_mut(key) {
+            bucket.tokens -= cost;
+        }
+    }
+    Ok(())
+}
+
+/// Who a bucket belongs to: a client IP, a credential and device, or a
+/// credential across all its devices.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    Ip(String),
+    Device(String, Option<String>),
+    Credential(String),
+}
+
+/// Request and upload byte buckets by client, shared by all workers.
+#[derive(Default)]
+pub struct RateLimiter {
+    requests: Mutex<HashMap<(RouteGroup, BucketKey), Bucket>>,
+    upload_bytes: Mutex<HashMap<BucketKey, Bucket>>,
+    ips: Mutex<HashMap<BucketKey, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Admits one request from `ip` under `RATE_LIMIT_IP_PER_MIN`.
+    pub fn check_ip(&self, env: &EnvConfig, ip: &str, now: i64) -> Result<(), Refusal> {
+        if env.rate_limit_ip_per_min == 0 {
+            return Ok(());
+        }
+        let capacity = env.rate_limit_ip_per_min as f64;
+        take(
+            &self.ips,
+            &[(BucketKey::Ip(ip.to_string()), capacity)],
+            1.0,
+            MINUTE_MS,
+            now,
+        )
+    }
+
+    /// Admits one `group` request of `bytes` from `credential` on `device`.
+    /// The device has the group's budget; the credential over all its
+    /// devices has `RATE_LIMIT_MAX_DEVICES` times that. A request refused for
+    /// its bytes still uses up its request token.
+    pub fn check(
+        &self,
+        env: &EnvConfig,
+        group: RouteGroup,
+        credential: &str,
+        device: Option<&str>,
+        bytes: u64,
+        now: i64,
+    ) -> Result<(), Refusal> {
+        let device_key = BucketKey::Device(credential.to_string(), device.map(str::to_string));
+        let credential_key = BucketKey::Credential(credential.to_string());
+        let devices = env.rate_limit_max_devices.max(1) as f64;
+        let per_minute = group.per_minute(env);
+        if per_minute > 0 {
+            let capacity = per_minute as f64;
+            take(
+                &self.requests,
+                &[
+                    ((group, device_key.clone()), capacity),
+                    ((group, credential_key.clone()), capacity * devices),
+                ],
+                1.0,
+                MINUTE_MS,
+                now,
+            )?;
+        }
+        if group.counts_bytes() && env.upload_bytes_per_hour > 0 && bytes > 0 {
+            let capacity = env.upload_bytes_per_hour as f64;
+            take(
+                &self.upload_bytes,
+                &[(device_key, capacity), (credential_key, capacity * devices)],
+                bytes as f64,
+                HOUR_MS,
+                now,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The credential of an authenticated request, as a bucket owner.
+fn credential_key(credential: &Credential) -> String {
+    match &credential.key_id {
+        Some(key_id) => format!("key:{}", key_id),
+        None => format!("account:{}", credential.account_id),
+    }
+}
+
+fn refused(refusal: Refusal, reason: &str) -> HttpResponse {
+    match refusal {
+        Refusal::Wait(wait_ms) => HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", ((wait_ms + 999) / 1000).max(1).to_string()))
+            .body(reason.to_string()),
+        Refusal::TooLarge => HttpResponse::PayloadTooLarge().body("upload byte quota exceeded"),
+    }
+}
+
+/// The declared body length. A body streamed without `Content-Length`
+/// (chunked, or HTTP/2) has none; a request with neither carries no body.
+fn body_length(req: &HttpRequest) -> Option<u64> {
+    match header_str(req, CONTENT_LENGTH.as_str()) {
+        Some(len) => len.parse::<u64>().ok(),
+        None if req.headers().contains_key(TRANSFER_ENCODING)
+            || req.version() >= Version::HTTP_2 =>
+        {
+            None
+        }
+        None => Some(0),
+    }
+}
+
+/// Refuses the request with 429 and `Retry-After` when its client is over
+/// the route group's budget. Bodies are measured by `Content-Length`, which
+/// the server holds them to; uploads without one get 411, and bodies larger
+/// than the whole byte quota 413.
+pub(crate) fn check_rate_limit(
+    req: &HttpRequest,
+    state: &AppState,
+    credential: &Credential,
+) -> Result<(), HttpResponse> {
+    let group = RouteGroup::of(req);
+    let bytes = match body_length(req) {
+        Some(bytes) => bytes,
+        None if group.counts_bytes() && state.env.upload_bytes_per_hour > 0 => {
+            return Err(HttpResponse::LengthRequired().body("Content-Length required"));
+        }
+        None => 0,
+    };
+    let now = Utc::now().timestamp_millis();
+    state
+        .rate_limits
+        .check(
+            &state.env,
+            group,
+            &credential_key(credential),
+            header_str(req, DEVICE_ID_HEADER),
+            bytes,
+            now,
+        )
+        .map_err(|refusal| refused(refusal, "rate limit exceeded"))
+}
+
+/// Middleware applying the per-IP budget to every request, before
+/// authentication or signature checks.
+pub async fn limit_per_ip(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(state) = req.app_data::<web::Data<AppState>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let ip = client_ip(req.request(), &state.env);
+    let now = Utc::now().timestamp_millis();
+    if let Err(refusal) = state.rate_limits.check_ip(&state.env, &ip, now) {
+        return Ok(req.into_response(refused(refusal, "too many requests from this address")));
+    }
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
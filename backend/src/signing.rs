@@ -0,0 +1,269 @@
+//! Signed requests for deployments served over plain HTTP: with
+//! `SIGNING_SECRET` set, every request must carry an HMAC-SHA256 over its
+//! method, path, timestamp, nonce and body hash. Stale timestamps and
+//! reused nonces are refused, so a captured request cannot be replayed.
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpResponse};
+use bytes::BytesMut;
+use chrono::Utc;
+use futures_util::StreamExt;
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+use crate::auth::{constant_time_eq, sign};
+use crate::{AppState, MAX_JSON_BYTES};
+
+/// Request time as server epoch ms.
+pub const SIGNATURE_TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+/// A client-chosen unique value; each may be used once.
+pub const SIGNATURE_NONCE_HEADER: &str = "X-Signature-Nonce";
+
+/// Hex HMAC-SHA256 of [`string_to_sign`] under `SIGNING_SECRET`.
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// Longest nonce accepted, so the cache cannot be fed huge keys.
+const MAX_NONCE_LEN: usize = 128;
+
+/// Nonces seen within the timestamp window. A nonce only has to be
+/// remembered until its timestamp is too old to be accepted anyway.
+#[derive(Default)]
+pub struct NonceCache {
+    /// Nonce to the epoch ms after which it may be forgotten.
+    seen: Mutex<HashMap<String, i64>>,
+}
+
+impl NonceCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records `nonce` until `expires_at`; `false` if it is already recorded.
+    pub fn insert(&self, nonce: &str, expires_at: i64, now: i64) -> bool {
+        let mut seen = self.seen.lock().expect("nonce cache poisoned");
+        seen.retain(|_, expiry| *expiry > now);
+        if seen.contains_key(nonce) {
+            return false;
+        }
+        seen.insert(nonce.to_string(), expires_at);
+        true
+    }
+}
+
+/// The canonical text a client signs: method, path with query, timestamp,
+/// nonce and hex SHA-256 of the body, one per line.
+pub fn string_to_sign(
+    method: &str,
+    path_and_query: &str,
+    timestamp: i64,
+    nonce: &str,
+    body: &[u8],
+) -> String {
+    signed_prefix(method, path_and_query, timestamp, nonce) + &hex::encode(Sha256::digest(body))
+}
+
+/// [`string_to_sign`] up to the body hash.
+fn signed_prefix(method: &str, path_and_query: &str, timestamp: i64, nonce: &str) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n",
+        method.to_ascii_uppercase(),
+        path_and_query,
+        timestamp,
+        nonce
+    )
+}
+
+/// The `X-Signature` value for a request, as a client computes it.
+pub fn sign_request(
+    secret: &str,
+    method: &str,
+    path_and_query: &str,
+    timestamp: i64,
+    nonce: &str,
+    body: &[u8],
+) -> String {
+    hex::encode(sign(
+        secret,
+        &string_to_sign(method, path_and_query, timestamp, nonce, body),
+    ))
+}
+
+fn header<'a>(req: &'a ServiceRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+/// A request whose signature headers passed every check that does not need
+/// the body; only the body hash is left to compare.
+struct PendingSignature {
+    secret: String,
+    prefix: String,
+    signature: Vec<u8>,
+}
+
+impl PendingSignature {
+    /// Checks the signature headers are present and well-formed, then the
+    /// timestamp window, then records the nonce; the error is the reason
+    /// given to the client.
+    fn check(state: &AppState, req: &ServiceRequest) -> Result<Self, &'static str> {
+        let (Some(timestamp), Some(nonce), Some(signature)) = (
+            header(req, SIGNATURE_TIMESTAMP_HEADER),
+            header(req, SIGNATURE_NONCE_HEADER),
+            header(req, SIGNATURE_HEADER),
+        ) else {
+            return Err("missing request signature");
+        };
+        let timestamp = timestamp
+            .parse::<i64>()
+            .map_err(|_| "invalid signature timestamp")?;
+        if nonce.len() > MAX_NONCE_LEN {
+            return Err("invalid signature nonce");
+        }
+        let signature = hex::decode(signature).map_err(|_| "invalid signature")?;
+        let now = Utc::now().timestamp_millis();
+        let window = state.env.signature_max_skew_secs * 1000;
+        if (now - timestamp).abs() > window {
+            return Err("stale signature timestamp");
+        }
+        // Recorded before the body is read, so a replay is refused without
+        // reading it; a forged request only burns a nonce of its own choosing.
+        if !state.nonces.insert(nonce, timestamp + window, now) {
+            return Err("replayed request");
+        }
+        let path_and_query = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or_else(|| req.path());
+        Ok(Self {
+            secret: state.env.signing_secret.clone(),
+            prefix: signed_prefix(req.method().as_str(), path_and_query, timestamp, nonce),
+            signature,
+        })
+    }
+
+    fn matches(&self, body_hash: &[u8]) -> bool {
+        let expected = sign(
+            &self.secret,
+            &(self.prefix.clone() + &hex::encode(body_hash)),
+        );
+        constant_time_eq(&self.signature, &expected)
+    }
+}
+
+/// Routes whose handlers read the body as it arrives, apply their own size
+/// limit, and act only once it has ended cleanly. Their body is checked as it
+/// passes through: if the signature does not match, the read that would end
+/// it fails instead. Other handlers may act without reading the body, so
+/// theirs is read and checked before they run.
+const STREAMED_ROUTES: &[(&str, &str)] = &[
+    ("PUT", "/images/blob/{hash}"),
+    ("PUT", "/images/uploads/{hash}"),
+];
+
+fn streams_body(req: &ServiceRequest) -> bool {
+    let Some(pattern) = req.match_pattern() else {
+        return false;
+    };
+    STREAMED_ROUTES
+        .iter()
+        .any(|&(method, route)| req.method().as_str() == method && pattern == route)
+}
+
+/// `payload` passed through unchanged while it is hashed; at its end a
+/// signature mismatch sets `failed` and fails the read.
+fn signed_payload(payload: Payload, pending: PendingSignature, failed: Rc<Cell<bool>>) -> Payload {
+    let stream =
+        futures_util::stream::unfold(Some((payload, Sha256::new(), pending)), move |state| {
+            let failed = failed.clone();
+            async move {
+                let (mut payload, mut hasher, pending) = state?;
+                match payload.next().await {
+                    Some(Ok(chunk)) => {
+                        hasher.update(&chunk);
+                        Some((Ok(chunk), Some((payload, hasher, pending))))
+                    }
+                    Some(Err(e)) => Some((Err(e), None)),
+                    None if pending.matches(&hasher.finalize()) => None,
+                    None => {
+                        failed.set(true);
+                        let e = std::io::Error::new(ErrorKind::InvalidData, "invalid signature");
+                        Some((Err(PayloadError::Io(e)), None))
+                    }
+                }
+            }
+        });
+    Payload::Stream {
+        payload: Box::pin(stream),
+    }
+}
+
+fn refuse(req: ServiceRequest, reason: &str) -> ServiceResponse<BoxBody> {
+    warn!(
+        "signing: {} {} refused: {}",
+        req.method(),
+        req.path(),
+        reason
+    );
+    req.into_response(HttpResponse::Unauthorized().body(reason.to_string()))
+}
+
+/// Middleware verifying request signatures when `SIGNING_SECRET` is set; a
+/// no-op otherwise. The headers, timestamp and nonce are checked before any
+/// of the body is read, then the body is hashed as it is read.
+pub async fn verify_request_signature(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(state) = req.app_data::<web::Data<AppState>>().cloned() else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    if state.env.signing_secret.is_empty() {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+    let pending = match PendingSignature::check(&state, &req) {
+        Ok(pending) => pending,
+        Err(reason) => return Ok(refuse(req, reason)),
+    };
+    let mut payload = req.take_payload();
+    if streams_body(&req) {
+        let failed = Rc::new(Cell::new(false));
+        req.set_payload(signed_payload(payload, pending, failed.clone()));
+        let resp = next.call(req).await?.map_into_boxed_body();
+        if !failed.get() {
+            return Ok(resp);
+        }
+        let (req, _) = resp.into_parts();
+        return Ok(refuse(
+            ServiceRequest::from_request(req),
+            "invalid signature",
+        ));
+    }
+    let mut hasher = Sha256::new();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_JSON_BYTES {
+            return Ok(req.into_response(HttpResponse::PayloadTooLarge().finish()));
+        }
+        hasher.update(&chunk);
+        body.extend_from_slice(&chunk);
+    }
+    if !pending.matches(&hasher.finalize()) {
+        return Ok(refuse(req, "invalid signature"));
+    }
+    req.set_payload(Payload::from(body.freeze()));
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
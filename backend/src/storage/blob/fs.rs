@@ -0,0 +1,168 @@
+//! Blob store on the local filesystem: `<root>/<account>/<hash[..2]>/<hash>`.
+
+use async_trait::async_trait;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use super::{check_blob_key, BlobChunks, BlobStore};
+use crate::storage::{StorageError, StorageResult};
+
+/// Names in-flight temp files uniquely within this process.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    /// Uses `root` (created if missing) for blobs and their temp files.
+    pub async fn open(root: impl Into<PathBuf>) -> StorageResult<Self> {
+        let root = root.into();
+        tokio::fs::create_dir_all(root.join("tmp"))
+            .await
+            .map_err(|e| io_error("create blob dir", &root, e))?;
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// A fresh temp file name; blobs are written there first, then renamed
+    /// over the target so readers never see a partial blob.
+    fn temp_path(&self) -> PathBuf {
+        self.root.join("tmp").join(format!(
+            "{}-{}.part",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    /// Syncs the `written` temp file and renames it to `path`; the temp file
+    /// is removed if anything failed.
+    async fn install(
+        &self,
+        written: std::io::Result<tokio::fs::File>,
+        temp: &Path,
+        path: &Path,
+    ) -> StorageResult<()> {
+        let installed = async {
+            written?.sync_all().await?;
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::rename(temp, path).await
+        }
+        .await;
+        if let Err(e) = installed {
+            let _ = tokio::fs::remove_file(temp).await;
+            return Err(io_error("write blob", path, e));
+        }
+        Ok(())
+    }
+
+    fn blob_path(&self, account: &str, hash: &str) -> StorageResult<PathBuf> {
+        check_blob_key("account", account)?;
+        check_blob_key("hash", hash)?;
+        let shard = &hash[..hash.len().min(2)];
+        Ok(self.root.join(account).join(shard).join(hash))
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsBlobStore {
+    async fn put(&self, account: &str, hash: &str, data: &[u8]) -> StorageResult<()> {
+        let path = self.blob_path(account, hash)?;
+        let temp = self.temp_path();
+        let written = async {
+            let mut file = tokio::fs::File::create(&temp).await?;
+            file.write_all(data).await?;
+            Ok(file)
+        }
+        .await;
+        self.install(written, &temp, &path).await
+    }
+
+    async fn put_stream(
+        &self,
+        account: &str,
+        hash: &str,
+        chunks: &mut BlobChunks,
+    ) -> StorageResult<u64> {
+        let path = self.blob_path(account, hash)?;
+        let temp = self.temp_path();
+        let mut len = 0u64;
+        let mut failed = None;
+        let written = async {
+            let mut file = tokio::fs::File::create(&temp).await?;
+            while let Some(chunk) = chunks.recv().await {
+                match chunk {
+                    Ok(chunk) => {
+                        file.write_all(&chunk).await?;
+                        len += chunk.len() as u64;
+                    }
+                    Err(e) => {
+                        failed = Some(e);
+                        break;
+                    }
+                }
+            }
+            Ok(file)
+        }
+        .await;
+        if let Some(e) = failed {
+            let _ = tokio::fs::remove_file(&temp).await;
+            return Err(e);
+        }
+        self.install(written, &temp, &path).await?;
+        Ok(len)
+    }
+
+    async fn get(&self, account: &str, hash: &str) -> StorageResult<Option<Vec<u8>>> {
+        let path = self.blob_path(account, hash)?;
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_error("read blob", &path, e)),
+        }
+    }
+
+    async fn get_range(
+        &self,
+        account: &str,
+        hash: &str,
+        start: u64,
+        end: u64,
+    ) -> StorageResult<Option<Vec<u8>>> {
+        let path = self.blob_path(account, hash)?;
+        let read: std::io::Result<Vec<u8>> = async {
+            let mut file = tokio::fs::File::open(&path).await?;
+            file.seek(std::io::SeekFrom::Start(start)).await?;
+            let mut data = Vec::new();
+            file.take(end.saturating_sub(start).saturating_add(1))
+                .read_to_end(&mut data)
+                .await?;
+            Ok(data)
+        }
+        .await;
+        match read {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io_error("read blob", &path, e)),
+        }
+    }
+
+    async fn delete(&self, account: &str, hash: &str) -> StorageResult<()> {
+        let path = self.blob_path(account, hash)?;
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(io_error("delete blob", &path, e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn io_error(what: &str, path: &Path, e: std::io::Error) -> StorageError {
+    StorageError::Backend(format!("{} {}: {}", what, path.display(), e))
+}
@@ -0,0 +1,258 @@
+//! Where image ciphertext lives when it is not inline in `diary_images.blob_data`.
+//! Blobs are content-addressed: one object per (account, hash), holding the raw
+//! ciphertext (the base64 in `EncryptedBlob::data`, decoded). The IV and other
+//! metadata stay in `diary_images`.
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use super::{StorageError, StorageResult};
+use crate::models::DiaryImageSyncItem;
+
+pub mod fs;
+pub mod s3;
+
+pub use fs::FsBlobStore;
+pub use s3::{S3BlobStore, S3Config};
+
+/// Ciphertext arriving piece by piece, e.g. a request body as it is read. The
+/// sender closing the channel ends the data; an `Err` item aborts the write.
+pub type BlobChunks = mpsc::Receiver<StorageResult<Bytes>>;
+
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Stores `data` under `hash`, replacing any existing object atomically.
+    async fn put(&self, account: &str, hash: &str, data: &[u8]) -> StorageResult<()>;
+
+    /// Like `put` for data read from `chunks`; returns its length. Nothing is
+    /// stored if a chunk is an error. Stores that can write incrementally
+    /// override this; the default collects the data first.
+    async fn put_stream(
+        &self,
+        account: &str,
+        hash: &str,
+        chunks: &mut BlobChunks,
+    ) -> StorageResult<u64> {
+        let data = collect_chunks(chunks).await?;
+        self.put(account, hash, &data).await?;
+        Ok(data.len() as u64)
+    }
+
+    async fn get(&self, account: &str, hash: &str) -> StorageResult<Option<Vec<u8>>>;
+
+    /// Bytes `start..=end` of the object, fewer if it ends sooner. Stores that
+    /// can read part of an object override this; the default reads all of it.
+    async fn get_range(
+        &self,
+        account: &str,
+        hash: &str,
+        start: u64,
+        end: u64,
+    ) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self
+            .get(account, hash)
+            .await?
+            .map(|data| slice_range(&data, start, end).to_vec()))
+    }
+
+    /// Removes the object; a missing one is not an error.
+    async fn delete(&self, account: &str, hash: &str) -> StorageResult<()>;
+}
+
+/// Whether `value` is safe as a single path segment or object key segment:
+/// 1 to 128 of `[A-Za-z0-9_-]`. Account ids and hashes are used verbatim in
+/// blob locations, so both are held to this.
+pub fn is_blob_key(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 128
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Refuses account ids and hashes that are not [`is_blob_key`].
+pub(crate) fn check_blob_key(what: &str, value: &str) -> StorageResult<()> {
+    if is_blob_key(value) {
+        Ok(())
+    } else {
+        Err(StorageError::Invalid(format!(
+            "{} {:?} is not a valid blob key",
+            what, value
+        )))
+    }
+}
+
+/// Writes an uploaded image's ciphertext to `blobs` when one is configured.
+/// Returns what belongs in `diary_images.blob_data`: `None` once the blob
+/// store holds it, otherwise the inline base64.
+pub(crate) async fn store_image_blob<'a>(
+    blobs: Option<&dyn BlobStore>,
+    account: &str,
+    item: &'a DiaryImageSyncItem,
+) -> StorageResult<Option<&'a str>> {
+    let Some(blobs) = blobs else {
+        return Ok(Some(&item.blob.data));
+    };
+    let data = STANDARD
+        .decode(&item.blob.data)
+        .map_err(|e| StorageError::Invalid(format!("image {} is not base64: {}", item.hash, e)))?;
+    blobs.put(account, &item.hash, &data).await?;
+    Ok(None)
+}
+
+/// Decoded length of padded standard base64, worked out without decoding;
+/// `None` when `data` cannot be valid.
+pub(crate) fn base64_len(data: &str) -> Option<i64> {
+    if !data.len().is_multiple_of(4) {
+        return None;
+    }
+    let padding = data.bytes().rev().take_while(|&b| b == b'=').count();
+    if padding > 2 {
+        return None;
+    }
+    Some((data.len() / 4 * 3 - padding) as i64)
+}
+
+/// Copies one inline `blob_data` value into the blob store. `Invalid` errors
+/// (undecodable data, a hash unusable as a key) mean the row cannot be moved.
+pub(crate) async fn move_inline_blob(
+    blobs: &dyn BlobStore,
+    account: &str,
+    hash: &str,
+    inline: &str,
+) -> StorageResult<()> {
+    let data = STANDARD
+        .decode(inline)
+        .map_err(|e| StorageError::Invalid(format!("image {} is not base64: {}", hash, e)))?;
+    blobs.put(account, hash, &data).await
+}
+
+/// The base64 ciphertext of a stored image: the inline copy for rows written
+/// without a blob store, otherwise the blob store's object.
+pub(crate) async fn load_image_blob(
+    blobs: Option<&dyn BlobStore>,
+    account: &str,
+    hash: &str,
+    inline: Option<String>,
+) -> StorageResult<String> {
+    match inline {
+        Some(inline) => Ok(inline),
+        None => Ok(STANDARD.encode(fetch_blob(blobs, account, hash).await?)),
+    }
+}
+
+/// Like `store_image_blob` for an upload that arrived as raw bytes.
+pub(crate) async fn store_image_bytes(
+    blobs: Option<&dyn BlobStore>,
+    account: &str,
+    hash: &str,
+    data: &[u8],
+) -> StorageResult<Option<String>> {
+    let Some(blobs) = blobs else {
+        return Ok(Some(STANDARD.encode(data)));
+    };
+    blobs.put(account, hash, data).await?;
+    Ok(None)
+}
+
+/// Like `store_image_bytes` for ciphertext arriving in `chunks`, which only
+/// has to be held in memory when it is kept inline. Returns the inline base64
+/// (`None` once the blob store holds it) and the ciphertext length.
+pub(crate) async fn store_image_chunks(
+    blobs: Option<&dyn BlobStore>,
+    account: &str,
+    hash: &str,
+    chunks: &mut BlobChunks,
+) -> StorageResult<(Option<String>, i64)> {
+    match blobs {
+        Some(blobs) => {
+            let len = blobs.put_stream(account, hash, chunks).await?;
+            Ok((None, len as i64))
+        }
+        None => {
+            let data = collect_chunks(chunks).await?;
+            Ok((Some(STANDARD.encode(&data)), data.len() as i64))
+        }
+    }
+}
+
+/// Reads `chunks` to the end into one buffer.
+pub(crate) async fn collect_chunks(chunks: &mut BlobChunks) -> StorageResult<Vec<u8>> {
+    let mut data = Vec::new();
+    while let Some(chunk) = chunks.recv().await {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(data)
+}
+
+/// Like `load_image_blob`, returning the raw ciphertext.
+pub(crate) async fn load_image_bytes(
+    blobs: Option<&dyn BlobStore>,
+    account: &str,
+    hash: &str,
+    inline: Option<String>,
+) -> StorageResult<Vec<u8>> {
+    match inline {
+        Some(inline) => STANDARD
+            .decode(inline)
+            .map_err(|e| StorageError::Backend(format!("image {} is not base64: {}", hash, e))),
+        None => fetch_blob(blobs, account, hash).await,
+    }
+}
+
+/// Like `load_image_bytes` for bytes `start..=end` only, which must lie
+/// within the image; a blob-store object is not read beyond them.
+pub(crate) async fn load_image_range(
+    blobs: Option<&dyn BlobStore>,
+    account: &str,
+    hash: &str,
+    inline: Option<String>,
+    start: u64,
+    end: u64,
+) -> StorageResult<Vec<u8>> {
+    let data = match (inline, blobs) {
+        (Some(inline), _) => {
+            let data = load_image_bytes(blobs, account, hash, Some(inline)).await?;
+            slice_range(&data, start, end).to_vec()
+        }
+        (None, Some(blobs)) => blobs
+            .get_range(account, hash, start, end)
+            .await?
+            .ok_or_else(|| {
+                StorageError::Backend(format!("image {} is missing from the blob store", hash))
+            })?,
+        (None, None) => fetch_blob(None, account, hash).await?,
+    };
+    if data.len() as u64 != end - start + 1 {
+        return Err(StorageError::Backend(format!(
+            "image {} is shorter than its recorded size",
+            hash
+        )));
+    }
+    Ok(data)
+}
+
+/// `data[start..=end]`, cut short at the end of `data`.
+pub(crate) fn slice_range(data: &[u8], start: u64, end: u64) -> &[u8] {
+    let len = data.len() as u64;
+    &data[start.min(len) as usize..end.saturating_add(1).min(len) as usize]
+}
+
+async fn fetch_blob(
+    blobs: Option<&dyn BlobStore>,
+    account: &str,
+    hash: &str,
+) -> StorageResult<Vec<u8>> {
+    let Some(blobs) = blobs else {
+        return Err(StorageError::Backend(format!(
+            "image {} is kept in a blob store but none is configured",
+            hash
+        )));
+    };
+    blobs.get(account, hash).await?.ok_or_else(|| {
+        StorageError::Backend(format!("image {} is missing from the blob store", hash))
+    })
+}
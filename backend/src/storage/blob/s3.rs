@@ -0,0 +1,308 @@
+//! Blob store in an S3-compatible bucket (AWS S3, MinIO, ...), addressed
+//! path-style as `<endpoint>/<bucket>/<prefix><account>/<hash>` and signed with
+//! AWS Signature Version 4.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::header::{CONTENT_LENGTH, RANGE};
+use reqwest::{StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWriteExt;
+
+use super::{check_blob_key, slice_range, BlobChunks, BlobStore};
+use crate::storage::{StorageError, StorageResult};
+
+/// Names spool files for streamed uploads uniquely within this process.
+static SPOOL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    /// Base URL of the service, e.g. `http://127.0.0.1:9000` for a local MinIO.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prepended to every object key, e.g. `images/`.
+    pub prefix: String,
+}
+
+pub struct S3BlobStore {
+    client: reqwest::Client,
+    config: S3Config,
+    /// `host[:port]` exactly as sent in the `Host` header.
+    host: String,
+}
+
+impl S3BlobStore {
+    pub fn new(config: S3Config) -> StorageResult<Self> {
+        let url = Url::parse(&config.endpoint)
+            .map_err(|e| StorageError::Invalid(format!("S3 endpoint: {}", e)))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => {
+                return Err(StorageError::Invalid(format!(
+                    "S3 endpoint {} has no host",
+                    config.endpoint
+                )))
+            }
+        };
+        if config.bucket.is_empty() {
+            return Err(StorageError::Invalid("S3 bucket is not set".to_string()));
+        }
+        Ok(Self {
+            client: reqwest::Client::new(),
+            config,
+            host,
+        })
+    }
+
+    /// The object's path below the endpoint: `/<bucket>/<prefix><account>/<hash>`.
+    fn object_path(&self, account: &str, hash: &str) -> StorageResult<String> {
+        check_blob_key("account", account)?;
+        check_blob_key("hash", hash)?;
+        Ok(format!(
+            "/{}/{}{}/{}",
+            uri_encode(&self.config.bucket),
+            uri_encode(&self.config.prefix),
+            account,
+            hash
+        ))
+    }
+
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Vec<u8>,
+    ) -> StorageResult<reqwest::Response> {
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        self.request(method, path, payload_hash)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| StorageError::Backend(format!("S3 request failed: {}", e)))
+    }
+
+    /// A signed request for a body with SHA-256 `payload_hash`, to which the
+    /// caller adds the body.
+    fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        payload_hash: String,
+    ) -> reqwest::RequestBuilder {
+        let now = Utc::now();
+        let authorization = self.authorization(method.as_str(), path, &payload_hash, now);
+        let url = format!("{}{}", self.config.endpoint.trim_end_matches('/'), path);
+        self.client
+            .request(method, url)
+            .header("x-amz-date", amz_date(now))
+            .header("x-amz-content-sha256", payload_hash)
+            .header("authorization", authorization)
+    }
+
+    /// SigV4 `Authorization` header over `host`, `x-amz-content-sha256` and
+    /// `x-amz-date`, with no query string.
+    fn authorization(
+        &self,
+        method: &str,
+        path: &str,
+        payload_hash: &str,
+        now: DateTime<Utc>,
+    ) -> String {
+        let amz_date = amz_date(now);
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, self.host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key = hmac(
+            format!("AWS4{}", self.config.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [self.config.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.config.access_key, scope, signed_headers, signature
+        )
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3BlobStore {
+    async fn put(&self, account: &str, hash: &str, data: &[u8]) -> StorageResult<()> {
+        let path = self.object_path(account, hash)?;
+        let resp = self
+            .send(reqwest::Method::PUT, &path, data.to_vec())
+            .await?;
+        if !resp.status().is_success() {
+            return Err(s3_error("put", &path, resp).await);
+        }
+        Ok(())
+    }
+
+    /// A signed PUT needs the body's SHA-256 up front, so the data is spooled
+    /// to a temp file while it is hashed, then sent from the file.
+    async fn put_stream(
+        &self,
+        account: &str,
+        hash: &str,
+        chunks: &mut BlobChunks,
+    ) -> StorageResult<u64> {
+        let path = self.object_path(account, hash)?;
+        let spool = std::env::temp_dir().join(format!(
+            "syezw-s3-{}-{}.part",
+            std::process::id(),
+            SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let sent = async {
+            let mut file = tokio::fs::File::create(&spool)
+                .await
+                .map_err(|e| spool_error(&spool, e))?;
+            let mut hasher = Sha256::new();
+            let mut len = 0u64;
+            while let Some(chunk) = chunks.recv().await {
+                let chunk = chunk?;
+                hasher.update(&chunk);
+                file.write_all(&chunk)
+                    .await
+                    .map_err(|e| spool_error(&spool, e))?;
+                len += chunk.len() as u64;
+            }
+            file.flush().await.map_err(|e| spool_error(&spool, e))?;
+            let file = tokio::fs::File::open(&spool)
+                .await
+                .map_err(|e| spool_error(&spool, e))?;
+            let resp = self
+                .request(reqwest::Method::PUT, &path, hex::encode(hasher.finalize()))
+                .header(CONTENT_LENGTH, len)
+                .body(reqwest::Body::from(file))
+                .send()
+                .await
+                .map_err(|e| StorageError::Backend(format!("S3 request failed: {}", e)))?;
+            if !resp.status().is_success() {
+                return Err(s3_error("put", &path, resp).await);
+            }
+            Ok(len)
+        }
+        .await;
+        let _ = tokio::fs::remove_file(&spool).await;
+        sent
+    }
+
+    async fn get(&self, account: &str, hash: &str) -> StorageResult<Option<Vec<u8>>> {
+        let path = self.object_path(account, hash)?;
+        let resp = self.send(reqwest::Method::GET, &path, Vec::new()).await?;
+        match resp.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let body = resp
+                    .bytes()
+                    .await
+                    .map_err(|e| StorageError::Backend(format!("S3 get {}: {}", path, e)))?;
+                Ok(Some(body.to_vec()))
+            }
+            _ => Err(s3_error("get", &path, resp).await),
+        }
+    }
+
+    /// Sends a `Range` header, which is not signed; a store that ignores it
+    /// answers 200 with the whole object, which is then cut down here.
+    async fn get_range(
+        &self,
+        account: &str,
+        hash: &str,
+        start: u64,
+        end: u64,
+    ) -> StorageResult<Option<Vec<u8>>> {
+        let path = self.object_path(account, hash)?;
+        let resp = self
+            .request(
+                reqwest::Method::GET,
+                &path,
+                hex::encode(Sha256::digest(b"")),
+            )
+            .header(RANGE, format!("bytes={}-{}", start, end))
+            .send()
+            .await
+            .map_err(|e| StorageError::Backend(format!("S3 request failed: {}", e)))?;
+        let status = resp.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(s3_error("get", &path, resp).await);
+        }
+        let body = resp
+            .bytes()
+            .await
+            .map_err(|e| StorageError::Backend(format!("S3 get {}: {}", path, e)))?;
+        Ok(Some(if status == StatusCode::PARTIAL_CONTENT {
+            body.to_vec()
+        } else {
+            slice_range(&body, start, end).to_vec()
+        }))
+    }
+
+    async fn delete(&self, account: &str, hash: &str) -> StorageResult<()> {
+        let path = self.object_path(account, hash)?;
+        let resp = self
+            .send(reqwest::Method::DELETE, &path, Vec::new())
+            .await?;
+        if !resp.status().is_success() && resp.status() != StatusCode::NOT_FOUND {
+            return Err(s3_error("delete", &path, resp).await);
+        }
+        Ok(())
+    }
+}
+
+async fn s3_error(what: &str, path: &str, resp: reqwest::Response) -> StorageError {
+    let status = resp.status();
+    let body = resp.text().await.unwrap_or_default();
+    StorageError::Backend(format!("S3 {} {}: {} {}", what, path, status, body.trim()))
+}
+
+fn spool_error(path: &std::path::Path, e: std::io::Error) -> StorageError {
+    StorageError::Backend(format!("spool blob {}: {}", path.display(), e))
+}
+
+fn amz_date(now: DateTime<Utc>) -> String {
+    now.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// SigV4 URI encoding of a path: everything but unreserved characters and `/`
+/// is percent-encoded.
+fn uri_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
@@ -0,0 +1,1525 @@
+//! In-process storage with the same semantics as the SQL backends. Nothing is
+//! persisted; it exists so the whole app can be exercised without a database.
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use super::blob::{
+    base64_len, load_image_blob, load_image_bytes, load_image_range, move_inline_blob,
+    store_image_blob, store_image_bytes, store_image_chunks, BlobChunks, BlobStore,
+};
+use super::{
+    applied_updated_at, check_chunk, conflict, legacy_period_uuid, no_blob_store,
+    restored_updated_at, stale_write, supersedes, AuthSession, BlobMigration, ChunkOutcome,
+    FinalizeOutcome, ImageBytes, ImageLocks, ImageMeta, NewApiKey, NewImageUpload, Storage,
+    StorageError, StorageResult, SyncDirection, UploadOutcome, WriteResult,
+};
+use crate::models::{
+    ApiKeyInfo, AuthFailure, DeviceItem, DeviceRegisterRequest, DiaryImageRefItem,
+    DiaryImageSyncItem, DiarySyncItem, EncryptedBlob, ImageFetchResponse, ImageHashSize,
+    ImageRefKey, ImageRefsRequest, ImageUploadStatus, OrphanImage, PeriodMeta, PeriodSyncItem,
+    RecordKind, RevisionItem, SyncChangesResponse, SyncMeta, SyncMetaResponse, SyncUploadRequest,
+    TodoSyncItem, TombstoneItem,
+};
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+    blobs: Option<Arc<dyn BlobStore>>,
+    image_locks: ImageLocks,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps image ciphertext in `blobs` instead of in memory.
+    pub fn with_blob_store(mut self, blobs: Arc<dyn BlobStore>) -> Self {
+        self.blobs = Some(blobs);
+        self
+    }
+
+    /// Writes each image's ciphertext to the blob store (if any) ahead of the
+    /// metadata, returning the inline data to keep for each.
+    async fn store_blobs(
+        &self,
+        account: &str,
+        images: &[DiaryImageSyncItem],
+    ) -> StorageResult<Vec<Option<String>>> {
+        let mut inline = Vec::with_capacity(images.len());
+        for item in images {
+            let data = store_image_blob(self.blobs.as_deref(), account, item).await?;
+            inline.push(data.map(str::to_string));
+        }
+        Ok(inline)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    counters: Counters,
+    accounts: HashMap<String, AccountData>,
+    /// Registered API keys by id; not per account, like the `api_keys` table.
+    api_keys: BTreeMap<String, StoredApiKey>,
+    /// Login sessions by id.
+    sessions: HashMap<String, AuthSession>,
+    /// The `auth_failures` audit log, oldest first.
+    auth_failures: Vec<AuthFailure>,
+}
+
+/// Stand-ins for `sync_change_seq` and the history tables' revision ids,
+/// shared by all accounts like their SQL counterparts, and for the
+/// `auth_failures` ids.
+#[derive(Default, Clone, Copy)]
+struct Counters {
+    change_seq: i64,
+    revision_id: i64,
+    auth_failure_id: i64,
+}
+
+impl Counters {
+    fn next_change_seq(&mut self) -> i64 {
+        self.change_seq += 1;
+        self.change_seq
+    }
+
+    fn next_revision_id(&mut self) -> i64 {
+        self.revision_id += 1;
+        self.revision_id
+    }
+
+    fn next_auth_failure_id(&mut self) -> i64 {
+        self.auth_failure_id += 1;
+        self.auth_failure_id
+    }
+}
+
+/// A live record and the change sequence of its last write.
+#[derive(Clone)]
+struct Stored<T> {
+    record: T,
+    change_seq: i64,
+}
+
+#[derive(Clone)]
+struct Revision<T> {
+    revision_id: i64,
+    archived_at: i64,
+    record: T,
+}
+
+/// Mirrors a `diary_images` row: `data` is `None` when the blob store holds it.
+#[derive(Clone)]
+struct StoredImage {
+    iv: String,
+    size: Option<i64>,
+    updated_at: i64,
+    stored_at: i64,
+    /// `None` while a ref points at the image.
+    unreferenced_at: Option<i64>,
+    data: Option<String>,
+}
+
+/// Mirrors an `api_keys` row.
+struct StoredApiKey {
+    key_hash: String,
+    info: ApiKeyInfo,
+}
+
+/// Mirrors an `image_uploads` row with its chunks appended in `data`.
+#[derive(Clone)]
+struct PendingUpload {
+    iv: String,
+    size: i64,
+    updated_at: i64,
+    touched_at: i64,
+    data: Vec<u8>,
+}
+
+impl PendingUpload {
+    fn status(&self, hash: &str) -> ImageUploadStatus {
+        ImageUploadStatus {
+            hash: hash.to_string(),
+            size: self.size,
+            offset: self.data.len() as i64,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Tombstone {
+    deleted_at: i64,
+    change_seq: i64,
+    /// Start date of a deleted period.
+    start_date: Option<String>,
+}
+
+/// Maps keyed by uuid are `BTreeMap`s so pages come out in byte order of uuid,
+/// matching `ORDER BY uuid COLLATE "C"`.
+#[derive(Default, Clone)]
+struct AccountData {
+    diaries: BTreeMap<String, Stored<DiarySyncItem>>,
+    todos: BTreeMap<String, Stored<TodoSyncItem>>,
+    periods: BTreeMap<String, Stored<PeriodSyncItem>>,
+    diary_history: HashMap<String, Vec<Revision<DiarySyncItem>>>,
+    todo_history: HashMap<String, Vec<Revision<TodoSyncItem>>>,
+    tombstones: BTreeMap<(RecordKind, String), Tombstone>,
+    /// Keyed by hash.
+    images: BTreeMap<String, StoredImage>,
+    /// Keyed by (diary_uuid, file_name).
+    image_refs: BTreeMap<(String, String), DiaryImageRefItem>,
+    /// Unfinished resumable uploads, keyed by hash.
+    uploads: HashMap<String, PendingUpload>,
+    devices: HashMap<String, DeviceItem>,
+}
+
+/// The parts of a synced record the generic write path needs.
+trait Record: Clone {
+    fn uuid(&self) -> &str;
+    fn updated_at(&self) -> i64;
+    fn set_updated_at(&mut self, updated_at: i64);
+    fn payload(&self) -> &EncryptedBlob;
+}
+
+macro_rules! impl_record {
+    ($($ty:ty),*) => {$(
+        impl Record for $ty {
+            fn uuid(&self) -> &str {
+                &self.uuid
+            }
+            fn updated_at(&self) -> i64 {
+                self.updated_at
+            }
+            fn set_updated_at(&mut self, updated_at: i64) {
+                self.updated_at = updated_at;
+            }
+            fn payload(&self) -> &EncryptedBlob {
+                &self.payload
+            }
+        }
+    )*};
+}
+
+impl_record!(DiarySyncItem, TodoSyncItem, PeriodSyncItem);
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn apply_upload(
+        &self,
+        account: &str,
+        upload: &SyncUploadRequest,
+        history_limit: i64,
+    ) -> StorageResult<UploadOutcome> {
+        let _images = self
+            .image_locks
+            .lock(account, upload.images.iter().map(|i| i.hash.as_str()))
+            .await;
+        let inline_images = self
+            .store_blobs(account, &upload.images)
+            .await
+            .map_err(|e| e.context("image upsert failed"))?;
+        let mut state = self.state();
+        // Work on copies so a failed item leaves nothing behind, like a rolled back transaction.
+        let mut counters = state.counters;
+        let mut data = state.accounts.get(account).cloned().unwrap_or_default();
+        let now = Utc::now().timestamp_millis();
+
+        let mut outcome = UploadOutcome::default();
+        for item in &upload.diaries {
+            let mut item = item.clone();
+            let base_updated_at = item.base_updated_at.take();
+            match data.upsert_diary(&mut counters, item, base_updated_at, history_limit, now) {
+                None => outcome.counts.diaries += 1,
+                Some(c) => outcome.rejected.push(c),
+            }
+        }
+        for item in &upload.todos {
+            let mut item = item.clone();
+            let base_updated_at = item.base_updated_at.take();
+            match data.upsert_todo(&mut counters, item, base_updated_at, history_limit, now) {
+                None => outcome.counts.todos += 1,
+                Some(c) => outcome.rejected.push(c),
+            }
+        }
+        for item in &upload.periods {
+            match data
+                .upsert_period(&mut counters, item)
+                .map_err(|e| e.context("period upsert failed"))?
+            {
+                None => outcome.counts.periods += 1,
+                Some(c) => outcome.rejected.push(c),
+            }
+        }
+        for (item, inline) in upload.images.iter().zip(inline_images) {
+            data.put_image(item, inline);
+            data.put_image_ref(DiaryImageRefItem {
+                diary_uuid: item.diary_uuid.clone(),
+                file_name: item.file_name.clone(),
+                hash: item.hash.clone(),
+                updated_at: item.updated_at,
+            });
+            outcome.counts.images += 1;
+        }
+        // Deletions run last so a record uploaded and deleted in the same batch ends up deleted.
+        for item in &upload.deletions {
+            match data.apply_tombstone(&mut counters, item, history_limit, now) {
+                None => outcome.counts.deletions += 1,
+                Some(c) => outcome.rejected.push(c),
+            }
+        }
+
+        state.counters = counters;
+        state.accounts.insert(account.to_string(), data);
+        Ok(outcome)
+    }
+
+    async fn diary_page(
+        &self,
+        account: &str,
+        after: &str,
+        limit: i64,
+    ) -> StorageResult<Vec<DiarySyncItem>> {
+        Ok(self.read(account, |data| page(&data.diaries, after, limit)))
+    }
+
+    async fn todo_page(
+        &self,
+        account: &str,
+        after: &str,
+        limit: i64,
+    ) -> StorageResult<Vec<TodoSyncItem>> {
+        Ok(self.read(account, |data| page(&data.todos, after, limit)))
+    }
+
+    async fn period_page(
+        &self,
+        account: &str,
+        after: &str,
+        limit: i64,
+    ) -> StorageResult<Vec<PeriodSyncItem>> {
+        Ok(self.read(account, |data| page(&data.periods, after, limit)))
+    }
+
+    async fn tombstones(&self, account: &str) -> StorageResult<Vec<TombstoneItem>> {
+        Ok(self.read(account, |data| {
+            data.tombstones
+                .iter()
+                .map(|((kind, uuid), t)| tombstone_item(*kind, uuid, t))
+                .collect()
+        }))
+    }
+
+    async fn sync_meta(&self, account: &str) -> StorageResult<SyncMetaResponse> {
+        let deletions = self.tombstones(account).await?;
+        Ok(self.read(account, |data| SyncMetaResponse {
+            diaries: sync_meta(&data.diaries),
+            todos: sync_meta(&data.todos),
+            periods: data
+                .periods
+                .values()
+                .map(|stored| PeriodMeta {
+                    uuid: stored.record.uuid.clone(),
+                    start_date: stored.record.start_date.clone(),
+                    updated_at: stored.record.updated_at,
+                })
+                .collect(),
+            deletions,
+        }))
+    }
+
+    async fn changes_since(&self, account: &str, since: i64) -> StorageResult<SyncChangesResponse> {
+        Ok(self.read(account, |data| {
+            let mut deletions: Vec<(i64, TombstoneItem)> = data
+                .tombstones
+                .iter()
+                .filter(|(_, t)| t.change_seq > since)
+                .map(|((kind, uuid), t)| (t.change_seq, tombstone_item(*kind, uuid, t)))
+                .collect();
+            deletions.sort_by_key(|(seq, _)| *seq);
+            let cursor = [
+                max_change_seq(&data.diaries),
+                max_change_seq(&data.todos),
+                max_change_seq(&data.periods),
+                deletions.last().map(|(seq, _)| *seq).unwrap_or(0),
+            ]
+            .into_iter()
+            .fold(since, i64::max);
+            SyncChangesResponse {
+                diaries: changed_since(&data.diaries, since),
+                todos: changed_since(&data.todos, since),
+                periods: changed_since(&data.periods, since),
+                deletions: deletions.into_iter().map(|(_, t)| t).collect(),
+                cursor,
+            }
+        }))
+    }
+
+    async fn list_revisions(
+        &self,
+        account: &str,
+        kind: RecordKind,
+        uuid: &str,
+    ) -> StorageResult<Vec<RevisionItem>> {
+        let revisions = match kind {
+            RecordKind::Diary => {
+                self.read(account, |data| revision_items(&data.diary_history, uuid))
+            }
+            RecordKind::Todo => self.read(account, |data| revision_items(&data.todo_history, uuid)),
+            RecordKind::Period => {
+                return Err(StorageError::Invalid(
+                    "periods have no revision history".to_string(),
+                ));
+            }
+        };
+        Ok(revisions)
+    }
+
+    async fn restore_revision(
+        &self,
+        account: &str,
+        kind: RecordKind,
+        uuid: &str,
+        revision_id: i64,
+        history_limit: i64,
+    ) -> StorageResult<Option<i64>> {
+        if kind == RecordKind::Period {
+            return Err(StorageError::Invalid(
+                "periods have no revision history".to_string(),
+            ));
+        }
+        let mut state = self.state();
+        let State {
+            counters, accounts, ..
+        } = &mut *state;
+        let data = accounts.entry(account.to_string()).or_default();
+        let now = Utc::now().timestamp_millis();
+        let deleted_at = data
+            .tombstones
+            .get(&(kind, uuid.to_string()))
+            .map(|t| t.deleted_at);
+        let updated_at = match kind {
+            RecordKind::Diary => restore(
+                &mut data.diaries,
+                &mut data.diary_history,
+                counters,
+                uuid,
+                revision_id,
+                deleted_at,
+                history_limit,
+                now,
+            ),
+            RecordKind::Todo => restore(
+                &mut data.todos,
+                &mut data.todo_history,
+                counters,
+                uuid,
+                revision_id,
+                deleted_at,
+                history_limit,
+                now,
+            ),
+            RecordKind::Period => unreachable!("rejected above"),
+        };
+        if updated_at.is_some() {
+            data.tombstones.remove(&(kind, uuid.to_string()));
+        }
+        Ok(updated_at)
+    }
+
+    async fn record_device_sync(
+        &self,
+        account: &str,
+        device_id: &str,
+        direction: SyncDirection,
+        now: i64,
+    ) -> StorageResult<()> {
+        let mut state = self.state();
+        let device = state
+            .accounts
+            .entry(account.to_string())
+            .or_default()
+            .device(device_id, now);
+        device.last_seen = now;
+        match direction {
+            SyncDirection::Upload => device.last_upload_at = Some(now),
+            SyncDirection::Download => device.last_download_at = Some(now),
+        }
+        Ok(())
+    }
+
+    async fn register_device(
+        &self,
+        account: &str,
+        device: &DeviceRegisterRequest,
+        now: i64,
+    ) -> StorageResult<DeviceItem> {
+        let mut state = self.state();
+        let stored = state
+            .accounts
+            .entry(account.to_string())
+            .or_default()
+            .device(device.device_id.trim(), now);
+        stored.name = device.name.clone();
+        stored.app_version = device.app_version.clone();
+        stored.last_seen = now;
+        Ok(stored.clone())
+    }
+
+    async fn list_devices(&self, account: &str) -> StorageResult<Vec<DeviceItem>> {
+        let mut devices: Vec<DeviceItem> =
+            self.read(account, |data| data.devices.values().cloned().collect());
+        devices.sort_by_key(|d| std::cmp::Reverse(d.last_seen));
+        Ok(devices)
+    }
+
+    async fn fetch_image(
+        &self,
+        account: &str,
+        diary_uuid: &str,
+        file_name: &str,
+    ) -> StorageResult<Option<ImageFetchResponse>> {
+        let found = self.read(account, |data| {
+            let image_ref = data
+                .image_refs
+                .get(&(diary_uuid.to_string(), file_name.to_string()))?;
+            let image = data.images.get(&image_ref.hash)?;
+            Some((image_ref.clone(), image.clone()))
+        });
+        let Some((image_ref, image)) = found else {
+            return Ok(None);
+        };
+        let data =
+            load_image_blob(self.blobs.as_deref(), account, &image_ref.hash, image.data).await?;
+        Ok(Some(ImageFetchResponse {
+            file_name: image_ref.file_name,
+            diary_uuid: image_ref.diary_uuid,
+            hash: image_ref.hash,
+            updated_at: image_ref.updated_at,
+            blob: EncryptedBlob { iv: image.iv, data },
+        }))
+    }
+
+    async fn image_hashes(&self, account: &str) -> StorageResult<Vec<String>> {
+        Ok(self.read(account, |data| data.images.keys().cloned().collect()))
+    }
+
+    async fn image_sizes(
+        &self,
+        account: &str,
+        hashes: &[String],
+    ) -> StorageResult<Vec<ImageHashSize>> {
+        Ok(self.read(account, |data| {
+            hashes
+                .iter()
+                .filter_map(|hash| {
+                    data.images.get(hash).map(|image| ImageHashSize {
+                        hash: hash.clone(),
+                        size: image.size,
+                    })
+                })
+                .collect()
+        }))
+    }
+
+    async fn image_refs(
+        &self,
+        account: &str,
+        filter: &ImageRefsRequest,
+    ) -> StorageResult<Vec<DiaryImageRefItem>> {
+        Ok(self.read(account, |data| {
+            data.image_refs
+                .values()
+                .filter(|r| {
+                    filter
+                        .diary_uuids
+                        .as_ref()
+                        .is_none_or(|uuids| uuids.contains(&r.diary_uuid))
+                        && filter
+                            .updated_after
+                            .is_none_or(|after| r.updated_at > after)
+                })
+                .cloned()
+                .collect()
+        }))
+    }
+
+    async fn put_images(
+        &self,
+        account: &str,
+        images: &[DiaryImageSyncItem],
+    ) -> StorageResult<usize> {
+        let _images = self
+            .image_locks
+            .lock(account, images.iter().map(|i| i.hash.as_str()))
+            .await;
+        let inline_images = self.store_blobs(account, images).await?;
+        let mut state = self.state();
+        let data = state.accounts.entry(account.to_string()).or_default();
+        for (item, inline) in images.iter().zip(inline_images) {
+            data.put_image(item, inline);
+        }
+        Ok(images.len())
+    }
+
+    async fn put_image_bytes(
+        &self,
+        account: &str,
+        hash: &str,
+        image: &ImageBytes,
+    ) -> StorageResult<()> {
+        let _image = self.image_locks.lock(account, [hash]).await;
+        let inline = store_image_bytes(self.blobs.as_deref(), account, hash, &image.data).await?;
+        let mut state = self.state();
+        let data = state.accounts.entry(account.to_string()).or_default();
+        data.insert_image(
+            hash,
+            StoredImage {
+                iv: image.iv.clone(),
+                size: Some(image.data.len() as i64),
+                updated_at: image.updated_at,
+                stored_at: Utc::now().timestamp_millis(),
+                unreferenced_at: None,
+                data: inline,
+            },
+        );
+        Ok(())
+    }
+
+    async fn put_image_stream(
+        &self,
+        account: &str,
+        hash: &str,
+        image: &ImageMeta,
+        chunks: &mut BlobChunks,
+    ) -> StorageResult<i64> {
+        let _image = self.image_locks.lock(account, [hash]).await;
+        let (inline, size) =
+            store_image_chunks(self.blobs.as_deref(), account, hash, chunks).await?;
+        let mut state = self.state();
+        let data = state.accounts.entry(account.to_string()).or_default();
+        data.insert_image(
+            hash,
+            StoredImage {
+                iv: image.iv.clone(),
+                size: Some(size),
+                updated_at: image.updated_at,
+                stored_at: Utc::now().timestamp_millis(),
+                unreferenced_at: None,
+                data: inline,
+            },
+        );
+        Ok(size)
+    }
+
+    async fn image_bytes(&self, account: &str, hash: &str) -> StorageResult<Option<ImageBytes>> {
+        let Some(image) = self.read(account, |data| data.images.get(hash).cloned()) else {
+            return Ok(None);
+        };
+        let data = load_image_bytes(self.blobs.as_deref(), account, hash, image.data).await?;
+        Ok(Some(ImageBytes {
+            iv: image.iv,
+            updated_at: image.updated_at,
+            data,
+        }))
+    }
+
+    async fn image_meta(&self, account: &str, hash: &str) -> StorageResult<Option<ImageMeta>> {
+        Ok(self.read(account, |data| {
+            data.images.get(hash).map(|image| ImageMeta {
+                iv: image.iv.clone(),
+                updated_at: image.updated_at,
+                size: image.size,
+            })
+        }))
+    }
+
+    async fn image_range(
+        &self,
+        account: &str,
+        hash: &str,
+        start: u64,
+        end: u64,
+    ) -> StorageResult<Option<Vec<u8>>> {
+        let Some(inline) = self.read(account, |data| {
+            data.images.get(hash).map(|i| i.data.clone())
+        }) else {
+            return Ok(None);
+        };
+        let data =
+            load_image_range(self.blobs.as_deref(), account, hash, inline, start, end).await?;
+        Ok(Some(data))
+    }
+
+    async fn begin_image_upload(
+        &self,
+        account: &str,
+        hash: &str,
+        upload: &NewImageUpload,
+        now: i64,
+    ) -> StorageResult<ImageUploadStatus> {
+        let mut state = self.state();
+        let data = state.accounts.entry(account.to_string()).or_default();
+        let pending = data
+            .uploads
+            .entry(hash.to_string())
+            .or_insert(PendingUpload {
+                iv: upload.iv.clone(),
+                size: upload.size,
+                updated_at: upload.updated_at,
+                touched_at: now,
+                data: Vec::new(),
+            });
+        if pending.iv != upload.iv
+            || pending.size != upload.size
+            || pending.updated_at != upload.updated_at
+        {
+            *pending = PendingUpload {
+                iv: upload.iv.clone(),
+                size: upload.size,
+                updated_at: upload.updated_at,
+                touched_at: now,
+                data: Vec::new(),
+            };
+        }
+        pending.touched_at = now;
+        Ok(pending.status(hash))
+    }
+
+    async fn image_upload_status(
+        &self,
+        account: &str,
+        hash: &str,
+    ) -> StorageResult<Option<ImageUploadStatus>> {
+        Ok(self.read(account, |data| {
+            data.uploads.get(hash).map(|pending| pending.status(hash))
+        }))
+    }
+
+    async fn append_image_chunk(
+        &self,
+        account: &str,
+        hash: &str,
+        offset: i64,
+        chunk: &[u8],
+        now: i64,
+    ) -> StorageResult<Option<ChunkOutcome>> {
+        let mut state = self.state();
+        let pending = state
+            .accounts
+            .get_mut(account)
+            .and_then(|data| data.uploads.get_mut(hash));
+        let Some(pending) = pending else {
+            return Ok(None);
+        };
+        if let Some(outcome) = check_chunk(&pending.status(hash), offset, chunk.len())? {
+            return Ok(Some(outcome));
+        }
+        if !chunk.is_empty() {
+            pending.data.extend_from_slice(chunk);
+            pending.touched_at = now;
+        }
+        Ok(Some(ChunkOutcome::Appended(pending.status(hash))))
+    }
+
+    async fn finalize_image_upload(
+        &self,
+        account: &str,
+        hash: &str,
+    ) -> StorageResult<Option<FinalizeOutcome>> {
+        let _image = self.image_locks.lock(account, [hash]).await;
+        let Some(pending) = self.read(account, |data| data.uploads.get(hash).cloned()) else {
+            return Ok(None);
+        };
+        let status = pending.status(hash);
+        if status.offset < status.size {
+            return Ok(Some(FinalizeOutcome::Incomplete(status)));
+        }
+        let inline = store_image_bytes(self.blobs.as_deref(), account, hash, &pending.data).await?;
+        let mut state = self.state();
+        let data = state.accounts.entry(account.to_string()).or_default();
+        data.uploads.remove(hash);
+        data.insert_image(
+            hash,
+            StoredImage {
+                iv: pending.iv,
+                size: Some(pending.data.len() as i64),
+                updated_at: pending.updated_at,
+                stored_at: Utc::now().timestamp_millis(),
+                unreferenced_at: None,
+                data: inline,
+            },
+        );
+        Ok(Some(FinalizeOutcome::Stored))
+    }
+
+    async fn put_image_refs(
+        &self,
+        account: &str,
+        refs: &[DiaryImageRefItem],
+    ) -> StorageResult<usize> {
+        let mut state = self.state();
+        let data = state.accounts.entry(account.to_string()).or_default();
+        for item in refs {
+            data.put_image_ref(item.clone());
+        }
+        Ok(refs.len())
+    }
+
+    async fn delete_image_refs(&self, account: &str, keys: &[ImageRefKey]) -> StorageResult<usize> {
+        let mut state = self.state();
+        let Some(data) = state.accounts.get_mut(account) else {
+            return Ok(0);
+        };
+        let released: Vec<String> = keys
+            .iter()
+            .filter_map(|key| {
+                data.image_refs
+                    .remove(&(key.diary_uuid.clone(), key.file_name.clone()))
+                    .map(|item| item.hash)
+            })
+            .collect();
+        let removed = released.len();
+        data.release_images(released);
+        Ok(removed)
+    }
+
+    async fn replace_diary_image_refs(
+        &self,
+        account: &str,
+        diary_uuid: &str,
+        refs: &[DiaryImageRefItem],
+    ) -> StorageResult<usize> {
+        let mut state = self.state();
+        let data = state.accounts.entry(account.to_string()).or_default();
+        let released: Vec<String> = data
+            .image_refs
+            .extract_if(.., |(uuid, file_name), _| {
+                uuid == diary_uuid && !refs.iter().any(|r| r.file_name == *file_name)
+            })
+            .map(|(_, item)| item.hash)
+            .collect();
+        let removed = released.len();
+        for item in refs {
+            data.put_image_ref(DiaryImageRefItem {
+                diary_uuid: diary_uuid.to_string(),
+                ..item.clone()
+            });
+        }
+        // After the writes, which may point a kept ref at a released hash.
+        data.release_images(released);
+        Ok(removed)
+    }
+
+    async fn orphaned_images(&self, cutoff: i64) -> StorageResult<Vec<OrphanImage>> {
+        let state = self.state();
+        let mut orphans = Vec::new();
+        for (account, data) in &state.accounts {
+            for (hash, image) in &data.images {
+                let Some(unreferenced_at) = image.unreferenced_at else {
+                    continue;
+                };
+                if unreferenced_at < cutoff && !data.is_referenced(hash) {
+                    orphans.push(OrphanImage {
+                        account_id: account.clone(),
+                        hash: hash.clone(),
+                        stored_at: image.stored_at,
+                        unreferenced_at,
+                    });
+                }
+            }
+        }
+        orphans.sort_by(|a, b| (&a.account_id, &a.hash).cmp(&(&b.account_id, &b.hash)));
+        Ok(orphans)
+    }
+
+    async fn delete_orphaned_image(
+        &self,
+        account: &str,
+        hash: &str,
+        cutoff: i64,
+    ) -> StorageResult<bool> {
+        // Held until the blob is gone, so an upload of the same image waits
+        // and then writes both its blob and its data.
+        let _image = self.image_locks.lock(account, [hash]).await;
+        let removed = {
+            let mut state = self.state();
+            let Some(data) = state.accounts.get_mut(account) else {
+                return Ok(false);
+            };
+            let orphaned = data
+                .images
+                .get(hash)
+                .is_some_and(|image| image.unreferenced_at.is_some_and(|at| at < cutoff))
+                && !data.is_referenced(hash);
+            if !orphaned {
+                return Ok(false);
+            }
+            data.images.remove(hash)
+        };
+        // The row goes first: a leftover object is harmless, a row without one is not.
+        if let (Some(blobs), Some(StoredImage { data: None, .. })) =
+            (self.blobs.as_deref(), removed)
+        {
+            blobs.delete(account, hash).await?;
+        }
+        Ok(true)
+    }
+
+    async fn move_inline_blobs(&self) -> StorageResult<BlobMigration> {
+        let blobs = self.blobs.as_deref().ok_or_else(no_blob_store)?;
+        let inline: Vec<(String, String, String)> = {
+            let state = self.state();
+            state
+                .accounts
+                .iter()
+                .flat_map(|(account, data)| {
+                    data.images.iter().filter_map(move |(hash, image)| {
+                        Some((account.clone(), hash.clone(), image.data.clone()?))
+                    })
+                })
+                .collect()
+        };
+        let mut report = BlobMigration::default();
+        for (account, hash, data) in inline {
+            match move_inline_blob(blobs, &account, &hash, &data).await {
+                Ok(()) => {
+                    let mut state = self.state();
+                    let image = state
+                        .accounts
+                        .get_mut(&account)
+                        .and_then(|a| a.images.get_mut(&hash));
+                    if let Some(image) = image {
+                        if image.data.as_deref() == Some(data.as_str()) {
+                            image.data = None;
+                        }
+                    }
+                    report.moved += 1;
+                }
+                Err(StorageError::Invalid(_)) => report.skipped.push((account, hash)),
+                Err(e) => return Err(e.context("blob store write failed")),
+            }
+        }
+        Ok(report)
+    }
+
+    async fn create_api_key(&self, key: &NewApiKey) -> StorageResult<ApiKeyInfo> {
+        let info = ApiKeyInfo {
+            id: key.id.clone(),
+            account_id: key.account_id.clone(),
+            name: key.name.clone(),
+            scopes: key.scopes.clone(),
+            created_at: key.created_at,
+            last_used_at: None,
+            revoked_at: None,
+        };
+        self.state().api_keys.insert(
+            key.id.clone(),
+            StoredApiKey {
+                key_hash: key.key_hash.clone(),
+                info: info.clone(),
+            },
+        );
+        Ok(info)
+    }
+
+    async fn api_key_by_hash(&self, key_hash: &str) -> StorageResult<Option<ApiKeyInfo>> {
+        Ok(self
+            .state()
+            .api_keys
+            .values()
+            .find(|key| key.key_hash == key_hash)
+            .map(|key| key.info.clone()))
+    }
+
+    async fn list_api_keys(&self, account: Option<&str>) -> StorageResult<Vec<ApiKeyInfo>> {
+        let mut keys: Vec<ApiKeyInfo> = self
+            .state()
+            .api_keys
+            .values()
+            .filter(|key| account.is_none_or(|account| key.info.account_id == account))
+            .map(|key| key.info.clone())
+            .collect();
+        keys.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        Ok(keys)
+    }
+
+    async fn revoke_api_key(&self, id: &str, now: i64) -> StorageResult<Option<ApiKeyInfo>> {
+        Ok(self.state().api_keys.get_mut(id).map(|key| {
+            key.info.revoked_at.get_or_insert(now);
+            key.info.clone()
+        }))
+    }
+
+    async fn touch_api_key(&self, id: &str, now: i64) -> StorageResult<()> {
+        if let Some(key) = self.state().api_keys.get_mut(id) {
+            key.info.last_used_at = Some(now);
+        }
+        Ok(())
+    }
+
+    async fn has_api_keys(&self) -> StorageResult<bool> {
+        Ok(!self.state().api_keys.is_empty())
+    }
+
+    async fn create_auth_session(&self, session: &AuthSession) -> StorageResult<()> {
+        self.state()
+            .sessions
+            .insert(session.id.clone(), session.clone());
+        Ok(())
+    }
+
+    async fn auth_session(&self, id: &str) -> StorageResult<Option<AuthSession>> {
+        Ok(self.state().sessions.get(id).cloned())
+    }
+
+    async fn auth_session_by_refresh(&self, hash: &str) -> StorageResult<Option<AuthSession>> {
+        Ok(self
+            .state()
+            .sessions
+            .values()
+            .find(|s| s.refresh_hash == hash || s.previous_refresh_hash.as_deref() == Some(hash))
+            .cloned())
+    }
+
+    async fn rotate_auth_session(
+        &self,
+        id: &str,
+        old_hash: &str,
+        new_hash: &str,
+        now: i64,
+        expires_at: i64,
+    ) -> StorageResult<bool> {
+        let mut state = self.state();
+        let Some(session) = state.sessions.get_mut(id) else {
+            return Ok(false);
+        };
+        if session.refresh_hash != old_hash || session.revoked_at.is_some() {
+            return Ok(false);
+        }
+        session.previous_refresh_hash = Some(std::mem::replace(
+            &mut session.refresh_hash,
+            new_hash.to_string(),
+        ));
+        session.refreshed_at = now;
+        session.expires_at = expires_at;
+        Ok(true)
+    }
+
+    async fn revoke_auth_session(&self, id: &str, now: i64) -> StorageResult<bool> {
+        let mut state = self.state();
+        match state.sessions.get_mut(id) {
+            Some(session) if session.revoked_at.is_none() => {
+                session.revoked_at = Some(now);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn revoke_auth_sessions(
+        &self,
+        account: &str,
+        key_id: Option<&str>,
+        now: i64,
+    ) -> StorageResult<usize> {
+        let mut state = self.state();
+        let mut revoked = 0;
+        for session in state.sessions.values_mut() {
+            if session.account_id == account
+                && key_id.is_none_or(|key_id| session.key_id.as_deref() == Some(key_id))
+                && session.revoked_at.is_none()
+            {
+                session.revoked_at = Some(now);
+                revoked += 1;
+            }
+        }
+        Ok(revoked)
+    }
+
+    async fn record_auth_failure(
+        &self,
+        ip: &str,
+        path: &str,
+        reason: &str,
+        now: i64,
+    ) -> StorageResult<()> {
+        let mut state = self.state();
+        let id = state.counters.next_auth_failure_id();
+        state.auth_failures.push(AuthFailure {
+            id,
+            ip: ip.to_string(),
+            path: path.to_string(),
+            reason: reason.to_string(),
+            created_at: now,
+        });
+        Ok(())
+    }
+
+    async fn prune_auth_failures(&self, before: i64) -> StorageResult<u64> {
+        let mut state = self.state();
+        let logged = state.auth_failures.len();
+        state
+            .auth_failures
+            .retain(|failure| failure.created_at >= before);
+        Ok((logged - state.auth_failures.len()) as u64)
+    }
+
+    async fn expire_image_uploads(&self, before: i64) -> StorageResult<u64> {
+        let mut state = self.state();
+        let mut expired = 0;
+        for data in state.accounts.values_mut() {
+            let pending = data.uploads.len();
+            data.uploads.retain(|_, upload| upload.touched_at >= before);
+            expired += (pending - data.uploads.len()) as u64;
+        }
+        Ok(expired)
+    }
+
+    async fn auth_failures(&self, since: i64, limit: i64) -> StorageResult<Vec<AuthFailure>> {
+        Ok(self
+            .state()
+            .auth_failures
+            .iter()
+            .rev()
+            .filter(|failure| failure.created_at >= since)
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+}
+
+impl MemoryStorage {
+    /// Runs `f` against the account's data; unknown accounts read as empty.
+    fn read<R>(&self, account: &str, f: impl FnOnce(&AccountData) -> R) -> R {
+        let state = self.state();
+        match state.accounts.get(account) {
+            Some(data) => f(data),
+            None => f(&AccountData::default()),
+        }
+    }
+}
+
+impl AccountData {
+    fn upsert_diary(
+        &mut self,
+        counters: &mut Counters,
+        item: DiarySyncItem,
+        base_updated_at: Option<i64>,
+        history_limit: i64,
+        now: i64,
+    ) -> WriteResult {
+        let uuid = item.uuid.clone();
+        if let Some(rejected) =
+            self.clear_tombstone(RecordKind::Diary, &uuid, item.updated_at, base_updated_at)
+        {
+            return Some(rejected);
+        }
+        upsert(
+            &mut self.diaries,
+            Some(&mut self.diary_history),
+            counters,
+            RecordKind::Diary,
+            uuid,
+            item,
+            base_updated_at,
+            history_limit,
+            now,
+        )
+    }
+
+    fn upsert_todo(
+        &mut self,
+        counters: &mut Counters,
+        item: TodoSyncItem,
+        base_updated_at: Option<i64>,
+        history_limit: i64,
+        now: i64,
+    ) -> WriteResult {
+        let uuid = item.uuid.clone();
+        if let Some(rejected) =
+            self.clear_tombstone(RecordKind::Todo, &uuid, item.updated_at, base_updated_at)
+        {
+            return Some(rejected);
+        }
+        upsert(
+            &mut self.todos,
+            Some(&mut self.todo_history),
+            counters,
+            RecordKind::Todo,
+            uuid,
+            item,
+            base_updated_at,
+            history_limit,
+            now,
+        )
+    }
+
+    fn upsert_period(
+        &mut self,
+        counters: &mut Counters,
+        item: &PeriodSyncItem,
+    ) -> StorageResult<WriteResult> {
+        let start_date = NaiveDate::parse_from_str(&item.start_date, "%Y-%m-%d")
+            .map_err(|e| StorageError::Invalid(e.to_string()))?;
+        let end_date = NaiveDate::parse_from_str(&item.end_date, "%Y-%m-%d")
+            .map_err(|e| StorageError::Invalid(e.to_string()))?;
+        let uuid = if item.uuid.is_empty() {
+            self.resolve_period_uuid(start_date)
+        } else {
+            item.uuid.clone()
+        };
+        if let Some(rejected) = self.clear_tombstone(
+            RecordKind::Period,
+            &uuid,
+            item.updated_at,
+            item.base_updated_at,
+        ) {
+            return Ok(Some(rejected));
+        }
+        let record = PeriodSyncItem {
+            uuid: uuid.clone(),
+            start_date: start_date.to_string(),
+            end_date: end_date.to_string(),
+            base_updated_at: None,
+            ..item.clone()
+        };
+        Ok(upsert(
+            &mut self.periods,
+            None,
+            counters,
+            RecordKind::Period,
+            uuid,
+            record,
+            item.base_updated_at,
+            0,
+            0,
+        ))
+    }
+
+    /// Older clients identify periods by start date only: map such an item to the
+    /// stored period with that start date, or to the uuid a backfilled row would have.
+    fn resolve_period_uuid(&self, start_date: NaiveDate) -> String {
+        let start_date = start_date.to_string();
+        self.periods
+            .values()
+            .filter(|stored| stored.record.start_date == start_date)
+            .max_by_key(|stored| stored.record.updated_at)
+            .map(|stored| stored.record.uuid.clone())
+            .unwrap_or_else(|| legacy_period_uuid(&start_date))
+    }
+
+    /// Drops the tombstone for a record that is being written again, unless the
+    /// tombstone supersedes the write.
+    fn clear_tombstone(
+        &mut self,
+        kind: RecordKind,
+        uuid: &str,
+        updated_at: i64,
+        base_updated_at: Option<i64>,
+    ) -> WriteResult {
+        let key = (kind, uuid.to_string());
+        let deleted_at = self.tombstones.get(&key)?.deleted_at;
+        if !supersedes(updated_at, base_updated_at, deleted_at) {
+            return Some(conflict(kind, uuid, deleted_at, true));
+        }
+        self.tombstones.remove(&key);
+        None
+    }
+
+    /// Deletes the live record and records its tombstone, unless the server holds
+    /// a version edited after the deletion that the client has not seen.
+    fn apply_tombstone(
+        &mut self,
+        counters: &mut Counters,
+        item: &TombstoneItem,
+        history_limit: i64,
+        now: i64,
+    ) -> WriteResult {
+        let legacy_start_date = match item.kind {
+            RecordKind::Period => NaiveDate::parse_from_str(&item.uuid, "%Y-%m-%d").ok(),
+            _ => None,
+        };
+        let uuid = match legacy_start_date {
+            Some(start_date) => self.resolve_period_uuid(start_date),
+            None => item.uuid.clone(),
+        };
+        let current = match item.kind {
+            RecordKind::Diary => self.diaries.get(&uuid).map(|s| s.record.updated_at),
+            RecordKind::Todo => self.todos.get(&uuid).map(|s| s.record.updated_at),
+            RecordKind::Period => self.periods.get(&uuid).map(|s| s.record.updated_at),
+        };
+        if let Some(current) = current {
+            // A deletion stamped at the same instant as the stored edit still wins.
+            if current > item.deleted_at && item.base_updated_at != Some(current) {
+                return Some(conflict(item.kind, &uuid, current, false));
+            }
+        }
+
+        let mut start_date = legacy_start_date.map(|date| date.to_string());
+        match item.kind {
+            RecordKind::Diary => {
+                if let Some(stored) = self.diaries.remove(&uuid) {
+                    archive(
+                        &mut self.diary_history,
+                        counters,
+                        stored.record,
+                        history_limit,
+                        now,
+                    );
+                }
+                let released: Vec<String> = self
+                    .image_refs
+                    .extract_if(.., |(diary_uuid, _), _| *diary_uuid == uuid)
+                    .map(|(_, item)| item.hash)
+                    .collect();
+                self.release_images(released);
+            }
+            RecordKind::Todo => {
+                if let Some(stored) = self.todos.remove(&uuid) {
+                    archive(
+                        &mut self.todo_history,
+                        counters,
+                        stored.record,
+                        history_limit,
+                        now,
+                    );
+                }
+            }
+            RecordKind::Period => {
+                start_date = self
+                    .periods
+                    .remove(&uuid)
+                    .map(|stored| stored.record.start_date)
+                    .or(start_date);
+            }
+        }
+        let change_seq = counters.next_change_seq();
+        let tombstone = self
+            .tombstones
+            .entry((item.kind, uuid))
+            .or_insert(Tombstone {
+                deleted_at: item.deleted_at,
+                change_seq,
+                start_date: None,
+            });
+        tombstone.deleted_at = tombstone.deleted_at.max(item.deleted_at);
+        tombstone.change_seq = change_seq;
+        if start_date.is_some() {
+            tombstone.start_date = start_date;
+        }
+        None
+    }
+
+    fn put_image(&mut self, item: &DiaryImageSyncItem, inline: Option<String>) {
+        // Store image blob once per hash.
+        self.insert_image(
+            &item.hash,
+            StoredImage {
+                iv: item.blob.iv.clone(),
+                size: base64_len(&item.blob.data),
+                updated_at: item.updated_at,
+                stored_at: Utc::now().timestamp_millis(),
+                unreferenced_at: None,
+                data: inline,
+            },
+        );
+    }
+
+    /// Stores an image, unreferenced from `stored_at` unless a ref already points at it.
+    fn insert_image(&mut self, hash: &str, mut image: StoredImage) {
+        if !self.is_referenced(hash) {
+            image.unreferenced_at = Some(image.stored_at);
+        }
+        self.images.insert(hash.to_string(), image);
+    }
+
+    /// Starts the grace period of those of `hashes` that just lost their last ref.
+    fn release_images(&mut self, hashes: impl IntoIterator<Item = String>) {
+        let now = Utc::now().timestamp_millis();
+        for hash in hashes {
+            if self.is_referenced(&hash) {
+                continue;
+            }
+            if let Some(image) = self.images.get_mut(&hash) {
+                image.unreferenced_at.get_or_insert(now);
+            }
+        }
+    }
+
+    fn is_referenced(&self, hash: &str) -> bool {
+        self.image_refs.values().any(|r| r.hash == hash)
+    }
+
+    fn put_image_ref(&mut self, item: DiaryImageRefItem) {
+        if let Some(image) = self.images.get_mut(&item.hash) {
+            image.unreferenced_at = None;
+        }
+        let hash = item.hash.clone();
+        let previous = self
+            .image_refs
+            .insert((item.diary_uuid.clone(), item.file_name.clone()), item);
+        if let Some(previous) = previous.filter(|previous| previous.hash != hash) {
+            self.release_images([previous.hash]);
+        }
+    }
+
+    fn device(&mut self, device_id: &str, now: i64) -> &mut DeviceItem {
+        self.devices
+            .entry(device_id.to_string())
+            .or_insert_with(|| DeviceItem {
+                device_id: device_id.to_string(),
+                name: String::new(),
+                app_version: String::new(),
+                first_seen: now,
+                last_seen: now,
+                last_upload_at: None,
+                last_download_at: None,
+            })
+    }
+}
+
+/// The conditional upsert shared by every record kind. `history` is `None` for
+/// periods, which keep no revisions.
+#[allow(clippy::too_many_arguments)]
+fn upsert<T: Record>(
+    table: &mut BTreeMap<String, Stored<T>>,
+    history: Option<&mut HashMap<String, Vec<Revision<T>>>>,
+    counters: &mut Counters,
+    kind: RecordKind,
+    uuid: String,
+    mut record: T,
+    base_updated_at: Option<i64>,
+    history_limit: i64,
+    now: i64,
+) -> WriteResult {
+    if let Some(stored) = table.get(&uuid) {
+        let current = stored.record.updated_at();
+        if !supersedes(record.updated_at(), base_updated_at, current) {
+            let stored = Some((current, stored.record.payload().clone()));
+            return stale_write(kind, &uuid, record.updated_at(), record.payload(), stored);
+        }
+        record.set_updated_at(applied_updated_at(record.updated_at(), current));
+    }
+    let change_seq = counters.next_change_seq();
+    let previous = table.insert(uuid, Stored { record, change_seq });
+    if let (Some(history), Some(previous)) = (history, previous) {
+        archive(history, counters, previous.record, history_limit, now);
+    }
+    None
+}
+
+/// Appends the replaced version to its history, keeping the newest `history_limit`.
+fn archive<T: Record>(
+    history: &mut HashMap<String, Vec<Revision<T>>>,
+    counters: &mut Counters,
+    record: T,
+    history_limit: i64,
+    now: i64,
+) {
+    if history_limit <= 0 {
+        return;
+    }
+    let revisions = history.entry(record.uuid().to_string()).or_default();
+    revisions.push(Revision {
+        revision_id: counters.next_revision_id(),
+        archived_at: now,
+        record,
+    });
+    let excess = revisions.len().saturating_sub(history_limit as usize);
+    revisions.drain(..excess);
+}
+
+/// Makes an archived revision current again, archiving whatever it replaces.
+#[allow(clippy::too_many_arguments)]
+fn restore<T: Record>(
+    table: &mut BTreeMap<String, Stored<T>>,
+    history: &mut HashMap<String, Vec<Revision<T>>>,
+    counters: &mut Counters,
+    uuid: &str,
+    revision_id: i64,
+    deleted_at: Option<i64>,
+    history_limit: i64,
+    now: i64,
+) -> Option<i64> {
+    let mut record = history
+        .get(uuid)?
+        .iter()
+        .find(|r| r.revision_id == revision_id)?
+        .record
+        .clone();
+    let current = table.get(uuid).map(|s| s.record.updated_at());
+    let updated_at = restored_updated_at(current, deleted_at, now);
+    record.set_updated_at(updated_at);
+    let change_seq = counters.next_change_seq();
+    if let Some(previous) = table.insert(uuid.to_string(), Stored { record, change_seq }) {
+        archive(history, counters, previous.record, history_limit, now);
+    }
+    Some(updated_at)
+}
+
+fn page<T: Clone>(table: &BTreeMap<String, Stored<T>>, after: &str, limit: i64) -> Vec<T> {
+    table
+        .range::<str, _>((Bound::Excluded(after), Bound::Unbounded))
+        .take(limit.max(0) as usize)
+        .map(|(_, stored)| stored.record.clone())
+        .collect()
+}
+
+fn sync_meta<T: Record>(table: &BTreeMap<String, Stored<T>>) -> Vec<SyncMeta> {
+    table
+        .iter()
+        .map(|(uuid, stored)| SyncMeta {
+            uuid: uuid.clone(),
+            updated_at: stored.record.updated_at(),
+        })
+        .collect()
+}
+
+fn changed_since<T: Clone>(table: &BTreeMap<String, Stored<T>>, since: i64) -> Vec<T> {
+    let mut changed: Vec<&Stored<T>> = table.values().filter(|s| s.change_seq > since).collect();
+    changed.sort_by_key(|s| s.change_seq);
+    changed.into_iter().map(|s| s.record.clone()).collect()
+}
+
+fn max_change_seq<T>(table: &BTreeMap<String, Stored<T>>) -> i64 {
+    table.values().map(|s| s.change_seq).max().unwrap_or(0)
+}
+
+fn revision_items<T: Record>(
+    history: &HashMap<String, Vec<Revision<T>>>,
+    uuid: &str,
+) -> Vec<RevisionItem> {
+    history
+        .get(uuid)
+        .map(|revisions| {
+            revisions
+                .iter()
+                .rev()
+                .map(|r| RevisionItem {
+                    revision_id: r.revision_id,
+                    updated_at: r.record.updated_at(),
+                    archived_at: r.archived_at,
+                    payload: r.record.payload().clone(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn tombstone_item(kind: RecordKind, uuid: &str, tombstone: &Tombstone) -> TombstoneItem {
+    TombstoneItem {
+        kind,
+        uuid: uuid.to_string(),
+        deleted_at: tombstone.deleted_at,
+        base_updated_at: None,
+        start_date: tombstone.start_date.clone(),
+    }
+}
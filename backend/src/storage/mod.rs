@@ -0,0 +1,659 @@
+//! Persistence behind the HTTP handlers. Handlers only talk to [`Storage`];
+//! each backend keeps the same sync semantics (account scoping, conditional
+//! writes, tombstones, revision history, change cursors).
+
+use async_trait::async_trait;
+use sqlx::migrate::{AppliedMigration, MigrateError, Migrator};
+use std::collections::HashMap;
+use std::sync::{Arc, PoisonError, Weak};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::models::{
+    ApiKeyInfo, ApiScope, AuthFailure, DeviceItem, DeviceRegisterRequest, DiaryImageRefItem,
+    DiaryImageSyncItem, DiarySyncItem, EncryptedBlob, ImageFetchResponse, ImageHashSize,
+    ImageRefKey, ImageRefsRequest, ImageUploadStatus, OrphanImage, PeriodSyncItem, RecordKind,
+    RevisionItem, SyncChangesResponse, SyncConflict, SyncCounts, SyncMetaResponse,
+    SyncUploadRequest, TodoSyncItem, TombstoneItem,
+};
+
+pub mod blob;
+pub mod memory;
+pub mod postgres;
+mod sql;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+pub use blob::{is_blob_key, BlobChunks, BlobStore, FsBlobStore, S3BlobStore, S3Config};
+pub use memory::MemoryStorage;
+pub use postgres::PgStorage;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStorage;
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// The request carried data the store cannot accept (e.g. a malformed date).
+    Invalid(String),
+    /// The underlying database failed.
+    Backend(String),
+    /// The database schema does not match what this binary expects.
+    Schema(String),
+}
+
+impl StorageError {
+    /// Prefixes the message with what was being done, e.g. `"diary upsert failed"`.
+    pub fn context(self, what: &str) -> Self {
+        match self {
+            StorageError::Invalid(msg) => StorageError::Invalid(format!("{}: {}", what, msg)),
+            StorageError::Backend(msg) => StorageError::Backend(format!("{}: {}", what, msg)),
+            StorageError::Schema(msg) => StorageError::Schema(format!("{}: {}", what, msg)),
+        }
+    }
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Invalid(msg) | StorageError::Backend(msg) | StorageError::Schema(msg) => {
+                f.write_str(msg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<sqlx::Error> for StorageError {
+    fn from(e: sqlx::Error) -> Self {
+        StorageError::Backend(e.to_string())
+    }
+}
+
+impl From<MigrateError> for StorageError {
+    fn from(e: MigrateError) -> Self {
+        StorageError::Schema(e.to_string())
+    }
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncDirection {
+    Upload,
+    Download,
+}
+
+/// What startup does about migrations the database has not applied yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationMode {
+    /// Apply them.
+    Apply,
+    /// Only verify the schema is current; refuse to start otherwise.
+    Check,
+}
+
+/// The schema version a migrator brings the database to.
+pub fn latest_schema_version(migrator: &Migrator) -> i64 {
+    migrator.iter().map(|m| m.version).max().unwrap_or(0)
+}
+
+/// Rejects a database migrated by a newer binary and, in `Check` mode, one
+/// this binary would still have to migrate. Returns the database's version.
+pub(crate) fn check_schema_version(
+    migrator: &Migrator,
+    applied: &[AppliedMigration],
+    mode: MigrationMode,
+) -> StorageResult<i64> {
+    let known = latest_schema_version(migrator);
+    let current = applied.iter().map(|m| m.version).max().unwrap_or(0);
+    if current > known {
+        return Err(StorageError::Schema(format!(
+            "database schema version {} is newer than this binary supports ({}); upgrade the server",
+            current, known
+        )));
+    }
+    if mode == MigrationMode::Check {
+        if let Some(pending) = migrator
+            .iter()
+            .find(|m| !applied.iter().any(|a| a.version == m.version))
+        {
+            return Err(StorageError::Schema(format!(
+                "migration {} ({}) has not been applied; start once with AUTO_MIGRATE=true",
+                pending.version, pending.description
+            )));
+        }
+    }
+    Ok(current)
+}
+
+/// What an upload wrote; rejected items are listed separately from `counts`.
+#[derive(Debug, Default)]
+pub struct UploadOutcome {
+    pub counts: SyncCounts,
+    pub rejected: Vec<SyncConflict>,
+}
+
+/// One image as raw ciphertext, for the binary image endpoints.
+#[derive(Debug, Clone)]
+pub struct ImageBytes {
+    pub iv: String,
+    pub updated_at: i64,
+    pub data: Vec<u8>,
+}
+
+/// An image's metadata without its ciphertext, for conditional and range requests.
+#[derive(Debug, Clone)]
+pub struct ImageMeta {
+    pub iv: String,
+    pub updated_at: i64,
+    /// Ciphertext length; `None` when not known, e.g. for an upload still being
+    /// read or an image stored before the length was recorded.
+    pub size: Option<i64>,
+}
+
+/// An API key to register; the key itself is never stored.
+#[derive(Debug, Clone)]
+pub struct NewApiKey {
+    pub id: String,
+    pub account_id: String,
+    pub name: String,
+    pub key_hash: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_at: i64,
+}
+
+/// A login session (an `auth_sessions` row).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthSession {
+    pub id: String,
+    pub account_id: String,
+    /// Registry key the session was opened with; `None` for environment keys.
+    pub key_id: Option<String>,
+    pub scopes: Vec<ApiScope>,
+    /// SHA-256 of the current refresh token.
+    pub refresh_hash: String,
+    /// SHA-256 of the refresh token the current one replaced.
+    pub previous_refresh_hash: Option<String>,
+    pub created_at: i64,
+    pub refreshed_at: i64,
+    /// When the current refresh token lapses.
+    pub expires_at: i64,
+    pub revoked_at: Option<i64>,
+}
+
+/// A resumable upload to start: the blob's IV, total size and `updated_at`.
+#[derive(Debug, Clone)]
+pub struct NewImageUpload {
+    pub iv: String,
+    pub size: i64,
+    pub updated_at: i64,
+}
+
+/// What happened to one chunk of a resumable upload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkOutcome {
+    /// Stored; the status carries the new offset.
+    Appended(ImageUploadStatus),
+    /// The chunk did not start at the received offset and was dropped.
+    OffsetMismatch(ImageUploadStatus),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinalizeOutcome {
+    /// The image is stored and the upload session is gone.
+    Stored,
+    /// Not every byte has arrived yet; nothing was stored.
+    Incomplete(ImageUploadStatus),
+}
+
+/// Result of moving inline image ciphertext out to the blob store.
+#[derive(Debug, Default)]
+pub struct BlobMigration {
+    pub moved: usize,
+    /// `(account, hash)` of rows left inline because their data or hash
+    /// cannot be stored as a blob.
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Every operation is scoped to `account`.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Applies a `/sync/upload` batch atomically: diaries, todos, periods and
+    /// images first, deletions last. Stale items are rejected, not errors.
+    async fn apply_upload(
+        &self,
+        account: &str,
+        upload: &SyncUploadRequest,
+        history_limit: i64,
+    ) -> StorageResult<UploadOutcome>;
+
+    /// Up to `limit` diaries with uuid greater than `after`, in byte order of uuid.
+    async fn diary_page(
+        &self,
+        account: &str,
+        after: &str,
+        limit: i64,
+    ) -> StorageResult<Vec<DiarySyncItem>>;
+
+    /// Up to `limit` todos with uuid greater than `after`, in byte order of uuid.
+    async fn todo_page(
+        &self,
+        account: &str,
+        after: &str,
+        limit: i64,
+    ) -> StorageResult<Vec<TodoSyncItem>>;
+
+    /// Up to `limit` periods with uuid greater than `after`, in byte order of uuid.
+    async fn period_page(
+        &self,
+        account: &str,
+        after: &str,
+        limit: i64,
+    ) -> StorageResult<Vec<PeriodSyncItem>>;
+
+    async fn tombstones(&self, account: &str) -> StorageResult<Vec<TombstoneItem>>;
+
+    async fn sync_meta(&self, account: &str) -> StorageResult<SyncMetaResponse>;
+
+    /// Records and tombstones whose change sequence is greater than `since`.
+    async fn changes_since(&self, account: &str, since: i64) -> StorageResult<SyncChangesResponse>;
+
+    /// Archived revisions of a diary or todo, newest first.
+    async fn list_revisions(
+        &self,
+        account: &str,
+        kind: RecordKind,
+        uuid: &str,
+    ) -> StorageResult<Vec<RevisionItem>>;
+
+    /// Makes an archived revision current again and lifts any tombstone.
+    /// Returns the assigned `updated_at`, or `None` if the revision is unknown.
+    async fn restore_revision(
+        &self,
+        account: &str,
+        kind: RecordKind,
+        uuid: &str,
+        revision_id: i64,
+        history_limit: i64,
+    ) -> StorageResult<Option<i64>>;
+
+    /// Stamps a device's last successful sync, creating the device if needed.
+    async fn record_device_sync(
+        &self,
+        account: &str,
+        device_id: &str,
+        direction: SyncDirection,
+        now: i64,
+    ) -> StorageResult<()>;
+
+    async fn register_device(
+        &self,
+        account: &str,
+        device: &DeviceRegisterRequest,
+        now: i64,
+    ) -> StorageResult<DeviceItem>;
+
+    /// Devices, most recently seen first.
+    async fn list_devices(&self, account: &str) -> StorageResult<Vec<DeviceItem>>;
+
+    async fn fetch_image(
+        &self,
+        account: &str,
+        diary_uuid: &str,
+        file_name: &str,
+    ) -> StorageResult<Option<ImageFetchResponse>>;
+
+    async fn image_hashes(&self, account: &str) -> StorageResult<Vec<String>>;
+
+    /// Sizes of those of `hashes` the account holds; absent ones are left out.
+    async fn image_sizes(
+        &self,
+        account: &str,
+        hashes: &[String],
+    ) -> StorageResult<Vec<ImageHashSize>>;
+
+    async fn image_refs(
+        &self,
+        account: &str,
+        filter: &ImageRefsRequest,
+    ) -> StorageResult<Vec<DiaryImageRefItem>>;
+
+    /// Stores image blobs by hash (refs untouched); returns how many were written.
+    async fn put_images(
+        &self,
+        account: &str,
+        images: &[DiaryImageSyncItem],
+    ) -> StorageResult<usize>;
+
+    /// Upserts diary image refs; returns how many were written.
+    async fn put_image_refs(
+        &self,
+        account: &str,
+        refs: &[DiaryImageRefItem],
+    ) -> StorageResult<usize>;
+
+    /// Deletes the given refs; returns how many existed.
+    async fn delete_image_refs(&self, account: &str, keys: &[ImageRefKey]) -> StorageResult<usize>;
+
+    /// Makes `refs` the only refs of `diary_uuid`, deleting the others;
+    /// returns how many were deleted. Every ref must belong to the diary.
+    async fn replace_diary_image_refs(
+        &self,
+        account: &str,
+        diary_uuid: &str,
+        refs: &[DiaryImageRefItem],
+    ) -> StorageResult<usize>;
+
+    /// Stores one image's raw ciphertext under `hash` (refs untouched).
+    async fn put_image_bytes(
+        &self,
+        account: &str,
+        hash: &str,
+        image: &ImageBytes,
+    ) -> StorageResult<()>;
+
+    /// Like `put_image_bytes` for ciphertext read from `chunks` as it
+    /// arrives, which a blob store writes without buffering it. Returns the
+    /// ciphertext length; nothing is stored if a chunk is an error.
+    async fn put_image_stream(
+        &self,
+        account: &str,
+        hash: &str,
+        image: &ImageMeta,
+        chunks: &mut BlobChunks,
+    ) -> StorageResult<i64>;
+
+    /// An image's IV and raw ciphertext by hash.
+    async fn image_bytes(&self, account: &str, hash: &str) -> StorageResult<Option<ImageBytes>>;
+
+    /// Like `image_bytes` without reading the ciphertext.
+    async fn image_meta(&self, account: &str, hash: &str) -> StorageResult<Option<ImageMeta>>;
+
+    /// Bytes `start..=end` of the ciphertext, which must lie within its
+    /// `size`; only those are read from the blob store.
+    async fn image_range(
+        &self,
+        account: &str,
+        hash: &str,
+        start: u64,
+        end: u64,
+    ) -> StorageResult<Option<Vec<u8>>>;
+
+    /// Starts a resumable upload of `hash`. An unfinished upload with the same
+    /// IV, size and `updated_at` is kept so the client can resume it; any
+    /// other one is discarded. Either way the session counts as active at `now`.
+    async fn begin_image_upload(
+        &self,
+        account: &str,
+        hash: &str,
+        upload: &NewImageUpload,
+        now: i64,
+    ) -> StorageResult<ImageUploadStatus>;
+
+    async fn image_upload_status(
+        &self,
+        account: &str,
+        hash: &str,
+    ) -> StorageResult<Option<ImageUploadStatus>>;
+
+    /// Appends `data` at `offset`, marking the session active at `now`;
+    /// `None` when there is no such upload. A chunk running past the declared
+    /// size is `Invalid`.
+    async fn append_image_chunk(
+        &self,
+        account: &str,
+        hash: &str,
+        offset: i64,
+        data: &[u8],
+        now: i64,
+    ) -> StorageResult<Option<ChunkOutcome>>;
+
+    /// Stores a fully received upload like `put_image_bytes` and drops the
+    /// session; until then the image is invisible. `None` when there is no
+    /// such upload.
+    async fn finalize_image_upload(
+        &self,
+        account: &str,
+        hash: &str,
+    ) -> StorageResult<Option<FinalizeOutcome>>;
+
+    /// Images in any account that no ref points at and that were last
+    /// written before `cutoff` (server time, ms).
+    async fn orphaned_images(&self, cutoff: i64) -> StorageResult<Vec<OrphanImage>>;
+
+    /// Deletes an image found by `orphaned_images`, and its blob-store object,
+    /// if it is still unreferenced and older than `cutoff`. Returns whether it
+    /// was deleted.
+    async fn delete_orphaned_image(
+        &self,
+        account: &str,
+        hash: &str,
+        cutoff: i64,
+    ) -> StorageResult<bool>;
+
+    /// Moves every image still stored inline (all accounts) into the blob
+    /// store, clearing `blob_data` row by row. Safe to rerun after a failure.
+    async fn move_inline_blobs(&self) -> StorageResult<BlobMigration>;
+
+    /// Registers an API key by its hash.
+    async fn create_api_key(&self, key: &NewApiKey) -> StorageResult<ApiKeyInfo>;
+
+    /// The key registered under `key_hash`, revoked or not.
+    async fn api_key_by_hash(&self, key_hash: &str) -> StorageResult<Option<ApiKeyInfo>>;
+
+    /// Keys of one account, or of all accounts, oldest first.
+    async fn list_api_keys(&self, account: Option<&str>) -> StorageResult<Vec<ApiKeyInfo>>;
+
+    /// Marks a key revoked (keeping the first revocation time); `None` when
+    /// there is no such key.
+    async fn revoke_api_key(&self, id: &str, now: i64) -> StorageResult<Option<ApiKeyInfo>>;
+
+    /// Records that a key was just used.
+    async fn touch_api_key(&self, id: &str, now: i64) -> StorageResult<()>;
+
+    /// Whether any key was ever registered, revoked ones included.
+    async fn has_api_keys(&self) -> StorageResult<bool>;
+
+    async fn create_auth_session(&self, session: &AuthSession) -> StorageResult<()>;
+
+    async fn auth_session(&self, id: &str) -> StorageResult<Option<AuthSession>>;
+
+    /// The session whose current or previous refresh token hashes to `hash`.
+    async fn auth_session_by_refresh(&self, hash: &str) -> StorageResult<Option<AuthSession>>;
+
+    /// Swaps a live session's refresh token for a new one if `old_hash` is
+    /// still current, keeping `old_hash` as the previous one; returns false
+    /// when another refresh got there first.
+    async fn rotate_auth_session(
+        &self,
+        id: &str,
+        old_hash: &str,
+        new_hash: &str,
+        now: i64,
+        expires_at: i64,
+    ) -> StorageResult<bool>;
+
+    /// Revokes one session; false when there is no such live session.
+    async fn revoke_auth_session(&self, id: &str, now: i64) -> StorageResult<bool>;
+
+    /// Revokes the account's live sessions, or only those opened with
+    /// `key_id`; returns how many.
+    async fn revoke_auth_sessions(
+        &self,
+        account: &str,
+        key_id: Option<&str>,
+        now: i64,
+    ) -> StorageResult<usize>;
+
+    /// Appends a refused authentication attempt to the audit log.
+    async fn record_auth_failure(
+        &self,
+        ip: &str,
+        path: &str,
+        reason: &str,
+        now: i64,
+    ) -> StorageResult<()>;
+
+    /// Logged failures at or after `since`, newest first, at most `limit`.
+    async fn auth_failures(&self, since: i64, limit: i64) -> StorageResult<Vec<AuthFailure>>;
+
+    /// Drops logged failures from before `before`; returns how many.
+    async fn prune_auth_failures(&self, before: i64) -> StorageResult<u64>;
+
+    /// Drops unfinished uploads, and their chunks, last active before
+    /// `before`; returns how many.
+    async fn expire_image_uploads(&self, before: i64) -> StorageResult<u64>;
+}
+
+/// Per-image locks, held by writers from storing an image's blob to writing
+/// its row, and by garbage collection from re-checking an orphan to deleting
+/// its blob, so GC cannot delete a blob an upload has just written. They
+/// cover this process, like the other in-memory state. Take them before
+/// starting a write transaction, never inside one.
+#[derive(Default)]
+pub(crate) struct ImageLocks {
+    locks: std::sync::Mutex<HashMap<(String, String), ImageLock>>,
+}
+
+/// A lock held by nobody is dropped; the entry is pruned on the next call.
+type ImageLock = Weak<tokio::sync::Mutex<()>>;
+
+impl ImageLocks {
+    /// Locks every listed image of `account`, in a fixed order so that two
+    /// writers cannot deadlock; the locks are released with the guards.
+    pub(crate) async fn lock<'a>(
+        &self,
+        account: &str,
+        hashes: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<OwnedMutexGuard<()>> {
+        let mut hashes: Vec<&str> = hashes.into_iter().collect();
+        hashes.sort_unstable();
+        hashes.dedup();
+        let locks: Vec<_> = {
+            let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+            locks.retain(|_, lock| lock.strong_count() > 0);
+            hashes
+                .into_iter()
+                .map(|hash| {
+                    let key = (account.to_string(), hash.to_string());
+                    if let Some(lock) = locks.get(&key).and_then(Weak::upgrade) {
+                        return lock;
+                    }
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    locks.insert(key, Arc::downgrade(&lock));
+                    lock
+                })
+                .collect()
+        };
+        let mut guards = Vec::with_capacity(locks.len());
+        for lock in locks {
+            guards.push(lock.lock_owned().await);
+        }
+        guards
+    }
+}
+
+/// `api_keys.scopes` column value for a scope list.
+pub(crate) fn join_scopes(scopes: &[ApiScope]) -> String {
+    scopes
+        .iter()
+        .map(ApiScope::as_str)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Reads `api_keys.scopes`, ignoring names this build does not know.
+pub(crate) fn parse_scopes(value: &str) -> Vec<ApiScope> {
+    value.split(',').filter_map(ApiScope::parse).collect()
+}
+
+/// Checks a chunk against an upload's received offset and declared size;
+/// `Some` is the outcome to report without writing anything.
+pub(crate) fn check_chunk(
+    status: &ImageUploadStatus,
+    offset: i64,
+    len: usize,
+) -> StorageResult<Option<ChunkOutcome>> {
+    if offset != status.offset {
+        return Ok(Some(ChunkOutcome::OffsetMismatch(status.clone())));
+    }
+    if offset + len as i64 > status.size {
+        return Err(StorageError::Invalid(format!(
+            "chunk at {} of {} bytes runs past the upload size {}",
+            offset, len, status.size
+        )));
+    }
+    Ok(None)
+}
+
+/// Rows `move_inline_blobs` reads per query.
+pub(crate) const BLOB_MIGRATION_BATCH: i64 = 100;
+
+pub(crate) fn no_blob_store() -> StorageError {
+    StorageError::Invalid("no blob store configured; set BLOB_STORE".to_string())
+}
+
+/// Outcome of a conditional write: `None` when applied (or an idempotent
+/// replay of the stored version), otherwise the conflict to report back.
+pub(crate) type WriteResult = Option<SyncConflict>;
+
+/// A write may replace the stored version when it is strictly newer, or when
+/// the client based its edit on exactly the version the server holds.
+pub(crate) fn supersedes(incoming: i64, base: Option<i64>, current: i64) -> bool {
+    incoming > current || base == Some(current)
+}
+
+/// `updated_at` stored for a write that supersedes `current`. A base match from
+/// a device whose clock is behind still moves the version forward, so devices
+/// comparing timestamps see the edit as newer than what they hold.
+pub(crate) fn applied_updated_at(incoming: i64, current: i64) -> i64 {
+    incoming.max(current + 1)
+}
+
+pub(crate) fn conflict(
+    kind: RecordKind,
+    uuid: &str,
+    server_updated_at: i64,
+    deleted: bool,
+) -> SyncConflict {
+    SyncConflict {
+        uuid: uuid.to_string(),
+        kind,
+        server_updated_at,
+        deleted,
+    }
+}
+
+/// Resolves why a conditional upsert did not apply: a replay of the stored
+/// version (same `updated_at` and payload) is not a conflict; anything else,
+/// including a different payload stamped with the same `updated_at`, reports
+/// the server's current version.
+pub(crate) fn stale_write(
+    kind: RecordKind,
+    uuid: &str,
+    incoming: i64,
+    payload: &EncryptedBlob,
+    current: Option<(i64, EncryptedBlob)>,
+) -> WriteResult {
+    match current {
+        Some((current, stored)) if current != incoming || stored != *payload => {
+            Some(conflict(kind, uuid, current, false))
+        }
+        _ => None,
+    }
+}
+
+/// The uuid given to a period that predates period uuids (backfilled rows and
+/// items from older clients).
+pub(crate) fn legacy_period_uuid(start_date: &str) -> String {
+    format!("period-{}", start_date)
+}
+
+/// `updated_at` for a restored revision: newer than the live version and any
+/// tombstone, so every device picks it up on its next download.
+pub(crate) fn restored_updated_at(current: Option<i64>, deleted_at: Option<i64>, now: i64) -> i64 {
+    [current, deleted_at]
+        .into_iter()
+        .flatten()
+        .map(|v| v + 1)
+        .fold(now, i64::max)
+}
@@ -0,0 +1,120 @@
+//! PostgreSQL storage; the schema is built by the migrations in `migrations/postgres`.
+
+use async_trait::async_trait;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::PgQueryResult;
+use sqlx::PgPool;
+use std::sync::Arc;
+
+use super::blob::BlobStore;
+use super::sql::{self, Dialect, RowsAffected, SqlBackend};
+use super::{ImageLocks, MigrationMode, StorageError, StorageResult};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// Advisory lock key taken by every transaction that bumps `sync_change_seq`.
+const SYNC_WRITE_LOCK_ID: i64 = 0x7379_6e63;
+
+#[derive(Clone)]
+pub struct PgStorage {
+    pool: PgPool,
+    blobs: Option<Arc<dyn BlobStore>>,
+    image_locks: Arc<ImageLocks>,
+}
+
+impl PgStorage {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            blobs: None,
+            image_locks: Arc::default(),
+        }
+    }
+
+    /// Keeps image ciphertext in `blobs` instead of `diary_images.blob_data`.
+    /// Rows written inline before the switch stay readable.
+    pub fn with_blob_store(mut self, blobs: Arc<dyn BlobStore>) -> Self {
+        self.blobs = Some(blobs);
+        self
+    }
+
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    /// Brings the schema up to date (or only verifies it) and returns its version.
+    pub async fn migrate(&self, mode: MigrationMode) -> StorageResult<i64> {
+        sql::migrate(self, &MIGRATOR, mode).await
+    }
+}
+
+impl Dialect for sqlx::Postgres {
+    const FOR_UPDATE: &'static str = " FOR UPDATE";
+    const UUID_COLLATE: &'static str = " COLLATE \"C\"";
+    const START_DATE: &'static str = "start_date::text as start_date";
+    const END_DATE: &'static str = "end_date::text as end_date";
+    const BUMP_CHANGE_SEQ: &'static str = ", change_seq = nextval('sync_change_seq')";
+    const GREATEST: &'static str = "GREATEST";
+}
+
+impl RowsAffected for PgQueryResult {
+    fn rows_affected(&self) -> u64 {
+        PgQueryResult::rows_affected(self)
+    }
+}
+
+#[async_trait]
+impl SqlBackend for PgStorage {
+    type Db = sqlx::Postgres;
+    type Guard = ();
+
+    fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+
+    fn blobs(&self) -> Option<&dyn BlobStore> {
+        self.blobs.as_deref()
+    }
+
+    fn image_locks(&self) -> &ImageLocks {
+        &self.image_locks
+    }
+
+    async fn begin_write(&self) -> StorageResult<((), sqlx::Transaction<'static, sqlx::Postgres>)> {
+        let tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| StorageError::from(e).context("db transaction start failed"))?;
+        Ok(((), tx))
+    }
+
+    /// Serializes writers that assign change sequences. Sequence values are
+    /// handed out before commit, so without this a reader could advance its
+    /// cursor past a row from a slower transaction that commits later with a
+    /// smaller sequence.
+    async fn begin_sync_write(
+        &self,
+    ) -> StorageResult<((), sqlx::Transaction<'static, sqlx::Postgres>)> {
+        let ((), mut tx) = self.begin_write().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(SYNC_WRITE_LOCK_ID)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| StorageError::from(e).context("sync write lock failed"))?;
+        Ok(((), tx))
+    }
+
+    async fn begin_read(&self) -> StorageResult<sqlx::Transaction<'static, sqlx::Postgres>> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| StorageError::from(e).context("db transaction start failed"))?;
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| StorageError::from(e).context("db transaction start failed"))?;
+        Ok(tx)
+    }
+}
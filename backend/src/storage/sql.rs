@@ -0,0 +1,2348 @@
+//! Storage logic shared by the Postgres and SQLite backends. Both speak the
+//! same SQL apart from a few fragments, which each database supplies through
+//! [`Dialect`]; the `Storage` impl and its helpers are written once over it.
+//!
+//! A backend module implements [`SqlBackend`] for its storage struct: the pool,
+//! the blob store, and two transaction openers returning a guard held until
+//! commit alongside the transaction: `begin_write` for ordinary writes and
+//! `begin_sync_write` for writes that assign change sequences, which must
+//! commit in sequence order.
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+use sqlx::database::HasArguments;
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::{
+    ColumnIndex, Database, Decode, Encode, Executor, IntoArguments, Pool, Row, Transaction, Type,
+};
+use std::marker::PhantomData;
+
+use super::blob::{
+    base64_len, load_image_blob, load_image_bytes, load_image_range, move_inline_blob,
+    store_image_blob, store_image_bytes, store_image_chunks, BlobChunks, BlobStore,
+};
+use super::{
+    check_chunk, check_schema_version, conflict, join_scopes, latest_schema_version,
+    legacy_period_uuid, no_blob_store, parse_scopes, restored_updated_at, stale_write, supersedes,
+    AuthSession, BlobMigration, ChunkOutcome, FinalizeOutcome, ImageBytes, ImageLocks, ImageMeta,
+    MigrationMode, NewApiKey, NewImageUpload, Storage, StorageError, StorageResult, SyncDirection,
+    UploadOutcome, WriteResult, BLOB_MIGRATION_BATCH,
+};
+use crate::models::{
+    ApiKeyInfo, AuthFailure, DeviceItem, DeviceRegisterRequest, DiaryImageRefItem,
+    DiaryImageSyncItem, DiarySyncItem, EncryptedBlob, ImageFetchResponse, ImageHashSize,
+    ImageRefKey, ImageRefsRequest, ImageUploadStatus, OrphanImage, PeriodMeta, PeriodSyncItem,
+    RecordKind, RevisionItem, SyncChangesResponse, SyncMeta, SyncMetaResponse, SyncUploadRequest,
+    TodoSyncItem, TombstoneItem,
+};
+
+/// The SQL that differs between the databases, spliced into the shared queries.
+pub(super) trait Dialect: Database<QueryResult: RowsAffected> {
+    /// Row lock on reads that precede a write, if any.
+    const FOR_UPDATE: &'static str;
+    /// Byte-order collation for uuid paging.
+    const UUID_COLLATE: &'static str;
+    /// Period `start_date` column selected as text.
+    const START_DATE: &'static str;
+    /// Period `end_date` column selected as text.
+    const END_DATE: &'static str;
+    /// Extra `SET` items that assign a fresh change sequence on upsert, when
+    /// the database does not do it in a trigger.
+    const BUMP_CHANGE_SEQ: &'static str;
+    /// The two-argument maximum function.
+    const GREATEST: &'static str;
+}
+
+/// The row count each database's query result reports under its own type.
+pub(super) trait RowsAffected {
+    fn rows_affected(&self) -> u64;
+}
+
+/// A storage struct backed by one of the SQL databases.
+#[async_trait]
+pub(super) trait SqlBackend: Send + Sync + 'static {
+    type Db: Dialect;
+    /// Held alongside a write transaction until it commits.
+    type Guard: Send;
+
+    fn pool(&self) -> &Pool<Self::Db>;
+    fn blobs(&self) -> Option<&dyn BlobStore>;
+    fn image_locks(&self) -> &ImageLocks;
+    async fn begin_write(&self) -> StorageResult<(Self::Guard, Transaction<'static, Self::Db>)>;
+    async fn begin_sync_write(
+        &self,
+    ) -> StorageResult<(Self::Guard, Transaction<'static, Self::Db>)>;
+    /// A read-only transaction whose queries all see one snapshot.
+    async fn begin_read(&self) -> StorageResult<Transaction<'static, Self::Db>>;
+}
+
+/// Brings the schema of `backend` up to date (or only verifies it) and returns its version.
+pub(super) async fn migrate<B: SqlBackend>(
+    backend: &B,
+    migrator: &Migrator,
+    mode: MigrationMode,
+) -> StorageResult<i64>
+where
+    <B::Db as Database>::Connection: Migrate,
+{
+    let mut conn = backend.pool().acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let applied = conn.list_applied_migrations().await?;
+    drop(conn);
+    let current = check_schema_version(migrator, &applied, mode)?;
+    if mode == MigrationMode::Check {
+        return Ok(current);
+    }
+    migrator.run(backend.pool()).await?;
+    Ok(latest_schema_version(migrator))
+}
+
+/// Re-checks that an image is still unreferenced, and has been for long
+/// enough, as it is deleted, so a ref written since `orphaned_images` keeps it.
+const DELETE_ORPHANED_IMAGE: &str = r#"
+    DELETE FROM diary_images
+    WHERE account_id = $1 AND hash = $2 AND unreferenced_at < $3
+      AND NOT EXISTS (
+          SELECT 1 FROM diary_image_refs r
+          WHERE r.account_id = diary_images.account_id AND r.hash = diary_images.hash
+      )
+    RETURNING blob_data IS NULL AS external
+"#;
+
+/// `count` numbered bind parameters starting at `$first`, for an `IN (...)` list.
+fn placeholders(first: usize, count: usize) -> String {
+    (first..first + count)
+        .map(|n| format!("${n}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+const SELECT_API_KEYS: &str =
+    "SELECT id, account_id, name, scopes, created_at, last_used_at, revoked_at FROM api_keys";
+
+#[async_trait]
+impl<B, D> Storage for B
+where
+    B: SqlBackend<Db = D>,
+    D: Dialect,
+    for<'c> &'c mut D::Connection: Executor<'c, Database = D>,
+    for<'q> <D as HasArguments<'q>>::Arguments: IntoArguments<'q, D>,
+    for<'q> &'q str: Encode<'q, D> + Type<D>,
+    for<'q> &'q [u8]: Encode<'q, D> + Type<D>,
+    i64: for<'q> Encode<'q, D> + for<'r> Decode<'r, D> + Type<D>,
+    Option<i64>: for<'q> Encode<'q, D>,
+    bool: for<'q> Encode<'q, D> + for<'r> Decode<'r, D> + Type<D>,
+    String: for<'q> Encode<'q, D> + for<'r> Decode<'r, D> + Type<D>,
+    Option<String>: for<'q> Encode<'q, D>,
+    for<'q> Option<&'q str>: Encode<'q, D>,
+    Vec<u8>: for<'r> Decode<'r, D> + Type<D>,
+    NaiveDate: for<'q> Encode<'q, D> + Type<D>,
+    Option<NaiveDate>: for<'q> Encode<'q, D>,
+    usize: ColumnIndex<D::Row>,
+    for<'a> &'a str: ColumnIndex<D::Row>,
+{
+    async fn apply_upload(
+        &self,
+        account: &str,
+        upload: &SyncUploadRequest,
+        history_limit: i64,
+    ) -> StorageResult<UploadOutcome> {
+        let _images = self
+            .image_locks()
+            .lock(account, upload.images.iter().map(|i| i.hash.as_str()))
+            .await;
+        let (_guard, mut tx) = self.begin_sync_write().await?;
+
+        let mut outcome = UploadOutcome::default();
+        for item in &upload.diaries {
+            match Sql::upsert_diary(&mut tx, account, item, history_limit)
+                .await
+                .map_err(|e| e.context("diary upsert failed"))?
+            {
+                None => outcome.counts.diaries += 1,
+                Some(c) => outcome.rejected.push(c),
+            }
+        }
+        for item in &upload.todos {
+            match Sql::upsert_todo(&mut tx, account, item, history_limit)
+                .await
+                .map_err(|e| e.context("todo upsert failed"))?
+            {
+                None => outcome.counts.todos += 1,
+                Some(c) => outcome.rejected.push(c),
+            }
+        }
+        for item in &upload.periods {
+            match Sql::upsert_period(&mut tx, account, item)
+                .await
+                .map_err(|e| e.context("period upsert failed"))?
+            {
+                None => outcome.counts.periods += 1,
+                Some(c) => outcome.rejected.push(c),
+            }
+        }
+        for item in &upload.images {
+            Sql::upsert_image(&mut tx, self.blobs(), account, item)
+                .await
+                .map_err(|e| e.context("image upsert failed"))?;
+            outcome.counts.images += 1;
+        }
+        // Deletions run last so a record uploaded and deleted in the same batch ends up deleted.
+        for item in &upload.deletions {
+            match Sql::apply_tombstone(&mut tx, account, item, history_limit)
+                .await
+                .map_err(|e| e.context("deletion failed"))?
+            {
+                None => outcome.counts.deletions += 1,
+                Some(c) => outcome.rejected.push(c),
+            }
+        }
+
+        tx.commit()
+            .await
+            .map_err(|e| StorageError::from(e).context("commit failed"))?;
+        Ok(outcome)
+    }
+
+    async fn diary_page(
+        &self,
+        account: &str,
+        after: &str,
+        limit: i64,
+    ) -> StorageResult<Vec<DiarySyncItem>> {
+        let rows = sqlx::query(&format!(
+            r#"
+            SELECT uuid, author, timestamp, updated_at, payload_iv, payload_data
+            FROM diary_sync
+            WHERE account_id = $1 AND uuid > $2{uuid_collate}
+            ORDER BY uuid{uuid_collate}
+            LIMIT $3
+            "#,
+            uuid_collate = D::UUID_COLLATE
+        ))
+        .bind(account)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(rows.iter().map(Sql::diary_from_row).collect())
+    }
+
+    async fn todo_page(
+        &self,
+        account: &str,
+        after: &str,
+        limit: i64,
+    ) -> StorageResult<Vec<TodoSyncItem>> {
+        let rows = sqlx::query(&format!(
+            r#"
+            SELECT uuid, author, is_completed, created_at, completed_at, updated_at, payload_iv, payload_data
+            FROM todo_sync
+            WHERE account_id = $1 AND uuid > $2{uuid_collate}
+            ORDER BY uuid{uuid_collate}
+            LIMIT $3
+            "#,
+            uuid_collate = D::UUID_COLLATE
+        ))
+        .bind(account)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(rows.iter().map(Sql::todo_from_row).collect())
+    }
+
+    async fn period_page(
+        &self,
+        account: &str,
+        after: &str,
+        limit: i64,
+    ) -> StorageResult<Vec<PeriodSyncItem>> {
+        let rows = sqlx::query(&format!(
+            r#"
+            SELECT uuid, {start_date}, {end_date}, updated_at, payload_iv, payload_data
+            FROM period_sync
+            WHERE account_id = $1 AND uuid > $2{uuid_collate}
+            ORDER BY uuid{uuid_collate}
+            LIMIT $3
+            "#,
+            start_date = D::START_DATE,
+            end_date = D::END_DATE,
+            uuid_collate = D::UUID_COLLATE
+        ))
+        .bind(account)
+        .bind(after)
+        .bind(limit)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(rows.iter().map(Sql::period_from_row).collect())
+    }
+
+    async fn tombstones(&self, account: &str) -> StorageResult<Vec<TombstoneItem>> {
+        let rows = sqlx::query(&format!(
+            "SELECT kind, uuid, deleted_at, {} FROM sync_tombstones WHERE account_id = $1",
+            D::START_DATE
+        ))
+        .bind(account)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(rows.iter().filter_map(Sql::tombstone_from_row).collect())
+    }
+
+    async fn sync_meta(&self, account: &str) -> StorageResult<SyncMetaResponse> {
+        let diary_rows =
+            sqlx::query("SELECT uuid, updated_at FROM diary_sync WHERE account_id = $1")
+                .bind(account)
+                .fetch_all(self.pool())
+                .await
+                .map_err(|e| StorageError::from(e).context("diary query failed"))?;
+        let todo_rows = sqlx::query("SELECT uuid, updated_at FROM todo_sync WHERE account_id = $1")
+            .bind(account)
+            .fetch_all(self.pool())
+            .await
+            .map_err(|e| StorageError::from(e).context("todo query failed"))?;
+        let period_rows = sqlx::query(&format!(
+            "SELECT uuid, {start_date}, updated_at FROM period_sync WHERE account_id = $1",
+            start_date = D::START_DATE
+        ))
+        .bind(account)
+        .fetch_all(self.pool())
+        .await
+        .map_err(|e| StorageError::from(e).context("period query failed"))?;
+        let deletions = self
+            .tombstones(account)
+            .await
+            .map_err(|e| e.context("tombstone query failed"))?;
+
+        let sync_meta = |row: D::Row| SyncMeta {
+            uuid: row.get("uuid"),
+            updated_at: row.get("updated_at"),
+        };
+        Ok(SyncMetaResponse {
+            diaries: diary_rows.into_iter().map(sync_meta).collect(),
+            todos: todo_rows.into_iter().map(sync_meta).collect(),
+            periods: period_rows
+                .into_iter()
+                .map(|row| PeriodMeta {
+                    uuid: row.get("uuid"),
+                    start_date: row.get("start_date"),
+                    updated_at: row.get("updated_at"),
+                })
+                .collect(),
+            deletions,
+        })
+    }
+
+    async fn changes_since(&self, account: &str, since: i64) -> StorageResult<SyncChangesResponse> {
+        // One snapshot for all four reads: a write committing between them
+        // could otherwise put a later sequence in a later table and move the
+        // cursor past rows of an earlier one.
+        let mut tx = self.begin_read().await?;
+        let mut cursor = since;
+
+        let diary_rows = sqlx::query(
+            r#"
+            SELECT uuid, author, timestamp, updated_at, payload_iv, payload_data, change_seq
+            FROM diary_sync
+            WHERE account_id = $1 AND change_seq > $2
+            ORDER BY change_seq
+            "#,
+        )
+        .bind(account)
+        .bind(since)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| StorageError::from(e).context("diary query failed"))?;
+        cursor = cursor.max(Sql::max_change_seq(&diary_rows));
+
+        let todo_rows = sqlx::query(
+            r#"
+            SELECT uuid, author, is_completed, created_at, completed_at, updated_at, payload_iv, payload_data, change_seq
+            FROM todo_sync
+            WHERE account_id = $1 AND change_seq > $2
+            ORDER BY change_seq
+            "#,
+        )
+        .bind(account)
+        .bind(since)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| StorageError::from(e).context("todo query failed"))?;
+        cursor = cursor.max(Sql::max_change_seq(&todo_rows));
+
+        let period_rows = sqlx::query(&format!(
+            r#"
+            SELECT uuid, {start_date}, {end_date}, updated_at, payload_iv, payload_data, change_seq
+            FROM period_sync
+            WHERE account_id = $1 AND change_seq > $2
+            ORDER BY change_seq
+            "#,
+            start_date = D::START_DATE,
+            end_date = D::END_DATE
+        ))
+        .bind(account)
+        .bind(since)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| StorageError::from(e).context("period query failed"))?;
+        cursor = cursor.max(Sql::max_change_seq(&period_rows));
+
+        let tombstone_rows = sqlx::query(&format!(
+            r#"
+            SELECT kind, uuid, deleted_at, change_seq, {start_date}
+            FROM sync_tombstones
+            WHERE account_id = $1 AND change_seq > $2
+            ORDER BY change_seq
+            "#,
+            start_date = D::START_DATE
+        ))
+        .bind(account)
+        .bind(since)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| StorageError::from(e).context("tombstone query failed"))?;
+        cursor = cursor.max(Sql::max_change_seq(&tombstone_rows));
+        tx.commit().await?;
+
+        Ok(SyncChangesResponse {
+            diaries: diary_rows.iter().map(Sql::diary_from_row).collect(),
+            todos: todo_rows.iter().map(Sql::todo_from_row).collect(),
+            periods: period_rows.iter().map(Sql::period_from_row).collect(),
+            deletions: tombstone_rows
+                .iter()
+                .filter_map(Sql::tombstone_from_row)
+                .collect(),
+            cursor,
+        })
+    }
+
+    async fn list_revisions(
+        &self,
+        account: &str,
+        kind: RecordKind,
+        uuid: &str,
+    ) -> StorageResult<Vec<RevisionItem>> {
+        let sql = match kind {
+            RecordKind::Diary => {
+                r#"
+                SELECT revision_id, updated_at, archived_at, payload_iv, payload_data
+                FROM diary_history
+                WHERE account_id = $1 AND uuid = $2
+                ORDER BY revision_id DESC
+                "#
+            }
+            RecordKind::Todo => {
+                r#"
+                SELECT revision_id, updated_at, archived_at, payload_iv, payload_data
+                FROM todo_history
+                WHERE account_id = $1 AND uuid = $2
+                ORDER BY revision_id DESC
+                "#
+            }
+            RecordKind::Period => {
+                return Err(StorageError::Invalid(
+                    "periods have no revision history".to_string(),
+                ));
+            }
+        };
+        let rows = sqlx::query(sql)
+            .bind(account)
+            .bind(uuid)
+            .fetch_all(self.pool())
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| RevisionItem {
+                revision_id: row.get("revision_id"),
+                updated_at: row.get("updated_at"),
+                archived_at: row.get("archived_at"),
+                payload: EncryptedBlob {
+                    iv: row.get("payload_iv"),
+                    data: row.get("payload_data"),
+                },
+            })
+            .collect())
+    }
+
+    async fn restore_revision(
+        &self,
+        account: &str,
+        kind: RecordKind,
+        uuid: &str,
+        revision_id: i64,
+        history_limit: i64,
+    ) -> StorageResult<Option<i64>> {
+        let (current_sql, restore_sql) = match kind {
+            RecordKind::Diary => (
+                &format!(
+                    "SELECT updated_at FROM diary_sync WHERE account_id = $1 AND uuid = $2{}",
+                    D::FOR_UPDATE
+                ),
+                &format!(
+                    r#"
+                    INSERT INTO diary_sync (
+                        account_id, uuid, author, timestamp, updated_at, payload_iv, payload_data
+                    )
+                    SELECT account_id, uuid, author, timestamp, $4, payload_iv, payload_data
+                    FROM diary_history
+                    WHERE account_id = $1 AND uuid = $2 AND revision_id = $3
+                    ON CONFLICT (account_id, uuid) DO UPDATE SET
+                        author = EXCLUDED.author,
+                        timestamp = EXCLUDED.timestamp,
+                        updated_at = EXCLUDED.updated_at,
+                        payload_iv = EXCLUDED.payload_iv,
+                        payload_data = EXCLUDED.payload_data{bump_change_seq}
+                    "#,
+                    bump_change_seq = D::BUMP_CHANGE_SEQ
+                ),
+            ),
+            RecordKind::Todo => (
+                &format!(
+                    "SELECT updated_at FROM todo_sync WHERE account_id = $1 AND uuid = $2{}",
+                    D::FOR_UPDATE
+                ),
+                &format!(
+                    r#"
+                    INSERT INTO todo_sync (
+                        account_id, uuid, author, is_completed, created_at, completed_at, updated_at,
+                        payload_iv, payload_data
+                    )
+                    SELECT account_id, uuid, author, is_completed, created_at, completed_at, $4,
+                        payload_iv, payload_data
+                    FROM todo_history
+                    WHERE account_id = $1 AND uuid = $2 AND revision_id = $3
+                    ON CONFLICT (account_id, uuid) DO UPDATE SET
+                        author = EXCLUDED.author,
+                        is_completed = EXCLUDED.is_completed,
+                        created_at = EXCLUDED.created_at,
+                        completed_at = EXCLUDED.completed_at,
+                        updated_at = EXCLUDED.updated_at,
+                        payload_iv = EXCLUDED.payload_iv,
+                        payload_data = EXCLUDED.payload_data{bump_change_seq}
+                    "#,
+                    bump_change_seq = D::BUMP_CHANGE_SEQ
+                ),
+            ),
+            RecordKind::Period => {
+                return Err(StorageError::Invalid(
+                    "periods have no revision history".to_string(),
+                ));
+            }
+        };
+
+        let (_guard, mut tx) = self.begin_sync_write().await?;
+
+        let current: Option<i64> = sqlx::query_scalar(current_sql)
+            .bind(account)
+            .bind(uuid)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| StorageError::from(e).context("current version query failed"))?;
+        let deleted_at: Option<i64> = sqlx::query_scalar(
+            "SELECT deleted_at FROM sync_tombstones WHERE account_id = $1 AND kind = $2 AND uuid = $3",
+        )
+        .bind(account)
+        .bind(kind.as_str())
+        .bind(uuid)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| StorageError::from(e).context("tombstone query failed"))?;
+        let updated_at = restored_updated_at(current, deleted_at, Utc::now().timestamp_millis());
+
+        Sql::archive_revision(&mut tx, account, kind, uuid, None, history_limit)
+            .await
+            .map_err(|e| StorageError::from(e).context("archive failed"))?;
+        let restored = sqlx::query(restore_sql)
+            .bind(account)
+            .bind(uuid)
+            .bind(revision_id)
+            .bind(updated_at)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| StorageError::from(e).context("restore failed"))?
+            .rows_affected();
+        if restored == 0 {
+            // Dropping the transaction rolls back the archive above.
+            return Ok(None);
+        }
+        sqlx::query(
+            "DELETE FROM sync_tombstones WHERE account_id = $1 AND kind = $2 AND uuid = $3",
+        )
+        .bind(account)
+        .bind(kind.as_str())
+        .bind(uuid)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| StorageError::from(e).context("tombstone removal failed"))?;
+        tx.commit()
+            .await
+            .map_err(|e| StorageError::from(e).context("commit failed"))?;
+        Ok(Some(updated_at))
+    }
+
+    async fn record_device_sync(
+        &self,
+        account: &str,
+        device_id: &str,
+        direction: SyncDirection,
+        now: i64,
+    ) -> StorageResult<()> {
+        let sql = match direction {
+            SyncDirection::Upload => {
+                r#"
+                INSERT INTO devices (account_id, device_id, first_seen, last_seen, last_upload_at)
+                VALUES ($1, $2, $3, $3, $3)
+                ON CONFLICT (account_id, device_id) DO UPDATE SET
+                    last_seen = EXCLUDED.last_seen,
+                    last_upload_at = EXCLUDED.last_upload_at
+                "#
+            }
+            SyncDirection::Download => {
+                r#"
+                INSERT INTO devices (account_id, device_id, first_seen, last_seen, last_download_at)
+                VALUES ($1, $2, $3, $3, $3)
+                ON CONFLICT (account_id, device_id) DO UPDATE SET
+                    last_seen = EXCLUDED.last_seen,
+                    last_download_at = EXCLUDED.last_download_at
+                "#
+            }
+        };
+        sqlx::query(sql)
+            .bind(account)
+            .bind(device_id)
+            .bind(now)
+            .execute(self.pool())
+            .await?;
+        Ok(())
+    }
+
+    async fn register_device(
+        &self,
+        account: &str,
+        device: &DeviceRegisterRequest,
+        now: i64,
+    ) -> StorageResult<DeviceItem> {
+        let row = sqlx::query(
+            r#"
+            INSERT INTO devices (account_id, device_id, name, app_version, first_seen, last_seen)
+            VALUES ($1, $2, $3, $4, $5, $5)
+            ON CONFLICT (account_id, device_id) DO UPDATE SET
+                name = EXCLUDED.name,
+                app_version = EXCLUDED.app_version,
+                last_seen = EXCLUDED.last_seen
+            RETURNING device_id, name, app_version, first_seen, last_seen, last_upload_at, last_download_at
+            "#,
+        )
+        .bind(account)
+        .bind(device.device_id.trim())
+        .bind(&device.name)
+        .bind(&device.app_version)
+        .bind(now)
+        .fetch_one(self.pool())
+        .await?;
+        Ok(Sql::device_from_row(&row))
+    }
+
+    async fn list_devices(&self, account: &str) -> StorageResult<Vec<DeviceItem>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT device_id, name, app_version, first_seen, last_seen, last_upload_at, last_download_at
+            FROM devices
+            WHERE account_id = $1
+            ORDER BY last_seen DESC
+            "#,
+        )
+        .bind(account)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(rows.iter().map(Sql::device_from_row).collect())
+    }
+
+    async fn fetch_image(
+        &self,
+        account: &str,
+        diary_uuid: &str,
+        file_name: &str,
+    ) -> StorageResult<Option<ImageFetchResponse>> {
+        let row = sqlx::query(
+            r#"
+            SELECT r.file_name, r.diary_uuid, r.updated_at, r.hash, i.blob_iv, i.blob_data
+            FROM diary_image_refs r
+            JOIN diary_images i ON i.account_id = r.account_id AND i.hash = r.hash
+            WHERE r.account_id = $1 AND r.diary_uuid = $2 AND r.file_name = $3
+            "#,
+        )
+        .bind(account)
+        .bind(diary_uuid)
+        .bind(file_name)
+        .fetch_optional(self.pool())
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let hash: String = row.get("hash");
+        let data = load_image_blob(self.blobs(), account, &hash, row.get("blob_data")).await?;
+        Ok(Some(ImageFetchResponse {
+            file_name: row.get("file_name"),
+            diary_uuid: row.get("diary_uuid"),
+            hash,
+            updated_at: row.get("updated_at"),
+            blob: EncryptedBlob {
+                iv: row.get("blob_iv"),
+                data,
+            },
+        }))
+    }
+
+    async fn image_hashes(&self, account: &str) -> StorageResult<Vec<String>> {
+        let rows = sqlx::query("SELECT hash FROM diary_images WHERE account_id = $1")
+            .bind(account)
+            .fetch_all(self.pool())
+            .await?;
+        Ok(rows.into_iter().map(|row| row.get("hash")).collect())
+    }
+
+    async fn image_sizes(
+        &self,
+        account: &str,
+        hashes: &[String],
+    ) -> StorageResult<Vec<ImageHashSize>> {
+        if hashes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sql = format!(
+            "SELECT hash, size FROM diary_images WHERE account_id = $1 AND hash IN ({})",
+            placeholders(2, hashes.len())
+        );
+        let mut query = sqlx::query(&sql).bind(account);
+        for hash in hashes {
+            query = query.bind(hash);
+        }
+        let rows = query.fetch_all(self.pool()).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| ImageHashSize {
+                hash: row.get("hash"),
+                size: row.get("size"),
+            })
+            .collect())
+    }
+
+    async fn image_refs(
+        &self,
+        account: &str,
+        filter: &ImageRefsRequest,
+    ) -> StorageResult<Vec<DiaryImageRefItem>> {
+        let mut sql = String::from(
+            "SELECT diary_uuid, file_name, hash, updated_at FROM diary_image_refs WHERE account_id = $1",
+        );
+        let mut next = 2;
+        if let Some(diary_uuids) = &filter.diary_uuids {
+            if diary_uuids.is_empty() {
+                return Ok(Vec::new());
+            }
+            sql += &format!(
+                " AND diary_uuid IN ({})",
+                placeholders(next, diary_uuids.len())
+            );
+            next += diary_uuids.len();
+        }
+        if filter.updated_after.is_some() {
+            sql += &format!(" AND updated_at > ${next}");
+        }
+        let mut query = sqlx::query(&sql).bind(account);
+        for diary_uuid in filter.diary_uuids.iter().flatten() {
+            query = query.bind(diary_uuid);
+        }
+        if let Some(updated_after) = filter.updated_after {
+            query = query.bind(updated_after);
+        }
+        let rows = query.fetch_all(self.pool()).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| DiaryImageRefItem {
+                diary_uuid: row.get("diary_uuid"),
+                file_name: row.get("file_name"),
+                hash: row.get("hash"),
+                updated_at: row.get("updated_at"),
+            })
+            .collect())
+    }
+
+    async fn put_images(
+        &self,
+        account: &str,
+        images: &[DiaryImageSyncItem],
+    ) -> StorageResult<usize> {
+        let _images = self
+            .image_locks()
+            .lock(account, images.iter().map(|i| i.hash.as_str()))
+            .await;
+        let (_guard, mut tx) = self.begin_write().await?;
+        for item in images {
+            Sql::put_image_row(&mut tx, self.blobs(), account, item).await?;
+        }
+        tx.commit().await?;
+        Ok(images.len())
+    }
+
+    async fn put_image_bytes(
+        &self,
+        account: &str,
+        hash: &str,
+        image: &ImageBytes,
+    ) -> StorageResult<()> {
+        let _image = self.image_locks().lock(account, [hash]).await;
+        let blob_data = store_image_bytes(self.blobs(), account, hash, &image.data).await?;
+        let (_guard, mut tx) = self.begin_write().await?;
+        Sql::write_image_row(
+            &mut tx,
+            account,
+            hash,
+            &image.iv,
+            blob_data.as_deref(),
+            Some(image.data.len() as i64),
+            image.updated_at,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn put_image_stream(
+        &self,
+        account: &str,
+        hash: &str,
+        image: &ImageMeta,
+        chunks: &mut BlobChunks,
+    ) -> StorageResult<i64> {
+        let _image = self.image_locks().lock(account, [hash]).await;
+        let (blob_data, size) = store_image_chunks(self.blobs(), account, hash, chunks).await?;
+        let (_guard, mut tx) = self.begin_write().await?;
+        Sql::write_image_row(
+            &mut tx,
+            account,
+            hash,
+            &image.iv,
+            blob_data.as_deref(),
+            Some(size),
+            image.updated_at,
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(size)
+    }
+
+    async fn image_bytes(&self, account: &str, hash: &str) -> StorageResult<Option<ImageBytes>> {
+        let row = sqlx::query(
+            "SELECT blob_iv, blob_data, updated_at FROM diary_images WHERE account_id = $1 AND hash = $2",
+        )
+        .bind(account)
+        .bind(hash)
+        .fetch_optional(self.pool())
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let data = load_image_bytes(self.blobs(), account, hash, row.get("blob_data")).await?;
+        Ok(Some(ImageBytes {
+            iv: row.get("blob_iv"),
+            updated_at: row.get("updated_at"),
+            data,
+        }))
+    }
+
+    async fn image_meta(&self, account: &str, hash: &str) -> StorageResult<Option<ImageMeta>> {
+        let row = sqlx::query(
+            "SELECT blob_iv, updated_at, size FROM diary_images WHERE account_id = $1 AND hash = $2",
+        )
+        .bind(account)
+        .bind(hash)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(row.map(|row| ImageMeta {
+            iv: row.get("blob_iv"),
+            updated_at: row.get("updated_at"),
+            size: row.get("size"),
+        }))
+    }
+
+    async fn image_range(
+        &self,
+        account: &str,
+        hash: &str,
+        start: u64,
+        end: u64,
+    ) -> StorageResult<Option<Vec<u8>>> {
+        let inline: Option<Option<String>> = sqlx::query_scalar(
+            "SELECT blob_data FROM diary_images WHERE account_id = $1 AND hash = $2",
+        )
+        .bind(account)
+        .bind(hash)
+        .fetch_optional(self.pool())
+        .await?;
+        let Some(inline) = inline else {
+            return Ok(None);
+        };
+        let data = load_image_range(self.blobs(), account, hash, inline, start, end).await?;
+        Ok(Some(data))
+    }
+
+    async fn begin_image_upload(
+        &self,
+        account: &str,
+        hash: &str,
+        upload: &NewImageUpload,
+        now: i64,
+    ) -> StorageResult<ImageUploadStatus> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        let row = sqlx::query(&format!(
+            r#"
+            SELECT blob_iv, size, received, updated_at FROM image_uploads
+            WHERE account_id = $1 AND hash = $2{for_update}
+            "#,
+            for_update = D::FOR_UPDATE
+        ))
+        .bind(account)
+        .bind(hash)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(row) = row {
+            let same = row.get::<String, _>("blob_iv") == upload.iv
+                && row.get::<i64, _>("size") == upload.size
+                && row.get::<i64, _>("updated_at") == upload.updated_at;
+            if same {
+                sqlx::query(
+                    "UPDATE image_uploads SET touched_at = $3 WHERE account_id = $1 AND hash = $2",
+                )
+                .bind(account)
+                .bind(hash)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                return Ok(ImageUploadStatus {
+                    hash: hash.to_string(),
+                    size: upload.size,
+                    offset: row.get("received"),
+                });
+            }
+        }
+        sqlx::query("DELETE FROM image_upload_chunks WHERE account_id = $1 AND hash = $2")
+            .bind(account)
+            .bind(hash)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO image_uploads (account_id, hash, blob_iv, size, received, updated_at, created_at, touched_at)
+            VALUES ($1, $2, $3, $4, 0, $5, $6, $6)
+            ON CONFLICT (account_id, hash) DO UPDATE SET
+                blob_iv = EXCLUDED.blob_iv,
+                size = EXCLUDED.size,
+                received = 0,
+                updated_at = EXCLUDED.updated_at,
+                created_at = EXCLUDED.created_at,
+                touched_at = EXCLUDED.touched_at
+            "#,
+        )
+        .bind(account)
+        .bind(hash)
+        .bind(&upload.iv)
+        .bind(upload.size)
+        .bind(upload.updated_at)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(ImageUploadStatus {
+            hash: hash.to_string(),
+            size: upload.size,
+            offset: 0,
+        })
+    }
+
+    async fn image_upload_status(
+        &self,
+        account: &str,
+        hash: &str,
+    ) -> StorageResult<Option<ImageUploadStatus>> {
+        let row = sqlx::query(
+            "SELECT size, received FROM image_uploads WHERE account_id = $1 AND hash = $2",
+        )
+        .bind(account)
+        .bind(hash)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(row.map(|row| ImageUploadStatus {
+            hash: hash.to_string(),
+            size: row.get("size"),
+            offset: row.get("received"),
+        }))
+    }
+
+    async fn append_image_chunk(
+        &self,
+        account: &str,
+        hash: &str,
+        offset: i64,
+        data: &[u8],
+        now: i64,
+    ) -> StorageResult<Option<ChunkOutcome>> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        let row = sqlx::query(&format!(
+            "SELECT size, received FROM image_uploads WHERE account_id = $1 AND hash = $2{}",
+            D::FOR_UPDATE
+        ))
+        .bind(account)
+        .bind(hash)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let mut status = ImageUploadStatus {
+            hash: hash.to_string(),
+            size: row.get("size"),
+            offset: row.get("received"),
+        };
+        if let Some(outcome) = check_chunk(&status, offset, data.len())? {
+            return Ok(Some(outcome));
+        }
+        if !data.is_empty() {
+            sqlx::query(
+                r#"
+                INSERT INTO image_upload_chunks (account_id, hash, chunk_offset, data)
+                VALUES ($1, $2, $3, $4)
+                "#,
+            )
+            .bind(account)
+            .bind(hash)
+            .bind(offset)
+            .bind(data)
+            .execute(&mut *tx)
+            .await?;
+            status.offset += data.len() as i64;
+            sqlx::query(
+                "UPDATE image_uploads SET received = $3, touched_at = $4 WHERE account_id = $1 AND hash = $2",
+            )
+            .bind(account)
+            .bind(hash)
+            .bind(status.offset)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(Some(ChunkOutcome::Appended(status)))
+    }
+
+    async fn finalize_image_upload(
+        &self,
+        account: &str,
+        hash: &str,
+    ) -> StorageResult<Option<FinalizeOutcome>> {
+        // The image lock alone covers the blob write; no transaction (or the
+        // SQLite write lock) is held while the blob store works.
+        let _image = self.image_locks().lock(account, [hash]).await;
+        let mut tx = self.begin_read().await?;
+        let row = sqlx::query(
+            "SELECT blob_iv, size, received, updated_at FROM image_uploads WHERE account_id = $1 AND hash = $2",
+        )
+        .bind(account)
+        .bind(hash)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let (size, received): (i64, i64) = (row.get("size"), row.get("received"));
+        if received < size {
+            return Ok(Some(FinalizeOutcome::Incomplete(ImageUploadStatus {
+                hash: hash.to_string(),
+                size,
+                offset: received,
+            })));
+        }
+        let chunks = sqlx::query(
+            r#"
+            SELECT data FROM image_upload_chunks
+            WHERE account_id = $1 AND hash = $2
+            ORDER BY chunk_offset
+            "#,
+        )
+        .bind(account)
+        .bind(hash)
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        let mut data = Vec::with_capacity(size as usize);
+        for chunk in chunks {
+            data.extend_from_slice(&chunk.get::<Vec<u8>, _>("data"));
+        }
+
+        let blob_data = store_image_bytes(self.blobs(), account, hash, &data).await?;
+        let (_guard, mut tx) = self.begin_write().await?;
+        Sql::write_image_row(
+            &mut tx,
+            account,
+            hash,
+            &row.get::<String, _>("blob_iv"),
+            blob_data.as_deref(),
+            Some(data.len() as i64),
+            row.get("updated_at"),
+        )
+        .await?;
+        sqlx::query("DELETE FROM image_upload_chunks WHERE account_id = $1 AND hash = $2")
+            .bind(account)
+            .bind(hash)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM image_uploads WHERE account_id = $1 AND hash = $2")
+            .bind(account)
+            .bind(hash)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(FinalizeOutcome::Stored))
+    }
+
+    async fn put_image_refs(
+        &self,
+        account: &str,
+        refs: &[DiaryImageRefItem],
+    ) -> StorageResult<usize> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        for item in refs {
+            Sql::write_image_ref(
+                &mut tx,
+                account,
+                &item.diary_uuid,
+                &item.file_name,
+                &item.hash,
+                item.updated_at,
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(refs.len())
+    }
+
+    async fn delete_image_refs(&self, account: &str, keys: &[ImageRefKey]) -> StorageResult<usize> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        let mut released = Vec::new();
+        for key in keys {
+            let hash: Option<String> = sqlx::query_scalar(
+                "DELETE FROM diary_image_refs WHERE account_id = $1 AND diary_uuid = $2 AND file_name = $3 RETURNING hash",
+            )
+            .bind(account)
+            .bind(&key.diary_uuid)
+            .bind(&key.file_name)
+            .fetch_optional(&mut *tx)
+            .await?;
+            released.extend(hash);
+        }
+        Sql::release_images(&mut tx, account, &released).await?;
+        tx.commit().await?;
+        Ok(released.len())
+    }
+
+    async fn replace_diary_image_refs(
+        &self,
+        account: &str,
+        diary_uuid: &str,
+        refs: &[DiaryImageRefItem],
+    ) -> StorageResult<usize> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        let mut sql =
+            String::from("DELETE FROM diary_image_refs WHERE account_id = $1 AND diary_uuid = $2");
+        if !refs.is_empty() {
+            sql += &format!(" AND file_name NOT IN ({})", placeholders(3, refs.len()));
+        }
+        sql += " RETURNING hash";
+        let mut delete = sqlx::query_scalar(&sql).bind(account).bind(diary_uuid);
+        for item in refs {
+            delete = delete.bind(&item.file_name);
+        }
+        let released: Vec<String> = delete.fetch_all(&mut *tx).await?;
+        for item in refs {
+            Sql::write_image_ref(
+                &mut tx,
+                account,
+                diary_uuid,
+                &item.file_name,
+                &item.hash,
+                item.updated_at,
+            )
+            .await?;
+        }
+        // After the writes, which may point a kept ref at a released hash.
+        Sql::release_images(&mut tx, account, &released).await?;
+        tx.commit().await?;
+        Ok(released.len())
+    }
+
+    async fn orphaned_images(&self, cutoff: i64) -> StorageResult<Vec<OrphanImage>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT account_id, hash, stored_at, unreferenced_at
+            FROM diary_images
+            WHERE unreferenced_at < $1
+              AND NOT EXISTS (
+                  SELECT 1 FROM diary_image_refs r
+                  WHERE r.account_id = diary_images.account_id AND r.hash = diary_images.hash
+              )
+            ORDER BY account_id, hash
+            "#,
+        )
+        .bind(cutoff)
+        .fetch_all(self.pool())
+        .await
+        .map_err(|e| StorageError::from(e).context("orphaned image query failed"))?;
+        Ok(rows
+            .into_iter()
+            .map(|row| OrphanImage {
+                account_id: row.get("account_id"),
+                hash: row.get("hash"),
+                stored_at: row.get("stored_at"),
+                unreferenced_at: row.get("unreferenced_at"),
+            })
+            .collect())
+    }
+
+    async fn delete_orphaned_image(
+        &self,
+        account: &str,
+        hash: &str,
+        cutoff: i64,
+    ) -> StorageResult<bool> {
+        // Held until the blob is gone, so an upload of the same image
+        // waits and then writes both its blob and its row.
+        let _image = self.image_locks().lock(account, [hash]).await;
+        let row = {
+            let (_guard, mut tx) = self.begin_write().await?;
+            let row = sqlx::query(DELETE_ORPHANED_IMAGE)
+                .bind(account)
+                .bind(hash)
+                .bind(cutoff)
+                .fetch_optional(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            row
+        };
+        let Some(row) = row else {
+            return Ok(false);
+        };
+        // The row goes first: a leftover object is harmless, a row without one is not.
+        if let (Some(blobs), true) = (self.blobs(), row.get::<bool, _>("external")) {
+            blobs.delete(account, hash).await?;
+        }
+        Ok(true)
+    }
+
+    async fn move_inline_blobs(&self) -> StorageResult<BlobMigration> {
+        let blobs = self.blobs().ok_or_else(no_blob_store)?;
+        let mut report = BlobMigration::default();
+        let (mut after_account, mut after_hash) = (String::new(), String::new());
+        loop {
+            let rows = sqlx::query(
+                r#"
+                SELECT account_id, hash, blob_data
+                FROM diary_images
+                WHERE blob_data IS NOT NULL AND (account_id, hash) > ($1, $2)
+                ORDER BY account_id, hash
+                LIMIT $3
+                "#,
+            )
+            .bind(&after_account)
+            .bind(&after_hash)
+            .bind(BLOB_MIGRATION_BATCH)
+            .fetch_all(self.pool())
+            .await
+            .map_err(|e| StorageError::from(e).context("inline image query failed"))?;
+            if rows.is_empty() {
+                return Ok(report);
+            }
+            for row in rows {
+                let (account, hash, inline): (String, String, String) =
+                    (row.get("account_id"), row.get("hash"), row.get("blob_data"));
+                match move_inline_blob(blobs, &account, &hash, &inline).await {
+                    Ok(()) => {
+                        // Only clear the copy that was moved; a concurrent upload may have replaced it.
+                        sqlx::query(
+                            r#"
+                            UPDATE diary_images SET blob_data = NULL
+                            WHERE account_id = $1 AND hash = $2 AND blob_data = $3
+                            "#,
+                        )
+                        .bind(&account)
+                        .bind(&hash)
+                        .bind(&inline)
+                        .execute(self.pool())
+                        .await?;
+                        report.moved += 1;
+                    }
+                    Err(StorageError::Invalid(_)) => {
+                        report.skipped.push((account.clone(), hash.clone()))
+                    }
+                    Err(e) => return Err(e.context("blob store write failed")),
+                }
+                (after_account, after_hash) = (account, hash);
+            }
+        }
+    }
+
+    async fn create_api_key(&self, key: &NewApiKey) -> StorageResult<ApiKeyInfo> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO api_keys (id, account_id, name, key_hash, scopes, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+        )
+        .bind(&key.id)
+        .bind(&key.account_id)
+        .bind(&key.name)
+        .bind(&key.key_hash)
+        .bind(join_scopes(&key.scopes))
+        .bind(key.created_at)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(ApiKeyInfo {
+            id: key.id.clone(),
+            account_id: key.account_id.clone(),
+            name: key.name.clone(),
+            scopes: key.scopes.clone(),
+            created_at: key.created_at,
+            last_used_at: None,
+            revoked_at: None,
+        })
+    }
+
+    async fn api_key_by_hash(&self, key_hash: &str) -> StorageResult<Option<ApiKeyInfo>> {
+        let row = sqlx::query(&format!("{} WHERE key_hash = $1", SELECT_API_KEYS))
+            .bind(key_hash)
+            .fetch_optional(self.pool())
+            .await?;
+        Ok(row.as_ref().map(Sql::api_key_from_row))
+    }
+
+    async fn list_api_keys(&self, account: Option<&str>) -> StorageResult<Vec<ApiKeyInfo>> {
+        let rows = sqlx::query(&format!(
+            "{} WHERE $1 IS NULL OR account_id = $1 ORDER BY created_at, id",
+            SELECT_API_KEYS
+        ))
+        .bind(account)
+        .fetch_all(self.pool())
+        .await?;
+        Ok(rows.iter().map(Sql::api_key_from_row).collect())
+    }
+
+    async fn revoke_api_key(&self, id: &str, now: i64) -> StorageResult<Option<ApiKeyInfo>> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        sqlx::query("UPDATE api_keys SET revoked_at = COALESCE(revoked_at, $2) WHERE id = $1")
+            .bind(id)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        let row = sqlx::query(&format!("{} WHERE id = $1", SELECT_API_KEYS))
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(row.as_ref().map(Sql::api_key_from_row))
+    }
+
+    async fn touch_api_key(&self, id: &str, now: i64) -> StorageResult<()> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        sqlx::query("UPDATE api_keys SET last_used_at = $2 WHERE id = $1")
+            .bind(id)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn has_api_keys(&self) -> StorageResult<bool> {
+        let row = sqlx::query("SELECT EXISTS (SELECT 1 FROM api_keys) AS any_keys")
+            .fetch_one(self.pool())
+            .await?;
+        Ok(row.get("any_keys"))
+    }
+
+    async fn create_auth_session(&self, session: &AuthSession) -> StorageResult<()> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO auth_sessions
+                (id, account_id, key_id, scopes, refresh_hash, created_at, refreshed_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+        )
+        .bind(&session.id)
+        .bind(&session.account_id)
+        .bind(&session.key_id)
+        .bind(join_scopes(&session.scopes))
+        .bind(&session.refresh_hash)
+        .bind(session.created_at)
+        .bind(session.refreshed_at)
+        .bind(session.expires_at)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn auth_session(&self, id: &str) -> StorageResult<Option<AuthSession>> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, account_id, key_id, scopes, refresh_hash, previous_refresh_hash,
+                   created_at, refreshed_at, expires_at, revoked_at
+            FROM auth_sessions
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(row.as_ref().map(Sql::auth_session_from_row))
+    }
+
+    async fn auth_session_by_refresh(&self, hash: &str) -> StorageResult<Option<AuthSession>> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, account_id, key_id, scopes, refresh_hash, previous_refresh_hash,
+                   created_at, refreshed_at, expires_at, revoked_at
+            FROM auth_sessions
+            WHERE refresh_hash = $1 OR previous_refresh_hash = $1
+            LIMIT 1
+            "#,
+        )
+        .bind(hash)
+        .fetch_optional(self.pool())
+        .await?;
+        Ok(row.as_ref().map(Sql::auth_session_from_row))
+    }
+
+    async fn rotate_auth_session(
+        &self,
+        id: &str,
+        old_hash: &str,
+        new_hash: &str,
+        now: i64,
+        expires_at: i64,
+    ) -> StorageResult<bool> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        let result = sqlx::query(
+            r#"
+            UPDATE auth_sessions
+            SET previous_refresh_hash = refresh_hash, refresh_hash = $3,
+                refreshed_at = $4, expires_at = $5
+            WHERE id = $1 AND refresh_hash = $2 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(id)
+        .bind(old_hash)
+        .bind(new_hash)
+        .bind(now)
+        .bind(expires_at)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn revoke_auth_session(&self, id: &str, now: i64) -> StorageResult<bool> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        let result = sqlx::query(
+            "UPDATE auth_sessions SET revoked_at = $2 WHERE id = $1 AND revoked_at IS NULL",
+        )
+        .bind(id)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn revoke_auth_sessions(
+        &self,
+        account: &str,
+        key_id: Option<&str>,
+        now: i64,
+    ) -> StorageResult<usize> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        let result = sqlx::query(
+            r#"
+            UPDATE auth_sessions SET revoked_at = $3
+            WHERE account_id = $1 AND ($2 IS NULL OR key_id = $2) AND revoked_at IS NULL
+            "#,
+        )
+        .bind(account)
+        .bind(key_id)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected() as usize)
+    }
+
+    async fn record_auth_failure(
+        &self,
+        ip: &str,
+        path: &str,
+        reason: &str,
+        now: i64,
+    ) -> StorageResult<()> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        sqlx::query(
+            "INSERT INTO auth_failures (ip, path, reason, created_at) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(ip)
+        .bind(path)
+        .bind(reason)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn prune_auth_failures(&self, before: i64) -> StorageResult<u64> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        let result = sqlx::query("DELETE FROM auth_failures WHERE created_at < $1")
+            .bind(before)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn expire_image_uploads(&self, before: i64) -> StorageResult<u64> {
+        let (_guard, mut tx) = self.begin_write().await?;
+        sqlx::query(
+            r#"
+            DELETE FROM image_upload_chunks
+            WHERE EXISTS (
+                SELECT 1 FROM image_uploads u
+                WHERE u.account_id = image_upload_chunks.account_id
+                  AND u.hash = image_upload_chunks.hash
+                  AND u.touched_at < $1
+            )
+            "#,
+        )
+        .bind(before)
+        .execute(&mut *tx)
+        .await?;
+        let result = sqlx::query("DELETE FROM image_uploads WHERE touched_at < $1")
+            .bind(before)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn auth_failures(&self, since: i64, limit: i64) -> StorageResult<Vec<AuthFailure>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, ip, path, reason, created_at FROM auth_failures
+            WHERE created_at >= $1
+            ORDER BY created_at DESC, id DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(since)
+        .bind(limit.max(0))
+        .fetch_all(self.pool())
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| AuthFailure {
+                id: row.get("id"),
+                ip: row.get("ip"),
+                path: row.get("path"),
+                reason: row.get("reason"),
+                created_at: row.get("created_at"),
+            })
+            .collect())
+    }
+}
+
+/// Statements and row conversions shared by the `Storage` impl, over database `D`.
+struct Sql<D>(PhantomData<D>);
+
+impl<D> Sql<D>
+where
+    D: Dialect,
+    for<'c> &'c mut D::Connection: Executor<'c, Database = D>,
+    for<'q> <D as HasArguments<'q>>::Arguments: IntoArguments<'q, D>,
+    for<'q> &'q str: Encode<'q, D> + Type<D>,
+    for<'q> &'q [u8]: Encode<'q, D> + Type<D>,
+    i64: for<'q> Encode<'q, D> + for<'r> Decode<'r, D> + Type<D>,
+    Option<i64>: for<'q> Encode<'q, D>,
+    bool: for<'q> Encode<'q, D> + for<'r> Decode<'r, D> + Type<D>,
+    String: for<'q> Encode<'q, D> + for<'r> Decode<'r, D> + Type<D>,
+    Option<String>: for<'q> Encode<'q, D>,
+    for<'q> Option<&'q str>: Encode<'q, D>,
+    Vec<u8>: for<'r> Decode<'r, D> + Type<D>,
+    NaiveDate: for<'q> Encode<'q, D> + Type<D>,
+    Option<NaiveDate>: for<'q> Encode<'q, D>,
+    usize: ColumnIndex<D::Row>,
+    for<'a> &'a str: ColumnIndex<D::Row>,
+{
+    async fn upsert_diary(
+        tx: &mut Transaction<'_, D>,
+        account: &str,
+        item: &DiarySyncItem,
+        history_limit: i64,
+    ) -> StorageResult<WriteResult> {
+        if let Some(rejected) = Self::clear_tombstone(
+            tx,
+            account,
+            RecordKind::Diary,
+            &item.uuid,
+            item.updated_at,
+            item.base_updated_at,
+        )
+        .await?
+        {
+            return Ok(Some(rejected));
+        }
+        Self::archive_revision(
+            tx,
+            account,
+            RecordKind::Diary,
+            &item.uuid,
+            Some((item.updated_at, item.base_updated_at)),
+            history_limit,
+        )
+        .await?;
+        let applied = sqlx::query(&format!(
+            r#"
+            INSERT INTO diary_sync (account_id, uuid, author, timestamp, updated_at, payload_iv, payload_data)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (account_id, uuid) DO UPDATE SET
+                author = EXCLUDED.author,
+                timestamp = EXCLUDED.timestamp,
+                updated_at = {greatest}(EXCLUDED.updated_at, diary_sync.updated_at + 1),
+                payload_iv = EXCLUDED.payload_iv,
+                payload_data = EXCLUDED.payload_data{bump_change_seq}
+            WHERE diary_sync.updated_at < EXCLUDED.updated_at OR diary_sync.updated_at = $8
+            "#,
+            greatest = D::GREATEST,
+            bump_change_seq = D::BUMP_CHANGE_SEQ
+        ))
+        .bind(account)
+        .bind(&item.uuid)
+        .bind(&item.author)
+        .bind(item.timestamp)
+        .bind(item.updated_at)
+        .bind(&item.payload.iv)
+        .bind(&item.payload.data)
+        .bind(item.base_updated_at)
+        .execute(&mut **tx)
+        .await?
+        .rows_affected();
+        if applied > 0 {
+            return Ok(None);
+        }
+        let current = sqlx::query(
+            "SELECT updated_at, payload_iv, payload_data FROM diary_sync WHERE account_id = $1 AND uuid = $2",
+        )
+        .bind(account)
+        .bind(&item.uuid)
+        .fetch_optional(&mut **tx)
+        .await?;
+        Ok(stale_write(
+            RecordKind::Diary,
+            &item.uuid,
+            item.updated_at,
+            &item.payload,
+            current.as_ref().map(Self::stored_version),
+        ))
+    }
+
+    async fn upsert_todo(
+        tx: &mut Transaction<'_, D>,
+        account: &str,
+        item: &TodoSyncItem,
+        history_limit: i64,
+    ) -> StorageResult<WriteResult> {
+        if let Some(rejected) = Self::clear_tombstone(
+            tx,
+            account,
+            RecordKind::Todo,
+            &item.uuid,
+            item.updated_at,
+            item.base_updated_at,
+        )
+        .await?
+        {
+            return Ok(Some(rejected));
+        }
+        Self::archive_revision(
+            tx,
+            account,
+            RecordKind::Todo,
+            &item.uuid,
+            Some((item.updated_at, item.base_updated_at)),
+            history_limit,
+        )
+        .await?;
+        let applied = sqlx::query(&format!(
+            r#"
+            INSERT INTO todo_sync (
+                account_id, uuid, author, is_completed, created_at, completed_at, updated_at,
+                payload_iv, payload_data
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (account_id, uuid) DO UPDATE SET
+                author = EXCLUDED.author,
+                is_completed = EXCLUDED.is_completed,
+                created_at = EXCLUDED.created_at,
+                completed_at = EXCLUDED.completed_at,
+                updated_at = {greatest}(EXCLUDED.updated_at, todo_sync.updated_at + 1),
+                payload_iv = EXCLUDED.payload_iv,
+                payload_data = EXCLUDED.payload_data{bump_change_seq}
+            WHERE todo_sync.updated_at < EXCLUDED.updated_at OR todo_sync.updated_at = $10
+            "#,
+            greatest = D::GREATEST,
+            bump_change_seq = D::BUMP_CHANGE_SEQ
+        ))
+        .bind(account)
+        .bind(&item.uuid)
+        .bind(&item.author)
+        .bind(item.is_completed)
+        .bind(item.created_at)
+        .bind(item.completed_at)
+        .bind(item.updated_at)
+        .bind(&item.payload.iv)
+        .bind(&item.payload.data)
+        .bind(item.base_updated_at)
+        .execute(&mut **tx)
+        .await?
+        .rows_affected();
+        if applied > 0 {
+            return Ok(None);
+        }
+        let current = sqlx::query(
+            "SELECT updated_at, payload_iv, payload_data FROM todo_sync WHERE account_id = $1 AND uuid = $2",
+        )
+        .bind(account)
+        .bind(&item.uuid)
+        .fetch_optional(&mut **tx)
+        .await?;
+        Ok(stale_write(
+            RecordKind::Todo,
+            &item.uuid,
+            item.updated_at,
+            &item.payload,
+            current.as_ref().map(Self::stored_version),
+        ))
+    }
+
+    /// Older clients identify periods by start date only: map such an item to the
+    /// stored period with that start date, or to the uuid a backfilled row would have.
+    async fn resolve_period_uuid(
+        tx: &mut Transaction<'_, D>,
+        account: &str,
+        start_date: NaiveDate,
+    ) -> Result<String, sqlx::Error> {
+        let stored: Option<String> = sqlx::query_scalar(
+            r#"
+            SELECT uuid FROM period_sync
+            WHERE account_id = $1 AND start_date = $2
+            ORDER BY updated_at DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(account)
+        .bind(start_date)
+        .fetch_optional(&mut **tx)
+        .await?;
+        Ok(stored.unwrap_or_else(|| legacy_period_uuid(&start_date.to_string())))
+    }
+
+    async fn upsert_period(
+        tx: &mut Transaction<'_, D>,
+        account: &str,
+        item: &PeriodSyncItem,
+    ) -> StorageResult<WriteResult> {
+        let start_date = NaiveDate::parse_from_str(&item.start_date, "%Y-%m-%d")
+            .map_err(|e| StorageError::Invalid(e.to_string()))?;
+        let end_date = NaiveDate::parse_from_str(&item.end_date, "%Y-%m-%d")
+            .map_err(|e| StorageError::Invalid(e.to_string()))?;
+        let uuid = if item.uuid.is_empty() {
+            Self::resolve_period_uuid(tx, account, start_date).await?
+        } else {
+            item.uuid.clone()
+        };
+        if let Some(rejected) = Self::clear_tombstone(
+            tx,
+            account,
+            RecordKind::Period,
+            &uuid,
+            item.updated_at,
+            item.base_updated_at,
+        )
+        .await?
+        {
+            return Ok(Some(rejected));
+        }
+        let applied = sqlx::query(&format!(
+            r#"
+            INSERT INTO period_sync (
+                account_id, uuid, start_date, end_date, updated_at, payload_iv, payload_data
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (account_id, uuid) DO UPDATE SET
+                start_date = EXCLUDED.start_date,
+                end_date = EXCLUDED.end_date,
+                updated_at = {greatest}(EXCLUDED.updated_at, period_sync.updated_at + 1),
+                payload_iv = EXCLUDED.payload_iv,
+                payload_data = EXCLUDED.payload_data{bump_change_seq}
+            WHERE period_sync.updated_at < EXCLUDED.updated_at OR period_sync.updated_at = $8
+            "#,
+            greatest = D::GREATEST,
+            bump_change_seq = D::BUMP_CHANGE_SEQ
+        ))
+        .bind(account)
+        .bind(&uuid)
+        .bind(start_date)
+        .bind(end_date)
+        .bind(item.updated_at)
+        .bind(&item.payload.iv)
+        .bind(&item.payload.data)
+        .bind(item.base_updated_at)
+        .execute(&mut **tx)
+        .await?
+        .rows_affected();
+        if applied > 0 {
+            return Ok(None);
+        }
+        let current = sqlx::query(
+            "SELECT updated_at, payload_iv, payload_data FROM period_sync WHERE account_id = $1 AND uuid = $2",
+        )
+        .bind(account)
+        .bind(&uuid)
+        .fetch_optional(&mut **tx)
+        .await?;
+        Ok(stale_write(
+            RecordKind::Period,
+            &uuid,
+            item.updated_at,
+            &item.payload,
+            current.as_ref().map(Self::stored_version),
+        ))
+    }
+
+    /// Stores an image once per hash: the ciphertext goes to the blob store when
+    /// one is configured (before the row, so a committed row always has its blob),
+    /// otherwise inline.
+    async fn put_image_row(
+        tx: &mut Transaction<'_, D>,
+        blobs: Option<&dyn BlobStore>,
+        account: &str,
+        item: &DiaryImageSyncItem,
+    ) -> StorageResult<()> {
+        let blob_data = store_image_blob(blobs, account, item).await?;
+        Self::write_image_row(
+            tx,
+            account,
+            &item.hash,
+            &item.blob.iv,
+            blob_data,
+            base64_len(&item.blob.data),
+            item.updated_at,
+        )
+        .await
+    }
+
+    /// Upserts a `diary_images` row stamped with the server time, which is also
+    /// its `unreferenced_at` unless a ref already points at it; `blob_data` is
+    /// `None` when the blob store holds it. `size` is the ciphertext length.
+    async fn write_image_row(
+        tx: &mut Transaction<'_, D>,
+        account: &str,
+        hash: &str,
+        iv: &str,
+        blob_data: Option<&str>,
+        size: Option<i64>,
+        updated_at: i64,
+    ) -> StorageResult<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO diary_images
+                (account_id, hash, blob_iv, blob_data, size, updated_at, stored_at, unreferenced_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, CASE
+                WHEN EXISTS (
+                    SELECT 1 FROM diary_image_refs r WHERE r.account_id = $1 AND r.hash = $2
+                ) THEN NULL
+                ELSE $7
+            END)
+            ON CONFLICT (account_id, hash) DO UPDATE SET
+                blob_iv = EXCLUDED.blob_iv,
+                blob_data = EXCLUDED.blob_data,
+                size = EXCLUDED.size,
+                updated_at = EXCLUDED.updated_at,
+                stored_at = EXCLUDED.stored_at,
+                unreferenced_at = EXCLUDED.unreferenced_at
+            "#,
+        )
+        .bind(account)
+        .bind(hash)
+        .bind(iv)
+        .bind(blob_data)
+        .bind(size)
+        .bind(updated_at)
+        .bind(Utc::now().timestamp_millis())
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    async fn upsert_image(
+        tx: &mut Transaction<'_, D>,
+        blobs: Option<&dyn BlobStore>,
+        account: &str,
+        item: &DiaryImageSyncItem,
+    ) -> StorageResult<()> {
+        Self::put_image_row(tx, blobs, account, item).await?;
+
+        // Track diary reference.
+        Self::write_image_ref(
+            tx,
+            account,
+            &item.diary_uuid,
+            &item.file_name,
+            &item.hash,
+            item.updated_at,
+        )
+        .await
+    }
+
+    /// Upserts a ref, marking its image referenced again and, when the ref is
+    /// repointed, the image it pointed at unreferenced if nothing else uses it.
+    async fn write_image_ref(
+        tx: &mut Transaction<'_, D>,
+        account: &str,
+        diary_uuid: &str,
+        file_name: &str,
+        hash: &str,
+        updated_at: i64,
+    ) -> StorageResult<()> {
+        let previous: Option<String> = sqlx::query_scalar(
+            "SELECT hash FROM diary_image_refs WHERE account_id = $1 AND diary_uuid = $2 AND file_name = $3",
+        )
+        .bind(account)
+        .bind(diary_uuid)
+        .bind(file_name)
+        .fetch_optional(&mut **tx)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO diary_image_refs (account_id, diary_uuid, file_name, hash, updated_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (account_id, diary_uuid, file_name) DO UPDATE SET
+                hash = EXCLUDED.hash,
+                updated_at = EXCLUDED.updated_at
+            "#,
+        )
+        .bind(account)
+        .bind(diary_uuid)
+        .bind(file_name)
+        .bind(hash)
+        .bind(updated_at)
+        .execute(&mut **tx)
+        .await?;
+        sqlx::query(
+            "UPDATE diary_images SET unreferenced_at = NULL WHERE account_id = $1 AND hash = $2",
+        )
+        .bind(account)
+        .bind(hash)
+        .execute(&mut **tx)
+        .await?;
+        if let Some(previous) = previous.filter(|previous| previous != hash) {
+            Self::release_images(tx, account, &[previous]).await?;
+        }
+        Ok(())
+    }
+
+    /// Stamps `unreferenced_at` on those of `hashes` that just lost their last
+    /// ref, starting their garbage-collection grace period.
+    async fn release_images(
+        tx: &mut Transaction<'_, D>,
+        account: &str,
+        hashes: &[String],
+    ) -> StorageResult<()> {
+        let now = Utc::now().timestamp_millis();
+        for hash in hashes {
+            sqlx::query(
+                r#"
+                UPDATE diary_images SET unreferenced_at = $3
+                WHERE account_id = $1 AND hash = $2 AND unreferenced_at IS NULL
+                  AND NOT EXISTS (
+                      SELECT 1 FROM diary_image_refs r
+                      WHERE r.account_id = diary_images.account_id AND r.hash = diary_images.hash
+                  )
+                "#,
+            )
+            .bind(account)
+            .bind(hash)
+            .bind(now)
+            .execute(&mut **tx)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Drops the tombstone for a record that is being written again. Returns a
+    /// conflict when the tombstone supersedes the write, in which case the write
+    /// is stale and must not resurrect the record.
+    async fn clear_tombstone(
+        tx: &mut Transaction<'_, D>,
+        account: &str,
+        kind: RecordKind,
+        uuid: &str,
+        updated_at: i64,
+        base_updated_at: Option<i64>,
+    ) -> StorageResult<WriteResult> {
+        let deleted_at: Option<i64> = sqlx::query_scalar(
+            "SELECT deleted_at FROM sync_tombstones WHERE account_id = $1 AND kind = $2 AND uuid = $3",
+        )
+        .bind(account)
+        .bind(kind.as_str())
+        .bind(uuid)
+        .fetch_optional(&mut **tx)
+        .await?;
+        match deleted_at {
+            None => Ok(None),
+            Some(deleted_at) if !supersedes(updated_at, base_updated_at, deleted_at) => {
+                Ok(Some(conflict(kind, uuid, deleted_at, true)))
+            }
+            Some(_) => {
+                sqlx::query(
+                    "DELETE FROM sync_tombstones WHERE account_id = $1 AND kind = $2 AND uuid = $3",
+                )
+                .bind(account)
+                .bind(kind.as_str())
+                .bind(uuid)
+                .execute(&mut **tx)
+                .await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Deletes the live record and records its tombstone, unless the server holds
+    /// a version edited after the deletion that the client has not seen.
+    async fn apply_tombstone(
+        tx: &mut Transaction<'_, D>,
+        account: &str,
+        item: &TombstoneItem,
+        history_limit: i64,
+    ) -> StorageResult<WriteResult> {
+        let legacy_start_date = match item.kind {
+            RecordKind::Period => NaiveDate::parse_from_str(&item.uuid, "%Y-%m-%d").ok(),
+            _ => None,
+        };
+        let resolved;
+        let item = match legacy_start_date {
+            Some(start_date) => {
+                resolved = TombstoneItem {
+                    uuid: Self::resolve_period_uuid(tx, account, start_date).await?,
+                    ..item.clone()
+                };
+                &resolved
+            }
+            None => item,
+        };
+        let current: Option<i64> = match item.kind {
+            RecordKind::Diary => {
+                sqlx::query_scalar(&format!(
+                    "SELECT updated_at FROM diary_sync WHERE account_id = $1 AND uuid = $2{}",
+                    D::FOR_UPDATE
+                ))
+                .bind(account)
+                .bind(&item.uuid)
+                .fetch_optional(&mut **tx)
+                .await
+            }
+            RecordKind::Todo => {
+                sqlx::query_scalar(&format!(
+                    "SELECT updated_at FROM todo_sync WHERE account_id = $1 AND uuid = $2{}",
+                    D::FOR_UPDATE
+                ))
+                .bind(account)
+                .bind(&item.uuid)
+                .fetch_optional(&mut **tx)
+                .await
+            }
+            RecordKind::Period => {
+                sqlx::query_scalar(&format!(
+                    "SELECT updated_at FROM period_sync WHERE account_id = $1 AND uuid = $2{}",
+                    D::FOR_UPDATE
+                ))
+                .bind(account)
+                .bind(&item.uuid)
+                .fetch_optional(&mut **tx)
+                .await
+            }
+        }?;
+        if let Some(current) = current {
+            // A deletion stamped at the same instant as the stored edit still wins.
+            if current > item.deleted_at && item.base_updated_at != Some(current) {
+                return Ok(Some(conflict(item.kind, &item.uuid, current, false)));
+            }
+        }
+
+        // Recorded before the row goes, so a period tombstone keeps its start
+        // date for clients that know periods by date only.
+        sqlx::query(&format!(
+            r#"
+            INSERT INTO sync_tombstones (account_id, kind, uuid, deleted_at, start_date)
+            VALUES ($1, $2, $3, $4, COALESCE(
+                (SELECT start_date FROM period_sync WHERE account_id = $1 AND uuid = $3 AND $2 = 'period'),
+                $5
+            ))
+            ON CONFLICT (account_id, kind, uuid) DO UPDATE SET
+                deleted_at = {greatest}(sync_tombstones.deleted_at, EXCLUDED.deleted_at),
+                start_date = COALESCE(EXCLUDED.start_date, sync_tombstones.start_date){bump_change_seq}
+            "#,
+            greatest = D::GREATEST,
+            bump_change_seq = D::BUMP_CHANGE_SEQ
+        ))
+        .bind(account)
+        .bind(item.kind.as_str())
+        .bind(&item.uuid)
+        .bind(item.deleted_at)
+        .bind(legacy_start_date)
+        .execute(&mut **tx)
+        .await?;
+        Self::archive_revision(tx, account, item.kind, &item.uuid, None, history_limit).await?;
+        match item.kind {
+            RecordKind::Diary => {
+                sqlx::query("DELETE FROM diary_sync WHERE account_id = $1 AND uuid = $2")
+                    .bind(account)
+                    .bind(&item.uuid)
+                    .execute(&mut **tx)
+                    .await?;
+                let released: Vec<String> = sqlx::query_scalar(
+                    "DELETE FROM diary_image_refs WHERE account_id = $1 AND diary_uuid = $2 RETURNING hash",
+                )
+                .bind(account)
+                .bind(&item.uuid)
+                .fetch_all(&mut **tx)
+                .await?;
+                Self::release_images(tx, account, &released).await?;
+            }
+            RecordKind::Todo => {
+                sqlx::query("DELETE FROM todo_sync WHERE account_id = $1 AND uuid = $2")
+                    .bind(account)
+                    .bind(&item.uuid)
+                    .execute(&mut **tx)
+                    .await?;
+            }
+            RecordKind::Period => {
+                sqlx::query("DELETE FROM period_sync WHERE account_id = $1 AND uuid = $2")
+                    .bind(account)
+                    .bind(&item.uuid)
+                    .execute(&mut **tx)
+                    .await?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Copies the stored diary/todo into its history table before it is replaced.
+    /// With `superseded_by = Some((updated_at, base_updated_at))` only a row the
+    /// conditional upsert is about to overwrite is archived; `None` archives the
+    /// row unconditionally (deletions, restores). Periods keep no history.
+    async fn archive_revision(
+        tx: &mut Transaction<'_, D>,
+        account: &str,
+        kind: RecordKind,
+        uuid: &str,
+        superseded_by: Option<(i64, Option<i64>)>,
+        history_limit: i64,
+    ) -> Result<(), sqlx::Error> {
+        if history_limit <= 0 {
+            return Ok(());
+        }
+        let (archive_sql, prune_sql) = match kind {
+            RecordKind::Diary => (
+                r#"
+                INSERT INTO diary_history (
+                    account_id, uuid, author, timestamp, updated_at, payload_iv, payload_data, archived_at
+                )
+                SELECT account_id, uuid, author, timestamp, updated_at, payload_iv, payload_data, $3
+                FROM diary_sync
+                WHERE account_id = $1 AND uuid = $2
+                    AND ($4 IS NULL OR updated_at < $4 OR updated_at = $5)
+                "#,
+                r#"
+                DELETE FROM diary_history
+                WHERE account_id = $1 AND uuid = $2 AND revision_id NOT IN (
+                    SELECT revision_id FROM diary_history
+                    WHERE account_id = $1 AND uuid = $2 ORDER BY revision_id DESC LIMIT $3
+                )
+                "#,
+            ),
+            RecordKind::Todo => (
+                r#"
+                INSERT INTO todo_history (
+                    account_id, uuid, author, is_completed, created_at, completed_at, updated_at,
+                    payload_iv, payload_data, archived_at
+                )
+                SELECT account_id, uuid, author, is_completed, created_at, completed_at, updated_at,
+                    payload_iv, payload_data, $3
+                FROM todo_sync
+                WHERE account_id = $1 AND uuid = $2
+                    AND ($4 IS NULL OR updated_at < $4 OR updated_at = $5)
+                "#,
+                r#"
+                DELETE FROM todo_history
+                WHERE account_id = $1 AND uuid = $2 AND revision_id NOT IN (
+                    SELECT revision_id FROM todo_history
+                    WHERE account_id = $1 AND uuid = $2 ORDER BY revision_id DESC LIMIT $3
+                )
+                "#,
+            ),
+            RecordKind::Period => return Ok(()),
+        };
+        let (updated_at, base_updated_at) = match superseded_by {
+            Some((updated_at, base)) => (Some(updated_at), base),
+            None => (None, None),
+        };
+        let archived = sqlx::query(archive_sql)
+            .bind(account)
+            .bind(uuid)
+            .bind(Utc::now().timestamp_millis())
+            .bind(updated_at)
+            .bind(base_updated_at)
+            .execute(&mut **tx)
+            .await?
+            .rows_affected();
+        if archived > 0 {
+            sqlx::query(prune_sql)
+                .bind(account)
+                .bind(uuid)
+                .bind(history_limit)
+                .execute(&mut **tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn max_change_seq(rows: &[D::Row]) -> i64 {
+        rows.iter()
+            .map(|row| row.get::<i64, _>("change_seq"))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// `updated_at` and payload of a stored record, to tell a replay from a conflict.
+    fn stored_version(row: &D::Row) -> (i64, EncryptedBlob) {
+        (
+            row.get("updated_at"),
+            EncryptedBlob {
+                iv: row.get("payload_iv"),
+                data: row.get("payload_data"),
+            },
+        )
+    }
+
+    fn diary_from_row(row: &D::Row) -> DiarySyncItem {
+        DiarySyncItem {
+            uuid: row.get("uuid"),
+            author: row.get("author"),
+            timestamp: row.get("timestamp"),
+            updated_at: row.get("updated_at"),
+            payload: EncryptedBlob {
+                iv: row.get("payload_iv"),
+                data: row.get("payload_data"),
+            },
+            base_updated_at: None,
+        }
+    }
+
+    fn todo_from_row(row: &D::Row) -> TodoSyncItem {
+        TodoSyncItem {
+            uuid: row.get("uuid"),
+            author: row.get("author"),
+            is_completed: row.get("is_completed"),
+            created_at: row.get("created_at"),
+            completed_at: row.get("completed_at"),
+            updated_at: row.get("updated_at"),
+            payload: EncryptedBlob {
+                iv: row.get("payload_iv"),
+                data: row.get("payload_data"),
+            },
+            base_updated_at: None,
+        }
+    }
+
+    fn period_from_row(row: &D::Row) -> PeriodSyncItem {
+        PeriodSyncItem {
+            uuid: row.get("uuid"),
+            start_date: row.get("start_date"),
+            end_date: row.get("end_date"),
+            updated_at: row.get("updated_at"),
+            payload: EncryptedBlob {
+                iv: row.get("payload_iv"),
+                data: row.get("payload_data"),
+            },
+            base_updated_at: None,
+        }
+    }
+
+    fn tombstone_from_row(row: &D::Row) -> Option<TombstoneItem> {
+        let kind: String = row.get("kind");
+        Some(TombstoneItem {
+            kind: RecordKind::parse(&kind)?,
+            uuid: row.get("uuid"),
+            deleted_at: row.get("deleted_at"),
+            base_updated_at: None,
+            start_date: row.get("start_date"),
+        })
+    }
+
+    fn device_from_row(row: &D::Row) -> DeviceItem {
+        DeviceItem {
+            device_id: row.get("device_id"),
+            name: row.get("name"),
+            app_version: row.get("app_version"),
+            first_seen: row.get("first_seen"),
+            last_seen: row.get("last_seen"),
+            last_upload_at: row.get("last_upload_at"),
+            last_download_at: row.get("last_download_at"),
+        }
+    }
+
+    fn api_key_from_row(row: &D::Row) -> ApiKeyInfo {
+        ApiKeyInfo {
+            id: row.get("id"),
+            account_id: row.get("account_id"),
+            name: row.get("name"),
+            scopes: parse_scopes(&row.get::<String, _>("scopes")),
+            created_at: row.get("created_at"),
+            last_used_at: row.get("last_used_at"),
+            revoked_at: row.get("revoked_at"),
+        }
+    }
+
+    fn auth_session_from_row(row: &D::Row) -> AuthSession {
+        AuthSession {
+            id: row.get("id"),
+            account_id: row.get("account_id"),
+            key_id: row.get("key_id"),
+            scopes: parse_scopes(&row.get::<String, _>("scopes")),
+            refresh_hash: row.get("refresh_hash"),
+            previous_refresh_hash: row.get("previous_refresh_hash"),
+            created_at: row.get("created_at"),
+            refreshed_at: row.get("refreshed_at"),
+            expires_at: row.get("expires_at"),
+            revoked_at: row.get("revoked_at"),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! SQLite storage (cargo feature `sqlite`); the schema is built by the
+//! migrations in `migrations/sqlite`.
+
+use async_trait::async_trait;
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteQueryResult};
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use super::blob::BlobStore;
+use super::sql::{self, Dialect, RowsAffected, SqlBackend};
+use super::{ImageLocks, MigrationMode, StorageError, StorageResult};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+#[derive(Clone)]
+pub struct SqliteStorage {
+    pool: SqlitePool,
+    write_lock: Arc<Mutex<()>>,
+    blobs: Option<Arc<dyn BlobStore>>,
+    image_locks: Arc<ImageLocks>,
+}
+
+impl SqliteStorage {
+    /// Opens (creating if needed) the database file at `path`; call
+    /// [`SqliteStorage::migrate`] before use.
+    pub async fn connect(path: &str) -> StorageResult<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(5));
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await?;
+        Ok(Self {
+            pool,
+            write_lock: Arc::new(Mutex::new(())),
+            blobs: None,
+            image_locks: Arc::default(),
+        })
+    }
+
+    /// Keeps image ciphertext in `blobs` instead of `diary_images.blob_data`.
+    /// Rows written inline before the switch stay readable.
+    pub fn with_blob_store(mut self, blobs: Arc<dyn BlobStore>) -> Self {
+        self.blobs = Some(blobs);
+        self
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    /// Brings the schema up to date (or only verifies it) and returns its version.
+    pub async fn migrate(&self, mode: MigrationMode) -> StorageResult<i64> {
+        sql::migrate(self, &MIGRATOR, mode).await
+    }
+}
+
+impl Dialect for sqlx::Sqlite {
+    const FOR_UPDATE: &'static str = "";
+    const UUID_COLLATE: &'static str = "";
+    const START_DATE: &'static str = "start_date";
+    const END_DATE: &'static str = "end_date";
+    const BUMP_CHANGE_SEQ: &'static str = "";
+    const GREATEST: &'static str = "MAX";
+}
+
+impl RowsAffected for SqliteQueryResult {
+    fn rows_affected(&self) -> u64 {
+        SqliteQueryResult::rows_affected(self)
+    }
+}
+
+#[async_trait]
+impl SqlBackend for SqliteStorage {
+    type Db = sqlx::Sqlite;
+    type Guard = OwnedMutexGuard<()>;
+
+    fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    fn blobs(&self) -> Option<&dyn BlobStore> {
+        self.blobs.as_deref()
+    }
+
+    fn image_locks(&self) -> &ImageLocks {
+        &self.image_locks
+    }
+
+    /// SQLite allows one writer at a time, and a deferred transaction that
+    /// upgrades to a write while another writer is active fails with
+    /// `SQLITE_BUSY` instead of waiting. Serializing write transactions here
+    /// avoids that and, like the Postgres advisory lock, keeps change
+    /// sequences committing in order.
+    async fn begin_write(
+        &self,
+    ) -> StorageResult<(
+        OwnedMutexGuard<()>,
+        sqlx::Transaction<'static, sqlx::Sqlite>,
+    )> {
+        let guard = self.write_lock.clone().lock_owned().await;
+        let tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|e| StorageError::from(e).context("db transaction start failed"))?;
+        Ok((guard, tx))
+    }
+
+    /// Writes are already serialized by `begin_write`.
+    async fn begin_sync_write(
+        &self,
+    ) -> StorageResult<(
+        OwnedMutexGuard<()>,
+        sqlx::Transaction<'static, sqlx::Sqlite>,
+    )> {
+        self.begin_write().await
+    }
+
+    /// In WAL mode a deferred transaction keeps the snapshot of its first read,
+    /// and readers do not wait for the write lock.
+    async fn begin_read(&self) -> StorageResult<sqlx::Transaction<'static, sqlx::Sqlite>> {
+        self.pool
+            .begin()
+            .await
+            .map_err(|e| StorageError::from(e).context("db transaction start failed"))
+    }
+}
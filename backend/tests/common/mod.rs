@@ -0,0 +1,52 @@
+//! Fixtures shared by the test binaries that always run.
+
+use syezw_sync_backend::db::EnvConfig;
+
+/// A fixed configuration with the documented defaults, so nothing set in the
+/// developer's or CI's environment changes what these tests see. Auth is
+/// off, admin endpoints are off and signing is off until a test turns them
+/// on.
+pub fn test_env() -> EnvConfig {
+    EnvConfig {
+        storage_backend: "postgres".to_string(),
+        sqlite_path: "syezw.db".to_string(),
+        blob_store: "database".to_string(),
+        blob_dir: "blobs".to_string(),
+        s3_endpoint: String::new(),
+        s3_bucket: String::new(),
+        s3_region: "us-east-1".to_string(),
+        s3_access_key: String::new(),
+        s3_secret_key: String::new(),
+        s3_prefix: String::new(),
+        auto_migrate: true,
+        host: "localhost".to_string(),
+        port: 5432,
+        database: "syezw".to_string(),
+        user: "postgres".to_string(),
+        password: "postgres".to_string(),
+        api_key: String::new(),
+        account_keys: vec![],
+        history_max_revisions: 20,
+        admin_key: String::new(),
+        image_gc_grace_secs: 7 * 24 * 3600,
+        image_gc_interval_secs: 0,
+        token_secret: "test-token-secret".to_string(),
+        access_token_ttl_secs: 15 * 60,
+        refresh_token_ttl_secs: 30 * 24 * 3600,
+        signing_secret: String::new(),
+        signature_max_skew_secs: 300,
+        auth_lockout_threshold: 5,
+        auth_lockout_base_secs: 60,
+        auth_lockout_max_secs: 3600,
+        trusted_proxies: vec![],
+        auth_failure_retention_secs: 30 * 24 * 3600,
+        image_upload_ttl_secs: 24 * 3600,
+        rate_limit_ip_per_min: 600,
+        rate_limit_meta_per_min: 120,
+        rate_limit_download_per_min: 60,
+        rate_limit_upload_per_min: 60,
+        rate_limit_image_upload_per_min: 600,
+        upload_bytes_per_hour: 2 * 1024 * 1024 * 1024,
+        rate_limit_max_devices: 5,
+    }
+}
@@ -0,0 +1,2080 @@
+//! The full app from `main.rs` against `MemoryStorage`; needs no database.
+
+mod common;
+
+use actix_web::http::header::{HeaderValue, TRANSFER_ENCODING};
+use actix_web::middleware::from_fn;
+use actix_web::{test, web, App};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use common::test_env;
+use std::sync::Arc;
+use syezw_sync_backend::db::{parse_account_keys, AccountKey};
+use syezw_sync_backend::gc::{collect_image_garbage, sweep_expired};
+use syezw_sync_backend::models::{
+    ApiKeyCreateResponse, ApiKeyInfo, ApiKeyListResponse, ApiScope, AuthFailureListResponse,
+    AuthLockoutListResponse, AuthRefreshRequest, AuthTokenResponse, DeviceListResponse,
+    DeviceRegisterRequest, DiaryImageRefItem, DiaryImageSyncItem, DiarySyncItem, EncryptedBlob,
+    HistoryListRequest, HistoryListResponse, HistoryRestoreRequest, HistoryRestoreResponse,
+    ImageFetchRequest, ImageFetchResponse, ImageGcRequest, ImageGcResponse, ImageHashListResponse,
+    ImageHashSize, ImageMissingRequest, ImageMissingResponse, ImageRefKey, ImageRefsChangeResponse,
+    ImageRefsDeleteRequest, ImageRefsReplaceRequest, ImageRefsRequest, ImageRefsResponse,
+    ImageRefsUpsertRequest, ImageUploadBeginRequest, ImageUploadRequest, ImageUploadStatus,
+    PeriodMeta, PeriodSyncItem, RecordKind, SessionRevokeRequest, SessionRevokeResponse,
+    SyncChangesResponse, SyncDownloadEnvelope, SyncDownloadRequest, SyncMeta, SyncMetaResponse,
+    SyncUploadRequest, SyncUploadResponse, TodoSyncItem, TombstoneItem,
+};
+use syezw_sync_backend::ratelimit::limit_per_ip;
+use syezw_sync_backend::signing::{
+    sign_request, verify_request_signature, SIGNATURE_HEADER, SIGNATURE_NONCE_HEADER,
+    SIGNATURE_TIMESTAMP_HEADER,
+};
+use syezw_sync_backend::storage::{BlobStore, FsBlobStore, MemoryStorage, NewImageUpload, Storage};
+use syezw_sync_backend::{
+    configure_routes, AppState, DEVICE_ID_HEADER, IMAGE_IV_HEADER, IMAGE_UPDATED_AT_HEADER,
+    MAX_IMAGE_BYTES, MAX_MISSING_QUERY_IMAGES, MAX_REF_FILTER_DIARIES, MAX_UPLOAD_CHUNK_BYTES,
+};
+
+const API_KEY: &str = "memory-test-key";
+const OTHER_KEY: &str = "memory-other-key";
+
+fn app_state() -> AppState {
+    let mut env = test_env();
+    env.api_key = API_KEY.to_string();
+    env.account_keys = vec![AccountKey {
+        account_id: "other".to_string(),
+        key: OTHER_KEY.to_string(),
+    }];
+    env.history_max_revisions = 20;
+    AppState::new(env, Arc::new(MemoryStorage::new()))
+}
+
+macro_rules! init_app {
+    () => {
+        test::init_service(
+            App::new()
+                .wrap(from_fn(verify_request_signature))
+                .app_data(web::Data::new(app_state()))
+                .configure(configure_routes),
+        )
+        .await
+    };
+}
+
+fn blob() -> EncryptedBlob {
+    EncryptedBlob {
+        iv: "iv".to_string(),
+        data: "data".to_string(),
+    }
+}
+
+fn diary(uuid: &str, updated_at: i64) -> DiarySyncItem {
+    DiarySyncItem {
+        uuid: uuid.to_string(),
+        author: "a".to_string(),
+        timestamp: 1,
+        updated_at,
+        payload: EncryptedBlob {
+            iv: "iv".to_string(),
+            data: format!("data@{}", updated_at),
+        },
+        base_updated_at: None,
+    }
+}
+
+fn image(diary_uuid: &str, hash: &str) -> DiaryImageSyncItem {
+    DiaryImageSyncItem {
+        file_name: "img.jpg".to_string(),
+        diary_uuid: diary_uuid.to_string(),
+        hash: hash.to_string(),
+        updated_at: 6,
+        blob: blob(),
+    }
+}
+
+fn upload_of(diaries: Vec<DiarySyncItem>, deletions: Vec<TombstoneItem>) -> SyncUploadRequest {
+    SyncUploadRequest {
+        diaries,
+        todos: vec![],
+        periods: vec![],
+        images: vec![],
+        deletions,
+    }
+}
+
+fn download(limit: Option<usize>, page_token: Option<String>) -> SyncDownloadRequest {
+    SyncDownloadRequest {
+        diaries: vec![],
+        todos: vec![],
+        periods: vec![],
+        limit,
+        max_bytes: None,
+        page_token,
+    }
+}
+
+fn post<T: serde::Serialize>(uri: &str, key: &str, body: &T) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri(uri)
+        .insert_header(("X-API-Key", key))
+        .set_json(body)
+}
+
+#[actix_web::test]
+async fn memory_upload_then_download_round_trip() {
+    let app = init_app!();
+
+    let upload = SyncUploadRequest {
+        diaries: vec![diary("d_1", 2), diary("d_2", 2), diary("d_3", 2)],
+        todos: vec![TodoSyncItem {
+            uuid: "t_1".to_string(),
+            author: "a".to_string(),
+            is_completed: true,
+            created_at: 3,
+            completed_at: Some(4),
+            updated_at: 4,
+            payload: blob(),
+            base_updated_at: None,
+        }],
+        periods: vec![PeriodSyncItem {
+            uuid: String::new(),
+            start_date: "2025-01-01".to_string(),
+            end_date: "2025-01-05".to_string(),
+            updated_at: 5,
+            payload: blob(),
+            base_updated_at: None,
+        }],
+        images: vec![],
+        deletions: vec![],
+    };
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, post("/sync/upload", API_KEY, &upload).to_request())
+            .await;
+    assert!(resp.ok);
+    assert_eq!(
+        (resp.counts.diaries, resp.counts.todos, resp.counts.periods),
+        (3, 1, 1)
+    );
+
+    // Pages of two items resume where the previous one stopped.
+    let mut diaries = Vec::new();
+    let mut page_token = None;
+    loop {
+        let resp: SyncDownloadEnvelope = test::call_and_read_body_json(
+            &app,
+            post("/sync/download", API_KEY, &download(Some(2), page_token)).to_request(),
+        )
+        .await;
+        assert!(resp.ok);
+        diaries.extend(resp.data.diaries.into_iter().map(|d| d.uuid));
+        match resp.next_page_token {
+            Some(token) => page_token = Some(token),
+            None => break,
+        }
+    }
+    assert_eq!(diaries, vec!["d_1", "d_2", "d_3"]);
+
+    let resp: SyncDownloadEnvelope = test::call_and_read_body_json(
+        &app,
+        post("/sync/download", API_KEY, &download(None, None)).to_request(),
+    )
+    .await;
+    assert!(resp
+        .data
+        .todos
+        .iter()
+        .any(|t| t.uuid == "t_1" && t.is_completed && t.completed_at == Some(4)));
+    assert!(resp
+        .data
+        .periods
+        .iter()
+        .any(|p| p.uuid == "period-2025-01-01"
+            && p.start_date == "2025-01-01"
+            && p.end_date == "2025-01-05"));
+
+    // An older version of a diary is rejected with the server's version.
+    let resp: SyncUploadResponse = test::call_and_read_body_json(
+        &app,
+        post(
+            "/sync/upload",
+            API_KEY,
+            &upload_of(vec![diary("d_1", 1)], vec![]),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.counts.diaries, 0);
+    assert_eq!(resp.rejected.len(), 1);
+    assert_eq!(resp.rejected[0].server_updated_at, 2);
+
+    // A malformed period date fails the whole batch, leaving nothing behind.
+    let mut bad = upload_of(vec![diary("d_bad", 1)], vec![]);
+    bad.periods = vec![PeriodSyncItem {
+        uuid: "p_bad".to_string(),
+        start_date: "not-a-date".to_string(),
+        end_date: "2025-01-05".to_string(),
+        updated_at: 1,
+        payload: blob(),
+        base_updated_at: None,
+    }];
+    let resp = test::call_service(&app, post("/sync/upload", API_KEY, &bad).to_request()).await;
+    assert!(resp.status().is_server_error());
+
+    let req = test::TestRequest::post()
+        .uri("/sync/meta")
+        .insert_header(("X-API-Key", API_KEY))
+        .to_request();
+    let meta: SyncMetaResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(meta.diaries.len(), 3);
+    assert!(!meta.diaries.iter().any(|d| d.uuid == "d_bad"));
+    assert_eq!(meta.todos.len(), 1);
+    assert_eq!(meta.periods.len(), 1);
+}
+
+#[actix_web::test]
+async fn memory_image_hash_dedup_and_fetch() {
+    let app = init_app!();
+
+    let mut upload = upload_of(vec![diary("d_img", 2)], vec![]);
+    upload.images = vec![image("d_img", "hash123")];
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, post("/sync/upload", API_KEY, &upload).to_request())
+            .await;
+    assert_eq!(resp.counts.images, 1);
+
+    // The same blob uploaded again, then referenced from a second diary.
+    let upload = ImageUploadRequest {
+        images: vec![image("d_img", "hash123")],
+    };
+    let resp =
+        test::call_service(&app, post("/images/upload", API_KEY, &upload).to_request()).await;
+    assert!(resp.status().is_success());
+    let refs = ImageRefsUpsertRequest {
+        refs: vec![DiaryImageRefItem {
+            diary_uuid: "d_other".to_string(),
+            file_name: "img.jpg".to_string(),
+            hash: "hash123".to_string(),
+            updated_at: 7,
+        }],
+    };
+    let resp = test::call_service(
+        &app,
+        post("/images/refs/upsert", API_KEY, &refs).to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+
+    let req = test::TestRequest::post()
+        .uri("/images/hashes")
+        .insert_header(("X-API-Key", API_KEY))
+        .to_request();
+    let resp: ImageHashListResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.hashes, vec!["hash123".to_string()]);
+
+    let fetch = |diary_uuid: &str| ImageFetchRequest {
+        diary_uuid: diary_uuid.to_string(),
+        file_name: "img.jpg".to_string(),
+    };
+    let resp: ImageFetchResponse = test::call_and_read_body_json(
+        &app,
+        post("/images/fetch", API_KEY, &fetch("d_other")).to_request(),
+    )
+    .await;
+    assert_eq!(resp.hash, "hash123");
+    assert_eq!(resp.updated_at, 7);
+    assert_eq!(resp.blob.data, "data");
+
+    let resp = test::call_service(
+        &app,
+        post("/images/fetch", API_KEY, &fetch("d_missing")).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 404);
+
+    // Deleting a diary drops its refs but keeps the shared blob.
+    let deletion = upload_of(
+        vec![],
+        vec![TombstoneItem {
+            uuid: "d_img".to_string(),
+            kind: RecordKind::Diary,
+            deleted_at: 10,
+            base_updated_at: None,
+            start_date: None,
+        }],
+    );
+    let resp =
+        test::call_service(&app, post("/sync/upload", API_KEY, &deletion).to_request()).await;
+    assert!(resp.status().is_success());
+    let req = test::TestRequest::post()
+        .uri("/images/refs")
+        .insert_header(("X-API-Key", API_KEY))
+        .to_request();
+    let resp: ImageRefsResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.refs.len(), 1);
+    assert_eq!(resp.refs[0].diary_uuid, "d_other");
+    let resp = test::call_service(
+        &app,
+        post("/images/fetch", API_KEY, &fetch("d_other")).to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+}
+
+#[actix_web::test]
+async fn memory_changes_deletions_and_history() {
+    let app = init_app!();
+    let changes = |since: i64| {
+        test::TestRequest::get()
+            .uri(&format!("/sync/changes?since={}", since))
+            .insert_header(("X-API-Key", API_KEY))
+            .to_request()
+    };
+
+    let upload = upload_of(vec![diary("d_h", 1)], vec![]);
+    test::call_service(&app, post("/sync/upload", API_KEY, &upload).to_request()).await;
+    let first: SyncChangesResponse = test::call_and_read_body_json(&app, changes(0)).await;
+    assert_eq!(first.diaries.len(), 1);
+    assert!(first.cursor > 0);
+
+    // A different payload stamped with the stored instant is a conflict, not a replay.
+    let mut tied = diary("d_h", 1);
+    tied.payload.data = "tied".to_string();
+    let upload = upload_of(vec![tied], vec![]);
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, post("/sync/upload", API_KEY, &upload).to_request())
+            .await;
+    assert_eq!(resp.counts.diaries, 0);
+    assert_eq!(resp.rejected.len(), 1);
+    assert_eq!(resp.rejected[0].server_updated_at, 1);
+
+    // Overwrite, then delete: both bump the cursor and archive the old version.
+    let upload = upload_of(vec![diary("d_h", 2)], vec![]);
+    test::call_service(&app, post("/sync/upload", API_KEY, &upload).to_request()).await;
+    let deletion = upload_of(
+        vec![],
+        vec![TombstoneItem {
+            uuid: "d_h".to_string(),
+            kind: RecordKind::Diary,
+            deleted_at: 3,
+            base_updated_at: None,
+            start_date: None,
+        }],
+    );
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, post("/sync/upload", API_KEY, &deletion).to_request())
+            .await;
+    assert_eq!(resp.counts.deletions, 1);
+
+    // A stale copy from another device must not resurrect it.
+    let upload = upload_of(vec![diary("d_h", 2)], vec![]);
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, post("/sync/upload", API_KEY, &upload).to_request())
+            .await;
+    assert_eq!(resp.rejected.len(), 1);
+    assert!(resp.rejected[0].deleted);
+
+    let later: SyncChangesResponse =
+        test::call_and_read_body_json(&app, changes(first.cursor)).await;
+    assert!(later.diaries.is_empty());
+    assert_eq!(later.deletions.len(), 1);
+    assert!(later.cursor > first.cursor);
+
+    let list = HistoryListRequest {
+        kind: RecordKind::Diary,
+        uuid: "d_h".to_string(),
+    };
+    let history: HistoryListResponse =
+        test::call_and_read_body_json(&app, post("/history/list", API_KEY, &list).to_request())
+            .await;
+    assert_eq!(history.revisions.len(), 2);
+    assert_eq!(history.revisions[0].payload.data, "data@2");
+
+    let restore = HistoryRestoreRequest {
+        kind: RecordKind::Diary,
+        uuid: "d_h".to_string(),
+        revision_id: history.revisions[1].revision_id,
+    };
+    let restored: HistoryRestoreResponse = test::call_and_read_body_json(
+        &app,
+        post("/history/restore", API_KEY, &restore).to_request(),
+    )
+    .await;
+    assert!(restored.ok);
+    assert!(restored.updated_at > 3);
+
+    let after: SyncChangesResponse =
+        test::call_and_read_body_json(&app, changes(later.cursor)).await;
+    assert_eq!(after.diaries.len(), 1);
+    assert_eq!(after.diaries[0].payload.data, "data@1");
+    assert_eq!(after.diaries[0].updated_at, restored.updated_at);
+
+    let unknown = HistoryRestoreRequest {
+        revision_id: 9999,
+        ..restore
+    };
+    let resp = test::call_service(
+        &app,
+        post("/history/restore", API_KEY, &unknown).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 404);
+}
+
+#[actix_web::test]
+async fn memory_base_match_from_a_slow_clock_still_reaches_other_devices() {
+    let app = init_app!();
+    let upload = upload_of(vec![diary("d_skew", 100)], vec![]);
+    test::call_service(&app, post("/sync/upload", API_KEY, &upload).to_request()).await;
+
+    // Edited on top of the stored version by a device whose clock is behind.
+    let mut edit = diary("d_skew", 50);
+    edit.base_updated_at = Some(100);
+    let resp: SyncUploadResponse = test::call_and_read_body_json(
+        &app,
+        post("/sync/upload", API_KEY, &upload_of(vec![edit], vec![])).to_request(),
+    )
+    .await;
+    assert_eq!(resp.counts.diaries, 1);
+
+    // A device still holding the version at 100 must see the edit as newer.
+    let mut request = download(None, None);
+    request.diaries = vec![SyncMeta {
+        uuid: "d_skew".to_string(),
+        updated_at: 100,
+    }];
+    let resp: SyncDownloadEnvelope =
+        test::call_and_read_body_json(&app, post("/sync/download", API_KEY, &request).to_request())
+            .await;
+    let seen = resp.data.diaries.iter().find(|d| d.uuid == "d_skew");
+    assert_eq!(seen.map(|d| d.payload.data.as_str()), Some("data@50"));
+    assert_eq!(seen.map(|d| d.updated_at), Some(101));
+}
+
+#[actix_web::test]
+async fn memory_period_deletions_reach_clients_keyed_by_start_date() {
+    let app = init_app!();
+    let mut upload = upload_of(vec![], vec![]);
+    upload.periods = vec![PeriodSyncItem {
+        uuid: "p_uuid".to_string(),
+        start_date: "2025-02-01".to_string(),
+        end_date: "2025-02-05".to_string(),
+        updated_at: 1,
+        payload: blob(),
+        base_updated_at: None,
+    }];
+    test::call_service(&app, post("/sync/upload", API_KEY, &upload).to_request()).await;
+    let deletion = upload_of(
+        vec![],
+        vec![TombstoneItem {
+            uuid: "p_uuid".to_string(),
+            kind: RecordKind::Period,
+            deleted_at: 2,
+            base_updated_at: None,
+            start_date: None,
+        }],
+    );
+    test::call_service(&app, post("/sync/upload", API_KEY, &deletion).to_request()).await;
+
+    // An older client holds the period by start date only.
+    let mut request = download(None, None);
+    request.periods = vec![PeriodMeta {
+        uuid: String::new(),
+        start_date: "2025-02-01".to_string(),
+        updated_at: 1,
+    }];
+    let resp: SyncDownloadEnvelope =
+        test::call_and_read_body_json(&app, post("/sync/download", API_KEY, &request).to_request())
+            .await;
+    assert_eq!(resp.data.deletions.len(), 1);
+    assert_eq!(resp.data.deletions[0].uuid, "2025-02-01");
+    assert_eq!(resp.data.deletions[0].kind, RecordKind::Period);
+}
+
+#[actix_web::test]
+async fn memory_devices_and_accounts_are_isolated() {
+    let app = init_app!();
+
+    let register = DeviceRegisterRequest {
+        device_id: "phone".to_string(),
+        name: "Pixel".to_string(),
+        app_version: "1.2.3".to_string(),
+    };
+    let resp = test::call_service(
+        &app,
+        post("/devices/register", API_KEY, &register).to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", API_KEY))
+        .insert_header((DEVICE_ID_HEADER, "phone"))
+        .set_json(upload_of(vec![diary("shared", 5)], vec![]))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let devices = |key: &str| {
+        test::TestRequest::post()
+            .uri("/devices")
+            .insert_header(("X-API-Key", key))
+            .to_request()
+    };
+    let resp: DeviceListResponse = test::call_and_read_body_json(&app, devices(API_KEY)).await;
+    assert_eq!(resp.devices.len(), 1);
+    assert_eq!(resp.devices[0].name, "Pixel");
+    assert!(resp.devices[0].last_upload_at.is_some());
+    assert!(resp.devices[0].last_download_at.is_none());
+
+    // The other account sees none of that, and its writes to the same uuid stay its own.
+    let resp: DeviceListResponse = test::call_and_read_body_json(&app, devices(OTHER_KEY)).await;
+    assert!(resp.devices.is_empty());
+    let resp: SyncUploadResponse = test::call_and_read_body_json(
+        &app,
+        post(
+            "/sync/upload",
+            OTHER_KEY,
+            &upload_of(vec![diary("shared", 1)], vec![]),
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.counts.diaries, 1);
+    let resp: SyncDownloadEnvelope = test::call_and_read_body_json(
+        &app,
+        post("/sync/download", API_KEY, &download(None, None)).to_request(),
+    )
+    .await;
+    assert_eq!(resp.data.diaries.len(), 1);
+    assert_eq!(resp.data.diaries[0].updated_at, 5);
+
+    let resp = test::call_service(
+        &app,
+        post("/sync/download", "wrong", &download(None, None)).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 401);
+}
+
+#[actix_web::test]
+async fn memory_images_go_to_fs_blob_store() {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let root = std::env::temp_dir().join(format!("syezw_blobs_{}", nanos));
+    let blobs = FsBlobStore::open(&root).await.expect("open blob dir");
+    let mut state = app_state();
+    state.storage = Arc::new(MemoryStorage::new().with_blob_store(Arc::new(blobs)));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+
+    let ciphertext = b"\x00\x01 encrypted photo bytes \xff";
+    let mut item = image("d_fs", "abc123");
+    item.blob.data = STANDARD.encode(ciphertext);
+    let mut upload = upload_of(vec![diary("d_fs", 2)], vec![]);
+    upload.images = vec![item.clone()];
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, post("/sync/upload", API_KEY, &upload).to_request())
+            .await;
+    assert_eq!(resp.counts.images, 1);
+
+    // The raw ciphertext sits under the hash path; no temp files are left behind.
+    let path = root.join("default").join("ab").join("abc123");
+    assert_eq!(std::fs::read(&path).expect("blob file"), ciphertext);
+    assert_eq!(std::fs::read_dir(root.join("tmp")).unwrap().count(), 0);
+
+    let fetch = ImageFetchRequest {
+        diary_uuid: "d_fs".to_string(),
+        file_name: "img.jpg".to_string(),
+    };
+    let resp: ImageFetchResponse =
+        test::call_and_read_body_json(&app, post("/images/fetch", API_KEY, &fetch).to_request())
+            .await;
+    assert_eq!(resp.blob.data, item.blob.data);
+    assert_eq!(resp.blob.iv, "iv");
+
+    // Replacing the blob through /images/upload overwrites the file.
+    item.blob.data = STANDARD.encode(b"second version");
+    let replace = ImageUploadRequest {
+        images: vec![item.clone()],
+    };
+    let resp =
+        test::call_service(&app, post("/images/upload", API_KEY, &replace).to_request()).await;
+    assert!(resp.status().is_success());
+    assert_eq!(std::fs::read(&path).unwrap(), b"second version");
+
+    // Binary uploads are written to the file as they arrive; one over the
+    // limit is refused and leaves the stored blob and no temp file behind.
+    let put = |data: Vec<u8>| {
+        test::TestRequest::put()
+            .uri("/images/blob/abc123")
+            .insert_header(("X-API-Key", API_KEY))
+            .insert_header((IMAGE_IV_HEADER, "iv"))
+            .set_payload(data)
+            .to_request()
+    };
+    assert!(test::call_service(&app, put(b"third version".to_vec()))
+        .await
+        .status()
+        .is_success());
+    assert_eq!(std::fs::read(&path).unwrap(), b"third version");
+    let resp = test::call_service(&app, put(vec![1u8; MAX_IMAGE_BYTES + 1])).await;
+    assert_eq!(resp.status(), 413);
+    assert_eq!(std::fs::read(&path).unwrap(), b"third version");
+    assert_eq!(std::fs::read_dir(root.join("tmp")).unwrap().count(), 0);
+
+    // Hashes are used in paths, so anything but [A-Za-z0-9_-] is refused.
+    let mut bad = image("d_fs", "../escape");
+    bad.blob.data = STANDARD.encode(b"x");
+    let resp = test::call_service(
+        &app,
+        post(
+            "/images/upload",
+            API_KEY,
+            &ImageUploadRequest { images: vec![bad] },
+        )
+        .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+    let mut upload = upload_of(vec![diary("d_fs", 1)], vec![]);
+    upload.images = vec![image("d_fs", "../escape")];
+    let resp = test::call_service(&app, post("/sync/upload", API_KEY, &upload).to_request()).await;
+    assert_eq!(resp.status(), 400);
+    assert!(!root.join("escape").exists());
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[actix_web::test]
+async fn memory_binary_image_upload_and_download() {
+    let app = init_app!();
+    let ciphertext = b"\x00\xffraw ciphertext, no base64\x10";
+    let put = |uri: &str, key: &str| {
+        test::TestRequest::put()
+            .uri(uri)
+            .insert_header(("X-API-Key", key))
+            .insert_header(("Content-Type", "application/octet-stream"))
+    };
+    let req = put("/images/blob/bin1", API_KEY)
+        .insert_header((IMAGE_IV_HEADER, "iv-bin"))
+        .insert_header((IMAGE_UPDATED_AT_HEADER, "42"))
+        .set_payload(&ciphertext[..])
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/images/blob/bin1")
+        .insert_header(("X-API-Key", API_KEY))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers().get(IMAGE_IV_HEADER).unwrap(), "iv-bin");
+    assert_eq!(resp.headers().get(IMAGE_UPDATED_AT_HEADER).unwrap(), "42");
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/octet-stream"
+    );
+    assert_eq!(test::read_body(resp).await, &ciphertext[..]);
+
+    // Binary uploads and the JSON endpoints see the same image rows.
+    let refs = ImageRefsUpsertRequest {
+        refs: vec![DiaryImageRefItem {
+            diary_uuid: "d_bin".to_string(),
+            file_name: "bin.jpg".to_string(),
+            hash: "bin1".to_string(),
+            updated_at: 42,
+        }],
+    };
+    let resp = test::call_service(
+        &app,
+        post("/images/refs/upsert", API_KEY, &refs).to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let fetch = ImageFetchRequest {
+        diary_uuid: "d_bin".to_string(),
+        file_name: "bin.jpg".to_string(),
+    };
+    let resp: ImageFetchResponse =
+        test::call_and_read_body_json(&app, post("/images/fetch", API_KEY, &fetch).to_request())
+            .await;
+    assert_eq!(resp.blob.data, STANDARD.encode(ciphertext));
+    assert_eq!(resp.blob.iv, "iv-bin");
+
+    let mut item = image("d_json", "json1");
+    item.blob.data = STANDARD.encode(b"from json");
+    let upload = ImageUploadRequest { images: vec![item] };
+    let resp =
+        test::call_service(&app, post("/images/upload", API_KEY, &upload).to_request()).await;
+    assert!(resp.status().is_success());
+    let req = test::TestRequest::get()
+        .uri("/images/blob/json1")
+        .insert_header(("X-API-Key", API_KEY))
+        .to_request();
+    assert_eq!(test::call_and_read_body(&app, req).await, &b"from json"[..]);
+
+    // The IV is required, and other accounts cannot read the blob.
+    let req = put("/images/blob/bin2", API_KEY)
+        .set_payload(&b"x"[..])
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+    let req = test::TestRequest::get()
+        .uri("/images/blob/bin1")
+        .insert_header(("X-API-Key", OTHER_KEY))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+    let req = put("/images/blob/bin1", "wrong-key")
+        .insert_header((IMAGE_IV_HEADER, "iv"))
+        .set_payload(&b"x"[..])
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+}
+
+#[actix_web::test]
+async fn memory_resumable_upload_survives_a_disconnect() {
+    let app = init_app!();
+    let ciphertext: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+    let chunk = |offset: usize, data: &[u8]| {
+        test::TestRequest::put()
+            .uri(&format!("/images/uploads/big1?offset={}", offset))
+            .insert_header(("X-API-Key", API_KEY))
+            .set_payload(data.to_vec())
+            .to_request()
+    };
+    let get = |uri: &str| {
+        test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("X-API-Key", API_KEY))
+            .to_request()
+    };
+    let begin = ImageUploadBeginRequest {
+        iv: "iv-big".to_string(),
+        size: 1000,
+        updated_at: Some(77),
+    };
+    let status: ImageUploadStatus = test::call_and_read_body_json(
+        &app,
+        post("/images/uploads/big1", API_KEY, &begin).to_request(),
+    )
+    .await;
+    assert_eq!(status.offset, 0);
+
+    let status: ImageUploadStatus =
+        test::call_and_read_body_json(&app, chunk(0, &ciphertext[..400])).await;
+    assert_eq!(status.offset, 400);
+
+    // After a disconnect the client asks where to resume; beginning again
+    // with the same parameters keeps what was received.
+    let status: ImageUploadStatus =
+        test::call_and_read_body_json(&app, get("/images/uploads/big1")).await;
+    assert_eq!((status.offset, status.size), (400, 1000));
+    let status: ImageUploadStatus = test::call_and_read_body_json(
+        &app,
+        post("/images/uploads/big1", API_KEY, &begin).to_request(),
+    )
+    .await;
+    assert_eq!(status.offset, 400);
+
+    // A resent or skipped chunk is refused with the offset to use.
+    let resp = test::call_service(&app, chunk(0, &ciphertext[..400])).await;
+    assert_eq!(resp.status(), 409);
+    let status: ImageUploadStatus = test::read_body_json(resp).await;
+    assert_eq!(status.offset, 400);
+    let resp = test::call_service(&app, chunk(400, &ciphertext[..700])).await;
+    assert_eq!(resp.status(), 400);
+    let resp = test::call_service(&app, chunk(400, &vec![0u8; MAX_UPLOAD_CHUNK_BYTES + 1])).await;
+    assert_eq!(resp.status(), 413);
+
+    // Nothing is visible until the upload is finalized.
+    let finalize = || post("/images/uploads/big1/finalize", API_KEY, &()).to_request();
+    assert_eq!(test::call_service(&app, finalize()).await.status(), 409);
+    assert_eq!(
+        test::call_service(&app, get("/images/blob/big1"))
+            .await
+            .status(),
+        404
+    );
+    let hashes: ImageHashListResponse =
+        test::call_and_read_body_json(&app, post("/images/hashes", API_KEY, &()).to_request())
+            .await;
+    assert!(!hashes.hashes.contains(&"big1".to_string()));
+
+    let status: ImageUploadStatus =
+        test::call_and_read_body_json(&app, chunk(400, &ciphertext[400..])).await;
+    assert_eq!(status.offset, 1000);
+    assert!(test::call_service(&app, finalize())
+        .await
+        .status()
+        .is_success());
+
+    let resp = test::call_service(&app, get("/images/blob/big1")).await;
+    assert_eq!(resp.headers().get(IMAGE_IV_HEADER).unwrap(), "iv-big");
+    assert_eq!(resp.headers().get(IMAGE_UPDATED_AT_HEADER).unwrap(), "77");
+    assert_eq!(test::read_body(resp).await, ciphertext);
+    assert_eq!(
+        test::call_service(&app, get("/images/uploads/big1"))
+            .await
+            .status(),
+        404
+    );
+
+    // Sessions are per account.
+    let req = test::TestRequest::put()
+        .uri("/images/uploads/big1?offset=0")
+        .insert_header(("X-API-Key", OTHER_KEY))
+        .set_payload(vec![1u8])
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+    // The expiry sweep drops sessions idle for longer than IMAGE_UPLOAD_TTL_SECS.
+    let storage = MemoryStorage::new();
+    let upload = NewImageUpload {
+        iv: "iv".to_string(),
+        size: 10,
+        updated_at: 1,
+    };
+    storage
+        .begin_image_upload("default", "stale", &upload, 10)
+        .await
+        .unwrap();
+    storage
+        .append_image_chunk("default", "stale", 0, b"01234", 20)
+        .await
+        .unwrap();
+    let mut env = test_env();
+    env.image_upload_ttl_secs = 1;
+    let report = sweep_expired(&storage, &env, 1020).await.unwrap();
+    assert_eq!(report.image_uploads, 0);
+    let report = sweep_expired(&storage, &env, 1021).await.unwrap();
+    assert_eq!(report.image_uploads, 1);
+    assert!(storage
+        .image_upload_status("default", "stale")
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[actix_web::test]
+async fn memory_image_etag_and_range_requests() {
+    let app = init_app!();
+    let req = test::TestRequest::put()
+        .uri("/images/blob/etag1")
+        .insert_header(("X-API-Key", API_KEY))
+        .insert_header((IMAGE_IV_HEADER, "iv-e"))
+        .set_payload(&b"0123456789"[..])
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let get = |headers: &[(&'static str, &'static str)]| {
+        let mut req = test::TestRequest::get()
+            .uri("/images/blob/etag1")
+            .insert_header(("X-API-Key", API_KEY));
+        for &header in headers {
+            req = req.insert_header(header);
+        }
+        req.to_request()
+    };
+    let header = |resp: &actix_web::dev::ServiceResponse, name: &str| {
+        resp.headers()
+            .get(name)
+            .map(|v| v.to_str().unwrap().to_string())
+    };
+
+    let resp = test::call_service(&app, get(&[])).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(header(&resp, "etag").as_deref(), Some("\"etag1\""));
+    assert_eq!(header(&resp, "accept-ranges").as_deref(), Some("bytes"));
+
+    // A client that already has the image gets a 304 with no body.
+    for tag in ["\"etag1\"", "W/\"etag1\"", "\"other\", \"etag1\"", "*"] {
+        let req = test::TestRequest::get()
+            .uri("/images/blob/etag1")
+            .insert_header(("X-API-Key", API_KEY))
+            .insert_header(("If-None-Match", tag))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 304, "{}", tag);
+        assert_eq!(header(&resp, "etag").as_deref(), Some("\"etag1\""));
+        assert!(test::read_body(resp).await.is_empty());
+    }
+    let resp = test::call_service(&app, get(&[("If-None-Match", "\"other\"")])).await;
+    assert_eq!(resp.status(), 200);
+    let req = test::TestRequest::get()
+        .uri("/images/blob/nope")
+        .insert_header(("X-API-Key", API_KEY))
+        .insert_header(("If-None-Match", "\"nope\""))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+    // Ranges.
+    let resp = test::call_service(&app, get(&[("Range", "bytes=2-5")])).await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(
+        header(&resp, "content-range").as_deref(),
+        Some("bytes 2-5/10")
+    );
+    assert_eq!(test::read_body(resp).await, &b"2345"[..]);
+    let resp = test::call_service(&app, get(&[("Range", "bytes=-3")])).await;
+    assert_eq!(
+        header(&resp, "content-range").as_deref(),
+        Some("bytes 7-9/10")
+    );
+    assert_eq!(test::read_body(resp).await, &b"789"[..]);
+    let resp = test::call_service(&app, get(&[("Range", "bytes=8-100")])).await;
+    assert_eq!(test::read_body(resp).await, &b"89"[..]);
+    let resp = test::call_service(&app, get(&[("Range", "bytes=10-")])).await;
+    assert_eq!(resp.status(), 416);
+    assert_eq!(
+        header(&resp, "content-range").as_deref(),
+        Some("bytes */10")
+    );
+
+    // Multi-range, malformed and stale If-Range requests get the whole body.
+    for headers in [
+        &[("Range", "bytes=0-1,4-5")][..],
+        &[("Range", "items=0-1")][..],
+        &[("Range", "bytes=0-1"), ("If-Range", "\"older\"")][..],
+    ] {
+        let resp = test::call_service(&app, get(headers)).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(test::read_body(resp).await, &b"0123456789"[..]);
+    }
+    let resp = test::call_service(
+        &app,
+        get(&[("Range", "bytes=0-1"), ("If-Range", "\"etag1\"")]),
+    )
+    .await;
+    assert_eq!(resp.status(), 206);
+}
+
+#[actix_web::test]
+async fn memory_image_ranges_are_read_from_the_blob_store() {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let root = std::env::temp_dir().join(format!("syezw_range_blobs_{}", nanos));
+    let blobs = Arc::new(FsBlobStore::open(&root).await.expect("open blob dir"));
+    let mut state = app_state();
+    state.storage = Arc::new(MemoryStorage::new().with_blob_store(blobs.clone()));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+    let req = test::TestRequest::put()
+        .uri("/images/blob/ranged")
+        .insert_header(("X-API-Key", API_KEY))
+        .insert_header((IMAGE_IV_HEADER, "iv-r"))
+        .set_payload(&b"0123456789"[..])
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let get = |range: &'static str| {
+        test::TestRequest::get()
+            .uri("/images/blob/ranged")
+            .insert_header(("X-API-Key", API_KEY))
+            .insert_header(("Range", range))
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, get("bytes=2-5")).await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.headers().get(IMAGE_IV_HEADER).unwrap(), "iv-r");
+    assert_eq!(test::read_body(resp).await, &b"2345"[..]);
+    let resp = test::call_service(&app, get("bytes=-3")).await;
+    assert_eq!(test::read_body(resp).await, &b"789"[..]);
+    let resp = test::call_service(&app, get("bytes=10-")).await;
+    assert_eq!(resp.status(), 416);
+    assert_eq!(
+        blobs.get_range("default", "ranged", 8, 100).await.unwrap(),
+        Some(b"89".to_vec())
+    );
+
+    // The range is worked out from the recorded size, so a blob cut short
+    // is an error rather than a short 206.
+    let path = root.join("default").join("ra").join("ranged");
+    std::fs::write(&path, b"0123").unwrap();
+    assert_eq!(
+        test::call_service(&app, get("bytes=2-5")).await.status(),
+        500
+    );
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[actix_web::test]
+async fn memory_image_gc_deletes_only_old_unreferenced_images() {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let root = std::env::temp_dir().join(format!("syezw_gc_blobs_{}", nanos));
+    let blobs = FsBlobStore::open(&root).await.expect("open blob dir");
+    let mut state = app_state();
+    state.env.admin_key = "admin-secret".to_string();
+    state.storage = Arc::new(MemoryStorage::new().with_blob_store(Arc::new(blobs)));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+
+    // "kept" stays referenced; "gone" loses its ref when the diary's image is replaced.
+    let mut upload = upload_of(vec![diary("d_gc", 2)], vec![]);
+    upload.images = vec![image("d_gc", "gone"), image("d_gc", "kept")];
+    upload.images[1].file_name = "other.jpg".to_string();
+    for item in &mut upload.images {
+        item.blob.data = STANDARD.encode(item.hash.as_bytes());
+    }
+    let resp = test::call_service(&app, post("/sync/upload", API_KEY, &upload).to_request()).await;
+    assert!(resp.status().is_success());
+    let repoint = ImageRefsUpsertRequest {
+        refs: vec![DiaryImageRefItem {
+            diary_uuid: "d_gc".to_string(),
+            file_name: "img.jpg".to_string(),
+            hash: "kept".to_string(),
+            updated_at: 7,
+        }],
+    };
+    let resp = test::call_service(
+        &app,
+        post("/images/refs/upsert", API_KEY, &repoint).to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    std::thread::sleep(std::time::Duration::from_millis(5));
+
+    let gc = |key: &str, request: ImageGcRequest| {
+        test::TestRequest::post()
+            .uri("/admin/images/gc")
+            .insert_header(("X-Admin-Key", key.to_string()))
+            .set_json(request)
+            .to_request()
+    };
+    let now = |dry_run| ImageGcRequest {
+        dry_run,
+        grace_secs: Some(0),
+    };
+    assert_eq!(
+        test::call_service(&app, gc(API_KEY, now(true)))
+            .await
+            .status(),
+        401
+    );
+
+    // Within the (default, one week) grace period nothing is eligible.
+    let report: ImageGcResponse =
+        test::call_and_read_body_json(&app, gc("admin-secret", ImageGcRequest::default())).await;
+    assert!(report.orphans.is_empty());
+
+    let report: ImageGcResponse =
+        test::call_and_read_body_json(&app, gc("admin-secret", now(true))).await;
+    assert!(report.dry_run);
+    assert_eq!(report.deleted, 0);
+    let orphans: Vec<_> = report
+        .orphans
+        .iter()
+        .map(|o| (o.account_id.as_str(), o.hash.as_str()))
+        .collect();
+    assert_eq!(orphans, vec![("default", "gone")]);
+    let gone_path = root.join("default").join("go").join("gone");
+    assert!(gone_path.exists());
+
+    // A body that does not parse is refused, not read as "no dry run".
+    for body in [r#"{"dryRun":"no","graceSecs":0}"#, r#"{"graceSecs":0"#] {
+        let req = test::TestRequest::post()
+            .uri("/admin/images/gc")
+            .insert_header(("X-Admin-Key", "admin-secret"))
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(body)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+    assert!(gone_path.exists());
+    // An empty body is the defaults, so the grace period keeps it.
+    let req = test::TestRequest::post()
+        .uri("/admin/images/gc")
+        .insert_header(("X-Admin-Key", "admin-secret"))
+        .to_request();
+    let report: ImageGcResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report.deleted, 0);
+    assert!(gone_path.exists());
+
+    let report: ImageGcResponse =
+        test::call_and_read_body_json(&app, gc("admin-secret", now(false))).await;
+    assert_eq!(report.deleted, 1);
+    assert!(!gone_path.exists());
+    assert!(root.join("default").join("ke").join("kept").exists());
+    let hashes: ImageHashListResponse =
+        test::call_and_read_body_json(&app, post("/images/hashes", API_KEY, &()).to_request())
+            .await;
+    assert_eq!(hashes.hashes, vec!["kept".to_string()]);
+    let _ = std::fs::remove_dir_all(&root);
+
+    // Without ADMIN_KEY the endpoint is off.
+    let app = init_app!();
+    let resp = test::call_service(&app, gc("", now(true))).await;
+    assert_eq!(resp.status(), 403);
+}
+
+#[actix_web::test]
+async fn memory_image_gc_grace_starts_when_the_diary_is_deleted() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let mut state = app_state();
+    state.storage = storage.clone();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+
+    let mut upload = upload_of(vec![diary("d_old", 1)], vec![]);
+    upload.images = vec![image("d_old", "old")];
+    test::call_service(&app, post("/sync/upload", API_KEY, &upload).to_request()).await;
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    let released = chrono::Utc::now().timestamp_millis();
+    let deletion = upload_of(
+        vec![],
+        vec![TombstoneItem {
+            uuid: "d_old".to_string(),
+            kind: RecordKind::Diary,
+            deleted_at: 2,
+            base_updated_at: None,
+            start_date: None,
+        }],
+    );
+    test::call_service(&app, post("/sync/upload", API_KEY, &deletion).to_request()).await;
+
+    // Stored more than the grace period before this run, but unreferenced for less.
+    let report = collect_image_garbage(storage.as_ref(), 1, false, released + 990)
+        .await
+        .unwrap();
+    assert_eq!((report.orphans.len(), report.deleted), (0, 0));
+    assert_eq!(storage.image_hashes("default").await.unwrap(), vec!["old"]);
+
+    let report = collect_image_garbage(storage.as_ref(), 1, false, released + 2000)
+        .await
+        .unwrap();
+    assert_eq!(report.deleted, 1);
+    assert!(report.orphans[0].stored_at < report.orphans[0].unreferenced_at);
+    assert!(storage.image_hashes("default").await.unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn memory_image_ref_delete_replace_and_filter() {
+    let app = init_app!();
+    let image_ref = |diary_uuid: &str, file_name: &str, updated_at: i64| DiaryImageRefItem {
+        diary_uuid: diary_uuid.to_string(),
+        file_name: file_name.to_string(),
+        hash: format!("h_{}", file_name),
+        updated_at,
+    };
+    let refs = ImageRefsUpsertRequest {
+        refs: vec![
+            image_ref("d_a", "a1.jpg", 1),
+            image_ref("d_a", "a2.jpg", 2),
+            image_ref("d_a", "a3.jpg", 3),
+            image_ref("d_b", "b1.jpg", 4),
+        ],
+    };
+    let resp = test::call_service(
+        &app,
+        post("/images/refs/upsert", API_KEY, &refs).to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+
+    let list = |filter: &ImageRefsRequest| post("/images/refs", API_KEY, filter).to_request();
+    let names = |resp: ImageRefsResponse| {
+        let mut names: Vec<String> = resp.refs.into_iter().map(|r| r.file_name).collect();
+        names.sort();
+        names
+    };
+    let resp: ImageRefsResponse = test::call_and_read_body_json(
+        &app,
+        list(&ImageRefsRequest {
+            diary_uuids: Some(vec!["d_b".to_string()]),
+            updated_after: None,
+        }),
+    )
+    .await;
+    assert_eq!(names(resp), vec!["b1.jpg"]);
+    let resp: ImageRefsResponse = test::call_and_read_body_json(
+        &app,
+        list(&ImageRefsRequest {
+            diary_uuids: Some(vec!["d_a".to_string()]),
+            updated_after: Some(1),
+        }),
+    )
+    .await;
+    assert_eq!(names(resp), vec!["a2.jpg", "a3.jpg"]);
+    let too_many = ImageRefsRequest {
+        diary_uuids: Some(vec!["d".to_string(); MAX_REF_FILTER_DIARIES + 1]),
+        updated_after: None,
+    };
+    let resp = test::call_service(&app, list(&too_many)).await;
+    assert_eq!(resp.status(), 400);
+    // A filter that does not parse is refused rather than listing every ref.
+    for body in [r#"{"diaryUuids":"d_a"}"#, r#"{"diaryUuids":["d_a"]"#] {
+        let req = test::TestRequest::post()
+            .uri("/images/refs")
+            .insert_header(("X-API-Key", API_KEY))
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(body)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+
+    // Deleting reports only refs that existed.
+    let delete = ImageRefsDeleteRequest {
+        refs: vec![
+            ImageRefKey {
+                diary_uuid: "d_b".to_string(),
+                file_name: "b1.jpg".to_string(),
+            },
+            ImageRefKey {
+                diary_uuid: "d_b".to_string(),
+                file_name: "missing.jpg".to_string(),
+            },
+        ],
+    };
+    let resp: ImageRefsChangeResponse = test::call_and_read_body_json(
+        &app,
+        post("/images/refs/delete", API_KEY, &delete).to_request(),
+    )
+    .await;
+    assert_eq!(resp.removed, 1);
+
+    // Replacing keeps a2, updates it, adds a4 and drops a1 and a3.
+    let replace = ImageRefsReplaceRequest {
+        diary_uuid: "d_a".to_string(),
+        refs: vec![image_ref("d_a", "a2.jpg", 9), image_ref("d_a", "a4.jpg", 9)],
+    };
+    let resp: ImageRefsChangeResponse = test::call_and_read_body_json(
+        &app,
+        post("/images/refs/replace", API_KEY, &replace).to_request(),
+    )
+    .await;
+    assert_eq!((resp.written, resp.removed), (2, 2));
+    let resp: ImageRefsResponse =
+        test::call_and_read_body_json(&app, post("/images/refs", API_KEY, &()).to_request()).await;
+    assert_eq!(names(resp), vec!["a2.jpg", "a4.jpg"]);
+
+    let mismatched = ImageRefsReplaceRequest {
+        diary_uuid: "d_a".to_string(),
+        refs: vec![image_ref("d_b", "b2.jpg", 9)],
+    };
+    let resp = test::call_service(
+        &app,
+        post("/images/refs/replace", API_KEY, &mismatched).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+
+    // An empty set clears the diary.
+    let clear = ImageRefsReplaceRequest {
+        diary_uuid: "d_a".to_string(),
+        refs: vec![],
+    };
+    let resp: ImageRefsChangeResponse = test::call_and_read_body_json(
+        &app,
+        post("/images/refs/replace", API_KEY, &clear).to_request(),
+    )
+    .await;
+    assert_eq!(resp.removed, 2);
+}
+
+#[actix_web::test]
+async fn memory_missing_images_are_negotiated_by_hash_and_size() {
+    let app = init_app!();
+    // "json1" arrives as base64 (5 bytes), "bin1" as a raw body (3 bytes).
+    let mut item = image("d_miss", "json1");
+    item.blob.data = STANDARD.encode(b"12345");
+    let upload = ImageUploadRequest { images: vec![item] };
+    let resp =
+        test::call_service(&app, post("/images/upload", API_KEY, &upload).to_request()).await;
+    assert!(resp.status().is_success());
+    let req = test::TestRequest::put()
+        .uri("/images/blob/bin1")
+        .insert_header(("X-API-Key", API_KEY))
+        .insert_header((IMAGE_IV_HEADER, "iv"))
+        .set_payload(&b"abc"[..])
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let offer = |images: &[(&str, Option<i64>)]| ImageMissingRequest {
+        images: images
+            .iter()
+            .map(|(hash, size)| ImageHashSize {
+                hash: hash.to_string(),
+                size: *size,
+            })
+            .collect(),
+    };
+    let request = offer(&[
+        ("json1", Some(5)),
+        ("bin1", Some(4)),
+        ("new1", Some(1)),
+        ("new1", Some(1)),
+        ("json1", None),
+    ]);
+    let resp: ImageMissingResponse = test::call_and_read_body_json(
+        &app,
+        post("/images/missing", API_KEY, &request).to_request(),
+    )
+    .await;
+    assert_eq!(resp.missing, vec!["new1".to_string()]);
+    assert_eq!(resp.mismatched, vec!["bin1".to_string()]);
+
+    // Hashes are per account.
+    let resp: ImageMissingResponse = test::call_and_read_body_json(
+        &app,
+        post("/images/missing", OTHER_KEY, &offer(&[("bin1", Some(3))])).to_request(),
+    )
+    .await;
+    assert_eq!(resp.missing, vec!["bin1".to_string()]);
+
+    let too_many = offer(&vec![("h", None); MAX_MISSING_QUERY_IMAGES + 1]);
+    let resp = test::call_service(
+        &app,
+        post("/images/missing", API_KEY, &too_many).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+}
+
+#[actix_web::test]
+async fn memory_registry_keys_have_scopes_and_can_be_revoked() {
+    let mut state = app_state();
+    state.env.admin_key = "admin-secret".to_string();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+    let mint = |admin: (&str, &str), request: serde_json::Value| {
+        test::TestRequest::post()
+            .uri("/admin/api-keys")
+            .insert_header(admin)
+            .set_json(request)
+            .to_request()
+    };
+    let admin = ("X-Admin-Key", "admin-secret");
+
+    // Environment keys are not admin credentials.
+    let resp = test::call_service(
+        &app,
+        mint(("X-API-Key", API_KEY), serde_json::json!({ "name": "x" })),
+    )
+    .await;
+    assert_eq!(resp.status(), 401);
+    let resp = test::call_service(
+        &app,
+        mint(
+            admin,
+            serde_json::json!({ "name": "x", "scopes": ["root"] }),
+        ),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+
+    let backup: ApiKeyCreateResponse = test::call_and_read_body_json(
+        &app,
+        mint(
+            admin,
+            serde_json::json!({ "name": "backup", "scopes": ["read"] }),
+        ),
+    )
+    .await;
+    assert_eq!(backup.api_key.account_id, "default");
+    assert_eq!(backup.api_key.scopes, vec![ApiScope::Read]);
+    let phone: ApiKeyCreateResponse = test::call_and_read_body_json(
+        &app,
+        mint(
+            admin,
+            serde_json::json!({ "accountId": "other", "name": "phone" }),
+        ),
+    )
+    .await;
+    assert_eq!(phone.api_key.scopes, vec![ApiScope::Read, ApiScope::Write]);
+    assert_ne!(backup.key, phone.key);
+    // Account ids name blob locations, here and in ACCOUNT_KEYS alike.
+    let resp = test::call_service(
+        &app,
+        mint(
+            admin,
+            serde_json::json!({ "accountId": "../other", "name": "tablet" }),
+        ),
+    )
+    .await;
+    assert_eq!(resp.status(), 400);
+    let keys = parse_account_keys("ok_1:k1,../up:k2,has space:k3");
+    let accounts: Vec<_> = keys.iter().map(|k| k.account_id.as_str()).collect();
+    assert_eq!(accounts, vec!["ok_1"]);
+
+    // The read-only key sees the default account but cannot write to it.
+    let upload = upload_of(vec![diary("d_key", 1)], vec![]);
+    let resp = test::call_service(&app, post("/sync/upload", API_KEY, &upload).to_request()).await;
+    assert!(resp.status().is_success());
+    let meta: SyncMetaResponse =
+        test::call_and_read_body_json(&app, post("/sync/meta", &backup.key, &()).to_request())
+            .await;
+    assert_eq!(meta.diaries.len(), 1);
+    let resp = test::call_service(
+        &app,
+        post("/sync/upload", &backup.key, &upload).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 403);
+
+    // The phone key writes to its own account.
+    let resp =
+        test::call_service(&app, post("/sync/upload", &phone.key, &upload).to_request()).await;
+    assert!(resp.status().is_success());
+    let meta: SyncMetaResponse =
+        test::call_and_read_body_json(&app, post("/sync/meta", OTHER_KEY, &()).to_request()).await;
+    assert_eq!(meta.diaries.len(), 1);
+
+    let list = |query: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/admin/api-keys{}", query))
+            .insert_header(admin)
+            .to_request()
+    };
+    let keys: ApiKeyListResponse = test::call_and_read_body_json(&app, list("")).await;
+    assert_eq!(keys.api_keys.len(), 2);
+    assert!(keys.api_keys.iter().all(|k| k.last_used_at.is_some()));
+    let keys: ApiKeyListResponse =
+        test::call_and_read_body_json(&app, list("?accountId=other")).await;
+    assert_eq!(
+        keys.api_keys,
+        vec![ApiKeyInfo {
+            last_used_at: keys.api_keys[0].last_used_at,
+            ..phone.api_key.clone()
+        }]
+    );
+
+    let revoke = |id: &str| {
+        test::TestRequest::post()
+            .uri(&format!("/admin/api-keys/{}/revoke", id))
+            .insert_header(admin)
+            .to_request()
+    };
+    let revoked: ApiKeyInfo = test::call_and_read_body_json(&app, revoke(&phone.api_key.id)).await;
+    assert!(revoked.revoked_at.is_some());
+    let resp = test::call_service(&app, post("/sync/meta", &phone.key, &()).to_request()).await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(test::call_service(&app, revoke("nope")).await.status(), 404);
+
+    // A registry key with the admin scope can use the admin endpoints.
+    let ops: ApiKeyCreateResponse = test::call_and_read_body_json(
+        &app,
+        mint(
+            admin,
+            serde_json::json!({ "name": "ops", "scopes": ["admin"] }),
+        ),
+    )
+    .await;
+    let req = test::TestRequest::get()
+        .uri("/admin/api-keys")
+        .insert_header(("X-API-Key", ops.key.clone()))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let resp = test::call_service(&app, post("/sync/meta", &ops.key, &()).to_request()).await;
+    assert_eq!(resp.status(), 403);
+}
+
+#[actix_web::test]
+async fn memory_registering_a_key_ends_open_access() {
+    let mut state = app_state();
+    state.env.api_key = String::new();
+    state.env.account_keys = vec![];
+    state.env.admin_key = "admin-secret".to_string();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+    let resp = test::call_service(&app, post("/sync/meta", "", &()).to_request()).await;
+    assert!(resp.status().is_success());
+
+    let req = test::TestRequest::post()
+        .uri("/admin/api-keys")
+        .insert_header(("X-Admin-Key", "admin-secret"))
+        .set_json(serde_json::json!({ "name": "phone" }))
+        .to_request();
+    let phone: ApiKeyCreateResponse = test::call_and_read_body_json(&app, req).await;
+    let resp = test::call_service(&app, post("/sync/meta", "", &()).to_request()).await;
+    assert_eq!(resp.status(), 401);
+    let resp = test::call_service(&app, post("/sync/meta", &phone.key, &()).to_request()).await;
+    assert!(resp.status().is_success());
+
+    // Revoking the last key does not reopen the server.
+    let req = test::TestRequest::post()
+        .uri(&format!("/admin/api-keys/{}/revoke", phone.api_key.id))
+        .insert_header(("X-Admin-Key", "admin-secret"))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let resp = test::call_service(&app, post("/sync/meta", "", &()).to_request()).await;
+    assert_eq!(resp.status(), 401);
+}
+
+#[actix_web::test]
+async fn memory_access_tokens_refresh_rotation_and_revocation() {
+    let mut state = app_state();
+    state.env.admin_key = "admin-secret".to_string();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+    let login = |key: &str| {
+        test::TestRequest::post()
+            .uri("/auth/login")
+            .insert_header(("X-API-Key", key.to_string()))
+            .to_request()
+    };
+    let bearer = |uri: &str, token: &str| {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_json(())
+    };
+    let refresh = |token: &str| {
+        test::TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(AuthRefreshRequest {
+                refresh_token: token.to_string(),
+            })
+            .to_request()
+    };
+    assert_eq!(test::call_service(&app, login("wrong")).await.status(), 401);
+
+    let tokens: AuthTokenResponse = test::call_and_read_body_json(&app, login(OTHER_KEY)).await;
+    assert_eq!(tokens.scopes, vec![ApiScope::Read, ApiScope::Write]);
+    assert!(tokens.access_expires_at < tokens.refresh_expires_at);
+    let upload = upload_of(vec![diary("d_tok", 1)], vec![]);
+    let resp = test::call_service(
+        &app,
+        bearer("/sync/upload", &tokens.access_token)
+            .set_json(&upload)
+            .to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    // The token acts for the key's account.
+    let meta: SyncMetaResponse =
+        test::call_and_read_body_json(&app, post("/sync/meta", OTHER_KEY, &()).to_request()).await;
+    assert_eq!(meta.diaries.len(), 1);
+
+    // A tampered token is refused.
+    let (claims, signature) = tokens.access_token.split_once('.').unwrap();
+    let forged = format!("{}x.{}", claims, signature);
+    let resp = test::call_service(&app, bearer("/sync/meta", &forged).to_request()).await;
+    assert_eq!(resp.status(), 401);
+
+    // A guessed refresh token naming the session (its id is readable in the
+    // access token) is refused without touching the session.
+    let claims: serde_json::Value =
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+    let guess = format!("{}.{}", claims["sid"].as_str().unwrap(), "0".repeat(64));
+    assert_eq!(
+        test::call_service(&app, refresh(&guess)).await.status(),
+        401
+    );
+    assert!(!tokens
+        .refresh_token
+        .contains(claims["sid"].as_str().unwrap()));
+
+    // Refreshing rotates the refresh token; the old access token stays valid.
+    let rotated: AuthTokenResponse =
+        test::call_and_read_body_json(&app, refresh(&tokens.refresh_token)).await;
+    assert_ne!(rotated.refresh_token, tokens.refresh_token);
+    let resp = test::call_service(
+        &app,
+        bearer("/sync/meta", &rotated.access_token).to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+
+    // Replaying the old refresh token revokes the session and all its tokens.
+    assert_eq!(
+        test::call_service(&app, refresh(&tokens.refresh_token))
+            .await
+            .status(),
+        401
+    );
+    assert_eq!(
+        test::call_service(&app, refresh(&rotated.refresh_token))
+            .await
+            .status(),
+        401
+    );
+    let resp = test::call_service(
+        &app,
+        bearer("/sync/meta", &rotated.access_token).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 401);
+
+    // Logout ends only that session.
+    let first: AuthTokenResponse = test::call_and_read_body_json(&app, login(API_KEY)).await;
+    let second: AuthTokenResponse = test::call_and_read_body_json(&app, login(API_KEY)).await;
+    let resp = test::call_service(
+        &app,
+        bearer("/auth/logout", &first.access_token).to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let resp =
+        test::call_service(&app, bearer("/sync/meta", &first.access_token).to_request()).await;
+    assert_eq!(resp.status(), 401);
+    let resp = test::call_service(
+        &app,
+        bearer("/sync/meta", &second.access_token).to_request(),
+    )
+    .await;
+    assert!(resp.status().is_success());
+    let resp = test::call_service(&app, post("/auth/logout", API_KEY, &()).to_request()).await;
+    assert_eq!(resp.status(), 400);
+
+    // Admins can end every session of an account.
+    let req = test::TestRequest::post()
+        .uri("/admin/sessions/revoke")
+        .insert_header(("X-Admin-Key", "admin-secret"))
+        .set_json(SessionRevokeRequest {
+            account_id: "default".to_string(),
+        })
+        .to_request();
+    let revoked: SessionRevokeResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(revoked.revoked, 1);
+    let resp = test::call_service(
+        &app,
+        bearer("/sync/meta", &second.access_token).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 401);
+
+    // Tokens keep the key's scopes, and revoking the key ends its sessions.
+    let req = test::TestRequest::post()
+        .uri("/admin/api-keys")
+        .insert_header(("X-Admin-Key", "admin-secret"))
+        .set_json(serde_json::json!({ "name": "backup", "scopes": ["read"] }))
+        .to_request();
+    let backup: ApiKeyCreateResponse = test::call_and_read_body_json(&app, req).await;
+    let tokens: AuthTokenResponse = test::call_and_read_body_json(&app, login(&backup.key)).await;
+    assert_eq!(tokens.scopes, vec![ApiScope::Read]);
+    let resp = test::call_service(
+        &app,
+        bearer("/sync/upload", &tokens.access_token)
+            .set_json(&upload)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 403);
+    let req = test::TestRequest::post()
+        .uri(&format!("/admin/api-keys/{}/revoke", backup.api_key.id))
+        .insert_header(("X-Admin-Key", "admin-secret"))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let resp = test::call_service(
+        &app,
+        bearer("/sync/meta", &tokens.access_token).to_request(),
+    )
+    .await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(
+        test::call_service(&app, refresh(&tokens.refresh_token))
+            .await
+            .status(),
+        401
+    );
+}
+
+#[actix_web::test]
+async fn memory_signed_requests_reject_tampering_and_replays() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let mut state = app_state();
+    state.env.signing_secret = "lan-secret".to_string();
+    state.storage = storage.clone();
+    let app = test::init_service(
+        App::new()
+            .wrap(from_fn(verify_request_signature))
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+    let body = serde_json::to_vec(&upload_of(vec![diary("d_1", 2)], vec![])).unwrap();
+    let signed = |timestamp: i64, nonce: &str, body: &[u8], signature: String| {
+        test::TestRequest::post()
+            .uri("/sync/upload")
+            .insert_header(("X-API-Key", API_KEY))
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header((SIGNATURE_TIMESTAMP_HEADER, timestamp.to_string()))
+            .insert_header((SIGNATURE_NONCE_HEADER, nonce.to_string()))
+            .insert_header((SIGNATURE_HEADER, signature))
+            .set_payload(body.to_vec())
+            .to_request()
+    };
+    let sign = |timestamp: i64, nonce: &str, body: &[u8]| {
+        sign_request("lan-secret", "POST", "/sync/upload", timestamp, nonce, body)
+    };
+    let now = chrono::Utc::now().timestamp_millis();
+
+    // Unsigned requests are refused even with a valid key.
+    let resp = test::call_service(&app, post("/sync/upload", API_KEY, &()).to_request()).await;
+    assert_eq!(resp.status(), 401);
+
+    let resp = test::call_service(&app, signed(now, "n1", &body, sign(now, "n1", &body))).await;
+    assert!(resp.status().is_success());
+    let resp: SyncUploadResponse = test::read_body_json(resp).await;
+    assert_eq!(resp.counts.diaries, 1);
+
+    // The same request again is a replay.
+    let resp = test::call_service(&app, signed(now, "n1", &body, sign(now, "n1", &body))).await;
+    assert_eq!(resp.status(), 401);
+
+    // A body that does not match the signature.
+    let other = serde_json::to_vec(&upload_of(vec![diary("d_1", 1)], vec![])).unwrap();
+    let resp = test::call_service(&app, signed(now, "n2", &other, sign(now, "n2", &body))).await;
+    assert_eq!(resp.status(), 401);
+
+    // A correctly signed but stale request.
+    let stale = now - 10 * 60 * 1000;
+    let resp = test::call_service(&app, signed(stale, "n3", &body, sign(stale, "n3", &body))).await;
+    assert_eq!(resp.status(), 401);
+
+    // A wrong secret.
+    let forged = sign_request("guess", "POST", "/sync/upload", now, "n4", &body);
+    let resp = test::call_service(&app, signed(now, "n4", &body, forged)).await;
+    assert_eq!(resp.status(), 401);
+
+    // A handler that ignores the body does not run until it has been checked.
+    let req = test::TestRequest::post()
+        .uri("/auth/login")
+        .insert_header(("X-API-Key", API_KEY))
+        .insert_header((SIGNATURE_TIMESTAMP_HEADER, now.to_string()))
+        .insert_header((SIGNATURE_NONCE_HEADER, "n5"))
+        .insert_header((SIGNATURE_HEADER, sign(now, "n5", b"")))
+        .set_payload(&b"ignored"[..])
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+
+    // Image bodies are checked as they stream to the store, so they are not
+    // held to the JSON limit, and a mismatch stores nothing.
+    let put = |hash: &str, nonce: &str, data: &[u8], signed_data: &[u8]| {
+        let uri = format!("/images/blob/{}", hash);
+        let signature = sign_request("lan-secret", "PUT", &uri, now, nonce, signed_data);
+        test::TestRequest::put()
+            .uri(&uri)
+            .insert_header(("X-API-Key", API_KEY))
+            .insert_header((IMAGE_IV_HEADER, "iv"))
+            .insert_header((SIGNATURE_TIMESTAMP_HEADER, now.to_string()))
+            .insert_header((SIGNATURE_NONCE_HEADER, nonce.to_string()))
+            .insert_header((SIGNATURE_HEADER, signature))
+            .set_payload(data.to_vec())
+            .to_request()
+    };
+    let large = vec![7u8; 17 * 1024 * 1024];
+    let resp = test::call_service(&app, put("large", "n6", &large, &large)).await;
+    assert!(resp.status().is_success());
+    let meta = storage
+        .image_meta("default", "large")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(meta.size, Some(large.len() as i64));
+    let resp = test::call_service(&app, put("tampered", "n7", b"evil", b"good")).await;
+    assert_eq!(resp.status(), 401);
+    assert!(storage
+        .image_meta("default", "tampered")
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[actix_web::test]
+async fn memory_auth_failures_lock_out_the_client_and_are_audited() {
+    let mut state = app_state();
+    state.env.admin_key = "admin-secret".to_string();
+    state.env.auth_lockout_threshold = 3;
+    state.env.auth_lockout_base_secs = 60;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+    let phone: std::net::SocketAddr = "10.0.0.9:5000".parse().unwrap();
+    let laptop: std::net::SocketAddr = "10.0.0.10:5000".parse().unwrap();
+    let meta = |from: std::net::SocketAddr, key: &str| {
+        post("/sync/meta", key, &()).peer_addr(from).to_request()
+    };
+    let admin = |uri: &str| {
+        test::TestRequest::get()
+            .uri(uri)
+            .peer_addr(laptop)
+            .insert_header(("X-Admin-Key", "admin-secret"))
+            .to_request()
+    };
+
+    // Authenticating resets the count.
+    for _ in 0..2 {
+        let resp = test::call_service(&app, meta(phone, "wrong")).await;
+        assert_eq!(resp.status(), 401);
+    }
+    let resp = test::call_service(&app, meta(phone, API_KEY)).await;
+    assert!(resp.status().is_success());
+
+    for _ in 0..3 {
+        let resp = test::call_service(&app, meta(phone, "wrong")).await;
+        assert_eq!(resp.status(), 401);
+    }
+    // Locked out: even the right key is refused, other clients are not.
+    let resp = test::call_service(&app, meta(phone, API_KEY)).await;
+    assert_eq!(resp.status(), 429);
+    let retry_after: i64 = resp
+        .headers()
+        .get("Retry-After")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 60);
+    let resp = test::call_service(&app, meta(laptop, API_KEY)).await;
+    assert!(resp.status().is_success());
+
+    let resp: AuthLockoutListResponse =
+        test::call_and_read_body_json(&app, admin("/admin/auth/lockouts")).await;
+    assert_eq!(resp.lockouts.len(), 1);
+    assert_eq!(resp.lockouts[0].ip, "10.0.0.9");
+    assert_eq!(resp.lockouts[0].failures, 3);
+
+    // Refusals while locked out are not logged.
+    let resp: AuthFailureListResponse =
+        test::call_and_read_body_json(&app, admin("/admin/auth/failures")).await;
+    assert_eq!(resp.failures.len(), 5);
+    assert!(resp
+        .failures
+        .iter()
+        .all(|f| f.ip == "10.0.0.9" && f.path == "/sync/meta" && f.reason == "api key"));
+    assert!(resp.failures[0].id > resp.failures[4].id);
+    let resp: AuthFailureListResponse =
+        test::call_and_read_body_json(&app, admin("/admin/auth/failures?limit=2")).await;
+    assert_eq!(resp.failures.len(), 2);
+
+    // A wrong refresh token counts too.
+    let req = test::TestRequest::post()
+        .uri("/auth/refresh")
+        .peer_addr(laptop)
+        .set_json(AuthRefreshRequest {
+            refresh_token: "nope.nope".to_string(),
+        })
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+    let resp: AuthFailureListResponse =
+        test::call_and_read_body_json(&app, admin("/admin/auth/failures?limit=1")).await;
+    assert_eq!(
+        (
+            resp.failures[0].ip.as_str(),
+            resp.failures[0].reason.as_str()
+        ),
+        ("10.0.0.10", "refresh token")
+    );
+}
+
+#[actix_web::test]
+async fn memory_lockouts_key_on_the_client_behind_a_trusted_proxy() {
+    let mut state = app_state();
+    state.env.auth_lockout_threshold = 2;
+    state.env.trusted_proxies = vec!["10.0.0.1".parse().unwrap()];
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+    let meta = |peer: &str, forwarded: &str, key: &str| {
+        post("/sync/meta", key, &())
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("X-Forwarded-For", forwarded))
+            .to_request()
+    };
+
+    // Behind the proxy, the forwarded client is locked out, not the proxy.
+    for _ in 0..2 {
+        let resp = test::call_service(&app, meta("10.0.0.1:80", "203.0.113.5", "wrong")).await;
+        assert_eq!(resp.status(), 401);
+    }
+    let resp = test::call_service(&app, meta("10.0.0.1:80", "203.0.113.5", API_KEY)).await;
+    assert_eq!(resp.status(), 429);
+    let resp = test::call_service(&app, meta("10.0.0.1:80", "203.0.113.6", API_KEY)).await;
+    assert!(resp.status().is_success());
+
+    // Other peers cannot pick an address to be counted against.
+    for _ in 0..2 {
+        let resp = test::call_service(&app, meta("198.51.100.7:80", "203.0.113.6", "wrong")).await;
+        assert_eq!(resp.status(), 401);
+    }
+    let resp = test::call_service(&app, meta("10.0.0.1:80", "203.0.113.6", API_KEY)).await;
+    assert!(resp.status().is_success());
+    let resp = test::call_service(&app, meta("198.51.100.7:80", "203.0.113.5", API_KEY)).await;
+    assert_eq!(resp.status(), 429);
+}
+
+#[actix_web::test]
+async fn memory_rate_limits_are_per_device_and_route_group() {
+    let mut state = app_state();
+    state.env.rate_limit_meta_per_min = 2;
+    state.env.rate_limit_download_per_min = 2;
+    state.env.rate_limit_image_upload_per_min = 0;
+    state.env.upload_bytes_per_hour = 1000;
+    state.env.rate_limit_max_devices = 2;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+    let meta = |device: &str| {
+        post("/sync/meta", API_KEY, &())
+            .insert_header((DEVICE_ID_HEADER, device.to_string()))
+            .to_request()
+    };
+
+    for _ in 0..2 {
+        assert!(test::call_service(&app, meta("phone-a"))
+            .await
+            .status()
+            .is_success());
+    }
+    let resp = test::call_service(&app, meta("phone-a")).await;
+    assert_eq!(resp.status(), 429);
+    let retry_after: i64 = resp
+        .headers()
+        .get("Retry-After")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 30);
+
+    // Another phone on the same key, and another route group, have their own budget.
+    assert!(test::call_service(&app, meta("phone-b"))
+        .await
+        .status()
+        .is_success());
+    let req = post("/sync/download", API_KEY, &download(None, None))
+        .insert_header((DEVICE_ID_HEADER, "phone-a"))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    // Image uploads are only limited by bytes here.
+    let put = |hash: &str, device: &str| {
+        test::TestRequest::put()
+            .uri(&format!("/images/blob/{}", hash))
+            .insert_header(("X-API-Key", API_KEY))
+            .insert_header((DEVICE_ID_HEADER, device.to_string()))
+            .insert_header(("Content-Type", "application/octet-stream"))
+            .insert_header((IMAGE_IV_HEADER, "iv"))
+            .set_payload(vec![7u8; 600])
+            .to_request()
+    };
+    assert!(test::call_service(&app, put("q1", "phone-a"))
+        .await
+        .status()
+        .is_success());
+    let resp = test::call_service(&app, put("q2", "phone-a")).await;
+    assert_eq!(resp.status(), 429);
+    assert!(resp.headers().get("Retry-After").is_some());
+    assert!(test::call_service(&app, put("q2", "phone-b"))
+        .await
+        .status()
+        .is_success());
+
+    // New device ids do not lift the credential's ceiling of two devices' worth.
+    assert!(test::call_service(&app, meta("phone-c"))
+        .await
+        .status()
+        .is_success());
+    assert_eq!(
+        test::call_service(&app, meta("phone-d")).await.status(),
+        429
+    );
+    assert!(test::call_service(&app, put("q3", "phone-c"))
+        .await
+        .status()
+        .is_success());
+    assert_eq!(
+        test::call_service(&app, put("q4", "phone-d"))
+            .await
+            .status(),
+        429
+    );
+
+    // A body larger than the whole quota is never admitted, and one without
+    // a declared length cannot be measured.
+    let big = test::TestRequest::put()
+        .uri("/images/blob/q5")
+        .insert_header(("X-API-Key", API_KEY))
+        .insert_header((DEVICE_ID_HEADER, "phone-e"))
+        .insert_header((IMAGE_IV_HEADER, "iv"))
+        .set_payload(vec![7u8; 1001])
+        .to_request();
+    assert_eq!(test::call_service(&app, big).await.status(), 413);
+    let mut chunked = put("q6", "phone-e");
+    chunked.headers_mut().remove("Content-Length");
+    chunked
+        .headers_mut()
+        .insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+    assert_eq!(test::call_service(&app, chunked).await.status(), 411);
+}
+
+#[actix_web::test]
+async fn memory_every_request_draws_on_the_per_ip_budget() {
+    let mut state = app_state();
+    state.env.rate_limit_ip_per_min = 2;
+    let app = test::init_service(
+        App::new()
+            .wrap(from_fn(limit_per_ip))
+            .app_data(web::Data::new(state))
+            .configure(configure_routes),
+    )
+    .await;
+    let login = |peer: &str| {
+        post("/sync/meta", "wrong-key", &())
+            .peer_addr(peer.parse().unwrap())
+            .to_request()
+    };
+
+    // Failing, unauthenticated requests are limited too.
+    for _ in 0..2 {
+        assert_eq!(
+            test::call_service(&app, login("203.0.113.5:4000"))
+                .await
+                .status(),
+            401
+        );
+    }
+    let resp = test::call_service(&app, login("203.0.113.5:4000")).await;
+    assert_eq!(resp.status(), 429);
+    assert!(resp.headers().get("Retry-After").is_some());
+    assert_eq!(
+        test::call_service(&app, login("203.0.113.6:4000"))
+            .await
+            .status(),
+        401
+    );
+}
@@ -0,0 +1,392 @@
+//! `S3BlobStore` against an in-process S3 stand-in that checks SigV4
+//! signatures, plus an optional run against a real MinIO (`TEST_S3_*`).
+
+mod common;
+
+use actix_web::{test, web, App, HttpRequest, HttpResponse, HttpServer};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use common::test_env;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use syezw_sync_backend::models::{
+    DiaryImageSyncItem, EncryptedBlob, ImageFetchRequest, ImageFetchResponse, SyncUploadRequest,
+    SyncUploadResponse,
+};
+use syezw_sync_backend::storage::{BlobStore, MemoryStorage, S3BlobStore, S3Config, StorageError};
+use syezw_sync_backend::{configure_routes, AppState, IMAGE_IV_HEADER};
+
+const API_KEY: &str = "s3-test-key";
+const ACCESS_KEY: &str = "test-access";
+const SECRET_KEY: &str = "test-secret";
+const REGION: &str = "us-east-1";
+const BUCKET: &str = "syezw";
+
+type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Recomputes the SigV4 signature the way S3 does and compares it with the
+/// one in the `Authorization` header.
+fn signature_matches(req: &HttpRequest, body: &[u8]) -> bool {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let (amz_date, payload_hash) = (header("x-amz-date"), header("x-amz-content-sha256"));
+    if payload_hash != hex::encode(Sha256::digest(body)) || amz_date.len() < 8 {
+        return false;
+    }
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/s3/aws4_request", date, REGION);
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        req.method(),
+        req.uri().path(),
+        header("host"),
+        payload_hash,
+        amz_date,
+        signed_headers,
+        payload_hash
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let mut key = hmac(format!("AWS4{}", SECRET_KEY).as_bytes(), date.as_bytes());
+    for part in [REGION, "s3", "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    let expected = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        ACCESS_KEY,
+        scope,
+        signed_headers,
+        hex::encode(hmac(&key, string_to_sign.as_bytes()))
+    );
+    header("authorization") == expected
+}
+
+async fn fake_s3(req: HttpRequest, body: web::Bytes, objects: web::Data<Objects>) -> HttpResponse {
+    if !signature_matches(&req, &body) {
+        return HttpResponse::Forbidden().body("SignatureDoesNotMatch");
+    }
+    let key = req.uri().path().to_string();
+    let mut objects = objects.lock().unwrap();
+    match req.method().as_str() {
+        "PUT" => {
+            objects.insert(key, body.to_vec());
+            HttpResponse::Ok().finish()
+        }
+        "GET" => match (objects.get(&key), byte_range(&req)) {
+            (Some(data), Some((start, end))) => {
+                HttpResponse::PartialContent().body(data[start..=end.min(data.len() - 1)].to_vec())
+            }
+            (Some(data), None) => HttpResponse::Ok().body(data.clone()),
+            (None, _) => HttpResponse::NotFound().body("NoSuchKey"),
+        },
+        "DELETE" => {
+            objects.remove(&key);
+            HttpResponse::NoContent().finish()
+        }
+        _ => HttpResponse::MethodNotAllowed().finish(),
+    }
+}
+
+/// A `Range: bytes=<start>-<end>` header; other forms are not used by the store.
+fn byte_range(req: &HttpRequest) -> Option<(usize, usize)> {
+    let spec = req.headers().get("range")?.to_str().ok()?;
+    let (start, end) = spec.strip_prefix("bytes=")?.split_once('-')?;
+    Some((start.parse().ok()?, end.parse().ok()?))
+}
+
+/// Starts the stand-in on a free port and returns its endpoint and object map.
+fn start_fake_s3() -> (String, Objects) {
+    let objects: Objects = Arc::default();
+    let data = web::Data::new(objects.clone());
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(data.clone())
+            .app_data(web::PayloadConfig::new(16 * 1024 * 1024))
+            .default_service(web::to(fake_s3))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .expect("bind fake s3");
+    let addr = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    (format!("http://{}", addr), objects)
+}
+
+fn s3_config(endpoint: &str) -> S3Config {
+    S3Config {
+        endpoint: endpoint.to_string(),
+        bucket: BUCKET.to_string(),
+        region: REGION.to_string(),
+        access_key: ACCESS_KEY.to_string(),
+        secret_key: SECRET_KEY.to_string(),
+        prefix: "images/".to_string(),
+    }
+}
+
+fn image(hash: &str, data: &[u8]) -> DiaryImageSyncItem {
+    DiaryImageSyncItem {
+        file_name: format!("{}.jpg", hash),
+        diary_uuid: "d_s3".to_string(),
+        hash: hash.to_string(),
+        updated_at: 2,
+        blob: EncryptedBlob {
+            iv: "iv".to_string(),
+            data: STANDARD.encode(data),
+        },
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn image_ref(item: &DiaryImageSyncItem) -> syezw_sync_backend::models::DiaryImageRefItem {
+    syezw_sync_backend::models::DiaryImageRefItem {
+        diary_uuid: item.diary_uuid.clone(),
+        file_name: item.file_name.clone(),
+        hash: item.hash.clone(),
+        updated_at: item.updated_at,
+    }
+}
+
+fn app_state(storage: MemoryStorage) -> AppState {
+    let mut env = test_env();
+    env.api_key = API_KEY.to_string();
+    env.account_keys = vec![];
+    AppState::new(env, Arc::new(storage))
+}
+
+#[actix_web::test]
+async fn s3_blob_store_round_trips_signed_requests() {
+    let (endpoint, objects) = start_fake_s3();
+    let store = S3BlobStore::new(s3_config(&endpoint)).expect("s3 store");
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(app_state(
+                MemoryStorage::new().with_blob_store(Arc::new(store)),
+            )))
+            .configure(configure_routes),
+    )
+    .await;
+
+    let ciphertext = b"\x00\x01 encrypted photo bytes \xff";
+    let item = image("abc123", ciphertext);
+    let upload = SyncUploadRequest {
+        diaries: vec![],
+        todos: vec![],
+        periods: vec![],
+        images: vec![item.clone()],
+        deletions: vec![],
+    };
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("x-api-key", API_KEY))
+        .set_json(&upload)
+        .to_request();
+    let resp: SyncUploadResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.counts.images, 1);
+
+    // The object holds the raw ciphertext under bucket/prefix/account/hash.
+    let key = format!("/{}/images/default/abc123", BUCKET);
+    assert_eq!(objects.lock().unwrap()[&key], ciphertext);
+
+    let req = test::TestRequest::post()
+        .uri("/images/fetch")
+        .insert_header(("x-api-key", API_KEY))
+        .set_json(ImageFetchRequest {
+            diary_uuid: "d_s3".to_string(),
+            file_name: "abc123.jpg".to_string(),
+        })
+        .to_request();
+    let resp: ImageFetchResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.blob.data, item.blob.data);
+    assert_eq!(resp.blob.iv, "iv");
+}
+
+#[actix_web::test]
+async fn s3_blob_store_streams_binary_uploads() {
+    let (endpoint, objects) = start_fake_s3();
+    let store = S3BlobStore::new(s3_config(&endpoint)).expect("s3 store");
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(app_state(
+                MemoryStorage::new().with_blob_store(Arc::new(store)),
+            )))
+            .configure(configure_routes),
+    )
+    .await;
+
+    // Spooled and hashed on the way in, then sent with a matching signature.
+    let ciphertext: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let req = test::TestRequest::put()
+        .uri("/images/blob/streamed")
+        .insert_header(("x-api-key", API_KEY))
+        .insert_header((IMAGE_IV_HEADER, "iv"))
+        .set_payload(ciphertext.clone())
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let key = format!("/{}/images/default/streamed", BUCKET);
+    assert_eq!(objects.lock().unwrap()[&key], ciphertext);
+
+    let req = test::TestRequest::get()
+        .uri("/images/blob/streamed")
+        .insert_header(("x-api-key", API_KEY))
+        .to_request();
+    assert_eq!(test::call_and_read_body(&app, req).await, ciphertext);
+
+    // A range is fetched from S3 as a range.
+    let req = test::TestRequest::get()
+        .uri("/images/blob/streamed")
+        .insert_header(("x-api-key", API_KEY))
+        .insert_header(("Range", "bytes=1000-1999"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(test::read_body(resp).await, ciphertext[1000..2000]);
+}
+
+#[actix_web::test]
+async fn s3_blob_store_surfaces_missing_objects_and_auth_failures() {
+    let (endpoint, objects) = start_fake_s3();
+    let store = S3BlobStore::new(s3_config(&endpoint)).expect("s3 store");
+    assert_eq!(store.get("default", "nothing").await.unwrap(), None);
+    store.put("default", "doomed", b"x").await.unwrap();
+    store.delete("default", "doomed").await.unwrap();
+    assert_eq!(store.get("default", "doomed").await.unwrap(), None);
+    store.delete("default", "doomed").await.unwrap();
+
+    let mut config = s3_config(&endpoint);
+    config.secret_key = "wrong".to_string();
+    let wrong = S3BlobStore::new(config).expect("s3 store");
+    let err = wrong.put("default", "abc", b"x").await.unwrap_err();
+    assert!(matches!(err, StorageError::Backend(_)), "{}", err);
+    assert!(err.to_string().contains("403"), "{}", err);
+    assert!(objects.lock().unwrap().is_empty());
+
+    assert!(matches!(
+        store.put("default", "../escape", b"x").await,
+        Err(StorageError::Invalid(_))
+    ));
+    assert!(matches!(
+        S3BlobStore::new(S3Config {
+            bucket: String::new(),
+            ..s3_config(&endpoint)
+        }),
+        Err(StorageError::Invalid(_))
+    ));
+}
+
+#[cfg(feature = "sqlite")]
+#[actix_web::test]
+async fn migrate_blobs_moves_inline_images_to_s3() {
+    use syezw_sync_backend::storage::{MigrationMode, SqliteStorage, Storage};
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let path = std::env::temp_dir().join(format!("syezw_s3_migrate_{}.db", nanos));
+    let path = path.to_str().expect("utf-8 temp path");
+
+    // Images written while blobs were still kept in the database.
+    let inline = SqliteStorage::connect(path).await.expect("open sqlite");
+    inline.migrate(MigrationMode::Apply).await.expect("migrate");
+    let (first, second) = (image("hash_a", b"first"), image("hash_b", b"second"));
+    let bad = image("bad.hash", b"unmovable");
+    inline
+        .put_images("default", &[first.clone(), second.clone(), bad.clone()])
+        .await
+        .unwrap();
+    inline
+        .put_image_refs("default", &[image_ref(&first), image_ref(&bad)])
+        .await
+        .unwrap();
+    assert!(matches!(
+        inline.move_inline_blobs().await,
+        Err(StorageError::Invalid(_))
+    ));
+
+    let (endpoint, objects) = start_fake_s3();
+    let store = S3BlobStore::new(s3_config(&endpoint)).expect("s3 store");
+    let storage = SqliteStorage::connect(path)
+        .await
+        .expect("reopen sqlite")
+        .with_blob_store(Arc::new(store));
+    let report = storage.move_inline_blobs().await.expect("move blobs");
+    assert_eq!(report.moved, 2);
+    // Hashes that cannot be object keys stay inline rather than aborting the run.
+    assert_eq!(
+        report.skipped,
+        vec![("default".to_string(), "bad.hash".to_string())]
+    );
+    assert_eq!(
+        objects.lock().unwrap()[&format!("/{}/images/default/hash_a", BUCKET)],
+        b"first"
+    );
+
+    let (inline_rows,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM diary_images WHERE blob_data IS NOT NULL")
+            .fetch_one(storage.pool())
+            .await
+            .unwrap();
+    assert_eq!(inline_rows, 1);
+    let fetched = storage
+        .fetch_image("default", "d_s3", "hash_a.jpg")
+        .await
+        .unwrap()
+        .expect("moved image");
+    assert_eq!(fetched.blob.data, first.blob.data);
+
+    // A second run has nothing left to move.
+    let report = storage.move_inline_blobs().await.expect("move blobs again");
+    assert_eq!(report.moved, 0);
+    let _ = std::fs::remove_file(path);
+}
+
+/// Runs against a real S3-compatible service, e.g. a local MinIO started with
+/// `minio server /tmp/minio`; skipped unless `TEST_S3_ENDPOINT` is set.
+#[actix_web::test]
+async fn s3_blob_store_against_minio() {
+    let endpoint = std::env::var("TEST_S3_ENDPOINT").unwrap_or_default();
+    if endpoint.is_empty() {
+        eprintln!("[s3_blob_store_against_minio] TEST_S3_* vars not set, skipping");
+        return;
+    }
+    let var = |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.into());
+    let store = S3BlobStore::new(S3Config {
+        endpoint,
+        bucket: var("TEST_S3_BUCKET", "syezw-test"),
+        region: var("TEST_S3_REGION", REGION),
+        access_key: var("TEST_S3_ACCESS_KEY", "minioadmin"),
+        secret_key: var("TEST_S3_SECRET_KEY", "minioadmin"),
+        prefix: "test/".to_string(),
+    })
+    .expect("s3 store");
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let hash = format!("minio_{}", nanos);
+    store.put("default", &hash, b"ciphertext").await.unwrap();
+    assert_eq!(
+        store.get("default", &hash).await.unwrap().as_deref(),
+        Some(&b"ciphertext"[..])
+    );
+    assert_eq!(store.get("default", "missing_hash").await.unwrap(), None);
+}
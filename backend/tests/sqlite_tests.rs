@@ -0,0 +1,1066 @@
+#![cfg(feature = "sqlite")]
+
+mod common;
+
+use actix_web::{test, web, App};
+use common::test_env;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use syezw_sync_backend::gc::sweep_expired;
+use syezw_sync_backend::models::{
+    DiaryImageRefItem, DiaryImageSyncItem, DiarySyncItem, EncryptedBlob, HistoryListRequest,
+    HistoryListResponse, HistoryRestoreRequest, HistoryRestoreResponse, ImageFetchRequest,
+    ImageFetchResponse, ImageHashListResponse, ImageRefsUpsertRequest, ImageUploadBeginRequest,
+    ImageUploadRequest, ImageUploadStatus, PeriodSyncItem, RecordKind, SyncChangesResponse,
+    SyncDownloadEnvelope, SyncDownloadRequest, SyncMeta, SyncMetaResponse, SyncUploadRequest,
+    SyncUploadResponse, TodoSyncItem, TombstoneItem,
+};
+use syezw_sync_backend::storage::{
+    latest_schema_version, sqlite, MigrationMode, SqliteStorage, StorageError,
+};
+
+const API_KEY: &str = "sqlite-test-key";
+
+/// Opens and migrates a fresh SQLite database file in the temp dir.
+async fn open_storage(label: &str) -> SqliteStorage {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let path = std::env::temp_dir().join(format!("syezw_{}_{}.db", label, nanos));
+    let storage = SqliteStorage::connect(path.to_str().expect("utf-8 temp path"))
+        .await
+        .expect("open sqlite storage");
+    storage
+        .migrate(MigrationMode::Apply)
+        .await
+        .expect("migrate sqlite storage");
+    storage
+}
+
+fn app_state(storage: SqliteStorage) -> syezw_sync_backend::AppState {
+    let mut env = test_env();
+    env.api_key = API_KEY.to_string();
+    env.account_keys = vec![];
+    syezw_sync_backend::AppState::new(env, Arc::new(storage))
+}
+
+fn blob() -> EncryptedBlob {
+    EncryptedBlob {
+        iv: "iv".to_string(),
+        data: "data".to_string(),
+    }
+}
+
+fn diary(uuid: &str, updated_at: i64) -> DiarySyncItem {
+    DiarySyncItem {
+        uuid: uuid.to_string(),
+        author: "a".to_string(),
+        timestamp: 1,
+        updated_at,
+        payload: EncryptedBlob {
+            iv: "iv".to_string(),
+            data: format!("data@{}", updated_at),
+        },
+        base_updated_at: None,
+    }
+}
+
+fn upload_of(diaries: Vec<DiarySyncItem>, deletions: Vec<TombstoneItem>) -> SyncUploadRequest {
+    SyncUploadRequest {
+        diaries,
+        todos: vec![],
+        periods: vec![],
+        images: vec![],
+        deletions,
+    }
+}
+
+fn empty_download() -> SyncDownloadRequest {
+    SyncDownloadRequest {
+        diaries: vec![],
+        todos: vec![],
+        periods: vec![],
+        limit: None,
+        max_bytes: None,
+        page_token: None,
+    }
+}
+
+#[actix_web::test]
+async fn sqlite_upload_then_download_round_trip() {
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(app_state(open_storage("round_trip").await)))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            )
+            .route(
+                "/sync/download",
+                web::post().to(syezw_sync_backend::sync_download),
+            )
+            .route("/sync/meta", web::post().to(syezw_sync_backend::sync_meta)),
+    )
+    .await;
+
+    let upload = SyncUploadRequest {
+        diaries: vec![diary("d_1", 2)],
+        todos: vec![TodoSyncItem {
+            uuid: "t_1".to_string(),
+            author: "a".to_string(),
+            is_completed: true,
+            created_at: 3,
+            completed_at: Some(4),
+            updated_at: 4,
+            payload: blob(),
+            base_updated_at: None,
+        }],
+        periods: vec![PeriodSyncItem {
+            uuid: String::new(),
+            start_date: "2025-01-01".to_string(),
+            end_date: "2025-01-05".to_string(),
+            updated_at: 5,
+            payload: blob(),
+            base_updated_at: None,
+        }],
+        images: vec![DiaryImageSyncItem {
+            file_name: "img.jpg".to_string(),
+            diary_uuid: "d_1".to_string(),
+            hash: "hash123".to_string(),
+            updated_at: 6,
+            blob: blob(),
+        }],
+        deletions: vec![],
+    };
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(&upload)
+        .to_request();
+    let resp: SyncUploadResponse = test::call_and_read_body_json(&app, req).await;
+    assert!(resp.ok);
+    assert_eq!(
+        (
+            resp.counts.diaries,
+            resp.counts.todos,
+            resp.counts.periods,
+            resp.counts.images
+        ),
+        (1, 1, 1, 1)
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/sync/download")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(empty_download())
+        .to_request();
+    let resp: SyncDownloadEnvelope = test::call_and_read_body_json(&app, req).await;
+    assert!(resp.ok, "download ok");
+    let data = resp.data;
+    assert!(data.diaries.iter().any(|d| d.uuid == "d_1"));
+    assert!(data
+        .todos
+        .iter()
+        .any(|t| t.uuid == "t_1" && t.is_completed && t.completed_at == Some(4)));
+    assert!(data.periods.iter().any(|p| p.uuid == "period-2025-01-01"
+        && p.start_date == "2025-01-01"
+        && p.end_date == "2025-01-05"));
+
+    // An older version of the diary is rejected with the server's version.
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(upload_of(vec![diary("d_1", 1)], vec![]))
+        .to_request();
+    let resp: SyncUploadResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.counts.diaries, 0);
+    assert_eq!(resp.rejected.len(), 1);
+    assert_eq!(resp.rejected[0].server_updated_at, 2);
+
+    let req = test::TestRequest::post()
+        .uri("/sync/meta")
+        .insert_header(("X-API-Key", API_KEY))
+        .to_request();
+    let resp: SyncMetaResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.diaries.len(), 1);
+    assert_eq!(resp.diaries[0].updated_at, 2);
+    assert_eq!(resp.todos.len(), 1);
+    assert_eq!(resp.periods.len(), 1);
+}
+
+#[actix_web::test]
+async fn sqlite_image_hash_dedup_and_fetch() {
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(app_state(open_storage("images").await)))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            )
+            .route(
+                "/images/hashes",
+                web::post().to(syezw_sync_backend::image_hashes),
+            )
+            .route(
+                "/images/upload",
+                web::post().to(syezw_sync_backend::image_upload),
+            )
+            .route(
+                "/images/refs/upsert",
+                web::post().to(syezw_sync_backend::image_refs_upsert),
+            )
+            .route(
+                "/images/fetch",
+                web::post().to(syezw_sync_backend::image_fetch),
+            ),
+    )
+    .await;
+
+    let image = |diary_uuid: &str| DiaryImageSyncItem {
+        file_name: "img.jpg".to_string(),
+        diary_uuid: diary_uuid.to_string(),
+        hash: "hash123".to_string(),
+        updated_at: 6,
+        blob: blob(),
+    };
+    let mut upload = upload_of(vec![diary("d_img", 2)], vec![]);
+    upload.images = vec![image("d_img")];
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(&upload)
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    // The same blob uploaded again, then referenced from a second diary.
+    let req = test::TestRequest::post()
+        .uri("/images/upload")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(ImageUploadRequest {
+            images: vec![image("d_img")],
+        })
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::post()
+        .uri("/images/refs/upsert")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(ImageRefsUpsertRequest {
+            refs: vec![DiaryImageRefItem {
+                diary_uuid: "d_other".to_string(),
+                file_name: "img.jpg".to_string(),
+                hash: "hash123".to_string(),
+                updated_at: 7,
+            }],
+        })
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = test::TestRequest::post()
+        .uri("/images/hashes")
+        .insert_header(("X-API-Key", API_KEY))
+        .to_request();
+    let resp: ImageHashListResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.hashes, vec!["hash123".to_string()]);
+
+    let req = test::TestRequest::post()
+        .uri("/images/fetch")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(ImageFetchRequest {
+            diary_uuid: "d_other".to_string(),
+            file_name: "img.jpg".to_string(),
+        })
+        .to_request();
+    let resp: ImageFetchResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.hash, "hash123");
+    assert_eq!(resp.updated_at, 7);
+
+    let req = test::TestRequest::post()
+        .uri("/images/fetch")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(ImageFetchRequest {
+            diary_uuid: "d_missing".to_string(),
+            file_name: "img.jpg".to_string(),
+        })
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+}
+
+#[actix_web::test]
+async fn sqlite_changes_deletions_and_history() {
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(app_state(open_storage("history").await)))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            )
+            .route(
+                "/sync/changes",
+                web::get().to(syezw_sync_backend::sync_changes),
+            )
+            .route(
+                "/history/list",
+                web::post().to(syezw_sync_backend::history_list),
+            )
+            .route(
+                "/history/restore",
+                web::post().to(syezw_sync_backend::history_restore),
+            ),
+    )
+    .await;
+    let upload = |body: SyncUploadRequest| {
+        test::TestRequest::post()
+            .uri("/sync/upload")
+            .insert_header(("X-API-Key", API_KEY))
+            .set_json(body)
+            .to_request()
+    };
+    let changes = |since: i64| {
+        test::TestRequest::get()
+            .uri(&format!("/sync/changes?since={}", since))
+            .insert_header(("X-API-Key", API_KEY))
+            .to_request()
+    };
+
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, upload(upload_of(vec![diary("d_h", 1)], vec![]))).await;
+    assert_eq!(resp.counts.diaries, 1);
+    let first: SyncChangesResponse = test::call_and_read_body_json(&app, changes(0)).await;
+    assert_eq!(first.diaries.len(), 1);
+    assert!(first.cursor > 0);
+
+    // A different payload stamped with the stored instant is a conflict, not a replay.
+    let mut tied = diary("d_h", 1);
+    tied.payload.data = "tied".to_string();
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, upload(upload_of(vec![tied], vec![]))).await;
+    assert_eq!(resp.counts.diaries, 0);
+    assert_eq!(resp.rejected.len(), 1);
+    assert_eq!(resp.rejected[0].server_updated_at, 1);
+
+    // Overwrite, then delete: both bump the cursor and archive the old version.
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, upload(upload_of(vec![diary("d_h", 2)], vec![]))).await;
+    assert_eq!(resp.counts.diaries, 1);
+    let resp: SyncUploadResponse = test::call_and_read_body_json(
+        &app,
+        upload(upload_of(
+            vec![],
+            vec![TombstoneItem {
+                uuid: "d_h".to_string(),
+                kind: RecordKind::Diary,
+                deleted_at: 3,
+                base_updated_at: None,
+                start_date: None,
+            }],
+        )),
+    )
+    .await;
+    assert_eq!(resp.counts.deletions, 1);
+
+    let later: SyncChangesResponse =
+        test::call_and_read_body_json(&app, changes(first.cursor)).await;
+    assert!(later.diaries.is_empty());
+    assert_eq!(later.deletions.len(), 1);
+    assert!(later.cursor > first.cursor);
+
+    let req = test::TestRequest::post()
+        .uri("/history/list")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(HistoryListRequest {
+            kind: RecordKind::Diary,
+            uuid: "d_h".to_string(),
+        })
+        .to_request();
+    let history: HistoryListResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(history.revisions.len(), 2);
+    assert_eq!(history.revisions[0].payload.data, "data@2");
+
+    let req = test::TestRequest::post()
+        .uri("/history/restore")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(HistoryRestoreRequest {
+            kind: RecordKind::Diary,
+            uuid: "d_h".to_string(),
+            revision_id: history.revisions[1].revision_id,
+        })
+        .to_request();
+    let restored: HistoryRestoreResponse = test::call_and_read_body_json(&app, req).await;
+    assert!(restored.ok);
+    assert!(restored.updated_at > 3);
+
+    let after: SyncChangesResponse =
+        test::call_and_read_body_json(&app, changes(later.cursor)).await;
+    assert_eq!(after.diaries.len(), 1);
+    assert_eq!(after.diaries[0].payload.data, "data@1");
+    assert_eq!(after.diaries[0].updated_at, restored.updated_at);
+}
+
+#[actix_web::test]
+async fn sqlite_base_match_from_a_slow_clock_still_reaches_other_devices() {
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(app_state(open_storage("slow_clock").await)))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            )
+            .route(
+                "/sync/download",
+                web::post().to(syezw_sync_backend::sync_download),
+            ),
+    )
+    .await;
+    let post = |uri: &str, body: &SyncUploadRequest| {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header(("X-API-Key", API_KEY))
+            .set_json(body)
+            .to_request()
+    };
+    test::call_service(
+        &app,
+        post(
+            "/sync/upload",
+            &upload_of(vec![diary("d_skew", 100)], vec![]),
+        ),
+    )
+    .await;
+
+    // Edited on top of the stored version by a device whose clock is behind.
+    let mut edit = diary("d_skew", 50);
+    edit.base_updated_at = Some(100);
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, post("/sync/upload", &upload_of(vec![edit], vec![])))
+            .await;
+    assert_eq!(resp.counts.diaries, 1);
+
+    // A device still holding the version at 100 must see the edit as newer.
+    let mut request = empty_download();
+    request.diaries = vec![SyncMeta {
+        uuid: "d_skew".to_string(),
+        updated_at: 100,
+    }];
+    let req = test::TestRequest::post()
+        .uri("/sync/download")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(&request)
+        .to_request();
+    let resp: SyncDownloadEnvelope = test::call_and_read_body_json(&app, req).await;
+    let seen = resp.data.diaries.iter().find(|d| d.uuid == "d_skew");
+    assert_eq!(seen.map(|d| d.payload.data.as_str()), Some("data@50"));
+    assert_eq!(seen.map(|d| d.updated_at), Some(101));
+}
+
+#[actix_web::test]
+async fn sqlite_schema_version_is_checked_on_startup() {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let path = std::env::temp_dir().join(format!("syezw_migrations_{}.db", nanos));
+    let storage = SqliteStorage::connect(path.to_str().expect("utf-8 temp path"))
+        .await
+        .expect("open sqlite storage");
+
+    // Check mode refuses an unmigrated database; applying brings it to the latest version.
+    assert!(matches!(
+        storage.migrate(MigrationMode::Check).await,
+        Err(StorageError::Schema(_))
+    ));
+    let latest = latest_schema_version(&sqlite::MIGRATOR);
+    assert_eq!(storage.migrate(MigrationMode::Apply).await.unwrap(), latest);
+    assert_eq!(storage.migrate(MigrationMode::Check).await.unwrap(), latest);
+
+    // A database migrated by a newer binary is refused in either mode.
+    sqlx::query(
+        r#"
+        INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+        VALUES ($1, 'from the future', 1, x'00', 0)
+        "#,
+    )
+    .bind(latest + 1)
+    .execute(storage.pool())
+    .await
+    .expect("record future migration");
+    for mode in [MigrationMode::Apply, MigrationMode::Check] {
+        match storage.migrate(mode).await {
+            Err(StorageError::Schema(msg)) => assert!(msg.contains("newer"), "{}", msg),
+            other => panic!("expected schema error, got {:?}", other),
+        }
+    }
+}
+
+#[actix_web::test]
+async fn sqlite_binary_image_round_trip() {
+    let storage = open_storage("binary_images").await;
+    let pool = storage.pool().clone();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(app_state(storage)))
+            .configure(syezw_sync_backend::configure_routes),
+    )
+    .await;
+
+    let ciphertext = b"\x01\x02binary\xfe";
+    let req = test::TestRequest::put()
+        .uri("/images/blob/hash_bin")
+        .insert_header(("X-API-Key", API_KEY))
+        .insert_header((syezw_sync_backend::IMAGE_IV_HEADER, "iv-bin"))
+        .set_payload(&ciphertext[..])
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    // Without a blob store the bytes are kept inline as base64, as JSON uploads are.
+    let (blob_data,): (Option<String>,) =
+        sqlx::query_as("SELECT blob_data FROM diary_images WHERE hash = 'hash_bin'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(blob_data.as_deref(), Some("AQJiaW5hcnn+"));
+
+    let req = test::TestRequest::get()
+        .uri("/images/blob/hash_bin")
+        .insert_header(("X-API-Key", API_KEY))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.headers()
+            .get(syezw_sync_backend::IMAGE_IV_HEADER)
+            .unwrap(),
+        "iv-bin"
+    );
+    assert_eq!(test::read_body(resp).await, &ciphertext[..]);
+
+    let req = test::TestRequest::get()
+        .uri("/images/blob/hash_bin")
+        .insert_header(("X-API-Key", API_KEY))
+        .insert_header(("If-None-Match", "\"hash_bin\""))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 304);
+
+    let req = test::TestRequest::get()
+        .uri("/images/blob/hash_missing")
+        .insert_header(("X-API-Key", API_KEY))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+}
+
+#[actix_web::test]
+async fn sqlite_resumable_upload_finalizes_into_images() {
+    let storage = open_storage("resumable").await;
+    let pool = storage.pool().clone();
+    let state = app_state(storage);
+    let storage = state.storage.clone();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(syezw_sync_backend::configure_routes),
+    )
+    .await;
+    let chunk = |offset: usize, data: &[u8]| {
+        test::TestRequest::put()
+            .uri(&format!("/images/uploads/hash_big?offset={}", offset))
+            .insert_header(("X-API-Key", API_KEY))
+            .set_payload(data.to_vec())
+            .to_request()
+    };
+    let req = test::TestRequest::post()
+        .uri("/images/uploads/hash_big")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(ImageUploadBeginRequest {
+            iv: "iv-big".to_string(),
+            size: 10,
+            updated_at: None,
+        })
+        .to_request();
+    let status: ImageUploadStatus = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(status.offset, 0);
+
+    let status: ImageUploadStatus = test::call_and_read_body_json(&app, chunk(0, b"01234")).await;
+    assert_eq!(status.offset, 5);
+    assert_eq!(
+        test::call_service(&app, chunk(3, b"34567")).await.status(),
+        409
+    );
+    let (images,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM diary_images")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(images, 0);
+
+    let status: ImageUploadStatus = test::call_and_read_body_json(&app, chunk(5, b"56789")).await;
+    assert_eq!(status.offset, 10);
+    let req = test::TestRequest::post()
+        .uri("/images/uploads/hash_big/finalize")
+        .insert_header(("X-API-Key", API_KEY))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/images/blob/hash_big")
+        .insert_header(("X-API-Key", API_KEY))
+        .to_request();
+    assert_eq!(
+        test::call_and_read_body(&app, req).await,
+        &b"0123456789"[..]
+    );
+    let (sessions, chunks): (i64, i64) = sqlx::query_as(
+        "SELECT (SELECT COUNT(*) FROM image_uploads), (SELECT COUNT(*) FROM image_upload_chunks)",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!((sessions, chunks), (0, 0));
+
+    // A session abandoned past IMAGE_UPLOAD_TTL_SECS is swept with its chunks.
+    let req = test::TestRequest::post()
+        .uri("/images/uploads/hash_big")
+        .insert_header(("X-API-Key", API_KEY))
+        .set_json(ImageUploadBeginRequest {
+            iv: "iv-big".to_string(),
+            size: 10,
+            updated_at: None,
+        })
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    assert!(test::call_service(&app, chunk(0, b"01234"))
+        .await
+        .status()
+        .is_success());
+    let mut env = test_env();
+    env.image_upload_ttl_secs = 60;
+    let later = chrono::Utc::now().timestamp_millis() + 61_000;
+    let report = sweep_expired(storage.as_ref(), &env, later).await.unwrap();
+    assert_eq!(report.image_uploads, 1);
+    let (sessions, chunks): (i64, i64) = sqlx::query_as(
+        "SELECT (SELECT COUNT(*) FROM image_uploads), (SELECT COUNT(*) FROM image_upload_chunks)",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!((sessions, chunks), (0, 0));
+}
+
+#[actix_web::test]
+async fn sqlite_orphaned_images_are_deleted_with_their_blobs() {
+    use syezw_sync_backend::storage::{FsBlobStore, Storage};
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let root = std::env::temp_dir().join(format!("syezw_sqlite_gc_{}", nanos));
+    let blobs = FsBlobStore::open(&root).await.expect("open blob dir");
+    let storage = open_storage("gc").await.with_blob_store(Arc::new(blobs));
+    let image = |hash: &str| DiaryImageSyncItem {
+        file_name: format!("{}.jpg", hash),
+        diary_uuid: "d_gc".to_string(),
+        hash: hash.to_string(),
+        updated_at: 1,
+        blob: EncryptedBlob {
+            iv: "iv".to_string(),
+            data: "Z2M=".to_string(),
+        },
+    };
+    storage
+        .put_images("default", &[image("orphan"), image("used")])
+        .await
+        .unwrap();
+    storage
+        .put_image_refs(
+            "default",
+            &[DiaryImageRefItem {
+                diary_uuid: "d_gc".to_string(),
+                file_name: "used.jpg".to_string(),
+                hash: "used".to_string(),
+                updated_at: 1,
+            }],
+        )
+        .await
+        .unwrap();
+
+    let cutoff = chrono::Utc::now().timestamp_millis() + 1;
+    let orphans = storage.orphaned_images(cutoff).await.unwrap();
+    let hashes: Vec<_> = orphans.iter().map(|o| o.hash.as_str()).collect();
+    assert_eq!(hashes, vec!["orphan"]);
+    assert!(storage.orphaned_images(0).await.unwrap().is_empty());
+
+    assert!(!storage
+        .delete_orphaned_image("default", "used", cutoff)
+        .await
+        .unwrap());
+    assert!(storage
+        .delete_orphaned_image("default", "orphan", cutoff)
+        .await
+        .unwrap());
+    assert!(!root.join("default").join("or").join("orphan").exists());
+    assert!(root.join("default").join("us").join("used").exists());
+    assert_eq!(
+        storage.image_hashes("default").await.unwrap(),
+        vec!["used".to_string()]
+    );
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[actix_web::test]
+async fn sqlite_image_gc_grace_starts_when_the_last_ref_goes() {
+    use syezw_sync_backend::gc::collect_image_garbage;
+    use syezw_sync_backend::models::ImageRefKey;
+    use syezw_sync_backend::storage::Storage;
+
+    let storage = open_storage("gc_grace").await;
+    storage
+        .put_image_refs(
+            "default",
+            &[DiaryImageRefItem {
+                diary_uuid: "d_old".to_string(),
+                file_name: "old.jpg".to_string(),
+                hash: "old".to_string(),
+                updated_at: 1,
+            }],
+        )
+        .await
+        .unwrap();
+    storage
+        .put_images(
+            "default",
+            &[DiaryImageSyncItem {
+                file_name: "old.jpg".to_string(),
+                diary_uuid: "d_old".to_string(),
+                hash: "old".to_string(),
+                updated_at: 1,
+                blob: EncryptedBlob {
+                    iv: "iv".to_string(),
+                    data: "Z2M=".to_string(),
+                },
+            }],
+        )
+        .await
+        .unwrap();
+    assert!(storage.orphaned_images(i64::MAX).await.unwrap().is_empty());
+
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    let released = chrono::Utc::now().timestamp_millis();
+    let key = ImageRefKey {
+        diary_uuid: "d_old".to_string(),
+        file_name: "old.jpg".to_string(),
+    };
+    assert_eq!(
+        storage.delete_image_refs("default", &[key]).await.unwrap(),
+        1
+    );
+
+    // Stored more than the grace period before this run, but unreferenced for less.
+    let report = collect_image_garbage(&storage, 1, false, released + 990)
+        .await
+        .unwrap();
+    assert_eq!((report.orphans.len(), report.deleted), (0, 0));
+    assert_eq!(storage.image_hashes("default").await.unwrap(), vec!["old"]);
+
+    let report = collect_image_garbage(&storage, 1, false, released + 2000)
+        .await
+        .unwrap();
+    assert_eq!(report.deleted, 1);
+    assert!(report.orphans[0].stored_at < report.orphans[0].unreferenced_at);
+    assert!(storage.image_hashes("default").await.unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn sqlite_image_refs_filter_delete_and_replace() {
+    use syezw_sync_backend::models::{ImageRefKey, ImageRefsRequest};
+    use syezw_sync_backend::storage::Storage;
+
+    let storage = open_storage("refs").await;
+    let image_ref = |diary_uuid: &str, file_name: &str, updated_at: i64| DiaryImageRefItem {
+        diary_uuid: diary_uuid.to_string(),
+        file_name: file_name.to_string(),
+        hash: format!("h_{}", file_name),
+        updated_at,
+    };
+    storage
+        .put_image_refs(
+            "default",
+            &[
+                image_ref("d_a", "a1.jpg", 1),
+                image_ref("d_a", "a2.jpg", 2),
+                image_ref("d_b", "b1.jpg", 3),
+                image_ref("d_c", "c1.jpg", 4),
+            ],
+        )
+        .await
+        .unwrap();
+    let names = |refs: Vec<DiaryImageRefItem>| {
+        let mut names: Vec<_> = refs.into_iter().map(|r| r.file_name).collect();
+        names.sort();
+        names
+    };
+    let filter = ImageRefsRequest {
+        diary_uuids: Some(vec!["d_a".to_string(), "d_b".to_string()]),
+        updated_after: Some(1),
+    };
+    let refs = storage.image_refs("default", &filter).await.unwrap();
+    assert_eq!(names(refs), vec!["a2.jpg", "b1.jpg"]);
+    let none = ImageRefsRequest {
+        diary_uuids: Some(vec![]),
+        updated_after: None,
+    };
+    assert!(storage
+        .image_refs("default", &none)
+        .await
+        .unwrap()
+        .is_empty());
+
+    let removed = storage
+        .delete_image_refs(
+            "default",
+            &[ImageRefKey {
+                diary_uuid: "d_c".to_string(),
+                file_name: "c1.jpg".to_string(),
+            }],
+        )
+        .await
+        .unwrap();
+    assert_eq!(removed, 1);
+
+    let removed = storage
+        .replace_diary_image_refs(
+            "default",
+            "d_a",
+            &[image_ref("d_a", "a2.jpg", 5), image_ref("d_a", "a3.jpg", 5)],
+        )
+        .await
+        .unwrap();
+    assert_eq!(removed, 1);
+    let refs = storage
+        .image_refs("default", &ImageRefsRequest::default())
+        .await
+        .unwrap();
+    assert_eq!(names(refs), vec!["a2.jpg", "a3.jpg", "b1.jpg"]);
+}
+
+#[actix_web::test]
+async fn sqlite_image_sizes_are_recorded_for_missing_hash_queries() {
+    use syezw_sync_backend::models::ImageHashSize;
+    use syezw_sync_backend::storage::{ImageBytes, Storage};
+
+    let storage = open_storage("image_sizes").await;
+    let image = DiaryImageSyncItem {
+        file_name: "a.jpg".to_string(),
+        diary_uuid: "d_size".to_string(),
+        hash: "sized_json".to_string(),
+        updated_at: 1,
+        blob: EncryptedBlob {
+            iv: "iv".to_string(),
+            data: "aGVsbG8=".to_string(),
+        },
+    };
+    storage.put_images("default", &[image]).await.unwrap();
+    let bytes = ImageBytes {
+        iv: "iv".to_string(),
+        updated_at: 1,
+        data: vec![0; 7],
+    };
+    storage
+        .put_image_bytes("default", "sized_bin", &bytes)
+        .await
+        .unwrap();
+    // Rows from before the size column have none.
+    sqlx::query(
+        "INSERT INTO diary_images (account_id, hash, blob_iv, blob_data, updated_at, stored_at) \
+         VALUES ('default', 'legacy', 'iv', 'eA==', 1, 1)",
+    )
+    .execute(storage.pool())
+    .await
+    .unwrap();
+
+    let hashes: Vec<String> = ["sized_json", "sized_bin", "legacy", "absent"]
+        .iter()
+        .map(|h| h.to_string())
+        .collect();
+    let mut sizes = storage.image_sizes("default", &hashes).await.unwrap();
+    sizes.sort_by(|a, b| a.hash.cmp(&b.hash));
+    let size = |hash: &str, size| ImageHashSize {
+        hash: hash.to_string(),
+        size,
+    };
+    assert_eq!(
+        sizes,
+        vec![
+            size("legacy", None),
+            size("sized_bin", Some(7)),
+            size("sized_json", Some(5)),
+        ]
+    );
+    assert!(storage
+        .image_sizes("other", &hashes)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[actix_web::test]
+async fn sqlite_api_key_registry_round_trip() {
+    use syezw_sync_backend::models::ApiScope;
+    use syezw_sync_backend::storage::{NewApiKey, Storage};
+
+    let storage = open_storage("api_keys").await;
+    assert!(!storage.has_api_keys().await.unwrap());
+    let new_key = |id: &str, account: &str, created_at| NewApiKey {
+        id: id.to_string(),
+        account_id: account.to_string(),
+        name: format!("{} key", id),
+        key_hash: format!("hash_{}", id),
+        scopes: vec![ApiScope::Read, ApiScope::Admin],
+        created_at,
+    };
+    let first = storage
+        .create_api_key(&new_key("k1", "default", 1))
+        .await
+        .unwrap();
+    storage
+        .create_api_key(&new_key("k2", "other", 2))
+        .await
+        .unwrap();
+    assert!(storage.has_api_keys().await.unwrap());
+    assert_eq!(
+        storage.api_key_by_hash("hash_k1").await.unwrap(),
+        Some(first.clone())
+    );
+    assert_eq!(storage.api_key_by_hash("nope").await.unwrap(), None);
+
+    storage.touch_api_key("k1", 10).await.unwrap();
+    let revoked = storage.revoke_api_key("k1", 20).await.unwrap().unwrap();
+    assert_eq!(
+        (revoked.last_used_at, revoked.revoked_at),
+        (Some(10), Some(20))
+    );
+    let again = storage.revoke_api_key("k1", 30).await.unwrap().unwrap();
+    assert_eq!(again.revoked_at, Some(20));
+    assert!(storage.revoke_api_key("k9", 30).await.unwrap().is_none());
+
+    let ids = |keys: Vec<syezw_sync_backend::models::ApiKeyInfo>| {
+        keys.into_iter().map(|k| k.id).collect::<Vec<_>>()
+    };
+    assert_eq!(
+        ids(storage.list_api_keys(None).await.unwrap()),
+        vec!["k1", "k2"]
+    );
+    assert_eq!(
+        ids(storage.list_api_keys(Some("other")).await.unwrap()),
+        vec!["k2"]
+    );
+    assert_eq!(
+        storage.list_api_keys(Some("other")).await.unwrap()[0].scopes,
+        vec![ApiScope::Read, ApiScope::Admin]
+    );
+}
+
+#[actix_web::test]
+async fn sqlite_auth_sessions_rotate_and_revoke() {
+    use syezw_sync_backend::models::ApiScope;
+    use syezw_sync_backend::storage::{AuthSession, Storage};
+
+    let storage = open_storage("auth_sessions").await;
+    let session = |id: &str, key_id: Option<&str>| AuthSession {
+        id: id.to_string(),
+        account_id: "default".to_string(),
+        key_id: key_id.map(str::to_string),
+        scopes: vec![ApiScope::Read],
+        refresh_hash: format!("r0_{}", id),
+        previous_refresh_hash: None,
+        created_at: 1,
+        refreshed_at: 1,
+        expires_at: 100,
+        revoked_at: None,
+    };
+    for s in [
+        session("s1", None),
+        session("s2", Some("k1")),
+        session("s3", Some("k2")),
+    ] {
+        storage.create_auth_session(&s).await.unwrap();
+    }
+    assert_eq!(
+        storage.auth_session("s2").await.unwrap(),
+        Some(session("s2", Some("k1")))
+    );
+    assert_eq!(storage.auth_session("nope").await.unwrap(), None);
+
+    assert!(storage
+        .rotate_auth_session("s1", "r0_s1", "r1", 5, 200)
+        .await
+        .unwrap());
+    assert!(!storage
+        .rotate_auth_session("s1", "r0_s1", "r2", 6, 300)
+        .await
+        .unwrap());
+    let rotated = storage.auth_session("s1").await.unwrap().unwrap();
+    assert_eq!(
+        (
+            rotated.refresh_hash.as_str(),
+            rotated.previous_refresh_hash.as_deref(),
+            rotated.refreshed_at,
+            rotated.expires_at
+        ),
+        ("r1", Some("r0_s1"), 5, 200)
+    );
+    // Found by the current or the replaced refresh token.
+    for hash in ["r1", "r0_s1"] {
+        let found = storage.auth_session_by_refresh(hash).await.unwrap();
+        assert_eq!(found.map(|s| s.id), Some("s1".to_string()));
+    }
+    assert_eq!(storage.auth_session_by_refresh("r2").await.unwrap(), None);
+
+    assert_eq!(
+        storage
+            .revoke_auth_sessions("default", Some("k1"), 7)
+            .await
+            .unwrap(),
+        1
+    );
+    assert!(storage.revoke_auth_session("s1", 8).await.unwrap());
+    assert!(!storage.revoke_auth_session("s1", 9).await.unwrap());
+    assert!(!storage
+        .rotate_auth_session("s1", "r1", "r3", 10, 400)
+        .await
+        .unwrap());
+    assert_eq!(
+        storage
+            .revoke_auth_sessions("default", None, 11)
+            .await
+            .unwrap(),
+        1
+    );
+    let s3 = storage.auth_session("s3").await.unwrap().unwrap();
+    assert_eq!(s3.revoked_at, Some(11));
+}
+
+#[actix_web::test]
+async fn sqlite_auth_failures_are_logged_newest_first() {
+    use syezw_sync_backend::storage::Storage;
+
+    let storage = open_storage("auth_failures").await;
+    for (ip, now) in [("10.0.0.1", 1), ("10.0.0.2", 2), ("10.0.0.1", 3)] {
+        storage
+            .record_auth_failure(ip, "/sync/meta", "api key", now)
+            .await
+            .unwrap();
+    }
+    let failures = storage.auth_failures(0, 10).await.unwrap();
+    assert_eq!(
+        failures
+            .iter()
+            .map(|f| (f.ip.as_str(), f.created_at))
+            .collect::<Vec<_>>(),
+        vec![("10.0.0.1", 3), ("10.0.0.2", 2), ("10.0.0.1", 1)]
+    );
+    assert_eq!(failures[0].path, "/sync/meta");
+    assert_eq!(failures[0].reason, "api key");
+    assert_eq!(storage.auth_failures(2, 10).await.unwrap().len(), 2);
+    assert_eq!(storage.auth_failures(0, 1).await.unwrap()[0].created_at, 3);
+
+    assert_eq!(storage.prune_auth_failures(3).await.unwrap(), 2);
+    assert_eq!(storage.auth_failures(0, 10).await.unwrap().len(), 1);
+}
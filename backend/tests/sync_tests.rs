@@ -1,88 +1,90 @@
 use actix_web::{test, web, App};
 use dotenvy::dotenv;
 use sqlx::postgres::PgPoolOptions;
-use sqlx::Executor;
+use sqlx::PgPool;
 use std::env;
+use std::sync::Arc;
 
 use std::time::{SystemTime, UNIX_EPOCH};
-use syezw_sync_backend::db::EnvConfig;
+use syezw_sync_backend::db::{parse_account_keys, EnvConfig};
 use syezw_sync_backend::models::{
-    DiaryImageSyncItem, DiarySyncItem, EncryptedBlob, PeriodSyncItem, SyncDownloadEnvelope,
-    SyncDownloadRequest, SyncUploadRequest, TodoSyncItem,
+    ApiScope, DeviceListResponse, DeviceRegisterRequest, DiaryImageRefItem, DiaryImageSyncItem,
+    DiarySyncItem, EncryptedBlob, HistoryListRequest, HistoryListResponse, HistoryRestoreRequest,
+    HistoryRestoreResponse, ImageFetchRequest, ImageFetchResponse, ImageHashListResponse,
+    ImageRefKey, ImageRefsRequest, PeriodMeta, PeriodSyncItem, RecordKind, SyncChangesResponse,
+    SyncDownloadEnvelope, SyncDownloadRequest, SyncMeta, SyncMetaResponse, SyncUploadRequest,
+    SyncUploadResponse, TodoSyncItem, TombstoneItem,
+};
+use syezw_sync_backend::storage::{
+    AuthSession, ChunkOutcome, FinalizeOutcome, FsBlobStore, MigrationMode, NewApiKey,
+    NewImageUpload, PgStorage, Storage,
 };
 
-fn log_db_info(label: &str, host: &str, port: i32, db: &str, user: &str) {
-    eprintln!(
-        "[{}] DB config: host={}, port={}, db={}, user={}",
-        label, host, port, db, user
-    );
-}
-
-#[actix_web::test]
-async fn upload_then_download_round_trip() {
+/// Connects to the test database and runs the migrations, or returns `None`
+/// when the `PG_*` / `TEST_PG_DB` variables are not configured, so every
+/// test here is skipped without a database.
+async fn test_pool() -> Option<PgPool> {
     dotenv().ok();
-    let test_host = env::var("PG_HOST").unwrap_or_default();
-    let test_port = env::var("PG_PORT")
+    let host = env::var("PG_HOST").unwrap_or_default();
+    let port = env::var("PG_PORT")
         .ok()
         .and_then(|v| v.parse::<i32>().ok())
-        .unwrap_or(0);
-    let test_db = env::var("TEST_PG_DB").unwrap_or_default();
-    let test_user = env::var("PG_USER").unwrap_or_default();
-    let test_password = env::var("PG_PASSWORD").unwrap_or_default();
-    if test_host.is_empty() || test_db.is_empty() || test_user.is_empty() {
-        eprintln!(
-            "TEST_PG_* vars not set, skipping integration test. Missing: {}{}{}",
-            if test_host.is_empty() { "PG_HOST " } else { "" },
-            if test_db.is_empty() {
-                "TEST_PG_DB "
-            } else {
-                ""
-            },
-            if test_user.is_empty() { "PG_USER " } else { "" }
-        );
-        return;
+        .unwrap_or(5432);
+    let db = env::var("TEST_PG_DB").unwrap_or_default();
+    let user = env::var("PG_USER").unwrap_or_default();
+    let password = env::var("PG_PASSWORD").unwrap_or_default();
+    if host.is_empty() || db.is_empty() || user.is_empty() {
+        eprintln!("PG_HOST, TEST_PG_DB or PG_USER not set, skipping integration test");
+        return None;
     }
-
-    log_db_info(
-        "upload_then_download_round_trip",
-        &test_host,
-        if test_port == 0 { 5432 } else { test_port },
-        &test_db,
-        &test_user,
-    );
-
-    let test_db_url = format!(
-        "postgres://{}:{}@{}:{}/{}",
-        test_user,
-        test_password,
-        test_host,
-        if test_port == 0 { 5432 } else { test_port },
-        test_db
+    eprintln!(
+        "DB config: host={}, port={}, db={}, user={}",
+        host, port, db, user
     );
-
+    let url = format!("postgres://{}:{}@{}:{}/{}", user, password, host, port, db);
     let pool = PgPoolOptions::new()
         .max_connections(1)
-        .connect(&test_db_url)
+        .connect(&url)
         .await
         .expect("connect test db");
+    PgStorage::new(pool.clone())
+        .migrate(MigrationMode::Apply)
+        .await
+        .expect("migrate test db");
+    Some(pool)
+}
 
-    let schema = std::fs::read_to_string("sql/schema.sql").expect("read schema");
-    pool.execute(schema.as_str()).await.expect("apply schema");
-
-    let suffix = SystemTime::now()
+fn unique_suffix() -> u128 {
+    SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or_default()
-        .as_nanos();
+        .as_nanos()
+}
+
+fn blob() -> EncryptedBlob {
+    EncryptedBlob {
+        iv: "iv".to_string(),
+        data: "data".to_string(),
+    }
+}
+
+#[actix_web::test]
+async fn upload_then_download_round_trip() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+
+    let suffix = unique_suffix();
     let diary_uuid = format!("d1_{}", suffix);
     let todo_uuid = format!("t1_{}", suffix);
 
     let env_cfg = EnvConfig::from_env();
     let app = test::init_service(
         App::new()
-            .app_data(web::Data::new(syezw_sync_backend::AppState {
-                env: env_cfg.clone(),
-                pool: pool.clone(),
-            }))
+            .app_data(web::Data::new(syezw_sync_backend::AppState::new(
+                env_cfg.clone(),
+                Arc::new(PgStorage::new(pool.clone())),
+            )))
             .route(
                 "/sync/upload",
                 web::post().to(syezw_sync_backend::sync_upload),
@@ -105,6 +107,7 @@ async fn upload_then_download_round_trip() {
                 iv: "iv".to_string(),
                 data: "data".to_string(),
             },
+            base_updated_at: None,
         }],
         todos: vec![TodoSyncItem {
             uuid: todo_uuid.clone(),
@@ -117,8 +120,10 @@ async fn upload_then_download_round_trip() {
                 iv: "iv".to_string(),
                 data: "data".to_string(),
             },
+            base_updated_at: None,
         }],
         periods: vec![PeriodSyncItem {
+            uuid: String::new(),
             start_date: "2025-01-01".to_string(),
             end_date: "2025-01-05".to_string(),
             updated_at: 5,
@@ -126,6 +131,7 @@ async fn upload_then_download_round_trip() {
                 iv: "iv".to_string(),
                 data: "data".to_string(),
             },
+            base_updated_at: None,
         }],
         images: vec![DiaryImageSyncItem {
             file_name: "img.jpg".to_string(),
@@ -137,6 +143,7 @@ async fn upload_then_download_round_trip() {
                 data: "data".to_string(),
             },
         }],
+        deletions: vec![],
     };
 
     let req = test::TestRequest::post()
@@ -151,6 +158,9 @@ async fn upload_then_download_round_trip() {
         diaries: vec![],
         todos: vec![],
         periods: vec![],
+        limit: None,
+        max_bytes: None,
+        page_token: None,
     };
     let req = test::TestRequest::post()
         .uri("/sync/download")
@@ -172,68 +182,20 @@ async fn upload_then_download_round_trip() {
 
 #[actix_web::test]
 async fn upload_with_image_hash_dedup_and_fetch() {
-    dotenv().ok();
-    let test_host = env::var("PG_HOST").unwrap_or_default();
-    let test_port = env::var("PG_PORT")
-        .ok()
-        .and_then(|v| v.parse::<i32>().ok())
-        .unwrap_or(0);
-    let test_db = env::var("TEST_PG_DB").unwrap_or_default();
-    let test_user = env::var("PG_USER").unwrap_or_default();
-    let test_password = env::var("PG_PASSWORD").unwrap_or_default();
-    if test_host.is_empty() || test_db.is_empty() || test_user.is_empty() {
-        eprintln!(
-            "TEST_PG_* vars not set, skipping integration test. Missing: {}{}{}",
-            if test_host.is_empty() { "PG_HOST " } else { "" },
-            if test_db.is_empty() {
-                "TEST_PG_DB "
-            } else {
-                ""
-            },
-            if test_user.is_empty() { "PG_USER " } else { "" }
-        );
+    let Some(pool) = test_pool().await else {
         return;
-    }
-
-    log_db_info(
-        "upload_with_image_hash_dedup_and_fetch",
-        &test_host,
-        if test_port == 0 { 5432 } else { test_port },
-        &test_db,
-        &test_user,
-    );
-
-    let test_db_url = format!(
-        "postgres://{}:{}@{}:{}/{}",
-        test_user,
-        test_password,
-        test_host,
-        if test_port == 0 { 5432 } else { test_port },
-        test_db
-    );
-
-    let pool = PgPoolOptions::new()
-        .max_connections(1)
-        .connect(&test_db_url)
-        .await
-        .expect("connect test db");
-
-    let schema = std::fs::read_to_string("sql/schema.sql").expect("read schema");
-    pool.execute(schema.as_str()).await.expect("apply schema");
+    };
 
-    let suffix = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_nanos();
+    let suffix = unique_suffix();
     let diary_uuid = format!("d_img_1_{}", suffix);
 
     let env_cfg = EnvConfig::from_env();
     let app = test::init_service(
         App::new()
-            .app_data(web::Data::new(syezw_sync_backend::AppState {
-                env: env_cfg.clone(),
-                pool: pool.clone(),
-            }))
+            .app_data(web::Data::new(syezw_sync_backend::AppState::new(
+                env_cfg.clone(),
+                Arc::new(PgStorage::new(pool.clone())),
+            )))
             .route(
                 "/sync/upload",
                 web::post().to(syezw_sync_backend::sync_upload),
@@ -276,6 +238,7 @@ async fn upload_with_image_hash_dedup_and_fetch() {
                 iv: "iv".to_string(),
                 data: "data".to_string(),
             },
+            base_updated_at: None,
         }],
         todos: vec![],
         periods: vec![],
@@ -289,6 +252,7 @@ async fn upload_with_image_hash_dedup_and_fetch() {
                 data: "data".to_string(),
             },
         }],
+        deletions: vec![],
     };
 
     let req = test::TestRequest::post()
@@ -338,7 +302,7 @@ async fn upload_with_image_hash_dedup_and_fetch() {
         .uri("/images/fetch")
         .insert_header(("X-API-Key", std::env::var("API_KEY").unwrap()))
         .set_json(&syezw_sync_backend::models::ImageFetchRequest {
-            diary_uuid: diary_uuid,
+            diary_uuid,
             file_name: "img.jpg".to_string(),
         })
         .to_request();
@@ -349,3 +313,1565 @@ async fn upload_with_image_hash_dedup_and_fetch() {
         .await;
     assert_eq!(resp.hash, "hash123");
 }
+
+#[actix_web::test]
+async fn deletion_tombstone_propagates_and_cascades_refs() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let diary_uuid = format!("d_del_{}", suffix);
+    let todo_uuid = format!("t_del_{}", suffix);
+    let api_key = env::var("API_KEY").unwrap_or_default();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(syezw_sync_backend::AppState::new(
+                EnvConfig::from_env(),
+                Arc::new(PgStorage::new(pool.clone())),
+            )))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            )
+            .route(
+                "/sync/download",
+                web::post().to(syezw_sync_backend::sync_download),
+            )
+            .route("/sync/meta", web::post().to(syezw_sync_backend::sync_meta)),
+    )
+    .await;
+
+    let upload = SyncUploadRequest {
+        diaries: vec![DiarySyncItem {
+            uuid: diary_uuid.clone(),
+            author: "a".to_string(),
+            timestamp: 1,
+            updated_at: 2,
+            payload: blob(),
+            base_updated_at: None,
+        }],
+        todos: vec![TodoSyncItem {
+            uuid: todo_uuid.clone(),
+            author: "a".to_string(),
+            is_completed: false,
+            created_at: 3,
+            completed_at: None,
+            updated_at: 4,
+            payload: blob(),
+            base_updated_at: None,
+        }],
+        periods: vec![],
+        images: vec![DiaryImageSyncItem {
+            file_name: "img.jpg".to_string(),
+            diary_uuid: diary_uuid.clone(),
+            hash: format!("hash_del_{}", suffix),
+            updated_at: 2,
+            blob: blob(),
+        }],
+        deletions: vec![],
+    };
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(&upload)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let deletion = SyncUploadRequest {
+        diaries: vec![],
+        todos: vec![],
+        periods: vec![],
+        images: vec![],
+        deletions: vec![
+            TombstoneItem {
+                uuid: diary_uuid.clone(),
+                kind: RecordKind::Diary,
+                deleted_at: 10,
+                base_updated_at: None,
+                start_date: None,
+            },
+            TombstoneItem {
+                uuid: todo_uuid.clone(),
+                kind: RecordKind::Todo,
+                deleted_at: 10,
+                base_updated_at: None,
+                start_date: None,
+            },
+        ],
+    };
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(&deletion)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let refs: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM diary_image_refs WHERE diary_uuid = $1")
+            .bind(&diary_uuid)
+            .fetch_one(&pool)
+            .await
+            .expect("count refs");
+    assert_eq!(refs, 0, "diary refs cascade on deletion");
+
+    // A stale upload from the other phone must not resurrect the diary.
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(&upload)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert!(resp.status().is_success());
+
+    let req = test::TestRequest::post()
+        .uri("/sync/meta")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .to_request();
+    let meta: SyncMetaResponse = test::call_and_read_body_json(&app, req).await;
+    assert!(!meta.diaries.iter().any(|d| d.uuid == diary_uuid));
+    assert!(meta
+        .deletions
+        .iter()
+        .any(|d| d.uuid == diary_uuid && d.kind == RecordKind::Diary && d.deleted_at == 10));
+
+    let download_req = SyncDownloadRequest {
+        diaries: vec![SyncMeta {
+            uuid: diary_uuid.clone(),
+            updated_at: 2,
+        }],
+        todos: vec![SyncMeta {
+            uuid: todo_uuid.clone(),
+            updated_at: 4,
+        }],
+        periods: vec![],
+        limit: None,
+        max_bytes: None,
+        page_token: None,
+    };
+    let req = test::TestRequest::post()
+        .uri("/sync/download")
+        .insert_header(("X-API-Key", api_key))
+        .set_json(&download_req)
+        .to_request();
+    let resp: SyncDownloadEnvelope = test::call_and_read_body_json(&app, req).await;
+    assert!(resp.ok);
+    assert!(!resp.data.diaries.iter().any(|d| d.uuid == diary_uuid));
+    assert!(resp.data.deletions.iter().any(|d| d.uuid == diary_uuid));
+    assert!(resp.data.deletions.iter().any(|d| d.uuid == todo_uuid));
+}
+
+#[actix_web::test]
+async fn changes_since_cursor_returns_only_newer_rows() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let first_uuid = format!("d_chg_1_{}", suffix);
+    let second_uuid = format!("d_chg_2_{}", suffix);
+    let api_key = env::var("API_KEY").unwrap_or_default();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(syezw_sync_backend::AppState::new(
+                EnvConfig::from_env(),
+                Arc::new(PgStorage::new(pool.clone())),
+            )))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            )
+            .route(
+                "/sync/changes",
+                web::get().to(syezw_sync_backend::sync_changes),
+            ),
+    )
+    .await;
+
+    let diary = |uuid: &str, updated_at: i64| DiarySyncItem {
+        uuid: uuid.to_string(),
+        author: "a".to_string(),
+        timestamp: 1,
+        updated_at,
+        payload: blob(),
+        base_updated_at: None,
+    };
+    let upload = |diaries: Vec<DiarySyncItem>, deletions: Vec<TombstoneItem>| SyncUploadRequest {
+        diaries,
+        todos: vec![],
+        periods: vec![],
+        images: vec![],
+        deletions,
+    };
+
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(upload(vec![diary(&first_uuid, 1)], vec![]))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/sync/changes?since=0")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .to_request();
+    let full: SyncChangesResponse = test::call_and_read_body_json(&app, req).await;
+    assert!(full.diaries.iter().any(|d| d.uuid == first_uuid));
+    let cursor = full.cursor;
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/sync/changes?since={}", cursor))
+        .insert_header(("X-API-Key", api_key.clone()))
+        .to_request();
+    let empty: SyncChangesResponse = test::call_and_read_body_json(&app, req).await;
+    assert!(empty.diaries.is_empty());
+    assert_eq!(empty.cursor, cursor);
+
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(upload(
+            vec![diary(&second_uuid, 2)],
+            vec![TombstoneItem {
+                uuid: first_uuid.clone(),
+                kind: RecordKind::Diary,
+                deleted_at: 5,
+                base_updated_at: None,
+                start_date: None,
+            }],
+        ))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/sync/changes?since={}", cursor))
+        .insert_header(("X-API-Key", api_key))
+        .to_request();
+    let delta: SyncChangesResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(delta.diaries.len(), 1);
+    assert_eq!(delta.diaries[0].uuid, second_uuid);
+    assert_eq!(delta.deletions.len(), 1);
+    assert_eq!(delta.deletions[0].uuid, first_uuid);
+    assert!(delta.cursor > cursor);
+}
+
+#[actix_web::test]
+async fn changes_since_reads_one_snapshot() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let account = format!("acct_snapshot_{}", suffix);
+    let reader_name = format!("changes_snapshot_{}", suffix);
+    // The table lock, the blocked reader, the writer and the lock check each
+    // need a connection.
+    let pool = PgPoolOptions::new()
+        .max_connections(4)
+        .connect_with(
+            (*pool.connect_options())
+                .clone()
+                .application_name(&reader_name),
+        )
+        .await
+        .expect("connect test db");
+    let storage = Arc::new(PgStorage::new(pool.clone()));
+
+    // Stop the reader at its todo query, after it has read the diaries.
+    let mut lock = pool.begin().await.expect("begin");
+    sqlx::query("LOCK TABLE todo_sync IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut *lock)
+        .await
+        .expect("lock todo_sync");
+    let reader = actix_web::rt::spawn({
+        let (storage, account) = (storage.clone(), account.clone());
+        async move { storage.changes_since(&account, 0).await }
+    });
+    loop {
+        let waiting: bool = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM pg_locks l JOIN pg_stat_activity a ON a.pid = l.pid
+                WHERE l.relation = 'todo_sync'::regclass AND NOT l.granted
+                  AND a.application_name = $1
+            )
+            "#,
+        )
+        .bind(&reader_name)
+        .fetch_one(&pool)
+        .await
+        .expect("lock query");
+        if waiting {
+            break;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    // A diary and a later period commit between the reader's queries.
+    let diary_uuid = format!("d_snap_{}", suffix);
+    let period_uuid = format!("p_snap_{}", suffix);
+    storage
+        .apply_upload(
+            &account,
+            &SyncUploadRequest {
+                diaries: vec![DiarySyncItem {
+                    uuid: diary_uuid.clone(),
+                    author: "a".to_string(),
+                    timestamp: 1,
+                    updated_at: 1,
+                    payload: blob(),
+                    base_updated_at: None,
+                }],
+                todos: vec![],
+                periods: vec![PeriodSyncItem {
+                    uuid: period_uuid.clone(),
+                    start_date: "2025-01-01".to_string(),
+                    end_date: "2025-01-05".to_string(),
+                    updated_at: 1,
+                    payload: blob(),
+                    base_updated_at: None,
+                }],
+                images: vec![],
+                deletions: vec![],
+            },
+            0,
+        )
+        .await
+        .expect("upload");
+    lock.rollback().await.expect("unlock");
+
+    let first = reader.await.expect("reader").expect("changes");
+    let second = storage
+        .changes_since(&account, first.cursor)
+        .await
+        .expect("changes");
+    let seen = |uuid: &str| {
+        [&first, &second].iter().any(|c| {
+            c.diaries.iter().any(|d| d.uuid == uuid) || c.periods.iter().any(|p| p.uuid == uuid)
+        })
+    };
+    assert!(seen(&diary_uuid), "diary skipped by the cursor");
+    assert!(seen(&period_uuid));
+}
+
+#[actix_web::test]
+async fn stale_writes_are_rejected_with_server_version() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let diary_uuid = format!("d_stale_{}", unique_suffix());
+    let api_key = env::var("API_KEY").unwrap_or_default();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(syezw_sync_backend::AppState::new(
+                EnvConfig::from_env(),
+                Arc::new(PgStorage::new(pool.clone())),
+            )))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            ),
+    )
+    .await;
+
+    let upload = |updated_at: i64, base_updated_at: Option<i64>, data: &str| SyncUploadRequest {
+        diaries: vec![DiarySyncItem {
+            uuid: diary_uuid.clone(),
+            author: "a".to_string(),
+            timestamp: 1,
+            updated_at,
+            payload: EncryptedBlob {
+                iv: "iv".to_string(),
+                data: data.to_string(),
+            },
+            base_updated_at,
+        }],
+        todos: vec![],
+        periods: vec![],
+        images: vec![],
+        deletions: vec![],
+    };
+    let send = |body: SyncUploadRequest| {
+        test::TestRequest::post()
+            .uri("/sync/upload")
+            .insert_header(("X-API-Key", api_key.clone()))
+            .set_json(body)
+            .to_request()
+    };
+
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, send(upload(10, None, "newer"))).await;
+    assert!(resp.ok && resp.rejected.is_empty());
+
+    // Replaying the stored version is idempotent, not a conflict.
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, send(upload(10, None, "newer"))).await;
+    assert!(resp.rejected.is_empty());
+
+    // A different payload at the same instant is not a replay.
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, send(upload(10, None, "tied"))).await;
+    assert_eq!(resp.counts.diaries, 0);
+    assert_eq!(resp.rejected.len(), 1);
+    assert_eq!(resp.rejected[0].server_updated_at, 10);
+
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, send(upload(5, None, "older"))).await;
+    assert_eq!(resp.counts.diaries, 0);
+    assert_eq!(resp.rejected.len(), 1);
+    assert_eq!(resp.rejected[0].uuid, diary_uuid);
+    assert_eq!(resp.rejected[0].server_updated_at, 10);
+    assert!(!resp.rejected[0].deleted);
+
+    let stored: String = sqlx::query_scalar("SELECT payload_data FROM diary_sync WHERE uuid = $1")
+        .bind(&diary_uuid)
+        .fetch_one(&pool)
+        .await
+        .expect("read diary");
+    assert_eq!(stored, "newer");
+
+    // An edit based on the server's version applies even with a skewed clock,
+    // and still moves the stored version forward.
+    let resp: SyncUploadResponse =
+        test::call_and_read_body_json(&app, send(upload(7, Some(10), "merged"))).await;
+    assert!(resp.rejected.is_empty());
+    assert_eq!(resp.counts.diaries, 1);
+
+    let deletion = SyncUploadRequest {
+        diaries: vec![],
+        todos: vec![],
+        periods: vec![],
+        images: vec![],
+        deletions: vec![TombstoneItem {
+            uuid: diary_uuid.clone(),
+            kind: RecordKind::Diary,
+            deleted_at: 6,
+            base_updated_at: None,
+            start_date: None,
+        }],
+    };
+    let resp: SyncUploadResponse = test::call_and_read_body_json(&app, send(deletion)).await;
+    assert_eq!(resp.counts.deletions, 0);
+    assert_eq!(resp.rejected.len(), 1);
+    assert_eq!(resp.rejected[0].server_updated_at, 11);
+}
+
+#[actix_web::test]
+async fn overwritten_and_deleted_diaries_can_be_restored() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let diary_uuid = format!("d_hist_{}", unique_suffix());
+    let api_key = env::var("API_KEY").unwrap_or_default();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(syezw_sync_backend::AppState::new(
+                EnvConfig::from_env(),
+                Arc::new(PgStorage::new(pool.clone())),
+            )))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            )
+            .route(
+                "/history/list",
+                web::post().to(syezw_sync_backend::history_list),
+            )
+            .route(
+                "/history/restore",
+                web::post().to(syezw_sync_backend::history_restore),
+            ),
+    )
+    .await;
+
+    let upload = |updated_at: i64, data: &str, deletions: Vec<TombstoneItem>| SyncUploadRequest {
+        diaries: if data.is_empty() {
+            vec![]
+        } else {
+            vec![DiarySyncItem {
+                uuid: diary_uuid.clone(),
+                author: "a".to_string(),
+                timestamp: 1,
+                updated_at,
+                payload: EncryptedBlob {
+                    iv: "iv".to_string(),
+                    data: data.to_string(),
+                },
+                base_updated_at: None,
+            }]
+        },
+        todos: vec![],
+        periods: vec![],
+        images: vec![],
+        deletions,
+    };
+    for body in [upload(1, "first", vec![]), upload(2, "second", vec![])] {
+        let req = test::TestRequest::post()
+            .uri("/sync/upload")
+            .insert_header(("X-API-Key", api_key.clone()))
+            .set_json(body)
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    let list = |api_key: String| {
+        test::TestRequest::post()
+            .uri("/history/list")
+            .insert_header(("X-API-Key", api_key))
+            .set_json(HistoryListRequest {
+                kind: RecordKind::Diary,
+                uuid: diary_uuid.clone(),
+            })
+            .to_request()
+    };
+    let history: HistoryListResponse =
+        test::call_and_read_body_json(&app, list(api_key.clone())).await;
+    assert_eq!(history.revisions.len(), 1);
+    assert_eq!(history.revisions[0].payload.data, "first");
+    let first_revision = history.revisions[0].revision_id;
+
+    // Delete the diary, then bring the first version back.
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(upload(
+            0,
+            "",
+            vec![TombstoneItem {
+                uuid: diary_uuid.clone(),
+                kind: RecordKind::Diary,
+                deleted_at: 3,
+                base_updated_at: None,
+                start_date: None,
+            }],
+        ))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = test::TestRequest::post()
+        .uri("/history/restore")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(HistoryRestoreRequest {
+            kind: RecordKind::Diary,
+            uuid: diary_uuid.clone(),
+            revision_id: first_revision,
+        })
+        .to_request();
+    let restored: HistoryRestoreResponse = test::call_and_read_body_json(&app, req).await;
+    assert!(restored.ok);
+    assert!(restored.updated_at > 3);
+
+    let (data, updated_at): (String, i64) =
+        sqlx::query_as("SELECT payload_data, updated_at FROM diary_sync WHERE uuid = $1")
+            .bind(&diary_uuid)
+            .fetch_one(&pool)
+            .await
+            .expect("restored diary");
+    assert_eq!(data, "first");
+    assert_eq!(updated_at, restored.updated_at);
+    let tombstones: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM sync_tombstones WHERE uuid = $1")
+            .bind(&diary_uuid)
+            .fetch_one(&pool)
+            .await
+            .expect("count tombstones");
+    assert_eq!(tombstones, 0);
+
+    // The deleted "second" version was archived on delete and is listed too.
+    let history: HistoryListResponse = test::call_and_read_body_json(&app, list(api_key)).await;
+    assert!(history.revisions.iter().any(|r| r.payload.data == "second"));
+}
+
+#[actix_web::test]
+async fn download_pages_resume_with_continuation_token() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let uuids: Vec<String> = (0..5).map(|i| format!("d_page_{}_{}", suffix, i)).collect();
+    let api_key = env::var("API_KEY").unwrap_or_default();
+    // Paging through everything the shared account has accumulated takes
+    // more requests than one device's download budget.
+    let mut env_cfg = EnvConfig::from_env();
+    env_cfg.rate_limit_download_per_min = 0;
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(syezw_sync_backend::AppState::new(
+                env_cfg,
+                Arc::new(PgStorage::new(pool.clone())),
+            )))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            )
+            .route(
+                "/sync/download",
+                web::post().to(syezw_sync_backend::sync_download),
+            ),
+    )
+    .await;
+
+    let upload = SyncUploadRequest {
+        diaries: uuids
+            .iter()
+            .map(|uuid| DiarySyncItem {
+                uuid: uuid.clone(),
+                author: "a".to_string(),
+                timestamp: 1,
+                updated_at: 2,
+                payload: blob(),
+                base_updated_at: None,
+            })
+            .collect(),
+        todos: vec![],
+        periods: vec![],
+        images: vec![],
+        deletions: vec![],
+    };
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(&upload)
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    for (limit, max_bytes, page_max) in [(Some(2), None, 2), (None, Some(1), 1)] {
+        let mut seen = Vec::new();
+        let mut page_token = None;
+        loop {
+            let req = test::TestRequest::post()
+                .uri("/sync/download")
+                .insert_header(("X-API-Key", api_key.clone()))
+                .set_json(SyncDownloadRequest {
+                    diaries: vec![],
+                    todos: vec![],
+                    periods: vec![],
+                    limit,
+                    max_bytes,
+                    page_token: page_token.clone(),
+                })
+                .to_request();
+            let resp: SyncDownloadEnvelope = test::call_and_read_body_json(&app, req).await;
+            assert!(resp.ok);
+            let page_items =
+                resp.data.diaries.len() + resp.data.todos.len() + resp.data.periods.len();
+            assert!(page_items <= page_max);
+            seen.extend(resp.data.diaries.into_iter().map(|d| d.uuid));
+            match resp.next_page_token {
+                Some(token) => page_token = Some(token),
+                None => break,
+            }
+        }
+        for uuid in &uuids {
+            assert_eq!(
+                seen.iter().filter(|s| *s == uuid).count(),
+                1,
+                "{} once",
+                uuid
+            );
+        }
+        let mut sorted = seen.clone();
+        sorted.sort();
+        assert_eq!(seen, sorted, "diaries arrive in uuid order");
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/sync/download")
+        .insert_header(("X-API-Key", api_key))
+        .set_json(SyncDownloadRequest {
+            diaries: vec![],
+            todos: vec![],
+            periods: vec![],
+            limit: Some(1),
+            max_bytes: None,
+            page_token: Some("bogus".to_string()),
+        })
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 400);
+}
+
+#[actix_web::test]
+async fn devices_register_and_record_sync_times() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let device_id = format!("phone_{}", unique_suffix());
+    let api_key = env::var("API_KEY").unwrap_or_default();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(syezw_sync_backend::AppState::new(
+                EnvConfig::from_env(),
+                Arc::new(PgStorage::new(pool.clone())),
+            )))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            )
+            .route(
+                "/sync/download",
+                web::post().to(syezw_sync_backend::sync_download),
+            )
+            .route(
+                "/devices/register",
+                web::post().to(syezw_sync_backend::device_register),
+            )
+            .route("/devices", web::post().to(syezw_sync_backend::device_list)),
+    )
+    .await;
+
+    let req = test::TestRequest::post()
+        .uri("/devices/register")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(DeviceRegisterRequest {
+            device_id: device_id.clone(),
+            name: "Pixel".to_string(),
+            app_version: "1.2.3".to_string(),
+        })
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let list = || {
+        test::TestRequest::post()
+            .uri("/devices")
+            .insert_header(("X-API-Key", api_key.clone()))
+            .to_request()
+    };
+    let resp: DeviceListResponse = test::call_and_read_body_json(&app, list()).await;
+    let device = resp
+        .devices
+        .iter()
+        .find(|d| d.device_id == device_id)
+        .expect("registered device listed");
+    assert_eq!(device.name, "Pixel");
+    assert!(device.last_upload_at.is_none() && device.last_download_at.is_none());
+
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .insert_header((syezw_sync_backend::DEVICE_ID_HEADER, device_id.clone()))
+        .set_json(SyncUploadRequest {
+            diaries: vec![],
+            todos: vec![],
+            periods: vec![],
+            images: vec![],
+            deletions: vec![],
+        })
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let resp: DeviceListResponse = test::call_and_read_body_json(&app, list()).await;
+    let device = resp
+        .devices
+        .iter()
+        .find(|d| d.device_id == device_id)
+        .expect("device still listed");
+    assert!(device.last_upload_at.is_some());
+    assert!(device.last_download_at.is_none());
+    assert!(device.first_seen <= device.last_seen);
+}
+
+#[actix_web::test]
+async fn accounts_only_see_and_overwrite_their_own_data() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let shared_uuid = format!("d_acct_{}", suffix);
+    let shared_hash = format!("hash_acct_{}", suffix);
+    let (alice, bob) = (format!("alice_{}", suffix), format!("bob_{}", suffix));
+    let (alice_key, bob_key) = (format!("ka_{}", suffix), format!("kb_{}", suffix));
+
+    let mut env_cfg = EnvConfig::from_env();
+    env_cfg.account_keys =
+        parse_account_keys(&format!("{}:{},{}:{}", alice, alice_key, bob, bob_key));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(syezw_sync_backend::AppState::new(
+                env_cfg,
+                Arc::new(PgStorage::new(pool.clone())),
+            )))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            )
+            .route(
+                "/sync/download",
+                web::post().to(syezw_sync_backend::sync_download),
+            )
+            .route("/sync/meta", web::post().to(syezw_sync_backend::sync_meta))
+            .route(
+                "/images/hashes",
+                web::post().to(syezw_sync_backend::image_hashes),
+            ),
+    )
+    .await;
+
+    // Both accounts write the same diary uuid and image hash.
+    for (key, author, updated_at) in [(&alice_key, "alice", 10), (&bob_key, "bob", 5)] {
+        let req = test::TestRequest::post()
+            .uri("/sync/upload")
+            .insert_header(("X-API-Key", key.clone()))
+            .set_json(SyncUploadRequest {
+                diaries: vec![DiarySyncItem {
+                    uuid: shared_uuid.clone(),
+                    author: author.to_string(),
+                    timestamp: 1,
+                    updated_at,
+                    payload: blob(),
+                    base_updated_at: None,
+                }],
+                todos: vec![],
+                periods: vec![],
+                images: if author == "bob" {
+                    vec![DiaryImageSyncItem {
+                        file_name: "img.jpg".to_string(),
+                        diary_uuid: shared_uuid.clone(),
+                        hash: shared_hash.clone(),
+                        updated_at,
+                        blob: blob(),
+                    }]
+                } else {
+                    vec![]
+                },
+                deletions: vec![],
+            })
+            .to_request();
+        let resp: SyncUploadResponse = test::call_and_read_body_json(&app, req).await;
+        // Bob's older timestamp must not be rejected against Alice's row.
+        assert!(resp.ok && resp.rejected.is_empty());
+        assert_eq!(resp.counts.diaries, 1);
+    }
+
+    for (key, author) in [(&alice_key, "alice"), (&bob_key, "bob")] {
+        let req = test::TestRequest::post()
+            .uri("/sync/download")
+            .insert_header(("X-API-Key", key.clone()))
+            .set_json(SyncDownloadRequest {
+                diaries: vec![],
+                todos: vec![],
+                periods: vec![],
+                limit: None,
+                max_bytes: None,
+                page_token: None,
+            })
+            .to_request();
+        let resp: SyncDownloadEnvelope = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.data.diaries.len(), 1);
+        assert_eq!(resp.data.diaries[0].author, author);
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/images/hashes")
+        .insert_header(("X-API-Key", alice_key.clone()))
+        .to_request();
+    let resp: ImageHashListResponse = test::call_and_read_body_json(&app, req).await;
+    assert!(resp.hashes.is_empty());
+
+    let req = test::TestRequest::post()
+        .uri("/sync/meta")
+        .insert_header(("X-API-Key", bob_key.clone()))
+        .to_request();
+    let resp: SyncMetaResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(resp.diaries.len(), 1);
+    assert_eq!(resp.diaries[0].updated_at, 5);
+
+    let req = test::TestRequest::post()
+        .uri("/sync/meta")
+        .insert_header(("X-API-Key", "not-a-key"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 401);
+}
+
+#[actix_web::test]
+async fn periods_keep_uuid_across_start_date_edits_and_accept_legacy_items() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let period_uuid = format!("p_{}", suffix);
+    // A fresh account keeps start dates from colliding with earlier runs.
+    let api_key = format!("kp_{}", suffix);
+    let mut env_cfg = EnvConfig::from_env();
+    env_cfg.account_keys = parse_account_keys(&format!("periods_{}:{}", suffix, api_key));
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(syezw_sync_backend::AppState::new(
+                env_cfg,
+                Arc::new(PgStorage::new(pool.clone())),
+            )))
+            .route(
+                "/sync/upload",
+                web::post().to(syezw_sync_backend::sync_upload),
+            )
+            .route(
+                "/sync/download",
+                web::post().to(syezw_sync_backend::sync_download),
+            )
+            .route("/sync/meta", web::post().to(syezw_sync_backend::sync_meta)),
+    )
+    .await;
+
+    let period = |uuid: &str, start_date: &str, updated_at: i64| PeriodSyncItem {
+        uuid: uuid.to_string(),
+        start_date: start_date.to_string(),
+        end_date: "2025-04-30".to_string(),
+        updated_at,
+        payload: blob(),
+        base_updated_at: None,
+    };
+    let upload = |periods: Vec<PeriodSyncItem>, deletions: Vec<TombstoneItem>| {
+        test::TestRequest::post()
+            .uri("/sync/upload")
+            .insert_header(("X-API-Key", api_key.clone()))
+            .set_json(SyncUploadRequest {
+                diaries: vec![],
+                todos: vec![],
+                periods,
+                images: vec![],
+                deletions,
+            })
+            .to_request()
+    };
+    let meta = || {
+        test::TestRequest::post()
+            .uri("/sync/meta")
+            .insert_header(("X-API-Key", api_key.clone()))
+            .to_request()
+    };
+
+    // Correcting the start date updates the same record.
+    for item in [
+        period(&period_uuid, "2025-03-01", 1),
+        period(&period_uuid, "2025-03-03", 2),
+    ] {
+        let resp: SyncUploadResponse =
+            test::call_and_read_body_json(&app, upload(vec![item], vec![])).await;
+        assert_eq!(resp.counts.periods, 1);
+    }
+    let resp: SyncMetaResponse = test::call_and_read_body_json(&app, meta()).await;
+    assert_eq!(resp.periods.len(), 1);
+    assert_eq!(resp.periods[0].uuid, period_uuid);
+    assert_eq!(resp.periods[0].start_date, "2025-03-03");
+
+    // An older client without uuids edits it by start date and adds another.
+    let resp: SyncUploadResponse = test::call_and_read_body_json(
+        &app,
+        upload(
+            vec![period("", "2025-03-03", 3), period("", "2025-04-01", 4)],
+            vec![],
+        ),
+    )
+    .await;
+    assert_eq!(resp.counts.periods, 2);
+    let resp: SyncMetaResponse = test::call_and_read_body_json(&app, meta()).await;
+    assert_eq!(resp.periods.len(), 2);
+    let edited = resp
+        .periods
+        .iter()
+        .find(|p| p.uuid == period_uuid)
+        .expect("edited period kept its uuid");
+    assert_eq!(edited.updated_at, 3);
+    assert!(resp.periods.iter().any(|p| p.uuid == "period-2025-04-01"));
+
+    // The older client deletes by start date.
+    let resp: SyncUploadResponse = test::call_and_read_body_json(
+        &app,
+        upload(
+            vec![],
+            vec![TombstoneItem {
+                uuid: "2025-04-01".to_string(),
+                kind: RecordKind::Period,
+                deleted_at: 10,
+                base_updated_at: None,
+                start_date: None,
+            }],
+        ),
+    )
+    .await;
+    assert_eq!(resp.counts.deletions, 1);
+    let resp: SyncMetaResponse = test::call_and_read_body_json(&app, meta()).await;
+    assert_eq!(resp.periods.len(), 1);
+    assert!(resp
+        .deletions
+        .iter()
+        .any(|d| d.kind == RecordKind::Period && d.uuid == "period-2025-04-01"));
+
+    // Download metadata keyed by start date is still understood.
+    let req = test::TestRequest::post()
+        .uri("/sync/download")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(SyncDownloadRequest {
+            diaries: vec![],
+            todos: vec![],
+            periods: vec![PeriodMeta {
+                uuid: String::new(),
+                start_date: "2025-03-03".to_string(),
+                updated_at: 3,
+            }],
+            limit: None,
+            max_bytes: None,
+            page_token: None,
+        })
+        .to_request();
+    let resp: SyncDownloadEnvelope = test::call_and_read_body_json(&app, req).await;
+    assert!(resp.data.periods.is_empty());
+
+    // A newer client deletes the period by uuid; the older client, which
+    // holds both deleted periods by start date, is told by start date.
+    let resp: SyncUploadResponse = test::call_and_read_body_json(
+        &app,
+        upload(
+            vec![],
+            vec![TombstoneItem {
+                uuid: period_uuid.clone(),
+                kind: RecordKind::Period,
+                deleted_at: 11,
+                base_updated_at: None,
+                start_date: None,
+            }],
+        ),
+    )
+    .await;
+    assert_eq!(resp.counts.deletions, 1);
+    let legacy_meta = |start_date: &str, updated_at: i64| PeriodMeta {
+        uuid: String::new(),
+        start_date: start_date.to_string(),
+        updated_at,
+    };
+    let req = test::TestRequest::post()
+        .uri("/sync/download")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(SyncDownloadRequest {
+            diaries: vec![],
+            todos: vec![],
+            periods: vec![legacy_meta("2025-03-03", 3), legacy_meta("2025-04-01", 4)],
+            limit: None,
+            max_bytes: None,
+            page_token: None,
+        })
+        .to_request();
+    let resp: SyncDownloadEnvelope = test::call_and_read_body_json(&app, req).await;
+    let mut deleted: Vec<&str> = resp
+        .data
+        .deletions
+        .iter()
+        .map(|d| d.uuid.as_str())
+        .collect();
+    deleted.sort();
+    assert_eq!(deleted, vec!["2025-03-03", "2025-04-01"]);
+}
+
+#[actix_web::test]
+async fn fs_blob_store_keeps_only_image_metadata_in_postgres() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let (diary_uuid, hash, legacy_hash) = (
+        format!("d_blob_{}", suffix),
+        format!("hash_blob_{}", suffix),
+        format!("hash_inline_{}", suffix),
+    );
+    let api_key = env::var("API_KEY").unwrap_or_default();
+    let root = env::temp_dir().join(format!("syezw_pg_blobs_{}", suffix));
+    let blobs = FsBlobStore::open(&root).await.expect("open blob dir");
+
+    // A row written before the blob store was configured stays inline.
+    let image = |file_name: &str, hash: &str, data: &str| DiaryImageSyncItem {
+        file_name: file_name.to_string(),
+        diary_uuid: diary_uuid.clone(),
+        hash: hash.to_string(),
+        updated_at: 2,
+        blob: EncryptedBlob {
+            iv: "iv".to_string(),
+            data: data.to_string(),
+        },
+    };
+    PgStorage::new(pool.clone())
+        .put_images("default", &[image("old.jpg", &legacy_hash, "aW5saW5l")])
+        .await
+        .expect("inline image");
+    PgStorage::new(pool.clone())
+        .put_image_refs(
+            "default",
+            &[DiaryImageRefItem {
+                diary_uuid: diary_uuid.clone(),
+                file_name: "old.jpg".to_string(),
+                hash: legacy_hash.clone(),
+                updated_at: 2,
+            }],
+        )
+        .await
+        .expect("inline ref");
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(syezw_sync_backend::AppState::new(
+                EnvConfig::from_env(),
+                Arc::new(PgStorage::new(pool.clone()).with_blob_store(Arc::new(blobs))),
+            )))
+            .configure(syezw_sync_backend::configure_routes),
+    )
+    .await;
+
+    let upload = SyncUploadRequest {
+        diaries: vec![],
+        todos: vec![],
+        periods: vec![],
+        images: vec![image("new.jpg", &hash, "Y2lwaGVydGV4dA==")],
+        deletions: vec![],
+    };
+    let req = test::TestRequest::post()
+        .uri("/sync/upload")
+        .insert_header(("X-API-Key", api_key.clone()))
+        .set_json(&upload)
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let blob_data: Option<String> = sqlx::query_scalar(
+        "SELECT blob_data FROM diary_images WHERE account_id = 'default' AND hash = $1",
+    )
+    .bind(&hash)
+    .fetch_one(&pool)
+    .await
+    .expect("image row");
+    assert!(blob_data.is_none(), "ciphertext kept out of the row");
+    let path = root.join("default").join(&hash[..2]).join(&hash);
+    assert_eq!(std::fs::read(path).expect("blob file"), b"ciphertext");
+
+    for (file_name, data) in [("new.jpg", "Y2lwaGVydGV4dA=="), ("old.jpg", "aW5saW5l")] {
+        let req = test::TestRequest::post()
+            .uri("/images/fetch")
+            .insert_header(("X-API-Key", api_key.clone()))
+            .set_json(ImageFetchRequest {
+                diary_uuid: diary_uuid.clone(),
+                file_name: file_name.to_string(),
+            })
+            .to_request();
+        let resp: ImageFetchResponse = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resp.blob.data, data, "{}", file_name);
+    }
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[actix_web::test]
+async fn move_inline_blobs_empties_postgres_blob_data() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let hash = format!("hash_move_{}", suffix);
+    let item = DiaryImageSyncItem {
+        file_name: "moved.jpg".to_string(),
+        diary_uuid: format!("d_move_{}", suffix),
+        hash: hash.clone(),
+        updated_at: 2,
+        blob: EncryptedBlob {
+            iv: "iv".to_string(),
+            data: "bW92ZWQ=".to_string(),
+        },
+    };
+    PgStorage::new(pool.clone())
+        .put_images("default", &[item])
+        .await
+        .expect("inline image");
+
+    let root = env::temp_dir().join(format!("syezw_pg_move_{}", suffix));
+    let blobs = FsBlobStore::open(&root).await.expect("open blob dir");
+    let storage = PgStorage::new(pool.clone()).with_blob_store(Arc::new(blobs));
+    let report = storage.move_inline_blobs().await.expect("move blobs");
+    assert!(report.moved >= 1);
+    assert_eq!(
+        std::fs::read(root.join("default").join(&hash[..2]).join(&hash)).unwrap(),
+        b"moved"
+    );
+    let (inline,): (Option<String>,) = sqlx::query_as(
+        "SELECT blob_data FROM diary_images WHERE account_id = 'default' AND hash = $1",
+    )
+    .bind(&hash)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    assert_eq!(inline, None);
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[actix_web::test]
+async fn resumable_upload_in_postgres() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let storage = PgStorage::new(pool.clone());
+    let hash = format!("hash_chunked_{}", unique_suffix());
+    let upload = NewImageUpload {
+        iv: "iv".to_string(),
+        size: 6,
+        updated_at: 5,
+    };
+    let status = storage
+        .begin_image_upload("default", &hash, &upload, 1)
+        .await
+        .unwrap();
+    assert_eq!(status.offset, 0);
+    let outcome = storage
+        .append_image_chunk("default", &hash, 0, b"abc", 2)
+        .await
+        .unwrap();
+    assert!(matches!(outcome, Some(ChunkOutcome::Appended(s)) if s.offset == 3));
+    let outcome = storage
+        .append_image_chunk("default", &hash, 0, b"abc", 2)
+        .await
+        .unwrap();
+    assert!(matches!(outcome, Some(ChunkOutcome::OffsetMismatch(s)) if s.offset == 3));
+    // Resuming with the same parameters keeps the received bytes.
+    let status = storage
+        .begin_image_upload("default", &hash, &upload, 2)
+        .await
+        .unwrap();
+    assert_eq!(status.offset, 3);
+    assert!(matches!(
+        storage
+            .finalize_image_upload("default", &hash)
+            .await
+            .unwrap(),
+        Some(FinalizeOutcome::Incomplete(_))
+    ));
+    assert!(storage
+        .image_bytes("default", &hash)
+        .await
+        .unwrap()
+        .is_none());
+
+    storage
+        .append_image_chunk("default", &hash, 3, b"def", 3)
+        .await
+        .unwrap();
+    assert_eq!(
+        storage
+            .finalize_image_upload("default", &hash)
+            .await
+            .unwrap(),
+        Some(FinalizeOutcome::Stored)
+    );
+    let image = storage
+        .image_bytes("default", &hash)
+        .await
+        .unwrap()
+        .expect("finalized image");
+    assert_eq!(image.data, b"abcdef");
+    assert_eq!(image.updated_at, 5);
+    assert!(storage
+        .image_upload_status("default", &hash)
+        .await
+        .unwrap()
+        .is_none());
+
+    // An abandoned session goes once it has been idle past the cutoff; one
+    // touched since stays.
+    let stale = format!("{}_stale", hash);
+    storage
+        .begin_image_upload("default", &stale, &upload, 10)
+        .await
+        .unwrap();
+    storage
+        .append_image_chunk("default", &stale, 0, b"abc", 20)
+        .await
+        .unwrap();
+    storage.expire_image_uploads(20).await.unwrap();
+    assert!(storage
+        .image_upload_status("default", &stale)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(storage.expire_image_uploads(21).await.unwrap() >= 1);
+    assert!(storage
+        .image_upload_status("default", &stale)
+        .await
+        .unwrap()
+        .is_none());
+    let (chunks,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM image_upload_chunks WHERE hash = $1")
+            .bind(&stale)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(chunks, 0);
+}
+
+#[actix_web::test]
+async fn orphaned_images_are_deleted_from_postgres_and_blob_store() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let (orphan, used) = (
+        format!("gc_orphan_{}", suffix),
+        format!("gc_used_{}", suffix),
+    );
+    let root = env::temp_dir().join(format!("syezw_pg_gc_{}", suffix));
+    let blobs = FsBlobStore::open(&root).await.expect("open blob dir");
+    let storage = PgStorage::new(pool.clone()).with_blob_store(Arc::new(blobs));
+    let image = |hash: &str| DiaryImageSyncItem {
+        file_name: format!("{}.jpg", hash),
+        diary_uuid: format!("d_gc_{}", suffix),
+        hash: hash.to_string(),
+        updated_at: 1,
+        blob: EncryptedBlob {
+            iv: "iv".to_string(),
+            data: "Z2M=".to_string(),
+        },
+    };
+    storage
+        .put_images("default", &[image(&orphan), image(&used)])
+        .await
+        .unwrap();
+    storage
+        .put_image_refs(
+            "default",
+            &[DiaryImageRefItem {
+                diary_uuid: format!("d_gc_{}", suffix),
+                file_name: "used.jpg".to_string(),
+                hash: used.clone(),
+                updated_at: 1,
+            }],
+        )
+        .await
+        .unwrap();
+
+    let cutoff = chrono::Utc::now().timestamp_millis() + 1;
+    let orphans = storage.orphaned_images(cutoff).await.unwrap();
+    assert!(orphans.iter().any(|o| o.hash == orphan));
+    assert!(!orphans.iter().any(|o| o.hash == used));
+
+    assert!(!storage
+        .delete_orphaned_image("default", &used, cutoff)
+        .await
+        .unwrap());
+    assert!(storage
+        .delete_orphaned_image("default", &orphan, cutoff)
+        .await
+        .unwrap());
+    assert!(!root.join("default").join("gc").join(&orphan).exists());
+    assert!(root.join("default").join("gc").join(&used).exists());
+    let hashes = storage.image_hashes("default").await.unwrap();
+    assert!(hashes.contains(&used) && !hashes.contains(&orphan));
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[actix_web::test]
+async fn image_refs_filter_delete_and_replace_in_postgres() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let (diary_a, diary_b) = (format!("d_ra_{}", suffix), format!("d_rb_{}", suffix));
+    let storage = PgStorage::new(pool.clone());
+    let image_ref = |diary_uuid: &str, file_name: &str, updated_at: i64| DiaryImageRefItem {
+        diary_uuid: diary_uuid.to_string(),
+        file_name: file_name.to_string(),
+        hash: format!("h_{}_{}", file_name, suffix),
+        updated_at,
+    };
+    storage
+        .put_image_refs(
+            "default",
+            &[
+                image_ref(&diary_a, "a1.jpg", 1),
+                image_ref(&diary_a, "a2.jpg", 2),
+                image_ref(&diary_b, "b1.jpg", 3),
+            ],
+        )
+        .await
+        .unwrap();
+    let list = |updated_after| ImageRefsRequest {
+        diary_uuids: Some(vec![diary_a.clone(), diary_b.clone()]),
+        updated_after,
+    };
+    let names = |refs: Vec<DiaryImageRefItem>| {
+        let mut names: Vec<_> = refs.into_iter().map(|r| r.file_name).collect();
+        names.sort();
+        names
+    };
+    let refs = storage.image_refs("default", &list(Some(1))).await.unwrap();
+    assert_eq!(names(refs), vec!["a2.jpg", "b1.jpg"]);
+
+    let keys = [ImageRefKey {
+        diary_uuid: diary_b.clone(),
+        file_name: "b1.jpg".to_string(),
+    }];
+    assert_eq!(
+        storage.delete_image_refs("default", &keys).await.unwrap(),
+        1
+    );
+    assert_eq!(
+        storage.delete_image_refs("default", &keys).await.unwrap(),
+        0
+    );
+
+    let removed = storage
+        .replace_diary_image_refs("default", &diary_a, &[image_ref(&diary_a, "a3.jpg", 4)])
+        .await
+        .unwrap();
+    assert_eq!(removed, 2);
+    let refs = storage.image_refs("default", &list(None)).await.unwrap();
+    assert_eq!(names(refs), vec!["a3.jpg"]);
+}
+
+#[actix_web::test]
+async fn api_key_registry_round_trip_in_postgres() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let storage = PgStorage::new(pool.clone());
+    let account = format!("acct_keys_{}", suffix);
+    let new_key = |id: &str| NewApiKey {
+        id: format!("{}_{}", id, suffix),
+        account_id: account.clone(),
+        name: id.to_string(),
+        key_hash: format!("hash_{}_{}", id, suffix),
+        scopes: vec![ApiScope::Write],
+        created_at: 5,
+    };
+    let created = storage.create_api_key(&new_key("k1")).await.unwrap();
+    storage.create_api_key(&new_key("k2")).await.unwrap();
+    assert!(storage.has_api_keys().await.unwrap());
+    let found = storage
+        .api_key_by_hash(&format!("hash_k1_{}", suffix))
+        .await
+        .unwrap();
+    assert_eq!(found, Some(created.clone()));
+
+    storage.touch_api_key(&created.id, 7).await.unwrap();
+    let revoked = storage
+        .revoke_api_key(&created.id, 9)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        (revoked.last_used_at, revoked.revoked_at),
+        (Some(7), Some(9))
+    );
+    let listed = storage.list_api_keys(Some(&account)).await.unwrap();
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0], revoked);
+    assert_eq!(listed[1].scopes, vec![ApiScope::Write]);
+
+    // Other tests may run with auth disabled, which any registered key ends.
+    sqlx::query("DELETE FROM api_keys WHERE account_id = $1")
+        .bind(&account)
+        .execute(&pool)
+        .await
+        .unwrap();
+}
+
+#[actix_web::test]
+async fn auth_sessions_rotate_and_revoke_in_postgres() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let storage = PgStorage::new(pool.clone());
+    let account = format!("acct_sessions_{}", suffix);
+    let session = |id: &str, key_id: Option<&str>| AuthSession {
+        id: format!("{}_{}", id, suffix),
+        account_id: account.clone(),
+        key_id: key_id.map(str::to_string),
+        scopes: vec![ApiScope::Read, ApiScope::Write],
+        refresh_hash: "r0".to_string(),
+        previous_refresh_hash: None,
+        created_at: 1,
+        refreshed_at: 1,
+        expires_at: 100,
+        revoked_at: None,
+    };
+    let (s1, s2) = (session("s1", None), session("s2", Some("k1")));
+    storage.create_auth_session(&s1).await.unwrap();
+    storage.create_auth_session(&s2).await.unwrap();
+    assert_eq!(
+        storage.auth_session(&s1.id).await.unwrap(),
+        Some(s1.clone())
+    );
+
+    assert!(storage
+        .rotate_auth_session(&s1.id, "r0", "r1", 5, 200)
+        .await
+        .unwrap());
+    assert!(!storage
+        .rotate_auth_session(&s1.id, "r0", "r2", 6, 300)
+        .await
+        .unwrap());
+    assert_eq!(
+        storage
+            .revoke_auth_sessions(&account, Some("k1"), 7)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        storage
+            .revoke_auth_sessions(&account, None, 8)
+            .await
+            .unwrap(),
+        1
+    );
+    let s1 = storage.auth_session(&s1.id).await.unwrap().unwrap();
+    assert_eq!((s1.refresh_hash.as_str(), s1.revoked_at), ("r1", Some(8)));
+    assert!(!storage.revoke_auth_session(&s1.id, 9).await.unwrap());
+}
+
+#[actix_web::test]
+async fn auth_failures_are_logged_in_postgres() {
+    let Some(pool) = test_pool().await else {
+        return;
+    };
+    let suffix = unique_suffix();
+    let storage = PgStorage::new(pool.clone());
+    let ip = format!("test-{}", suffix);
+    let now = chrono::Utc::now().timestamp_millis();
+    storage
+        .record_auth_failure(&ip, "/sync/meta", "api key", now)
+        .await
+        .unwrap();
+    storage
+        .record_auth_failure(&ip, "/auth/refresh", "refresh token", now + 1)
+        .await
+        .unwrap();
+    let ours: Vec<_> = storage
+        .auth_failures(now, 1000)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|f| f.ip == ip)
+        .collect();
+    assert_eq!(
+        ours.iter()
+            .map(|f| (f.path.as_str(), f.reason.as_str()))
+            .collect::<Vec<_>>(),
+        vec![
+            ("/auth/refresh", "refresh token"),
+            ("/sync/meta", "api key")
+        ]
+    );
+
+    // Rows older than the cutoff are pruned; the two just written are not.
+    storage
+        .record_auth_failure(&ip, "/sync/meta", "api key", 1)
+        .await
+        .unwrap();
+    assert!(storage.prune_auth_failures(now).await.unwrap() >= 1);
+    let left = storage.auth_failures(0, 1000).await.unwrap();
+    assert_eq!(left.iter().filter(|f| f.ip == ip).count(), 2);
+
+    sqlx::query("DELETE FROM auth_failures WHERE ip = $1")
+        .bind(&ip)
+        .execute(&pool)
+        .await
+        .unwrap();
+}